@@ -1,5 +1,6 @@
 use authc::{AuthClient, AuthToken};
 use clap::{load_yaml, App};
+use std::io::{self, BufRead, Write};
 
 fn main() {
     let yml = load_yaml!("cli.yml");
@@ -8,31 +9,84 @@ fn main() {
 
     match matches.subcommand() {
         ("register", Some(args)) => {
-            let username = get_arg(&args, "username", "Please specify the username.");
-            let password = get_arg(&args, "password", "Please specify the password.");
-            let ethaddr = get_arg(&args, "ethaddr", "Please specify the ethrum address.");
-            let auth = set_auth_server(&args);
+            let username = get_arg(args, "username", "Please specify the username.");
+            let password = get_arg(args, "password", "Please specify the password.");
+            let ethaddr = get_arg(args, "ethaddr", "Please specify the ethrum address.");
+            let auth = set_auth_server(args);
 
-            if let Err(e) = auth.register(&username, &password, &ethaddr) {
-                exit_with(format!("Register failed with: {}", e));
+            match auth.register(&username, &password, &ethaddr) {
+                Ok(uuid) => println!("Registered {} with uuid {}", username, uuid),
+                Err(e) => exit_with(format!("Register failed with: {}", e)),
+            }
+        }
+        ("login", Some(args)) if args.is_present("device") => {
+            let auth = set_auth_server(args);
+            let device = match auth.start_device_login() {
+                Ok(device) => device,
+                Err(e) => exit_with(format!("Login failed with: {}", e)),
+            };
+            println!("Open {} and enter the code {}", device.verification_uri, device.user_code);
+            println!("Waiting for approval...");
+            match auth.wait_for_device_login(&device) {
+                Ok(token) => {
+                    println!("Auth Token: {}", token.serialize());
+                }
+                Err(e) => exit_with(format!("Login failed with: {}", e)),
             }
-            println!("Successfully register req sended :{}", username);
         }
         ("login", Some(args)) => {
-            let username = get_arg(&args, "username", "Please specify the username.");
-            let password = get_arg(&args, "password", "Please specify the password.");
-            let auth = set_auth_server(&args);
+            let username = get_arg(args, "username", "Please specify the username.");
+            let password = get_arg(args, "password", "Please specify the password.");
+            let auth = set_auth_server(args);
 
-            match auth.sign_in(&username, &password) {
+            let result = match args.value_of("otp") {
+                Some(otp) => auth.sign_in_with_otp(&username, &password, otp),
+                None => auth.sign_in(&username, &password),
+            };
+            match result {
                 Ok(token) => {
                     println!("Auth Token: {}", token.serialize());
                 }
                 Err(e) => exit_with(format!("Login failed with: {}", e)),
             }
         }
+        ("2fa-enroll", Some(args)) => {
+            let username = get_arg(args, "username", "Please specify the username.");
+            let password = get_arg(args, "password", "Please specify the password.");
+            let auth = set_auth_server(args);
+
+            let uuid = match auth.username_to_uuid(&username) {
+                Ok(id) => id,
+                Err(e) => exit_with(format!("Retrieving UUID failed with: {}", e)),
+            };
+            let enrollment = match auth.begin_totp(uuid, &password) {
+                Ok(enrollment) => enrollment,
+                Err(e) => exit_with(format!("Enrollment failed with: {}", e)),
+            };
+            println!("Add this account to your authenticator app:");
+            println!("  Secret: {}", enrollment.secret);
+            println!("  URI:    {}", enrollment.uri);
+            print!("Then enter the code it shows: ");
+            let _ = io::stdout().flush();
+            let mut otp = String::new();
+            if io::stdin().lock().read_line(&mut otp).is_err() {
+                exit_with("Could not read the code.");
+            }
+
+            match auth.confirm_totp(uuid, &password, otp.trim()) {
+                Ok(codes) => {
+                    println!("Two-factor authentication is enabled. Keep these recovery codes safe,");
+                    println!("each one signs you in once if you lose your authenticator:");
+                    for code in codes {
+                        println!("  {}", code);
+                    }
+                }
+                Err(e) => exit_with(format!("Confirming the code failed with: {}", e)),
+            }
+        }
         ("uuid", Some(args)) => {
-            let username = get_arg(&args, "username", "Please specify the username.");
-            let auth = set_auth_server(&args);
+            let username = get_arg(args, "username", "Please specify the username.");
+            let auth = set_auth_server(args);
 
             match auth.username_to_uuid(&username) {
                 Ok(id) => {
@@ -43,11 +97,11 @@ fn main() {
         }
         ("validate", Some(args)) => {
             let token: AuthToken =
-                match get_arg(&args, "token", "Please specify the token to verify.").parse() {
+                match get_arg(args, "token", "Please specify the token to verify.").parse() {
                     Ok(token) => token,
                     Err(e) => exit_with(format!("failed to parse token: {}", e)),
                 };
-            let auth = set_auth_server(&args);
+            let auth = set_auth_server(args);
 
             match auth.validate(token) {
                 Ok(id) => {
@@ -69,9 +123,9 @@ fn set_auth_server(args: &clap::ArgMatches) -> AuthClient {
     }
 }
 
-fn get_arg<T: std::fmt::Display>(args: &clap::ArgMatches, arg: T, error_msg: T) -> String
+fn get_arg<T>(args: &clap::ArgMatches, arg: T, error_msg: T) -> String
 where
-    T: std::convert::AsRef<str>,
+    T: std::fmt::Display + std::convert::AsRef<str>,
 {
     match args.value_of(arg) {
         Some(x) => x.to_string(),
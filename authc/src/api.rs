@@ -0,0 +1,539 @@
+//! The requests both clients send and how they read the answers. The
+//! blocking and the async client only differ in how they wait for the
+//! network.
+
+use crate::cache::Entry;
+use crate::{net_prehash, ApiVersion, AuthClientError, DevicePoll, RegisterError};
+use auth_common::{
+    AuthToken, ChangePassPayload, DeviceAuthorizationResponse, DeviceTokenPayload,
+    EmailChangePayload, EthActivePayload, EthBatchPayload, EthLookupPayload, EthLookupResponse,
+    OAuthErrorResponse, PasswordChangePayload, PasswordResetPayload, PasswordResetRequestPayload,
+    RegisterPayload, RegisterResponse, SearchVisibilityPayload, SignInPayload, SignInResponse, TotpConfirmPayload,
+    TotpConfirmResponse, TotpEnrollPayload, TotpEnrollResponse, Userinfo2LookupPayload,
+    Userinfo2LookupResponse, UserinfoLookupPayload, UserinfoLookupResponse, UserBatchResponse,
+    UserInfoResponse, UserSearchResponse, UsernameBatchPayload, UsernameLookupPayload,
+    UsernameLookupResponse, UuidBatchPayload, UuidLookupPayload, UuidLookupResponse,
+    ValidityCheckPayload, ValidityCheckResponse,
+};
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+/// A stream read that waits this long without even a keepalive gives up on
+/// the connection and reconnects.
+pub(crate) const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+pub(crate) type UserBatch = BTreeMap<String, Option<UserInfoResponse>>;
+
+/// A response, read to the end.
+pub(crate) struct Reply {
+    pub status: u16,
+    /// The `X-Error-Code` the server names errors with.
+    pub code: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// The `X-Error-Code` header of a response.
+pub(crate) fn error_code(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("X-Error-Code")
+        .and_then(|code| code.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// `value` as a single path segment. Everything but unreserved characters is
+/// percent-encoded, so a `/`, `?`, `#` or `%` in it can't change the route.
+fn segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// One request, and how to read its response.
+pub(crate) struct Request<T> {
+    pub method: Method,
+    /// Relative to the provider it is sent to.
+    pub path: String,
+    /// Lookups, which may be sent again when they fail.
+    pub idempotent: bool,
+    /// Sent as JSON.
+    pub body: Option<Vec<u8>>,
+    pub bearer: Option<String>,
+    pub headers: Vec<(&'static str, String)>,
+    pub timeout: Option<Duration>,
+    pub read: fn(Reply) -> Result<T, AuthClientError>,
+}
+
+/// Which routes requests use.
+#[derive(Debug, Clone)]
+pub(crate) struct Api {
+    pub version: ApiVersion,
+}
+
+impl Api {
+    pub fn new(version: ApiVersion) -> Self {
+        Self { version }
+    }
+
+    fn request<T>(
+        &self,
+        method: Method,
+        path: &str,
+        read: fn(Reply) -> Result<T, AuthClientError>,
+    ) -> Result<Request<T>, AuthClientError> {
+        Ok(Request {
+            idempotent: method == Method::GET,
+            method,
+            path: path.to_owned(),
+            body: None,
+            bearer: None,
+            headers: Vec::new(),
+            timeout: None,
+            read,
+        })
+    }
+
+    fn post<T>(
+        &self,
+        path: &str,
+        data: &impl Serialize,
+        read: fn(Reply) -> Result<T, AuthClientError>,
+    ) -> Result<Request<T>, AuthClientError> {
+        self.with_body(Method::POST, path, data, read)
+    }
+
+    /// A `POST` that only reads, so it can be sent again.
+    fn lookup<T>(
+        &self,
+        path: &str,
+        data: &impl Serialize,
+        read: fn(Reply) -> Result<T, AuthClientError>,
+    ) -> Result<Request<T>, AuthClientError> {
+        let mut request = self.post(path, data, read)?;
+        request.idempotent = true;
+        Ok(request)
+    }
+
+    fn with_body<T>(
+        &self,
+        method: Method,
+        path: &str,
+        data: &impl Serialize,
+        read: fn(Reply) -> Result<T, AuthClientError>,
+    ) -> Result<Request<T>, AuthClientError> {
+        let mut request = self.request(method, path, read)?;
+        request.body = Some(serde_json::to_vec(data)?);
+        Ok(request)
+    }
+
+    fn legacy_or_v1(&self, legacy: &'static str, v1: &'static str) -> &'static str {
+        match self.version {
+            ApiVersion::Legacy => legacy,
+            ApiVersion::V1 => v1,
+        }
+    }
+
+    pub fn register(&self, username: &str, password: &str, ethaddr: &str) -> Result<Request<Uuid>, AuthClientError> {
+        let data = RegisterPayload {
+            username: username.to_owned(),
+            password: net_prehash(password),
+            ethaddr: ethaddr.to_owned(),
+        };
+        self.post(self.legacy_or_v1("register", "v1/users"), &data, read_register)
+    }
+
+    pub fn username_to_uuid(&self, username: &str) -> Result<Request<Entry<Uuid>>, AuthClientError> {
+        if self.version == ApiVersion::V1 {
+            return self.request(Method::GET, &format!("v1/users/by-name/{}", segment(username)), |reply| {
+                read_entry(reply, USER_MISSING, |reply| Ok(read_json::<UserInfoResponse>(reply)?.uuid))
+            });
+        }
+        let data = UuidLookupPayload {
+            username: username.to_owned(),
+        };
+        self.lookup("username_to_uuid", &data, |reply| {
+            read_entry(reply, USER_MISSING, |reply| Ok(read_json::<UuidLookupResponse>(reply)?.uuid))
+        })
+    }
+
+    pub fn uuid_to_username(&self, uuid: Uuid) -> Result<Request<Entry<String>>, AuthClientError> {
+        if self.version == ApiVersion::V1 {
+            return self.request(Method::GET, &format!("v1/users/{}", uuid), |reply| {
+                read_entry(reply, USER_MISSING, |reply| Ok(read_json::<UserInfoResponse>(reply)?.username))
+            });
+        }
+        self.lookup("uuid_to_username", &UsernameLookupPayload { uuid }, |reply| {
+            read_entry(reply, USER_MISSING, |reply| Ok(read_json::<UsernameLookupResponse>(reply)?.username))
+        })
+    }
+
+    /// `uuid_to_username` on the legacy API, where batch lookups are made of
+    /// single ones, with an unknown uuid read as `None`.
+    pub fn legacy_username_of(&self, uuid: Uuid) -> Result<Request<Option<String>>, AuthClientError> {
+        self.lookup("uuid_to_username", &UsernameLookupPayload { uuid }, |reply| {
+            if is_missing(&reply) {
+                return Ok(None);
+            }
+            Ok(Some(read_json::<UsernameLookupResponse>(reply)?.username))
+        })
+    }
+
+    /// Like `legacy_username_of`, for `username_to_uuid`.
+    pub fn legacy_uuid_of(&self, username: &str) -> Result<Request<Option<Uuid>>, AuthClientError> {
+        let data = UuidLookupPayload {
+            username: username.to_owned(),
+        };
+        self.lookup("username_to_uuid", &data, |reply| {
+            if is_missing(&reply) {
+                return Ok(None);
+            }
+            Ok(Some(read_json::<UuidLookupResponse>(reply)?.uuid))
+        })
+    }
+
+    pub fn ping(&self) -> Result<Request<String>, AuthClientError> {
+        self.request(Method::GET, self.legacy_or_v1("ping", "v1/ping"), read_text)
+    }
+
+    pub fn eth_to_info(&self, ethaddr: &str) -> Result<Request<Entry<EthLookupResponse>>, AuthClientError> {
+        if self.version == ApiVersion::V1 {
+            return self.request(Method::GET, &format!("v1/users/by-eth/{}", segment(ethaddr)), |reply| {
+                read_entry(reply, ETHADDR_MISSING, |reply| {
+                    let info = read_json::<UserInfoResponse>(reply)?;
+                    Ok(EthLookupResponse {
+                        username: info.username,
+                        uuid: info.uuid,
+                        actived: info.actived,
+                    })
+                })
+            });
+        }
+        let data = EthLookupPayload {
+            ethaddr: ethaddr.to_owned(),
+        };
+        self.lookup("eth_to_info", &data, |reply| read_entry(reply, ETHADDR_MISSING, read_json))
+    }
+
+    pub fn username_to_info(&self, username: &str) -> Result<Request<UserinfoLookupResponse>, AuthClientError> {
+        if self.version == ApiVersion::V1 {
+            return self.request(Method::GET, &format!("v1/users/by-name/{}", segment(username)), |reply| {
+                let info = read_json::<UserInfoResponse>(reply)?;
+                Ok(UserinfoLookupResponse {
+                    uuid: info.uuid,
+                    ethaddr: info.ethaddr,
+                })
+            });
+        }
+        let data = UserinfoLookupPayload {
+            username: username.to_owned(),
+        };
+        self.lookup("username_to_info", &data, read_json)
+    }
+
+    pub fn uuid_to_info(&self, uuid: Uuid) -> Result<Request<Userinfo2LookupResponse>, AuthClientError> {
+        if self.version == ApiVersion::V1 {
+            return self.request(Method::GET, &format!("v1/users/{}", uuid), |reply| {
+                let info = read_json::<UserInfoResponse>(reply)?;
+                Ok(Userinfo2LookupResponse {
+                    username: info.username,
+                    ethaddr: info.ethaddr,
+                })
+            });
+        }
+        self.lookup("uuid_to_info", &Userinfo2LookupPayload { uuid }, read_json)
+    }
+
+    pub fn user_info(&self, uuid: Uuid) -> Result<Request<UserInfoResponse>, AuthClientError> {
+        self.request(Method::GET, &format!("v1/users/{}", uuid), read_json)
+    }
+
+    pub fn user_info_by_name(&self, username: &str) -> Result<Request<UserInfoResponse>, AuthClientError> {
+        self.request(Method::GET, &format!("v1/users/by-name/{}", segment(username)), read_json)
+    }
+
+    pub fn user_info_by_eth(&self, ethaddr: &str) -> Result<Request<UserInfoResponse>, AuthClientError> {
+        self.request(Method::GET, &format!("v1/users/by-eth/{}", segment(ethaddr)), read_json)
+    }
+
+    pub fn search_users(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        after: Option<&str>,
+    ) -> Result<Request<UserSearchResponse>, AuthClientError> {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("prefix", prefix);
+        if let Some(limit) = limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+        if let Some(after) = after {
+            query.append_pair("after", after);
+        }
+        self.request(Method::GET, &format!("v1/users/search?{}", query.finish()), read_json)
+    }
+
+    pub fn eth_active(&self, ethaddr: &str) -> Result<Request<()>, AuthClientError> {
+        if self.version == ApiVersion::V1 {
+            return self.request(Method::PUT, &format!("v1/users/by-eth/{}/active", segment(ethaddr)), read_empty);
+        }
+        let data = EthActivePayload {
+            ethaddr: ethaddr.to_owned(),
+        };
+        self.post("eth_active", &data, read_empty)
+    }
+
+    pub fn change_password(&self, ethaddr: &str, password: &str) -> Result<Request<()>, AuthClientError> {
+        let data = ChangePassPayload {
+            ethaddr: ethaddr.to_owned(),
+            password: net_prehash(password),
+        };
+        self.post("change_pass", &data, read_empty)
+    }
+
+    pub fn change_password_by_uuid(
+        &self,
+        uuid: Uuid,
+        current_password: &str,
+        password: &str,
+    ) -> Result<Request<()>, AuthClientError> {
+        let data = PasswordChangePayload {
+            current_password: net_prehash(current_password),
+            password: net_prehash(password),
+        };
+        self.with_body(Method::PUT, &format!("v1/users/{}/password", uuid), &data, read_empty)
+    }
+
+    pub fn set_searchable(&self, uuid: Uuid, password: &str, searchable: bool) -> Result<Request<()>, AuthClientError> {
+        let data = SearchVisibilityPayload {
+            password: net_prehash(password),
+            searchable,
+        };
+        self.with_body(Method::PUT, &format!("v1/users/{}/searchable", uuid), &data, read_empty)
+    }
+
+    pub fn set_email(&self, uuid: Uuid, password: &str, email: &str) -> Result<Request<()>, AuthClientError> {
+        let data = EmailChangePayload {
+            password: net_prehash(password),
+            email: email.trim().to_owned(),
+        };
+        self.with_body(Method::PUT, &format!("v1/users/{}/email", uuid), &data, read_empty)
+    }
+
+    pub fn uuids_batch(&self, uuids: &[Uuid]) -> Result<Request<UserBatch>, AuthClientError> {
+        let data = UuidBatchPayload {
+            uuids: uuids.to_vec(),
+        };
+        self.lookup("v1/users/batch/by-uuid", &data, read_batch)
+    }
+
+    pub fn usernames_batch<S: AsRef<str>>(&self, usernames: &[S]) -> Result<Request<UserBatch>, AuthClientError> {
+        let data = UsernameBatchPayload {
+            usernames: usernames.iter().map(|u| u.as_ref().to_owned()).collect(),
+        };
+        self.lookup("v1/users/batch/by-name", &data, read_batch)
+    }
+
+    pub fn ethaddrs_batch<S: AsRef<str>>(&self, ethaddrs: &[S]) -> Result<Request<UserBatch>, AuthClientError> {
+        let data = EthBatchPayload {
+            ethaddrs: ethaddrs.iter().map(|e| e.as_ref().to_owned()).collect(),
+        };
+        self.lookup("v1/users/batch/by-eth", &data, read_batch)
+    }
+
+    pub fn request_password_reset(&self, login: &str) -> Result<Request<()>, AuthClientError> {
+        let data = PasswordResetRequestPayload {
+            login: login.to_owned(),
+        };
+        self.post("v1/password-reset", &data, read_empty)
+    }
+
+    pub fn reset_password(&self, token: &str, password: &str) -> Result<Request<()>, AuthClientError> {
+        let data = PasswordResetPayload {
+            token: token.trim().to_owned(),
+            password: net_prehash(password),
+        };
+        self.post("v1/password-reset/complete", &data, read_empty)
+    }
+
+    pub fn sign_in(&self, username: &str, password: &str, otp: Option<String>) -> Result<Request<AuthToken>, AuthClientError> {
+        let data = SignInPayload {
+            username: username.to_owned(),
+            password: net_prehash(password),
+            otp,
+        };
+        self.post(self.legacy_or_v1("generate_token", "v1/tokens"), &data, |reply| {
+            Ok(read_json::<SignInResponse>(reply)?.token)
+        })
+    }
+
+    pub fn begin_totp(&self, uuid: Uuid, password: &str) -> Result<Request<TotpEnrollResponse>, AuthClientError> {
+        let data = TotpEnrollPayload {
+            password: net_prehash(password),
+        };
+        self.post(&format!("v1/users/{}/2fa", uuid), &data, read_json)
+    }
+
+    pub fn confirm_totp(&self, uuid: Uuid, password: &str, otp: &str) -> Result<Request<Vec<String>>, AuthClientError> {
+        let data = TotpConfirmPayload {
+            password: net_prehash(password),
+            otp: otp.trim().to_owned(),
+        };
+        self.post(&format!("v1/users/{}/2fa/confirm", uuid), &data, |reply| {
+            Ok(read_json::<TotpConfirmResponse>(reply)?.recovery_codes)
+        })
+    }
+
+    pub fn start_device_login(&self) -> Result<Request<DeviceAuthorizationResponse>, AuthClientError> {
+        self.request(Method::POST, "v1/device", read_json)
+    }
+
+    pub fn poll_device_login(&self, device: &DeviceAuthorizationResponse) -> Result<Request<DevicePoll>, AuthClientError> {
+        let data = DeviceTokenPayload {
+            device_code: device.device_code.clone(),
+        };
+        self.post("v1/device/token", &data, read_device_poll)
+    }
+
+    pub fn validate(&self, token: AuthToken) -> Result<Request<Uuid>, AuthClientError> {
+        let data = ValidityCheckPayload { token };
+        self.post(self.legacy_or_v1("verify", "v1/tokens/verify"), &data, |reply| {
+            Ok(read_json::<ValidityCheckResponse>(reply)?.uuid)
+        })
+    }
+
+    /// Opens the event stream, resuming after `cursor`. Only the status of
+    /// the response is read this way, the body is streamed.
+    pub fn events(&self, token: &str, cursor: Option<u64>) -> Result<Request<()>, AuthClientError> {
+        let mut request = self.request(Method::GET, "v1/events", read_empty)?;
+        request.bearer = Some(token.to_owned());
+        request.timeout = Some(STREAM_IDLE_TIMEOUT);
+        if let Some(cursor) = cursor {
+            request.headers.push(("Last-Event-ID", cursor.to_string()));
+        }
+        Ok(request)
+    }
+}
+
+/// If response code isn't a success it will return an error with the response code and plain text body.
+///
+/// Otherwise will deserialize the json based on given type (through turbofish notation)
+pub(crate) fn read_json<T: DeserializeOwned>(reply: Reply) -> Result<T, AuthClientError> {
+    if is_success(reply.status) {
+        Ok(serde_json::from_slice(&reply.body)?)
+    } else {
+        Err(server_error(reply))
+    }
+}
+
+/// Like `read_json` for responses without a body.
+pub(crate) fn read_empty(reply: Reply) -> Result<(), AuthClientError> {
+    if is_success(reply.status) {
+        Ok(())
+    } else {
+        Err(server_error(reply))
+    }
+}
+
+fn read_text(reply: Reply) -> Result<String, AuthClientError> {
+    if is_success(reply.status) {
+        Ok(String::from_utf8_lossy(&reply.body).into_owned())
+    } else {
+        Err(server_error(reply))
+    }
+}
+
+/// The new account's uuid, or which part of the registration was refused.
+/// Refusals are a 400 whose error code says why.
+fn read_register(reply: Reply) -> Result<Uuid, AuthClientError> {
+    if is_success(reply.status) {
+        return Ok(read_json::<RegisterResponse>(reply)?.uuid);
+    }
+    let refused = match (reply.status, reply.code.as_deref()) {
+        (_, Some("username_taken")) => RegisterError::UsernameTaken,
+        (_, Some("ethaddr_taken")) => RegisterError::EthaddrTaken,
+        (400, _) => RegisterError::Invalid(String::from_utf8_lossy(&reply.body).into_owned()),
+        _ => return Err(server_error(reply)),
+    };
+    Err(AuthClientError::Register(refused))
+}
+
+fn read_batch(reply: Reply) -> Result<UserBatch, AuthClientError> {
+    Ok(read_json::<UserBatchResponse>(reply)?.users)
+}
+
+fn read_device_poll(reply: Reply) -> Result<DevicePoll, AuthClientError> {
+    if is_success(reply.status) {
+        return Ok(DevicePoll::Approved(read_json::<SignInResponse>(reply)?.token));
+    }
+    match serde_json::from_slice::<OAuthErrorResponse>(&reply.body) {
+        Ok(err) if err.error == "authorization_pending" => Ok(DevicePoll::Pending),
+        Ok(err) if err.error == "slow_down" => Ok(DevicePoll::SlowDown),
+        Ok(OAuthErrorResponse {
+            error_description: Some(description),
+            ..
+        }) => Err(AuthClientError::ServerError(reply.status, description)),
+        _ => Err(server_error(reply)),
+    }
+}
+
+pub(crate) fn is_success(status: u16) -> bool {
+    (200..300).contains(&status)
+}
+
+pub(crate) fn server_error(reply: Reply) -> AuthClientError {
+    AuthClientError::ServerError(reply.status, String::from_utf8_lossy(&reply.body).into_owned())
+}
+
+/// Error codes of the server for a uuid, username or eth address that
+/// belongs to no account.
+const USER_MISSING: &str = "user_does_not_exist";
+const ETHADDR_MISSING: &str = "ethaddr_does_not_exist";
+
+/// Whether the server answered that the account does not exist. The legacy
+/// API answers malformed input with the same 400, only the code differs.
+fn is_missing(reply: &Reply) -> bool {
+    reply.code.as_deref() == Some(USER_MISSING)
+}
+
+/// Reads the answer to a cached lookup with `read`. Only a reply with the
+/// `missing` code is a `Missing` entry; any other refusal stays an error, so
+/// it isn't remembered.
+fn read_entry<T>(
+    reply: Reply,
+    missing: &str,
+    read: fn(Reply) -> Result<T, AuthClientError>,
+) -> Result<Entry<T>, AuthClientError> {
+    if reply.code.as_deref() == Some(missing) {
+        return Ok(Entry::Missing(reply.status, String::from_utf8_lossy(&reply.body).into_owned()));
+    }
+    Ok(Entry::Found(read(reply)?))
+}
+
+/// Adds the usernames of a batch lookup of `uuids` to `result`.
+pub(crate) fn usernames_of(uuids: &[Uuid], mut users: UserBatch, result: &mut HashMap<Uuid, Option<String>>) {
+    for &uuid in uuids {
+        let username = users
+            .remove(&uuid.to_string())
+            .flatten()
+            .map(|info| info.username);
+        result.insert(uuid, username);
+    }
+}
+
+/// Adds the uuids of a batch lookup of usernames to `result`.
+pub(crate) fn uuids_of(users: UserBatch, result: &mut HashMap<String, Option<Uuid>>) {
+    result.extend(
+        users
+            .into_iter()
+            .map(|(username, info)| (username, info.map(|info| info.uuid))),
+    );
+}
@@ -0,0 +1,423 @@
+use crate::api::{self, Api, Reply, Request};
+use crate::cache::Cache;
+use crate::events::{SseParser, Subscription};
+use crate::transport::{ClientBuilder, Step, Transport};
+use crate::{ApiVersion, AuthClientError, DevicePoll, StreamEvent};
+use auth_common::{
+    AuthToken, DeviceAuthorizationResponse, EthLookupResponse, TotpEnrollResponse,
+    Userinfo2LookupResponse, UserinfoLookupResponse, UserInfoResponse, UserSearchResponse,
+    MAX_BATCH_LOOKUP,
+};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{IntoUrl, Url};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::delay_for;
+use uuid::Uuid;
+
+/// Talks to the auth server without blocking, on a tokio 0.2 runtime. It has
+/// the methods of `AuthClient`, as `async fn`s.
+#[derive(Clone)]
+pub struct AsyncAuthClient {
+    client: reqwest::Client,
+    api: Api,
+    transport: Transport,
+    cache: Arc<Cache>,
+}
+
+impl ClientBuilder {
+    pub fn build_async(self) -> Result<AsyncAuthClient, AuthClientError> {
+        let mut client = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .user_agent(&self.user_agent);
+        for certificate in &self.certificates {
+            client = client.add_root_certificate(certificate.clone());
+        }
+        let client = client.build()?;
+        let (api, transport, cache) = self.parts()?;
+        Ok(AsyncAuthClient { client, api, transport, cache })
+    }
+}
+
+impl AsyncAuthClient {
+    /// A client with the defaults of `ClientBuilder`.
+    pub fn new<T: IntoUrl>(provider: T) -> Result<Self, AuthClientError> {
+        ClientBuilder::new(provider).build_async()
+    }
+
+    /// Selects the API version used for subsequent requests.
+    pub fn with_api_version(mut self, api: ApiVersion) -> Self {
+        self.api.version = api;
+        self
+    }
+
+    pub fn api_version(&self) -> ApiVersion {
+        self.api.version
+    }
+
+    /// Drops what the lookup cache knows about an account, for example after
+    /// renaming or deleting it some other way.
+    pub fn invalidate_uuid(&self, uuid: Uuid) {
+        self.cache.forget_uuid(uuid);
+    }
+
+    pub fn invalidate_username(&self, username: impl AsRef<str>) {
+        self.cache.forget_username(username.as_ref());
+    }
+
+    pub fn invalidate_ethaddr(&self, ethaddr: impl AsRef<str>) {
+        self.cache.forget_eth(ethaddr.as_ref());
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Updates the lookup cache with an account event received some other
+    /// way than `subscribe_events`, which does this itself.
+    pub fn apply_event(&self, event: &StreamEvent) {
+        self.cache.apply(event);
+    }
+
+    async fn send<T>(&self, request: Request<T>) -> Result<T, AuthClientError> {
+        let mut attempts = self.transport.attempts(&request);
+        loop {
+            let outcome = match self.prepare(&request, attempts.url(&request.path)?).send().await {
+                Ok(resp) => {
+                    let status = resp.status().as_u16();
+                    let code = api::error_code(resp.headers());
+                    resp.bytes().await.map(|body| Reply { status, code, body: body.to_vec() })
+                }
+                Err(err) => Err(err),
+            };
+            match attempts.outcome(outcome) {
+                Step::Done(reply) => return (request.read)(reply?),
+                Step::Retry(delay) => delay_for(delay).await,
+            }
+        }
+    }
+
+    fn prepare<T>(&self, request: &Request<T>, url: Url) -> reqwest::RequestBuilder {
+        let mut builder = self.client.request(request.method.clone(), url);
+        if let Some(body) = &request.body {
+            builder = builder.header(CONTENT_TYPE, "application/json").body(body.clone());
+        }
+        if let Some(token) = &request.bearer {
+            builder = builder.bearer_auth(token);
+        }
+        for (name, value) in &request.headers {
+            builder = builder.header(*name, value);
+        }
+        if let Some(timeout) = request.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder
+    }
+
+    pub async fn register(
+        &self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        ethaddr: impl AsRef<str>,
+    ) -> Result<Uuid, AuthClientError> {
+        let uuid = self.send(self.api.register(username.as_ref(), password.as_ref(), ethaddr.as_ref())?).await?;
+        self.cache.forget_username(username.as_ref());
+        self.cache.forget_eth(ethaddr.as_ref());
+        Ok(uuid)
+    }
+
+    pub async fn username_to_uuid(&self, username: impl AsRef<str>) -> Result<Uuid, AuthClientError> {
+        let username = username.as_ref();
+        if let Some(cached) = self.cache.uuid(username) {
+            return cached;
+        }
+        let entry = self.send(self.api.username_to_uuid(username)?).await?;
+        self.cache.put_uuid(username, &entry);
+        entry.result()
+    }
+
+    pub async fn uuid_to_username(&self, uuid: Uuid) -> Result<String, AuthClientError> {
+        if let Some(cached) = self.cache.username(uuid) {
+            return cached;
+        }
+        let entry = self.send(self.api.uuid_to_username(uuid)?).await?;
+        self.cache.put_username(uuid, &entry);
+        entry.result()
+    }
+
+    pub async fn ping(&self) -> Result<String, AuthClientError> {
+        self.send(self.api.ping()?).await
+    }
+
+    pub async fn eth_to_info(&self, ethaddr: impl AsRef<str>) -> Result<EthLookupResponse, AuthClientError> {
+        let ethaddr = ethaddr.as_ref();
+        if let Some(cached) = self.cache.eth(ethaddr) {
+            return cached;
+        }
+        let entry = self.send(self.api.eth_to_info(ethaddr)?).await?;
+        self.cache.put_eth(ethaddr, &entry);
+        entry.result()
+    }
+
+    pub async fn username_to_info(&self, username: impl AsRef<str>) -> Result<UserinfoLookupResponse, AuthClientError> {
+        self.send(self.api.username_to_info(username.as_ref())?).await
+    }
+
+    pub async fn uuid_to_info(&self, uuid: Uuid) -> Result<Userinfo2LookupResponse, AuthClientError> {
+        self.send(self.api.uuid_to_info(uuid)?).await
+    }
+
+    /// See `AuthClient::user_info`.
+    pub async fn user_info(&self, uuid: Uuid) -> Result<UserInfoResponse, AuthClientError> {
+        self.send(self.api.user_info(uuid)?).await
+    }
+
+    pub async fn user_info_by_name(&self, username: impl AsRef<str>) -> Result<UserInfoResponse, AuthClientError> {
+        self.send(self.api.user_info_by_name(username.as_ref())?).await
+    }
+
+    pub async fn user_info_by_eth(&self, ethaddr: impl AsRef<str>) -> Result<UserInfoResponse, AuthClientError> {
+        self.send(self.api.user_info_by_eth(ethaddr.as_ref())?).await
+    }
+
+    /// See `AuthClient::ethaddrs_to_info`.
+    pub async fn ethaddrs_to_info<S: AsRef<str>>(
+        &self,
+        ethaddrs: &[S],
+    ) -> Result<HashMap<String, Option<UserInfoResponse>>, AuthClientError> {
+        let mut result = HashMap::with_capacity(ethaddrs.len());
+        for chunk in ethaddrs.chunks(MAX_BATCH_LOOKUP) {
+            result.extend(self.send(self.api.ethaddrs_batch(chunk)?).await?);
+        }
+        Ok(result)
+    }
+
+    /// See `AuthClient::search_users`.
+    pub async fn search_users(
+        &self,
+        prefix: impl AsRef<str>,
+        limit: Option<usize>,
+        after: Option<&str>,
+    ) -> Result<UserSearchResponse, AuthClientError> {
+        self.send(self.api.search_users(prefix.as_ref(), limit, after)?).await
+    }
+
+    pub async fn eth_active(&self, ethaddr: impl AsRef<str>) -> Result<(), AuthClientError> {
+        self.send(self.api.eth_active(ethaddr.as_ref())?).await?;
+        self.cache.forget_eth(ethaddr.as_ref());
+        Ok(())
+    }
+
+    /// See `AuthClient::change_password`.
+    pub async fn change_password(
+        &self,
+        ethaddr: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.change_password(ethaddr.as_ref(), password.as_ref())?).await
+    }
+
+    pub async fn change_password_by_uuid(
+        &self,
+        uuid: Uuid,
+        current_password: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.change_password_by_uuid(uuid, current_password.as_ref(), password.as_ref())?)
+            .await
+    }
+
+    pub async fn set_searchable(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+        searchable: bool,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.set_searchable(uuid, password.as_ref(), searchable)?).await
+    }
+
+    /// See `AuthClient::set_email`.
+    pub async fn set_email(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+        email: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.set_email(uuid, password.as_ref(), email.as_ref())?).await
+    }
+
+    /// See `AuthClient::uuids_to_usernames`.
+    pub async fn uuids_to_usernames(
+        &self,
+        uuids: &[Uuid],
+    ) -> Result<HashMap<Uuid, Option<String>>, AuthClientError> {
+        let mut result = HashMap::with_capacity(uuids.len());
+        if self.api.version == ApiVersion::Legacy {
+            for &uuid in uuids {
+                result.insert(uuid, self.send(self.api.legacy_username_of(uuid)?).await?);
+            }
+            return Ok(result);
+        }
+
+        for chunk in uuids.chunks(MAX_BATCH_LOOKUP) {
+            let users = self.send(self.api.uuids_batch(chunk)?).await?;
+            api::usernames_of(chunk, users, &mut result);
+        }
+        Ok(result)
+    }
+
+    /// See `AuthClient::usernames_to_uuids`.
+    pub async fn usernames_to_uuids<S: AsRef<str>>(
+        &self,
+        usernames: &[S],
+    ) -> Result<HashMap<String, Option<Uuid>>, AuthClientError> {
+        let mut result = HashMap::with_capacity(usernames.len());
+        if self.api.version == ApiVersion::Legacy {
+            for username in usernames {
+                let uuid = self.send(self.api.legacy_uuid_of(username.as_ref())?).await?;
+                result.insert(username.as_ref().to_owned(), uuid);
+            }
+            return Ok(result);
+        }
+
+        for chunk in usernames.chunks(MAX_BATCH_LOOKUP) {
+            let users = self.send(self.api.usernames_batch(chunk)?).await?;
+            api::uuids_of(users, &mut result);
+        }
+        Ok(result)
+    }
+
+    /// See `AuthClient::request_password_reset`.
+    pub async fn request_password_reset(&self, login: impl AsRef<str>) -> Result<(), AuthClientError> {
+        self.send(self.api.request_password_reset(login.as_ref())?).await
+    }
+
+    pub async fn reset_password(
+        &self,
+        token: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.reset_password(token.as_ref(), password.as_ref())?).await
+    }
+
+    pub async fn sign_in(
+        &self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<AuthToken, AuthClientError> {
+        self.send(self.api.sign_in(username.as_ref(), password.as_ref(), None)?).await
+    }
+
+    pub async fn sign_in_with_otp(
+        &self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        otp: impl AsRef<str>,
+    ) -> Result<AuthToken, AuthClientError> {
+        let otp = Some(otp.as_ref().trim().to_owned());
+        self.send(self.api.sign_in(username.as_ref(), password.as_ref(), otp)?).await
+    }
+
+    /// See `AuthClient::begin_totp`.
+    pub async fn begin_totp(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+    ) -> Result<TotpEnrollResponse, AuthClientError> {
+        self.send(self.api.begin_totp(uuid, password.as_ref())?).await
+    }
+
+    pub async fn confirm_totp(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+        otp: impl AsRef<str>,
+    ) -> Result<Vec<String>, AuthClientError> {
+        self.send(self.api.confirm_totp(uuid, password.as_ref(), otp.as_ref())?).await
+    }
+
+    /// See `AuthClient::start_device_login`.
+    pub async fn start_device_login(&self) -> Result<DeviceAuthorizationResponse, AuthClientError> {
+        self.send(self.api.start_device_login()?).await
+    }
+
+    pub async fn poll_device_login(
+        &self,
+        device: &DeviceAuthorizationResponse,
+    ) -> Result<DevicePoll, AuthClientError> {
+        self.send(self.api.poll_device_login(device)?).await
+    }
+
+    /// Polls at the interval the server asked for until the user approved
+    /// the device.
+    pub async fn wait_for_device_login(
+        &self,
+        device: &DeviceAuthorizationResponse,
+    ) -> Result<AuthToken, AuthClientError> {
+        let mut interval = Duration::from_secs(device.interval.max(1));
+        loop {
+            delay_for(interval).await;
+            match self.poll_device_login(device).await? {
+                DevicePoll::Approved(token) => return Ok(token),
+                DevicePoll::SlowDown => interval += Duration::from_secs(5),
+                DevicePoll::Pending => {}
+            }
+        }
+    }
+
+    /// See `AuthClient::subscribe_events`. The returned future only finishes
+    /// once `on_event` returns `false` or the server rejects the token, so
+    /// spawn it as a task of its own.
+    pub async fn subscribe_events<F>(
+        &self,
+        token: &str,
+        after: Option<u64>,
+        mut on_event: F,
+    ) -> Result<Option<u64>, AuthClientError>
+    where
+        F: FnMut(StreamEvent) -> bool,
+    {
+        let mut subscription = Subscription::new(after);
+        loop {
+            let request = self.api.events(token, subscription.cursor)?;
+            let provider = self.transport.pick();
+            let sent = self.prepare(&request, self.transport.url(provider, &request.path)?).send().await;
+            self.transport.report(provider, matches!(&sent, Ok(resp) if !resp.status().is_server_error()));
+            match sent {
+                Ok(mut resp) if resp.status().is_success() => {
+                    subscription.connected();
+                    let mut parser = SseParser::default();
+                    let mut pending = Vec::new();
+                    // Read errors end the connection like a close does.
+                    while let Ok(Some(chunk)) = resp.chunk().await {
+                        pending.extend_from_slice(&chunk);
+                        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                            let line: Vec<u8> = pending.drain(..=end).collect();
+                            let message = parser.line(&String::from_utf8_lossy(&line));
+                            if let Some(event) = message.and_then(|message| subscription.event(message)) {
+                                self.cache.apply(&event);
+                            if !on_event(event) {
+                                    return Ok(subscription.cursor);
+                                }
+                            }
+                        }
+                    }
+                }
+                Ok(resp) if !resp.status().is_server_error() => {
+                    return Err(AuthClientError::ServerError(
+                        resp.status().as_u16(),
+                        resp.text().await?,
+                    ));
+                }
+                Ok(_) | Err(_) => subscription.failed(),
+            }
+            delay_for(subscription.delay()).await;
+        }
+    }
+
+    pub async fn validate(&self, token: AuthToken) -> Result<Uuid, AuthClientError> {
+        self.send(self.api.validate(token)?).await
+    }
+}
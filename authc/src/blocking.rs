@@ -0,0 +1,482 @@
+use crate::api::{self, Api, Reply, Request};
+use crate::cache::Cache;
+use crate::events::{SseParser, Subscription};
+use crate::transport::{ClientBuilder, Step, Transport};
+use crate::{ApiVersion, AuthClientError, DevicePoll, StreamEvent};
+use auth_common::{
+    AuthToken, DeviceAuthorizationResponse, EthLookupResponse, TotpEnrollResponse,
+    Userinfo2LookupResponse, UserinfoLookupResponse, UserInfoResponse, UserSearchResponse,
+    MAX_BATCH_LOOKUP,
+};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{IntoUrl, Url};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Talks to the auth server, blocking the calling thread for every request.
+pub struct AuthClient {
+    client: reqwest::blocking::Client,
+    api: Api,
+    transport: Transport,
+    cache: Arc<Cache>,
+}
+
+impl ClientBuilder {
+    pub fn build(self) -> Result<AuthClient, AuthClientError> {
+        let mut client = reqwest::blocking::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .user_agent(&self.user_agent);
+        for certificate in &self.certificates {
+            client = client.add_root_certificate(certificate.clone());
+        }
+        let client = client.build()?;
+        let (api, transport, cache) = self.parts()?;
+        Ok(AuthClient { client, api, transport, cache })
+    }
+}
+
+impl AuthClient {
+    /// A client with the defaults of `ClientBuilder`.
+    pub fn new<T: IntoUrl>(provider: T) -> Result<Self, AuthClientError> {
+        ClientBuilder::new(provider).build()
+    }
+
+    /// Selects the API version used for subsequent requests.
+    pub fn with_api_version(mut self, api: ApiVersion) -> Self {
+        self.api.version = api;
+        self
+    }
+
+    pub fn api_version(&self) -> ApiVersion {
+        self.api.version
+    }
+
+    /// Drops what the lookup cache knows about an account, for example after
+    /// renaming or deleting it some other way.
+    pub fn invalidate_uuid(&self, uuid: Uuid) {
+        self.cache.forget_uuid(uuid);
+    }
+
+    pub fn invalidate_username(&self, username: impl AsRef<str>) {
+        self.cache.forget_username(username.as_ref());
+    }
+
+    pub fn invalidate_ethaddr(&self, ethaddr: impl AsRef<str>) {
+        self.cache.forget_eth(ethaddr.as_ref());
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Updates the lookup cache with an account event received some other
+    /// way than `subscribe_events`, which does this itself.
+    pub fn apply_event(&self, event: &StreamEvent) {
+        self.cache.apply(event);
+    }
+
+    fn send<T>(&self, request: Request<T>) -> Result<T, AuthClientError> {
+        let mut attempts = self.transport.attempts(&request);
+        loop {
+            let outcome = self.prepare(&request, attempts.url(&request.path)?).send().and_then(|resp| {
+                let status = resp.status().as_u16();
+                let code = api::error_code(resp.headers());
+                Ok(Reply { status, code, body: resp.bytes()?.to_vec() })
+            });
+            match attempts.outcome(outcome) {
+                Step::Done(reply) => return (request.read)(reply?),
+                Step::Retry(delay) => thread::sleep(delay),
+            }
+        }
+    }
+
+    fn prepare<T>(&self, request: &Request<T>, url: Url) -> reqwest::blocking::RequestBuilder {
+        let mut builder = self.client.request(request.method.clone(), url);
+        if let Some(body) = &request.body {
+            builder = builder.header(CONTENT_TYPE, "application/json").body(body.clone());
+        }
+        if let Some(token) = &request.bearer {
+            builder = builder.bearer_auth(token);
+        }
+        for (name, value) in &request.headers {
+            builder = builder.header(*name, value);
+        }
+        if let Some(timeout) = request.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder
+    }
+
+    pub fn register(
+        &self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        ethaddr:  impl AsRef<str>,    // new add -max
+    ) -> Result<Uuid, AuthClientError> {
+        let uuid = self.send(self.api.register(username.as_ref(), password.as_ref(), ethaddr.as_ref())?)?;
+        self.cache.forget_username(username.as_ref());
+        self.cache.forget_eth(ethaddr.as_ref());
+        Ok(uuid)
+    }
+
+    pub fn username_to_uuid(
+        &self,
+        username: impl AsRef<str>,
+    ) -> Result<Uuid, AuthClientError> {
+        let username = username.as_ref();
+        if let Some(cached) = self.cache.uuid(username) {
+            return cached;
+        }
+        let entry = self.send(self.api.username_to_uuid(username)?)?;
+        self.cache.put_uuid(username, &entry);
+        entry.result()
+    }
+
+    pub fn uuid_to_username(&self, uuid: Uuid) -> Result<String, AuthClientError> {
+        if let Some(cached) = self.cache.username(uuid) {
+            return cached;
+        }
+        let entry = self.send(self.api.uuid_to_username(uuid)?)?;
+        self.cache.put_username(uuid, &entry);
+        entry.result()
+    }
+
+    /// The server's greeting, `Pong!` and the address it sees requests
+    /// come from.
+    pub fn ping(&self) -> Result<String, AuthClientError> {
+        self.send(self.api.ping()?)
+    }
+
+    pub fn eth_to_info(&self, ethaddr: impl AsRef<str>) -> Result<EthLookupResponse, AuthClientError> {
+        let ethaddr = ethaddr.as_ref();
+        if let Some(cached) = self.cache.eth(ethaddr) {
+            return cached;
+        }
+        let entry = self.send(self.api.eth_to_info(ethaddr)?)?;
+        self.cache.put_eth(ethaddr, &entry);
+        entry.result()
+    }
+
+    pub fn username_to_info(&self, username: impl AsRef<str>) -> Result<UserinfoLookupResponse, AuthClientError> {
+        self.send(self.api.username_to_info(username.as_ref())?)
+    }
+
+    pub fn uuid_to_info(&self, uuid: Uuid) -> Result<Userinfo2LookupResponse, AuthClientError> {
+        self.send(self.api.uuid_to_info(uuid)?)
+    }
+
+    /// The whole account record of a uuid.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected, like
+    /// the other `user_info` lookups.
+    pub fn user_info(&self, uuid: Uuid) -> Result<UserInfoResponse, AuthClientError> {
+        self.send(self.api.user_info(uuid)?)
+    }
+
+    pub fn user_info_by_name(&self, username: impl AsRef<str>) -> Result<UserInfoResponse, AuthClientError> {
+        self.send(self.api.user_info_by_name(username.as_ref())?)
+    }
+
+    pub fn user_info_by_eth(&self, ethaddr: impl AsRef<str>) -> Result<UserInfoResponse, AuthClientError> {
+        self.send(self.api.user_info_by_eth(ethaddr.as_ref())?)
+    }
+
+    /// Resolves many ethereum addresses at once, batching them into as few
+    /// requests as the server allows. Addresses without an account map to
+    /// `None`.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn ethaddrs_to_info<S: AsRef<str>>(
+        &self,
+        ethaddrs: &[S],
+    ) -> Result<HashMap<String, Option<UserInfoResponse>>, AuthClientError> {
+        let mut result = HashMap::with_capacity(ethaddrs.len());
+        for chunk in ethaddrs.chunks(MAX_BATCH_LOOKUP) {
+            result.extend(self.send(self.api.ethaddrs_batch(chunk)?)?);
+        }
+        Ok(result)
+    }
+
+    /// One page of the searchable accounts whose username starts with
+    /// `prefix`. Pass the `next` of a page as `after` to fetch the one after.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn search_users(
+        &self,
+        prefix: impl AsRef<str>,
+        limit: Option<usize>,
+        after: Option<&str>,
+    ) -> Result<UserSearchResponse, AuthClientError> {
+        self.send(self.api.search_users(prefix.as_ref(), limit, after)?)
+    }
+
+    /// Marks the account of an ethereum address as activated.
+    pub fn eth_active(&self, ethaddr: impl AsRef<str>) -> Result<(), AuthClientError> {
+        self.send(self.api.eth_active(ethaddr.as_ref())?)?;
+        self.cache.forget_eth(ethaddr.as_ref());
+        Ok(())
+    }
+
+    /// Changes the password of the account of an ethereum address, on the
+    /// legacy API whatever version is selected.
+    pub fn change_password(
+        &self,
+        ethaddr: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.change_password(ethaddr.as_ref(), password.as_ref())?)
+    }
+
+    /// Changes the password of an account, confirmed with its current
+    /// password.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn change_password_by_uuid(
+        &self,
+        uuid: Uuid,
+        current_password: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.change_password_by_uuid(uuid, current_password.as_ref(), password.as_ref())?)
+    }
+
+    /// Chooses whether an account shows up in `search_users`, confirmed with
+    /// its password.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn set_searchable(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+        searchable: bool,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.set_searchable(uuid, password.as_ref(), searchable)?)
+    }
+
+    /// Sets the email address of an account, confirmed with its password.
+    /// The server mails a verification link to it, and only a verified
+    /// address can receive password reset codes.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn set_email(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+        email: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.set_email(uuid, password.as_ref(), email.as_ref())?)
+    }
+
+    /// Resolves many uuids at once, batching them into as few requests as
+    /// the server allows. Uuids without an account map to `None`.
+    ///
+    /// Legacy servers have no batch routes, so with `ApiVersion::Legacy`
+    /// every uuid is looked up on its own.
+    pub fn uuids_to_usernames(
+        &self,
+        uuids: &[Uuid],
+    ) -> Result<HashMap<Uuid, Option<String>>, AuthClientError> {
+        let mut result = HashMap::with_capacity(uuids.len());
+        if self.api.version == ApiVersion::Legacy {
+            for &uuid in uuids {
+                result.insert(uuid, self.send(self.api.legacy_username_of(uuid)?)?);
+            }
+            return Ok(result);
+        }
+
+        for chunk in uuids.chunks(MAX_BATCH_LOOKUP) {
+            let users = self.send(self.api.uuids_batch(chunk)?)?;
+            api::usernames_of(chunk, users, &mut result);
+        }
+        Ok(result)
+    }
+
+    /// Resolves many usernames at once, batching them into as few requests
+    /// as the server allows. Usernames without an account map to `None`.
+    ///
+    /// Legacy servers have no batch routes, so with `ApiVersion::Legacy`
+    /// every username is looked up on its own.
+    pub fn usernames_to_uuids<S: AsRef<str>>(
+        &self,
+        usernames: &[S],
+    ) -> Result<HashMap<String, Option<Uuid>>, AuthClientError> {
+        let mut result = HashMap::with_capacity(usernames.len());
+        if self.api.version == ApiVersion::Legacy {
+            for username in usernames {
+                let uuid = self.send(self.api.legacy_uuid_of(username.as_ref())?)?;
+                result.insert(username.as_ref().to_owned(), uuid);
+            }
+            return Ok(result);
+        }
+
+        for chunk in usernames.chunks(MAX_BATCH_LOOKUP) {
+            let users = self.send(self.api.usernames_batch(chunk)?)?;
+            api::uuids_of(users, &mut result);
+        }
+        Ok(result)
+    }
+
+    /// Asks the server to mail a reset code to the verified email address of
+    /// the account named by `login`, a username or an email address. The
+    /// server answers the same whether or not the account exists.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn request_password_reset(&self, login: impl AsRef<str>) -> Result<(), AuthClientError> {
+        self.send(self.api.request_password_reset(login.as_ref())?)
+    }
+
+    /// Sets a new password with the code from the reset mail.
+    pub fn reset_password(
+        &self,
+        token: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<(), AuthClientError> {
+        self.send(self.api.reset_password(token.as_ref(), password.as_ref())?)
+    }
+
+    pub fn sign_in(
+        &self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<AuthToken, AuthClientError> {
+        self.send(self.api.sign_in(username.as_ref(), password.as_ref(), None)?)
+    }
+
+    /// Signs in to an account with two-factor authentication. `otp` is the
+    /// code shown by the authenticator app, or one of the recovery codes.
+    pub fn sign_in_with_otp(
+        &self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        otp: impl AsRef<str>,
+    ) -> Result<AuthToken, AuthClientError> {
+        let otp = Some(otp.as_ref().trim().to_owned());
+        self.send(self.api.sign_in(username.as_ref(), password.as_ref(), otp)?)
+    }
+
+    /// Starts enrolling an authenticator app. The returned secret, or the
+    /// `otpauth://` URI as a QR code, goes into the app; two-factor
+    /// authentication is only enabled once `confirm_totp` succeeds.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn begin_totp(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+    ) -> Result<TotpEnrollResponse, AuthClientError> {
+        self.send(self.api.begin_totp(uuid, password.as_ref())?)
+    }
+
+    /// Enables two-factor authentication with a code from the newly enrolled
+    /// app, returning the recovery codes. They are not shown again.
+    pub fn confirm_totp(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<str>,
+        otp: impl AsRef<str>,
+    ) -> Result<Vec<String>, AuthClientError> {
+        self.send(self.api.confirm_totp(uuid, password.as_ref(), otp.as_ref())?)
+    }
+
+    /// Starts a login for a device that can't ask for a password. Show the
+    /// user `user_code` and `verification_uri`, where they sign in and
+    /// approve it, then poll with `poll_device_login` or wait with
+    /// `wait_for_device_login`.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn start_device_login(&self) -> Result<DeviceAuthorizationResponse, AuthClientError> {
+        self.send(self.api.start_device_login()?)
+    }
+
+    /// Asks once whether the user approved the device. A denied or expired
+    /// login is an error.
+    pub fn poll_device_login(
+        &self,
+        device: &DeviceAuthorizationResponse,
+    ) -> Result<DevicePoll, AuthClientError> {
+        self.send(self.api.poll_device_login(device)?)
+    }
+
+    /// Polls at the interval the server asked for until the user approved
+    /// the device, blocking the calling thread meanwhile.
+    pub fn wait_for_device_login(
+        &self,
+        device: &DeviceAuthorizationResponse,
+    ) -> Result<AuthToken, AuthClientError> {
+        let mut interval = Duration::from_secs(device.interval.max(1));
+        loop {
+            thread::sleep(interval);
+            match self.poll_device_login(device)? {
+                DevicePoll::Approved(token) => return Ok(token),
+                DevicePoll::SlowDown => interval += Duration::from_secs(5),
+                DevicePoll::Pending => {}
+            }
+        }
+    }
+
+    /// Follows the account event stream with an event token of the server,
+    /// calling `on_event` for every ban, revoked session, rename and
+    /// deletion until it returns `false`. Returns the cursor of the last
+    /// event seen, to pass as `after` next time.
+    ///
+    /// Without `after` only new events are delivered. Dropped connections and
+    /// server errors are retried with backoff, resuming after the last event,
+    /// so this blocks the calling thread indefinitely; run it on its own.
+    /// Rejected tokens and bad cursors are returned as errors. Renames and
+    /// deletions also update the lookup cache of this client.
+    ///
+    /// Only available on the `/v1` API, whatever version is selected.
+    pub fn subscribe_events<F>(
+        &self,
+        token: &str,
+        after: Option<u64>,
+        mut on_event: F,
+    ) -> Result<Option<u64>, AuthClientError>
+    where
+        F: FnMut(StreamEvent) -> bool,
+    {
+        let mut subscription = Subscription::new(after);
+        loop {
+            let request = self.api.events(token, subscription.cursor)?;
+            let provider = self.transport.pick();
+            let sent = self.prepare(&request, self.transport.url(provider, &request.path)?).send();
+            self.transport.report(provider, matches!(&sent, Ok(resp) if !resp.status().is_server_error()));
+            match sent {
+                Ok(resp) if resp.status().is_success() => {
+                    subscription.connected();
+                    let mut reader = BufReader::new(resp);
+                    let mut parser = SseParser::default();
+                    let mut line = String::new();
+                    // Read errors end the connection like a close does.
+                    while let Ok(1..) = reader.read_line(&mut line) {
+                        let message = parser.line(&line);
+                        line.clear();
+                        if let Some(event) = message.and_then(|message| subscription.event(message)) {
+                            self.cache.apply(&event);
+                            if !on_event(event) {
+                                return Ok(subscription.cursor);
+                            }
+                        }
+                    }
+                }
+                Ok(resp) if !resp.status().is_server_error() => {
+                    return Err(AuthClientError::ServerError(
+                        resp.status().as_u16(),
+                        resp.text()?,
+                    ));
+                }
+                Ok(_) | Err(_) => subscription.failed(),
+            }
+            thread::sleep(subscription.delay());
+        }
+    }
+
+    pub fn validate(&self, token: AuthToken) -> Result<Uuid, AuthClientError> {
+        self.send(self.api.validate(token)?)
+    }
+}
@@ -0,0 +1,222 @@
+//! The optional lookup cache of both clients: uuid ↔ username and eth
+//! lookups, each kept for a while and dropping the least recently used
+//! answers when full.
+
+use crate::{AuthClientError, StreamEvent};
+use auth_common::{AccountEvent, EthLookupResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// An answer, or the "does not exist" error the server gave instead.
+#[derive(Clone)]
+pub(crate) enum Entry<T> {
+    Found(T),
+    Missing(u16, String),
+}
+
+impl<T: Clone> Entry<T> {
+    pub fn result(&self) -> Result<T, AuthClientError> {
+        match self {
+            Entry::Found(value) => Ok(value.clone()),
+            Entry::Missing(status, message) => Err(AuthClientError::ServerError(*status, message.clone())),
+        }
+    }
+}
+
+struct Slot<V> {
+    value: V,
+    expires: Instant,
+    used: u64,
+}
+
+/// A map of at most `capacity` entries that forgets the least recently used
+/// one to make room.
+struct Lru<K, V> {
+    slots: HashMap<K, Slot<V>>,
+    /// Keys by when they were last used.
+    order: BTreeMap<u64, K>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &K, now: Instant) -> Option<&V> {
+        match self.slots.get(key) {
+            Some(slot) if slot.expires <= now => {
+                self.remove(key);
+                None
+            }
+            Some(slot) => {
+                self.clock += 1;
+                self.order.remove(&slot.used);
+                self.order.insert(self.clock, key.clone());
+                let slot = self.slots.get_mut(key).unwrap();
+                slot.used = self.clock;
+                Some(&slot.value)
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V, expires: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.slots.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.slots.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.slots.insert(key, Slot { value, expires, used: self.clock });
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(slot) = self.slots.remove(key) {
+            self.order.remove(&slot.used);
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&V) -> bool) {
+        let order = &mut self.order;
+        self.slots.retain(|_, slot| {
+            let kept = keep(&slot.value);
+            if !kept {
+                order.remove(&slot.used);
+            }
+            kept
+        });
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.order.clear();
+    }
+}
+
+struct Lookups {
+    usernames: Lru<Uuid, Entry<String>>,
+    /// By lowercase username, like the server compares them.
+    uuids: Lru<String, Entry<Uuid>>,
+    /// By lowercase address.
+    eths: Lru<String, Entry<EthLookupResponse>>,
+}
+
+/// Remembered lookups, shared by the clones of a client. Disabled, never
+/// holding anything, with a capacity of 0.
+pub(crate) struct Cache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    lookups: Mutex<Lookups>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            ttl,
+            negative_ttl,
+            lookups: Mutex::new(Lookups {
+                usernames: Lru::new(capacity),
+                uuids: Lru::new(capacity),
+                eths: Lru::new(capacity),
+            }),
+        }
+    }
+
+    fn lookups(&self) -> std::sync::MutexGuard<'_, Lookups> {
+        self.lookups.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn expiry<T>(&self, entry: &Entry<T>) -> Instant {
+        match entry {
+            Entry::Found(_) => Instant::now() + self.ttl,
+            Entry::Missing(..) => Instant::now() + self.negative_ttl,
+        }
+    }
+
+    pub fn username(&self, uuid: Uuid) -> Option<Result<String, AuthClientError>> {
+        Some(self.lookups().usernames.get(&uuid, Instant::now())?.result())
+    }
+
+    pub fn uuid(&self, username: &str) -> Option<Result<Uuid, AuthClientError>> {
+        Some(self.lookups().uuids.get(&username.to_lowercase(), Instant::now())?.result())
+    }
+
+    pub fn eth(&self, ethaddr: &str) -> Option<Result<EthLookupResponse, AuthClientError>> {
+        Some(self.lookups().eths.get(&ethaddr.to_lowercase(), Instant::now())?.result())
+    }
+
+    pub fn put_username(&self, uuid: Uuid, entry: &Entry<String>) {
+        let expires = self.expiry(entry);
+        self.lookups().usernames.insert(uuid, entry.clone(), expires);
+    }
+
+    pub fn put_uuid(&self, username: &str, entry: &Entry<Uuid>) {
+        let expires = self.expiry(entry);
+        self.lookups().uuids.insert(username.to_lowercase(), entry.clone(), expires);
+    }
+
+    /// Also answers the uuid ↔ username lookups of the account found.
+    pub fn put_eth(&self, ethaddr: &str, entry: &Entry<EthLookupResponse>) {
+        let expires = self.expiry(entry);
+        let mut lookups = self.lookups();
+        if let Entry::Found(info) = entry {
+            lookups.usernames.insert(info.uuid, Entry::Found(info.username.clone()), expires);
+            lookups.uuids.insert(info.username.to_lowercase(), Entry::Found(info.uuid), expires);
+        }
+        lookups.eths.insert(ethaddr.to_lowercase(), entry.clone(), expires);
+    }
+
+    pub fn forget_uuid(&self, uuid: Uuid) {
+        let mut lookups = self.lookups();
+        lookups.usernames.remove(&uuid);
+        lookups.uuids.retain(|entry| !matches!(entry, Entry::Found(found) if *found == uuid));
+        lookups.eths.retain(|entry| !matches!(entry, Entry::Found(info) if info.uuid == uuid));
+    }
+
+    pub fn forget_username(&self, username: &str) {
+        self.lookups().uuids.remove(&username.to_lowercase());
+    }
+
+    pub fn forget_eth(&self, ethaddr: &str) {
+        self.lookups().eths.remove(&ethaddr.to_lowercase());
+    }
+
+    pub fn clear(&self) {
+        let mut lookups = self.lookups();
+        lookups.usernames.clear();
+        lookups.uuids.clear();
+        lookups.eths.clear();
+    }
+
+    /// Brings the cache up to date with an event of the account stream.
+    /// Lost events may have been renames or deletions, so they empty it.
+    pub fn apply(&self, event: &StreamEvent) {
+        match event {
+            StreamEvent::Account(record) => match &record.event {
+                AccountEvent::UsernameChanged { uuid, old_username, username } => {
+                    self.forget_uuid(*uuid);
+                    self.forget_username(old_username);
+                    self.forget_username(username);
+                }
+                AccountEvent::Deleted { uuid } => self.forget_uuid(*uuid),
+                AccountEvent::Banned { .. } | AccountEvent::SessionRevoked { .. } => {}
+            },
+            StreamEvent::Lost { .. } => self.clear(),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Reading the account event stream, shared by both clients: parsing
+//! `text/event-stream` lines and deciding when to reconnect.
+
+use crate::StreamEvent;
+use std::time::Duration;
+
+/// Longest wait between reconnection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// One server-sent event.
+#[derive(Default)]
+pub(crate) struct SseMessage {
+    id: Option<u64>,
+    event: String,
+    data: String,
+    retry: Option<u64>,
+}
+
+/// Splits a `text/event-stream` body into messages, fed one line at a time.
+#[derive(Default)]
+pub(crate) struct SseParser {
+    message: SseMessage,
+    fields: usize,
+}
+
+impl SseParser {
+    /// Takes the next line, returning the message it completes.
+    pub fn line(&mut self, line: &str) -> Option<SseMessage> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            if self.fields == 0 {
+                return None;
+            }
+            self.fields = 0;
+            return Some(std::mem::take(&mut self.message));
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        let message = &mut self.message;
+        match name {
+            "id" => message.id = value.parse().ok(),
+            "event" => message.event = value.to_owned(),
+            "data" => {
+                if !message.data.is_empty() {
+                    message.data.push('\n');
+                }
+                message.data.push_str(value);
+            }
+            "retry" => message.retry = value.parse().ok(),
+            _ => return None,
+        }
+        self.fields += 1;
+        None
+    }
+}
+
+/// Where a subscription is and how long to wait before reconnecting.
+pub(crate) struct Subscription {
+    pub cursor: Option<u64>,
+    retry: Duration,
+    delay: Duration,
+}
+
+impl Subscription {
+    pub fn new(after: Option<u64>) -> Self {
+        let retry = Duration::from_secs(3);
+        Self {
+            cursor: after,
+            retry,
+            delay: retry,
+        }
+    }
+
+    pub fn connected(&mut self) {
+        self.delay = self.retry;
+    }
+
+    /// Server errors, overload and unreachable servers are waited out, for
+    /// longer each time.
+    pub fn failed(&mut self) {
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// The event a message carries, moving the cursor past it.
+    pub fn event(&mut self, message: SseMessage) -> Option<StreamEvent> {
+        if let Some(ms) = message.retry {
+            self.retry = Duration::from_millis(ms);
+            self.delay = self.retry;
+        }
+        let event = match (message.event.as_str(), message.id) {
+            ("lost", Some(id)) => StreamEvent::Lost { cursor: id },
+            (_, Some(_)) => match serde_json::from_str(&message.data) {
+                Ok(record) => StreamEvent::Account(record),
+                // An event kind newer than this client.
+                Err(_) => return None,
+            },
+            _ => return None,
+        };
+        self.cursor = message.id;
+        Some(event)
+    }
+}
@@ -1,10 +1,31 @@
+//! Client for the auth server. `AuthClient` blocks the calling thread and
+//! `AsyncAuthClient` runs on tokio; they are enabled by the `blocking`
+//! (default) and `async` features.
+
+// Without either client only the shared types are left to use.
+#![cfg_attr(not(any(feature = "blocking", feature = "async")), allow(dead_code))]
+
+mod api;
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod cache;
+mod events;
+mod transport;
+
 use argon2::Config;
-pub use auth_common::AuthToken;
-use auth_common::{
-    RegisterPayload, SignInPayload, SignInResponse, UsernameLookupPayload, UsernameLookupResponse,
-    UuidLookupPayload, UuidLookupResponse, ValidityCheckPayload, ValidityCheckResponse,
+pub use auth_common::{
+    AccountEvent, AccountEventRecord, AuthToken, DeviceAuthorizationResponse, EthLookupResponse,
+    TotpEnrollResponse, Userinfo2LookupResponse, UserinfoLookupResponse, UserInfoResponse,
+    RegisterResponse, UserSearchHit, UserSearchResponse,
 };
-use reqwest::{IntoUrl, Url};
+#[cfg(feature = "async")]
+pub use async_client::AsyncAuthClient;
+#[cfg(feature = "blocking")]
+pub use blocking::AuthClient;
+pub use reqwest::Certificate;
+pub use transport::ClientBuilder;
 pub use uuid::Uuid;
 
 fn net_prehash(password: &str) -> String {
@@ -20,100 +41,51 @@ pub enum AuthClientError {
     ServerError(u16, String),
     RequestError(reqwest::Error),
     InvalidUrl(url::ParseError),
+    /// The server answered with a body this client doesn't understand.
+    InvalidResponse(serde_json::Error),
+    /// The server refused to create the account.
+    Register(RegisterError),
 }
-pub struct AuthClient {
-    client: reqwest::blocking::Client,
-    provider: Url,
-}
-
-impl AuthClient {
-    pub fn new<T: IntoUrl>(provider: T) -> Result<Self, AuthClientError> {
-        Ok(Self {
-            client: reqwest::blocking::Client::new(),
-            provider: provider.into_url()?,
-        })
-    }
-
-    pub fn register(
-        &self,
-        username: impl AsRef<str>,
-        password: impl AsRef<str>,
-        ethaddr:  impl AsRef<str>,    // new add -max
-    ) -> Result<(), AuthClientError> {
-        let data = RegisterPayload {
-            username: username.as_ref().to_owned(),
-            password: net_prehash(password.as_ref()),
-            ethaddr: ethaddr.as_ref().to_owned(),
-        };
-        let ep = self.provider.join("register")?;
-        self.client.post(ep).json(&data).send()?;
-        println!("register posted request");
-        Ok(())
-    }
-
-    pub fn username_to_uuid(
-        &self,
-        username: impl AsRef<str>,
-    ) -> Result<Uuid, AuthClientError> {
-        let data = UuidLookupPayload {
-            username: username.as_ref().to_owned(),
-        };
-        let ep = self.provider.join("username_to_uuid")?;
-        let resp = self.client.post(ep).json(&data).send()?;
-
-        Ok(handle_response::<UuidLookupResponse>(resp)?.uuid)
-    }
-
-    pub fn uuid_to_username(&self, uuid: Uuid) -> Result<String, AuthClientError> {
-        let data = UsernameLookupPayload { uuid };
-        let ep = self.provider.join("uuid_to_username")?;
-        let resp = self.client.post(ep).json(&data).send()?;
-
-        Ok(handle_response::<UsernameLookupResponse>(resp)?
-            .username)
-    }
-
-    pub fn sign_in(
-        &self,
-        username: impl AsRef<str>,
-        password: impl AsRef<str>,
-    ) -> Result<AuthToken, AuthClientError> {
-        let data = SignInPayload {
-            username: username.as_ref().to_owned(),
-            password: net_prehash(password.as_ref()),
-        };
 
-        let ep = self.provider.join("generate_token")?;
-        let resp = self.client.post(ep).json(&data).send()?;
-
-        Ok(handle_response::<SignInResponse>(resp)?.token)
-    }
-
-    pub fn validate(&self, token: AuthToken) -> Result<Uuid, AuthClientError> {
-        let data = ValidityCheckPayload { token };
+/// Why a registration was refused, see `AuthClient::register`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    UsernameTaken,
+    EthaddrTaken,
+    /// The username, password or eth address was malformed, with the
+    /// server's reason.
+    Invalid(String),
+}
 
-        let ep = self.provider.join("verify")?;
-        let resp = self.client.post(ep).json(&data).send()?;
+/// How far a device login is, see `AuthClient::poll_device_login`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevicePoll {
+    /// The user has not approved the device yet.
+    Pending,
+    /// Polled too fast; wait five seconds longer between polls from now on.
+    SlowDown,
+    Approved(AuthToken),
+}
 
-        Ok(handle_response::<ValidityCheckResponse>(resp)?.uuid)
-    }
+/// What an event stream subscriber is told, see
+/// `AuthClient::subscribe_events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    Account(AccountEventRecord),
+    /// The events up to `cursor` were dropped by the server before they
+    /// could be sent, so players may have been banned or renamed unnoticed.
+    /// Check the players that are online again.
+    Lost { cursor: u64 },
 }
 
-/// If response code isn't a success it will return an error with the response code and plain text body.
-///
-/// Otherwise will deserialize the json based on given type (through turbofish notation)
-fn handle_response<T>(resp: reqwest::blocking::Response) -> Result<T, AuthClientError>
-where
-    T: serde::Serialize + serde::de::DeserializeOwned,
-{
-    if resp.status().is_success() {
-        Ok(resp.json::<T>()?)
-    } else {
-        Err(AuthClientError::ServerError(
-            resp.status().as_u16(),
-            resp.text()?,
-        ))
-    }
+/// Which generation of the server API requests are sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApiVersion {
+    /// The original `POST` + JSON body routes, understood by every server.
+    #[default]
+    Legacy,
+    /// The resource-style routes under `/v1`.
+    V1,
 }
 
 impl std::fmt::Display for AuthClientError {
@@ -126,6 +98,16 @@ impl std::fmt::Display for AuthClientError {
             AuthClientError::InvalidUrl(e) => {
                 write!(f, "Got invalid url to make auth requests to: {}", e)
             }
+            AuthClientError::InvalidResponse(e) => {
+                write!(f, "Auth Server returned an invalid response: {}", e)
+            }
+            AuthClientError::Register(RegisterError::UsernameTaken) => {
+                write!(f, "That username is already taken.")
+            }
+            AuthClientError::Register(RegisterError::EthaddrTaken) => {
+                write!(f, "That ethereum address is already registered.")
+            }
+            AuthClientError::Register(RegisterError::Invalid(reason)) => write!(f, "{}", reason),
         }
     }
 }
@@ -141,3 +123,9 @@ impl From<reqwest::Error> for AuthClientError {
         AuthClientError::RequestError(err)
     }
 }
+
+impl From<serde_json::Error> for AuthClientError {
+    fn from(err: serde_json::Error) -> Self {
+        AuthClientError::InvalidResponse(err)
+    }
+}
@@ -0,0 +1,253 @@
+//! How requests reach the auth server, shared by both clients: which of the
+//! configured providers to send to, and when a failed request is sent again.
+
+use crate::api::{Api, Reply, Request};
+use crate::cache::Cache;
+use crate::{ApiVersion, AuthClientError};
+use reqwest::{Certificate, IntoUrl, Url};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest a failing provider is passed over before it is tried again.
+const MAX_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Configures an `AuthClient` or `AsyncAuthClient`, built with `build` and
+/// `build_async` respectively.
+pub struct ClientBuilder {
+    pub(crate) providers: Result<Vec<Url>, AuthClientError>,
+    pub(crate) version: ApiVersion,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) timeout: Duration,
+    pub(crate) retries: u32,
+    pub(crate) backoff: Duration,
+    pub(crate) user_agent: String,
+    pub(crate) certificates: Vec<Certificate>,
+    pub(crate) cache_capacity: usize,
+    pub(crate) cache_ttl: Duration,
+    pub(crate) negative_cache_ttl: Duration,
+}
+
+impl ClientBuilder {
+    /// Starts from the defaults: 10 second connect and 30 second request
+    /// timeouts, two retries starting at 200ms apart, no fallbacks and no
+    /// lookup cache.
+    pub fn new<T: IntoUrl>(provider: T) -> Self {
+        Self {
+            providers: provider.into_url().map(|url| vec![url]).map_err(Into::into),
+            version: ApiVersion::default(),
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            user_agent: concat!("authc/", env!("CARGO_PKG_VERSION")).to_owned(),
+            certificates: Vec::new(),
+            cache_capacity: 0,
+            cache_ttl: Duration::from_secs(300),
+            negative_cache_ttl: Duration::from_secs(30),
+        }
+    }
+
+    /// Adds a provider to use while the ones before it are failing. The
+    /// first provider is preferred again as soon as it answers.
+    pub fn fallback<T: IntoUrl>(mut self, provider: T) -> Self {
+        if let Ok(providers) = &mut self.providers {
+            match provider.into_url() {
+                Ok(url) => providers.push(url),
+                Err(err) => self.providers = Err(err.into()),
+            }
+        }
+        self
+    }
+
+    pub fn api_version(mut self, version: ApiVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// How long to wait for a connection to be established.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a whole request may take, from connecting to reading the
+    /// response. The event stream uses its own idle timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many more times a lookup is sent after the server could not be
+    /// reached, timed out or was unavailable. Requests that change accounts
+    /// or create and consume tokens are sent once, whatever this is.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The wait before the first retry, doubled for each one after it. Each
+    /// wait is randomly shortened by up to half so that clients that failed
+    /// together don't retry together.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Trusts `certificate` as a root in addition to the built-in ones, for
+    /// servers behind a private certificate authority.
+    pub fn add_root_certificate(mut self, certificate: Certificate) -> Self {
+        self.certificates.push(certificate);
+        self
+    }
+
+    /// Remembers up to `capacity` answers of each of `username_to_uuid`,
+    /// `uuid_to_username` and `eth_to_info`. 0, the default, turns the cache
+    /// off.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// How long a cached answer is used, 5 minutes by default.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// How long a cached "does not exist" is used, 30 seconds by default.
+    pub fn negative_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_cache_ttl = ttl;
+        self
+    }
+
+    pub(crate) fn parts(self) -> Result<(Api, Transport, Arc<Cache>), AuthClientError> {
+        let providers = self.providers?;
+        let health = Mutex::new(vec![Health::default(); providers.len()]);
+        let api = Api::new(self.version);
+        let transport = Transport {
+            providers: Arc::new(Providers { urls: providers, health }),
+            retries: self.retries,
+            backoff: self.backoff,
+        };
+        let cache = Cache::new(self.cache_capacity, self.cache_ttl, self.negative_cache_ttl);
+        Ok((api, transport, Arc::new(cache)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Health {
+    failures: u32,
+    /// Passed over until then.
+    down_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Providers {
+    urls: Vec<Url>,
+    health: Mutex<Vec<Health>>,
+}
+
+/// The providers of a client and its retry policy. Clones share what they
+/// learn about the providers' health.
+#[derive(Debug, Clone)]
+pub(crate) struct Transport {
+    providers: Arc<Providers>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Transport {
+    /// The first provider that isn't cooling down after failing, or the one
+    /// that will be done soonest.
+    pub fn pick(&self) -> usize {
+        let health = self.providers.health.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        health
+            .iter()
+            .position(|h| h.down_until.is_none_or(|until| until <= now))
+            .or_else(|| (0..health.len()).min_by_key(|&i| health[i].down_until))
+            .unwrap_or(0)
+    }
+
+    pub fn url(&self, provider: usize, path: &str) -> Result<Url, AuthClientError> {
+        Ok(self.providers.urls[provider].join(path)?)
+    }
+
+    /// Records whether `provider` answered. Each failure in a row doubles
+    /// how long it is passed over.
+    pub fn report(&self, provider: usize, ok: bool) {
+        let mut health = self.providers.health.lock().unwrap_or_else(|e| e.into_inner());
+        let health = &mut health[provider];
+        if ok {
+            *health = Health::default();
+        } else {
+            health.failures += 1;
+            let cooldown = Duration::from_secs(1 << (health.failures - 1).min(5));
+            health.down_until = Some(Instant::now() + cooldown.min(MAX_COOLDOWN));
+        }
+    }
+
+    pub fn attempts<T>(&self, request: &Request<T>) -> Attempts<'_> {
+        Attempts {
+            transport: self,
+            idempotent: request.idempotent,
+            retries: 0,
+            provider: self.pick(),
+        }
+    }
+}
+
+/// What to do after a try, see `Attempts::outcome`.
+pub(crate) enum Step {
+    Done(Result<Reply, AuthClientError>),
+    /// Wait this long and try again.
+    Retry(Duration),
+}
+
+/// The tries of sending one request.
+pub(crate) struct Attempts<'a> {
+    transport: &'a Transport,
+    idempotent: bool,
+    retries: u32,
+    provider: usize,
+}
+
+impl Attempts<'_> {
+    /// Where the next try goes.
+    pub fn url(&self, path: &str) -> Result<Url, AuthClientError> {
+        self.transport.url(self.provider, path)
+    }
+
+    pub fn outcome(&mut self, outcome: Result<Reply, reqwest::Error>) -> Step {
+        let failed = match &outcome {
+            Ok(reply) => is_unavailable(reply.status),
+            Err(_) => true,
+        };
+        self.transport.report(self.provider, !failed);
+        if failed && self.idempotent && self.retries < self.transport.retries {
+            let delay = self.transport.backoff * 2u32.saturating_pow(self.retries);
+            self.retries += 1;
+            self.provider = self.transport.pick();
+            return Step::Retry(jitter(delay));
+        }
+        Step::Done(outcome.map_err(Into::into))
+    }
+}
+
+/// Statuses of a server or proxy that can't take requests right now.
+fn is_unavailable(status: u16) -> bool {
+    matches!(status, 502..=504)
+}
+
+/// Somewhere between half of `delay` and all of it.
+fn jitter(delay: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    delay / 2 + (delay / 2).mul_f64(random as f64 / u64::MAX as f64)
+}
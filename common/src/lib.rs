@@ -1,9 +1,11 @@
 #![forbid(unsafe_code)]
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct AuthToken {
     pub unique: u64,
 }
@@ -39,61 +41,83 @@ impl AuthToken {
 
 // add ethaddr field.  -max.lee
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct RegisterPayload {
     pub username: String,
     pub password: String,
     pub ethaddr: String,
 }
 
+/// The account a registration created.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RegisterResponse {
+    pub uuid: Uuid,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SignInPayload {
     pub username: String,
     pub password: String,
+    /// Code from the authenticator app, or a recovery code. Required for
+    /// accounts with two-factor authentication enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub otp: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SignInResponse {
     pub token: AuthToken,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ValidityCheckPayload {
     pub token: AuthToken,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ValidityCheckResponse {
     pub uuid: Uuid,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UuidLookupPayload {
     pub username: String,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UuidLookupResponse {
     pub uuid: Uuid,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UsernameLookupPayload {
     pub uuid: Uuid,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UsernameLookupResponse {
     pub username: String,
 }
 
 // new struct --max
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct EthLookupPayload {
     pub ethaddr: String,
 }
 
 // new struct --max
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct EthActivePayload {
     pub ethaddr: String,
 }
@@ -101,6 +125,7 @@ pub struct EthActivePayload {
 
 // new struct --max
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ChangePassPayload {
     pub ethaddr: String,
     pub password: String,
@@ -108,6 +133,7 @@ pub struct ChangePassPayload {
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct EthLookupResponse {
     pub username: String,
     pub uuid: Uuid,
@@ -115,14 +141,491 @@ pub struct EthLookupResponse {
 }
 
 
+/// Body of the legacy `/username_to_info` route.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserinfoLookupPayload {
+    pub username: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UserinfoLookupResponse {
     pub uuid: Uuid,
     pub ethaddr: String,
 }
  
+/// Body of the legacy `/uuid_to_info` route.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Userinfo2LookupPayload {
+    pub uuid: Uuid,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Userinfo2LookupResponse {
     pub username: String,
     pub ethaddr: String,
-}
\ No newline at end of file
+}
+
+/// Full account record returned by the `/v1/users/...` lookups.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserInfoResponse {
+    pub uuid: Uuid,
+    pub username: String,
+    pub ethaddr: String,
+    pub actived: i32,
+}
+
+/// Most values a single batch lookup may ask for.
+pub const MAX_BATCH_LOOKUP: usize = 100;
+
+/// Body of `POST /v1/users/batch/by-uuid`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UuidBatchPayload {
+    pub uuids: Vec<Uuid>,
+}
+
+/// Body of `POST /v1/users/batch/by-name`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UsernameBatchPayload {
+    pub usernames: Vec<String>,
+}
+
+/// Body of `POST /v1/users/batch/by-eth`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct EthBatchPayload {
+    pub ethaddrs: Vec<String>,
+}
+
+/// Result of a batch lookup, keyed by the values as they were requested.
+/// Accounts that do not exist map to `null`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserBatchResponse {
+    pub users: BTreeMap<String, Option<UserInfoResponse>>,
+}
+
+/// Most results a single search request may ask for.
+pub const MAX_SEARCH_RESULTS: usize = 50;
+
+/// An account matched by `GET /v1/users/search`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserSearchHit {
+    pub uuid: Uuid,
+    pub username: String,
+}
+
+/// One page of search results. `next` is passed back as `after` to fetch
+/// the following page and is absent on the last one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserSearchResponse {
+    pub users: Vec<UserSearchHit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+/// Body of `PUT /v1/users/{uuid}/searchable`. The password confirms the
+/// change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SearchVisibilityPayload {
+    #[serde(default)]
+    pub password: String,
+    pub searchable: bool,
+}
+
+/// Body of `PUT /v1/users/{uuid}/password`. The current password confirms
+/// the change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PasswordChangePayload {
+    #[serde(default)]
+    pub current_password: String,
+    pub password: String,
+}
+
+/// Body of `PUT /v1/users/{uuid}/email`. The password confirms the change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct EmailChangePayload {
+    pub password: String,
+    pub email: String,
+}
+
+/// Body of `POST /v1/password-reset`. `login` is a username or an email address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PasswordResetRequestPayload {
+    pub login: String,
+}
+
+/// Body of `POST /v1/password-reset/complete`, with the code from the reset mail.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PasswordResetPayload {
+    pub token: String,
+    pub password: String,
+}
+
+/// Body of `POST /v1/users/{uuid}/2fa`. The password confirms the enrollment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TotpEnrollPayload {
+    pub password: String,
+}
+
+/// The TOTP secret, base32 encoded, and the `otpauth://` URI to put in a QR code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TotpEnrollResponse {
+    pub secret: String,
+    pub uri: String,
+}
+
+/// Body of `POST /v1/users/{uuid}/2fa/confirm`, with a code generated from the new secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TotpConfirmPayload {
+    pub password: String,
+    pub otp: String,
+}
+
+/// Single-use codes that stand in for the authenticator. They are only ever shown once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TotpConfirmResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Body of `POST /admin/oauth/clients`. Confidential clients get a secret,
+/// public ones (apps and single page sites) rely on PKCE alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct OAuthClientPayload {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub confidential: bool,
+}
+
+/// A registered OpenID Connect client. `client_secret` is only present in
+/// the answer to its registration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct OAuthClientResponse {
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub confidential: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct OAuthClientList {
+    pub clients: Vec<OAuthClientResponse>,
+}
+
+/// Successful answer of the OAuth token endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub id_token: String,
+    pub scope: String,
+}
+
+/// Answer of a device authorization request. The device shows `user_code`
+/// and `verification_uri` to the user, then polls with `device_code` every
+/// `interval` seconds until the user approved or `expires_in` passed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeviceAuthorizationResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    /// The verification page with the user code filled in, e.g. for a QR code.
+    pub verification_uri_complete: String,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeviceTokenPayload {
+    pub device_code: String,
+}
+
+/// Error answer of the OAuth endpoints and of device token polling.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct OAuthErrorResponse {
+    /// An RFC 6749 error code, such as `authorization_pending` or `slow_down`.
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_description: Option<String>,
+}
+
+/// Claims about an account, as returned by the userinfo endpoint and put
+/// into ID tokens. Which ones are present depends on the granted scopes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserInfoClaims {
+    /// The account uuid.
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ethaddr: Option<String>,
+}
+
+/// Body of `PUT /admin/users/{uuid}/ban`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BanPayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Account lifecycle events webhooks can subscribe to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum WebhookEvent {
+    #[serde(rename = "user.registered")]
+    Registered,
+    /// The ethereum address of the account was activated.
+    #[serde(rename = "user.activated")]
+    Activated,
+    #[serde(rename = "user.password_changed")]
+    PasswordChanged,
+    #[serde(rename = "user.banned")]
+    Banned,
+    #[serde(rename = "user.unbanned")]
+    Unbanned,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 5] = [
+        Self::Registered,
+        Self::Activated,
+        Self::PasswordChanged,
+        Self::Banned,
+        Self::Unbanned,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Registered => "user.registered",
+            Self::Activated => "user.activated",
+            Self::PasswordChanged => "user.password_changed",
+            Self::Banned => "user.banned",
+            Self::Unbanned => "user.unbanned",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|event| event.as_str() == name)
+    }
+}
+
+/// Body of `POST /admin/webhooks`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WebhookSubscriptionPayload {
+    /// Where events are posted, an http or https URL.
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+}
+
+/// A webhook subscription. `secret` signs the deliveries and is only present
+/// in the answer to its creation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WebhookSubscriptionResponse {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WebhookSubscriptionList {
+    pub webhooks: Vec<WebhookSubscriptionResponse>,
+}
+
+/// What a webhook receives. `id` identifies the event, so a receiver can
+/// drop the duplicates retries may cause.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WebhookNotification {
+    pub id: Uuid,
+    pub event: WebhookEvent,
+    /// Unix time the event happened at.
+    pub created: i64,
+    /// The account as it was right after the event.
+    pub user: UserInfoResponse,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookDeliveryStatus {
+    /// Not delivered yet, another attempt is scheduled.
+    Pending,
+    Delivered,
+    /// Gave up after the last attempt.
+    Failed,
+}
+
+/// One entry of the delivery log of a webhook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WebhookDeliveryInfo {
+    pub id: i64,
+    pub event_id: Uuid,
+    pub event: WebhookEvent,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: u32,
+    /// Status code of the last attempt, if the receiver answered at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_status_code: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    pub created: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_attempt: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivered: Option<i64>,
+}
+
+/// Latest deliveries of a webhook, newest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WebhookDeliveryList {
+    pub deliveries: Vec<WebhookDeliveryInfo>,
+}
+
+/// Body of `PUT /admin/users/{uuid}/username`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UsernameChangePayload {
+    pub username: String,
+}
+
+/// Something that happened to an account which connected game servers may
+/// have to act on, such as kicking the player.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AccountEvent {
+    Banned {
+        uuid: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// The outstanding tokens of the account were revoked.
+    SessionRevoked { uuid: Uuid },
+    UsernameChanged {
+        uuid: Uuid,
+        old_username: String,
+        username: String,
+    },
+    Deleted { uuid: Uuid },
+}
+
+impl AccountEvent {
+    /// The account the event is about.
+    pub fn uuid(&self) -> Uuid {
+        match self {
+            Self::Banned { uuid, .. }
+            | Self::SessionRevoked { uuid }
+            | Self::UsernameChanged { uuid, .. }
+            | Self::Deleted { uuid } => *uuid,
+        }
+    }
+
+    /// The `type` tag, also used as the SSE event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Banned { .. } => "banned",
+            Self::SessionRevoked { .. } => "session_revoked",
+            Self::UsernameChanged { .. } => "username_changed",
+            Self::Deleted { .. } => "deleted",
+        }
+    }
+}
+
+/// An event of the `/v1/events` stream. Reconnecting with `cursor` as the
+/// `after` parameter or `Last-Event-ID` resumes right after it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct AccountEventRecord {
+    pub cursor: u64,
+    /// Unix time the event happened at.
+    pub created: i64,
+    #[serde(flatten)]
+    pub event: AccountEvent,
+}
+
+/// A verified snapshot of the database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BackupInfo {
+    /// File name in the backup directory, which `auth-server --restore` takes.
+    pub name: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Unix time the snapshot was taken at.
+    pub created: i64,
+}
+
+/// Snapshots in the backup directory, newest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BackupList {
+    pub backups: Vec<BackupInfo>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Fail,
+}
+
+/// Outcome of a single readiness check.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HealthCheck {
+    pub status: HealthStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Body of `/healthz` and `/readyz`. `status` is `fail` if any check failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HealthResponse {
+    pub status: HealthStatus,
+    #[serde(default)]
+    pub checks: BTreeMap<String, HealthCheck>,
+}
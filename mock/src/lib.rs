@@ -0,0 +1,281 @@
+//! An in-process stand-in for the auth server, for testing code built on
+//! `authc` without running the real server and its database.
+//!
+//! `MockServer::start` listens on an ephemeral local port and keeps its
+//! accounts in memory. It answers the account, lookup and token routes of
+//! both API versions like the server does, on threads of its own, so it
+//! serves blocking and async clients alike. Tests seed accounts, force how
+//! tokens validate, inject failures and latency, and read back the requests
+//! that were made.
+
+#![forbid(unsafe_code)]
+
+mod routes;
+
+use auth_common::AuthToken;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub use auth_common;
+pub use uuid;
+
+/// An account to seed the mock with, see `MockServer::add_user`.
+#[derive(Debug, Clone)]
+pub struct MockUser {
+    uuid: Uuid,
+    username: String,
+    ethaddr: String,
+    pwhash: String,
+    actived: i32,
+}
+
+impl MockUser {
+    /// An account with a random uuid, an eth address derived from it and
+    /// the address not activated yet.
+    pub fn new(username: impl Into<String>, password: &str) -> Self {
+        let uuid = Uuid::new_v4();
+        Self {
+            ethaddr: format!("0x{}", &uuid.to_simple().to_string()[..32]) + "00000000",
+            uuid,
+            username: username.into(),
+            pwhash: prehash(password),
+            actived: -1,
+        }
+    }
+
+    pub fn uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    pub fn ethaddr(mut self, ethaddr: impl Into<String>) -> Self {
+        self.ethaddr = ethaddr.into();
+        self
+    }
+
+    pub fn activated(mut self) -> Self {
+        self.actived = 1;
+        self
+    }
+}
+
+/// How a token validates, whoever issued it, see `MockServer::force_token`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenOutcome {
+    /// Valid for the account, every time it is validated.
+    Valid(Uuid),
+    Invalid,
+}
+
+/// A failure or delay for requests to matching routes, see
+/// `MockServer::inject`.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    method: Option<String>,
+    path: String,
+    status: Option<(u16, String)>,
+    delay: Duration,
+    times: Option<u32>,
+}
+
+impl Fault {
+    /// Matches requests to `path`, without the query. A trailing `*`
+    /// matches every path starting with what comes before it.
+    pub fn on(path: impl Into<String>) -> Self {
+        Self {
+            method: None,
+            path: path.into(),
+            status: None,
+            delay: Duration::from_secs(0),
+            times: None,
+        }
+    }
+
+    /// Only matches requests with this method.
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.method = Some(method.into().to_ascii_uppercase());
+        self
+    }
+
+    /// Answers with `status` and `body` as text instead of handling the
+    /// request.
+    pub fn status(mut self, status: u16, body: impl Into<String>) -> Self {
+        self.status = Some((status, body.into()));
+        self
+    }
+
+    /// Waits this long before answering.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Only applies to the next `n` matching requests.
+    pub fn times(mut self, n: u32) -> Self {
+        self.times = Some(n);
+        self
+    }
+
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let path_matches = match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        };
+        path_matches && self.method.as_deref().is_none_or(|m| m == method) && self.times != Some(0)
+    }
+}
+
+/// A request the mock received.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Without the query.
+    pub path: String,
+    pub query: String,
+    /// Names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers.iter().find(|(n, _)| *n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// The body, read as JSON of type `T`.
+    pub fn json<T: DeserializeOwned>(&self) -> Option<T> {
+        serde_json::from_slice(&self.body).ok()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    users: Vec<MockUser>,
+    /// Issued by signing in, consumed by validating.
+    tokens: HashMap<AuthToken, Uuid>,
+    forced: HashMap<AuthToken, TokenOutcome>,
+    faults: Vec<Fault>,
+    latency: Duration,
+    requests: Vec<RecordedRequest>,
+}
+
+/// The mock server, stopped when dropped.
+pub struct MockServer {
+    url: String,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    /// Starts listening on an ephemeral port of 127.0.0.1.
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let handler_state = state.clone();
+        let server = rouille::Server::new("127.0.0.1:0", move |request| routes::handle(&handler_state, request))
+            .expect("binding the mock auth server");
+        let url = format!("http://{}/", server.server_addr());
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                server.poll();
+                thread::sleep(Duration::from_millis(2));
+            }
+        });
+        Self {
+            url,
+            state,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// The provider URL to give `authc`, ending in `/`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    /// Adds an account, replacing any with the same uuid, and returns its
+    /// uuid.
+    pub fn add_user(&self, user: MockUser) -> Uuid {
+        let uuid = user.uuid;
+        let mut state = self.state();
+        state.users.retain(|u| u.uuid != uuid);
+        state.users.push(user);
+        uuid
+    }
+
+    /// The uuid of a seeded or registered account.
+    pub fn uuid_of(&self, username: &str) -> Option<Uuid> {
+        let state = self.state();
+        state.users.iter().find(|u| u.username.eq_ignore_ascii_case(username)).map(|u| u.uuid)
+    }
+
+    /// Makes `token` validate as `outcome` instead of as issued.
+    pub fn force_token(&self, token: AuthToken, outcome: TokenOutcome) -> &Self {
+        self.state().forced.insert(token, outcome);
+        self
+    }
+
+    /// Applies `fault` to matching requests, before the faults added
+    /// earlier.
+    pub fn inject(&self, fault: Fault) -> &Self {
+        self.state().faults.insert(0, fault);
+        self
+    }
+
+    /// Removes every fault.
+    pub fn clear_faults(&self) -> &Self {
+        self.state().faults.clear();
+        self
+    }
+
+    /// Delays every answer by `latency`, on top of fault delays.
+    pub fn latency(&self, latency: Duration) -> &Self {
+        self.state().latency = latency;
+        self
+    }
+
+    /// The requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state().requests.clone()
+    }
+
+    pub fn clear_requests(&self) -> &Self {
+        self.state().requests.clear();
+        self
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A test that panicked while holding the state doesn't spoil it for the
+/// requests still being answered.
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The prehash `authc` sends instead of the password the player typed.
+fn prehash(password: &str) -> String {
+    let salt = fxhash::hash64(password);
+    let bytes = argon2::hash_raw(password.as_bytes(), &salt.to_le_bytes(), &argon2::Config::default()).unwrap();
+    hex::encode(&bytes)
+}
@@ -0,0 +1,333 @@
+//! Answers requests from the in-memory accounts, with the bodies, statuses
+//! and messages of the real server.
+
+use crate::{lock, MockUser, RecordedRequest, State, TokenOutcome};
+use auth_common::{
+    AuthToken, ChangePassPayload, EthActivePayload, EthBatchPayload, EthLookupPayload, EthLookupResponse,
+    PasswordChangePayload, RegisterPayload, RegisterResponse, SignInPayload, SignInResponse,
+    Userinfo2LookupPayload, Userinfo2LookupResponse, UserinfoLookupPayload, UserinfoLookupResponse,
+    UserBatchResponse, UserInfoResponse, UsernameBatchPayload, UsernameLookupPayload, UsernameLookupResponse,
+    UuidBatchPayload, UuidLookupPayload, UuidLookupResponse, ValidityCheckPayload, ValidityCheckResponse,
+};
+use rouille::{Request, Response};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::io::Read;
+use std::sync::Mutex;
+use std::thread;
+use uuid::Uuid;
+
+enum Reject {
+    UserExists,
+    EthExists,
+    UserDoesNotExist,
+    EthDoesNotExist,
+    InvalidLogin,
+    WrongPassword,
+    InvalidToken,
+    InvalidRequest(String),
+    InvalidEthAddr(String),
+    Json(serde_json::Error),
+    NotMocked,
+}
+
+impl Reject {
+    /// Like the server, the `/v1` routes answer a missing account with 404.
+    fn status_code(&self, v1: bool) -> u16 {
+        match self {
+            Self::UserDoesNotExist | Self::EthDoesNotExist if v1 => 404,
+            Self::NotMocked => 404,
+            Self::WrongPassword => 401,
+            _ => 400,
+        }
+    }
+
+    /// The `X-Error-Code` the server sends along.
+    fn code(&self) -> &'static str {
+        match self {
+            Self::UserExists => "username_taken",
+            Self::EthExists => "ethaddr_taken",
+            Self::UserDoesNotExist => "user_does_not_exist",
+            Self::EthDoesNotExist => "ethaddr_does_not_exist",
+            Self::InvalidLogin => "invalid_login",
+            Self::WrongPassword => "wrong_password",
+            Self::InvalidToken => "invalid_token",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::InvalidEthAddr(_) => "invalid_ethaddr",
+            Self::Json(_) => "invalid_json",
+            Self::NotMocked => "not_mocked",
+        }
+    }
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserExists => write!(f, "That username is already taken."),
+            Self::EthExists => write!(f, "That ethereum address is already registered."),
+            Self::UserDoesNotExist => write!(f, "That user does not exist."),
+            Self::EthDoesNotExist => write!(f, "That ethereum address does not exist."),
+            Self::InvalidLogin => write!(
+                f,
+                "The username + password or ethaddr combination was incorrect or the user does not exist."
+            ),
+            Self::WrongPassword => write!(f, "The password of the account is incorrect."),
+            Self::InvalidToken => write!(f, "The given token is invalid."),
+            Self::InvalidRequest(s) => write!(f, "The request was invalid in some form. Reason: {}", s),
+            Self::InvalidEthAddr(s) => write!(f, "The given eth addr is invalid: {}", s),
+            Self::Json(err) => write!(f, "Error decoding JSON: {}", err),
+            Self::NotMocked => write!(f, "The mock auth server has no such route."),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Reject {
+    fn from(err: serde_json::Error) -> Self {
+        Reject::Json(err)
+    }
+}
+
+pub(crate) fn handle(state: &Mutex<State>, request: &Request) -> Response {
+    let mut body = Vec::new();
+    if let Some(mut data) = request.data() {
+        let _ = data.read_to_end(&mut body);
+    }
+    let recorded = RecordedRequest {
+        method: request.method().to_owned(),
+        path: request.url(),
+        query: request.raw_query_string().to_owned(),
+        headers: request
+            .headers()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.to_owned()))
+            .collect(),
+        body,
+    };
+
+    let (delay, fault) = {
+        let mut state = lock(state);
+        state.requests.push(recorded.clone());
+        let latency = state.latency;
+        match state.faults.iter_mut().find(|f| f.matches(&recorded.method, &recorded.path)) {
+            Some(fault) => {
+                if let Some(times) = &mut fault.times {
+                    *times -= 1;
+                }
+                (latency + fault.delay, fault.status.clone())
+            }
+            None => (latency, None),
+        }
+    };
+    thread::sleep(delay);
+    if let Some((status, body)) = fault {
+        return Response::text(body).with_status_code(status);
+    }
+
+    let v1 = recorded.path.starts_with("/v1/");
+    let mut state = lock(state);
+    match route(&mut state, request, &recorded) {
+        Ok(response) => response,
+        Err(err) => Response::text(err.to_string())
+            .with_status_code(err.status_code(v1))
+            .with_unique_header("X-Error-Code", err.code()),
+    }
+}
+
+fn route(state: &mut State, request: &Request, req: &RecordedRequest) -> Result<Response, Reject> {
+    let segments: Vec<&str> = req.path.trim_start_matches('/').split('/').collect();
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["ping"]) | ("GET", ["v1", "ping"]) => {
+            Ok(Response::text(format!("Pong! {}", request.remote_addr().ip())))
+        }
+        ("POST", ["register"]) => Ok(Response::json(&register(state, body(req)?)?)),
+        ("POST", ["v1", "users"]) => Ok(Response::json(&register(state, body(req)?)?).with_status_code(201)),
+
+        ("POST", ["username_to_uuid"]) => {
+            let payload: UuidLookupPayload = body(req)?;
+            let uuid = by_name(state, &payload.username)?.uuid;
+            Ok(Response::json(&UuidLookupResponse { uuid }))
+        }
+        ("POST", ["uuid_to_username"]) => {
+            let payload: UsernameLookupPayload = body(req)?;
+            let username = by_uuid(state, payload.uuid)?.username.clone();
+            Ok(Response::json(&UsernameLookupResponse { username }))
+        }
+        ("POST", ["eth_to_info"]) => {
+            let payload: EthLookupPayload = body(req)?;
+            let user = by_eth(state, &payload.ethaddr)?;
+            Ok(Response::json(&EthLookupResponse {
+                username: user.username.clone(),
+                uuid: user.uuid,
+                actived: user.actived,
+            }))
+        }
+        ("POST", ["username_to_info"]) => {
+            let payload: UserinfoLookupPayload = body(req)?;
+            let user = by_name(state, &payload.username)?;
+            Ok(Response::json(&UserinfoLookupResponse {
+                uuid: user.uuid,
+                ethaddr: user.ethaddr.clone(),
+            }))
+        }
+        ("POST", ["uuid_to_info"]) => {
+            let payload: Userinfo2LookupPayload = body(req)?;
+            let user = by_uuid(state, payload.uuid)?;
+            Ok(Response::json(&Userinfo2LookupResponse {
+                username: user.username.clone(),
+                ethaddr: user.ethaddr.clone(),
+            }))
+        }
+
+        ("GET", ["v1", "users", "by-name", name]) => Ok(Response::json(&info(by_name(state, name)?))),
+        ("GET", ["v1", "users", "by-eth", addr]) => Ok(Response::json(&info(by_eth(state, addr)?))),
+        ("GET", ["v1", "users", uuid]) => Ok(Response::json(&info(by_uuid(state, parse_uuid(uuid)?)?))),
+        ("POST", ["v1", "users", "batch", "by-uuid"]) => {
+            let payload: UuidBatchPayload = body(req)?;
+            let users = payload
+                .uuids
+                .iter()
+                .map(|&uuid| (uuid.to_string(), by_uuid(state, uuid).ok().map(info)))
+                .collect();
+            Ok(Response::json(&UserBatchResponse { users }))
+        }
+        ("POST", ["v1", "users", "batch", "by-name"]) => {
+            let payload: UsernameBatchPayload = body(req)?;
+            let users = payload
+                .usernames
+                .iter()
+                .map(|name| (name.clone(), by_name(state, name).ok().map(info)))
+                .collect();
+            Ok(Response::json(&UserBatchResponse { users }))
+        }
+        ("POST", ["v1", "users", "batch", "by-eth"]) => {
+            let payload: EthBatchPayload = body(req)?;
+            let users = payload
+                .ethaddrs
+                .iter()
+                .map(|addr| (addr.clone(), by_eth(state, addr).ok().map(info)))
+                .collect();
+            Ok(Response::json(&UserBatchResponse { users }))
+        }
+
+        ("POST", ["eth_active"]) => {
+            let payload: EthActivePayload = body(req)?;
+            by_eth_mut(state, &payload.ethaddr)?.actived = 1;
+            Ok(Response::text("OK"))
+        }
+        ("PUT", ["v1", "users", "by-eth", addr, "active"]) => {
+            by_eth_mut(state, addr)?.actived = 1;
+            Ok(Response::empty_204())
+        }
+        ("POST", ["change_pass"]) => {
+            let payload: ChangePassPayload = body(req)?;
+            by_eth_mut(state, &payload.ethaddr)?.pwhash = payload.password;
+            Ok(Response::text("OK"))
+        }
+        ("PUT", ["v1", "users", uuid, "password"]) => {
+            let payload: PasswordChangePayload = body(req)?;
+            let uuid = parse_uuid(uuid)?;
+            match state.users.iter_mut().find(|u| u.uuid == uuid) {
+                Some(user) if user.pwhash == payload.current_password => user.pwhash = payload.password,
+                Some(_) => return Err(Reject::WrongPassword),
+                None => return Err(Reject::UserDoesNotExist),
+            }
+            Ok(Response::empty_204())
+        }
+
+        ("POST", ["generate_token"]) | ("POST", ["v1", "tokens"]) => {
+            let payload: SignInPayload = body(req)?;
+            let uuid = match by_name(state, &payload.username) {
+                Ok(user) if user.pwhash == payload.password => user.uuid,
+                _ => return Err(Reject::InvalidLogin),
+            };
+            let token = AuthToken::generate();
+            state.tokens.insert(token, uuid);
+            Ok(Response::json(&SignInResponse { token }))
+        }
+        ("POST", ["verify"]) | ("POST", ["v1", "tokens", "verify"]) => {
+            let payload: ValidityCheckPayload = body(req)?;
+            let uuid = match state.forced.get(&payload.token) {
+                Some(TokenOutcome::Valid(uuid)) => Some(*uuid),
+                Some(TokenOutcome::Invalid) => None,
+                None => state.tokens.remove(&payload.token),
+            };
+            let uuid = uuid.ok_or(Reject::InvalidToken)?;
+            Ok(Response::json(&ValidityCheckResponse { uuid }))
+        }
+
+        _ => Err(Reject::NotMocked),
+    }
+}
+
+fn body<T: DeserializeOwned>(req: &RecordedRequest) -> Result<T, Reject> {
+    Ok(serde_json::from_slice(&req.body)?)
+}
+
+fn parse_uuid(s: &str) -> Result<Uuid, Reject> {
+    s.parse().map_err(|_| Reject::InvalidRequest("Invalid uuid.".into()))
+}
+
+fn register(state: &mut State, payload: RegisterPayload) -> Result<RegisterResponse, Reject> {
+    let legal = |c: char| c.is_ascii_alphanumeric() || ['-', '_'].contains(&c);
+    if !(3..=32).contains(&payload.username.len()) || !payload.username.chars().all(legal) {
+        return Err(Reject::InvalidRequest(
+            "Username must be between 3 and 32 characters inclusive.".into(),
+        ));
+    }
+    if payload.ethaddr.len() != 42 || !payload.ethaddr.chars().all(|c| c.is_ascii_hexdigit() || c == 'x') {
+        return Err(Reject::InvalidEthAddr(
+            "Eth address must be between 42 characters with the hex prefix '0x'.".into(),
+        ));
+    }
+    if by_name(state, &payload.username).is_ok() {
+        return Err(Reject::UserExists);
+    }
+    if by_eth(state, &payload.ethaddr).is_ok() {
+        return Err(Reject::EthExists);
+    }
+    let uuid = Uuid::new_v4();
+    state.users.push(MockUser {
+        uuid,
+        username: payload.username,
+        ethaddr: payload.ethaddr.to_lowercase(),
+        pwhash: payload.password,
+        actived: -1,
+    });
+    Ok(RegisterResponse { uuid })
+}
+
+fn by_uuid(state: &State, uuid: Uuid) -> Result<&MockUser, Reject> {
+    state.users.iter().find(|u| u.uuid == uuid).ok_or(Reject::UserDoesNotExist)
+}
+
+fn by_name<'a>(state: &'a State, username: &str) -> Result<&'a MockUser, Reject> {
+    state
+        .users
+        .iter()
+        .find(|u| u.username.eq_ignore_ascii_case(username))
+        .ok_or(Reject::UserDoesNotExist)
+}
+
+fn by_eth<'a>(state: &'a State, ethaddr: &str) -> Result<&'a MockUser, Reject> {
+    state
+        .users
+        .iter()
+        .find(|u| u.ethaddr.eq_ignore_ascii_case(ethaddr))
+        .ok_or(Reject::EthDoesNotExist)
+}
+
+fn by_eth_mut<'a>(state: &'a mut State, ethaddr: &str) -> Result<&'a mut MockUser, Reject> {
+    state
+        .users
+        .iter_mut()
+        .find(|u| u.ethaddr.eq_ignore_ascii_case(ethaddr))
+        .ok_or(Reject::EthDoesNotExist)
+}
+
+fn info(user: &MockUser) -> UserInfoResponse {
+    UserInfoResponse {
+        uuid: user.uuid,
+        username: user.username.clone(),
+        ethaddr: user.ethaddr.clone(),
+        actived: user.actived,
+    }
+}
@@ -0,0 +1,142 @@
+//! The mock server behind both `authc` clients.
+
+use auth_mock::{Fault, MockServer, MockUser, TokenOutcome};
+use authc::{ApiVersion, AsyncAuthClient, AuthClient, AuthClientError, AuthToken, ClientBuilder, RegisterError};
+use std::time::{Duration, Instant};
+
+const ALICE_ETH: &str = "0x00000000000000000000000000000000000000a1";
+
+fn status<T: std::fmt::Debug>(result: Result<T, AuthClientError>) -> u16 {
+    match result {
+        Err(AuthClientError::ServerError(status, _)) => status,
+        other => panic!("expected a server error, got {:?}", other),
+    }
+}
+
+#[test]
+fn seeded_and_registered_accounts_on_both_api_versions() {
+    let mock = MockServer::start();
+    let alice = mock.add_user(MockUser::new("Alice", "hunter22").ethaddr(ALICE_ETH).activated());
+
+    for api in [ApiVersion::Legacy, ApiVersion::V1] {
+        let client = AuthClient::new(mock.url()).unwrap().with_api_version(api);
+        assert!(client.ping().unwrap().starts_with("Pong!"));
+        assert_eq!(client.username_to_uuid("alice").unwrap(), alice);
+        assert_eq!(client.uuid_to_username(alice).unwrap(), "Alice");
+        assert_eq!(client.eth_to_info(ALICE_ETH).unwrap().actived, 1);
+        let token = client.sign_in("alice", "hunter22").unwrap();
+        assert_eq!(client.validate(token).unwrap(), alice);
+        // Tokens are single use, like on the server.
+        assert_eq!(status(client.validate(token)), 400);
+        assert_eq!(status(client.sign_in("alice", "wrong")), 400);
+    }
+
+    let client = AuthClient::new(mock.url()).unwrap().with_api_version(ApiVersion::V1);
+    assert_eq!(status(client.user_info(uuid::Uuid::nil())), 404);
+    let bob = client.register("bob", "hunter22", "0x00000000000000000000000000000000000000b0").unwrap();
+    assert_eq!(mock.uuid_of("BOB"), Some(bob));
+    assert!(matches!(
+        client.register("bob", "hunter22", "0x00000000000000000000000000000000000000b1"),
+        Err(AuthClientError::Register(RegisterError::UsernameTaken))
+    ));
+    let names = client.uuids_to_usernames(&[alice, bob]).unwrap();
+    assert_eq!((names[&alice].as_deref(), names[&bob].as_deref()), (Some("Alice"), Some("bob")));
+}
+
+#[test]
+fn tokens_faults_latency_and_recorded_requests() {
+    let mock = MockServer::start();
+    let alice = mock.add_user(MockUser::new("alice", "hunter22"));
+    let client = ClientBuilder::new(mock.url())
+        .retry_backoff(Duration::from_millis(10))
+        .user_agent("game-server/7")
+        .build()
+        .unwrap();
+
+    mock.force_token(AuthToken { unique: 1 }, TokenOutcome::Valid(alice))
+        .force_token(AuthToken { unique: 2 }, TokenOutcome::Invalid);
+    assert_eq!(client.validate(AuthToken { unique: 1 }).unwrap(), alice);
+    assert_eq!(client.validate(AuthToken { unique: 1 }).unwrap(), alice);
+    assert!(client.validate(AuthToken { unique: 2 }).is_err());
+
+    // One failure is retried away, the sign in is not retried.
+    mock.clear_requests()
+        .inject(Fault::on("/username_to_uuid").status(503, "down").times(1))
+        .inject(Fault::on("/generate_*").method("post").status(500, "broken"));
+    assert_eq!(client.username_to_uuid("alice").unwrap(), alice);
+    assert_eq!(status(client.sign_in("alice", "hunter22")), 500);
+    let requests = mock.requests();
+    let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+    assert_eq!(paths, ["/username_to_uuid", "/username_to_uuid", "/generate_token"]);
+    assert_eq!(requests[0].header("User-Agent"), Some("game-server/7"));
+    let body: serde_json::Value = requests[2].json().unwrap();
+    assert_eq!(body["username"], "alice");
+
+    mock.clear_faults().latency(Duration::from_millis(300));
+    let started = Instant::now();
+    client.sign_in("alice", "hunter22").unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn works_from_async_tests() {
+    let mock = MockServer::start();
+    let alice = mock.add_user(MockUser::new("alice", "hunter22"));
+    mock.inject(Fault::on("/v1/users/*").delay(Duration::from_millis(50)));
+    let mut runtime = tokio02::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let client = AsyncAuthClient::new(mock.url()).unwrap().with_api_version(ApiVersion::V1);
+        assert_eq!(client.user_info(alice).await.unwrap().username, "alice");
+        let token = client.sign_in("alice", "hunter22").await.unwrap();
+        assert_eq!(client.validate(token).await.unwrap(), alice);
+    });
+    assert_eq!(mock.requests().len(), 3);
+}
+
+#[test]
+fn legacy_batches_only_read_missing_accounts_as_none() {
+    let mock = MockServer::start();
+    let alice = mock.add_user(MockUser::new("alice", "hunter22"));
+    let client = AuthClient::new(mock.url()).unwrap();
+
+    let uuids = client.usernames_to_uuids(&["alice", "nobody"]).unwrap();
+    assert_eq!((uuids["alice"], uuids["nobody"]), (Some(alice), None));
+    let names = client.uuids_to_usernames(&[alice, uuid::Uuid::nil()]).unwrap();
+    assert_eq!(names[&uuid::Uuid::nil()], None);
+
+    // Any other 400 is an error, not a missing account.
+    mock.inject(Fault::on("/username_to_uuid").status(400, "That user does not exist."));
+    assert_eq!(status(client.usernames_to_uuids(&["nobody"])), 400);
+}
+
+#[test]
+fn only_missing_accounts_are_cached_as_missing() {
+    let mock = MockServer::start();
+    let alice = mock.add_user(MockUser::new("alice", "hunter22"));
+    for (api, name) in [(ApiVersion::Legacy, "bob"), (ApiVersion::V1, "carol")] {
+        let client = ClientBuilder::new(mock.url())
+            .cache_capacity(10)
+            .api_version(api)
+            .build()
+            .unwrap();
+
+        // A 400 without the "does not exist" code is not remembered.
+        mock.inject(Fault::on("*").status(400, "malformed").times(1));
+        assert_eq!(status(client.username_to_uuid("alice")), 400);
+        assert_eq!(client.username_to_uuid("alice").unwrap(), alice);
+        mock.inject(Fault::on("*").status(400, "malformed").times(1));
+        assert_eq!(status(client.uuid_to_username(alice)), 400);
+        assert_eq!(client.uuid_to_username(alice).unwrap(), "alice");
+
+        // An account that does not exist is.
+        assert!(client.username_to_uuid(name).is_err());
+        mock.add_user(MockUser::new(name, "hunter22"));
+        assert!(client.username_to_uuid(name).is_err());
+        client.invalidate_username(name);
+        assert!(client.username_to_uuid(name).is_ok());
+    }
+}
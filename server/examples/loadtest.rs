@@ -0,0 +1,277 @@
+//! Reconnect-storm load test: many clients hitting the server at once.
+//!
+//! ```text
+//! cargo run --release -p auth-server --example loadtest -- [url] [clients] [mode]
+//! cargo run --release -p auth-server --example loadtest -- --compare BASELINE CURRENT [clients] [mode]
+//! ```
+//!
+//! `url` defaults to `http://127.0.0.1:8081/`, `clients` to 1000 and `mode`
+//! to `token`, where every client signs in (one argon2 verification each).
+//! `ping` skips the hashing and only measures the HTTP layer.
+//!
+//! `--compare` takes two server binaries instead of a URL, usually the
+//! thread-per-connection build from before the move to tokio/hyper and the
+//! current one. It starts each in turn on a fresh database, runs the same
+//! burst against it while sampling its threads and memory from `/proc`, and
+//! prints the results side by side.
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USERNAME: &str = "loadtest";
+const PASSWORD: &str = "loadtest-password";
+/// A request still unanswered after this long counts as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+enum Outcome {
+    Ok(Duration),
+    /// A `503`, and whether it said when to retry.
+    Busy(Duration, bool),
+    Failed(Duration, String),
+}
+
+async fn call(client: &Client<HttpConnector>, method: Method, url: &str, body: &str) -> Outcome {
+    let start = Instant::now();
+    let request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_owned()))
+        .expect("invalid request");
+    match tokio::time::timeout(REQUEST_TIMEOUT, client.request(request)).await {
+        Ok(Ok(resp)) if resp.status().is_success() => {
+            let _ = hyper::body::to_bytes(resp.into_body()).await;
+            Outcome::Ok(start.elapsed())
+        }
+        Ok(Ok(resp)) if resp.status().as_u16() == 503 => {
+            Outcome::Busy(start.elapsed(), resp.headers().contains_key("Retry-After"))
+        }
+        Ok(Ok(resp)) => Outcome::Failed(start.elapsed(), format!("status {}", resp.status())),
+        Ok(Err(err)) => Outcome::Failed(start.elapsed(), err.to_string()),
+        Err(_) => Outcome::Failed(start.elapsed(), "timed out".into()),
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let idx = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[idx]
+}
+
+/// What one burst came back with.
+#[derive(Default)]
+struct Report {
+    elapsed: Duration,
+    ok: Vec<Duration>,
+    busy: Vec<Duration>,
+    /// `503`s that carried `Retry-After`.
+    retry_after: usize,
+    failed: Vec<(Duration, String)>,
+    /// Peak threads and resident memory in KiB, when the server was started
+    /// by `--compare`.
+    peak: Option<(usize, u64)>,
+}
+
+impl Report {
+    fn rows(&self) -> Vec<(&'static str, String)> {
+        let mut rows = vec![
+            ("wall time", format!("{:.2?}", self.elapsed)),
+            ("succeeded", self.ok.len().to_string()),
+            ("503", self.busy.len().to_string()),
+            ("503 with Retry-After", format!("{}", self.retry_after)),
+            ("failed", self.failed.len().to_string()),
+            ("success p50", format!("{:.2?}", percentile(&self.ok, 0.5))),
+            ("success p99", format!("{:.2?}", percentile(&self.ok, 0.99))),
+            ("success max", format!("{:.2?}", self.ok.last().copied().unwrap_or_default())),
+            ("503 p99", format!("{:.2?}", percentile(&self.busy, 0.99))),
+        ];
+        if let Some((threads, rss)) = self.peak {
+            rows.push(("peak threads", threads.to_string()));
+            rows.push(("peak RSS", format!("{} MB", rss / 1024)));
+        }
+        rows
+    }
+}
+
+/// Sends `clients` requests of `mode` at `base` all at once.
+async fn burst(base: &str, clients: usize, mode: &str) -> Report {
+    let client = Client::new();
+    let credentials = format!(r#"{{"username":"{}","password":"{}"}}"#, USERNAME, PASSWORD);
+    let (method, url, body) = match mode {
+        "ping" => (Method::GET, format!("{}/ping", base), String::new()),
+        "token" => {
+            let register = format!(
+                r#"{{"username":"{}","password":"{}","ethaddr":"0x{:040x}"}}"#,
+                USERNAME, PASSWORD, 0x10ad_7e57u64
+            );
+            // Fails harmlessly when the account already exists.
+            call(&client, Method::POST, &format!("{}/register", base), &register).await;
+            (Method::POST, format!("{}/generate_token", base), credentials)
+        }
+        other => panic!("unknown mode {}", other),
+    };
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| {
+            let client = client.clone();
+            let (method, url, body) = (method.clone(), url.clone(), body.clone());
+            tokio::spawn(async move { call(&client, method, &url, &body).await })
+        })
+        .collect();
+
+    let mut report = Report::default();
+    for task in tasks {
+        match task.await.expect("client task panicked") {
+            Outcome::Ok(d) => report.ok.push(d),
+            Outcome::Busy(d, retry_after) => {
+                report.busy.push(d);
+                report.retry_after += retry_after as usize;
+            }
+            Outcome::Failed(d, err) => report.failed.push((d, err)),
+        }
+    }
+    report.elapsed = start.elapsed();
+    report.ok.sort();
+    report.busy.sort();
+    report
+}
+
+/// A server binary started for `--compare`, with a thread sampling its
+/// thread count and memory.
+struct Server {
+    child: Child,
+    base: String,
+    db: String,
+    peak: Arc<Mutex<(usize, u64)>>,
+    stop: Arc<AtomicBool>,
+    sampler: thread::JoinHandle<()>,
+}
+
+/// Threads and resident memory in KiB of process `pid`.
+fn proc_status(pid: u32) -> Option<(usize, u64)> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let field = |name: &str| -> Option<u64> {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        line[name.len()..].split_whitespace().next()?.parse().ok()
+    };
+    Some((field("Threads:")? as usize, field("VmRSS:")?))
+}
+
+impl Server {
+    async fn start(binary: &str) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let db = env::temp_dir()
+            .join(format!("loadtest-{}-{}.db", std::process::id(), port))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let child = Command::new(binary)
+            .env("AUTH_ADDR", format!("127.0.0.1:{}", port))
+            .env("AUTH_DB_DIR", &db)
+            .env_remove("RUST_LOG")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|err| panic!("could not start {}: {}", binary, err));
+        let base = format!("http://127.0.0.1:{}", port);
+
+        let client = Client::new();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !matches!(call(&client, Method::GET, &format!("{}/ping", base), "").await, Outcome::Ok(_)) {
+            assert!(Instant::now() < deadline, "{} did not come up", binary);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let peak = Arc::new(Mutex::new((0, 0)));
+        let stop = Arc::new(AtomicBool::new(false));
+        let sampler = {
+            let (pid, peak, stop) = (child.id(), peak.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if let Some((threads, rss)) = proc_status(pid) {
+                        let mut peak = peak.lock().unwrap();
+                        *peak = (peak.0.max(threads), peak.1.max(rss));
+                    }
+                    thread::sleep(SAMPLE_INTERVAL);
+                }
+            })
+        };
+        Self { child, base, db, peak, stop, sampler }
+    }
+
+    fn stop(mut self) -> (usize, u64) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.sampler.join().unwrap();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        for suffix in ["", ".lock", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", self.db, suffix));
+        }
+        let peak = *self.peak.lock().unwrap();
+        peak
+    }
+}
+
+fn print_first_failure(report: &Report) {
+    if let Some((_, err)) = report.failed.first() {
+        println!("first failure  {}", err);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--compare") {
+        let (baseline, current) = match (args.get(1), args.get(2)) {
+            (Some(baseline), Some(current)) => (baseline, current),
+            _ => panic!("--compare takes the baseline and the current server binary"),
+        };
+        let clients: usize = args.get(3).and_then(|v| v.parse().ok()).unwrap_or(1000);
+        let mode = args.get(4).map(String::as_str).unwrap_or("token");
+        println!("{} clients, mode {}", clients, mode);
+
+        let mut reports = Vec::new();
+        for binary in [baseline, current] {
+            let server = Server::start(binary).await;
+            let mut report = burst(&server.base, clients, mode).await;
+            report.peak = Some(server.stop());
+            reports.push(report);
+        }
+        println!("{:<22}{:>16}{:>16}", "", "baseline", "current");
+        for (before, after) in reports[0].rows().into_iter().zip(reports[1].rows()) {
+            println!("{:<22}{:>16}{:>16}", before.0, before.1, after.1);
+        }
+        for report in &reports {
+            print_first_failure(report);
+        }
+        return;
+    }
+
+    let mut args = args.into_iter();
+    let base = args
+        .next()
+        .unwrap_or_else(|| "http://127.0.0.1:8081/".into());
+    let base = base.trim_end_matches('/').to_owned();
+    let clients: usize = args.next().and_then(|v| v.parse().ok()).unwrap_or(1000);
+    let mode = args.next().unwrap_or_else(|| "token".into());
+
+    println!("{} clients, mode {}, target {}", clients, mode, base);
+    let report = burst(&base, clients, &mode).await;
+    for (name, value) in report.rows() {
+        println!("{:<22}{:>16}", name, value);
+    }
+    print_first_failure(&report);
+}
@@ -0,0 +1,428 @@
+//! `auth-admin`, account management against the server's database directly,
+//! for operators without `sqlite3` and hand-written SQL.
+//!
+//! ```text
+//! auth-admin [--db PATH] [--json] list [--after USERNAME] [--limit N]
+//! auth-admin [--db PATH] [--json] search TEXT
+//! auth-admin [--db PATH] [--json] show USER
+//! auth-admin [--db PATH] passwd USER [--password PASSWORD]
+//! auth-admin [--db PATH] set-actived USER VALUE
+//! auth-admin [--db PATH] rename USER USERNAME
+//! auth-admin [--db PATH] delete USER --yes
+//! auth-admin [--db PATH] ban USER [--reason REASON]
+//! auth-admin [--db PATH] unban USER
+//! auth-admin [--db PATH] [--json] check
+//! ```
+//!
+//! `USER` is a uuid, an ethaddr or a username. Changes go through the same
+//! functions the server uses, so they are validated, hashed and recorded as
+//! account events and webhooks the same way. Commands that change accounts
+//! refuse to run while a server holds the database, like `--restore`. The
+//! tokens a server saved when it stopped come back when it starts again
+//! though: revoke them with `DELETE /admin/users/{uuid}/tokens` after
+//! banning or deleting offline.
+
+use crate::auth::{self, decapitalize, AuthError};
+use crate::backup;
+use crate::health;
+use crate::transfer::NO_ETHADDR_PREFIX;
+use crate::web::verify_username;
+use auth_common::{HealthCheck, HealthResponse, HealthStatus};
+use rusqlite::{params, Row};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, BufRead, Write};
+use uuid::Uuid;
+
+/// Accounts listed when no `--limit` is given.
+const DEFAULT_LIMIT: usize = 50;
+
+const ACCOUNT_COLUMNS: &str = "uuid, display_username, ethaddr, actived, email, searchable, banned, ban_reason, totp_secret IS NOT NULL";
+
+/// One account as shown. Password hashes and secrets never are.
+#[derive(Debug, Serialize)]
+pub struct AccountDetails {
+    pub uuid: Uuid,
+    pub username: String,
+    /// `None` for accounts imported without one.
+    pub ethaddr: Option<String>,
+    pub actived: i32,
+    pub email: Option<String>,
+    pub searchable: bool,
+    pub banned: bool,
+    pub ban_reason: Option<String>,
+    /// Whether two-factor authentication is enabled.
+    pub totp: bool,
+}
+
+impl AccountDetails {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let uuid: String = row.get(0)?;
+        let ethaddr: String = row.get(2)?;
+        Ok(Self {
+            uuid: Uuid::parse_str(&uuid).unwrap_or_else(|_| Uuid::nil()),
+            username: row.get(1)?,
+            ethaddr: Some(ethaddr).filter(|ethaddr| !ethaddr.starts_with(NO_ETHADDR_PREFIX)),
+            actived: row.get(3)?,
+            email: row.get(4)?,
+            searchable: row.get(5)?,
+            banned: row.get(6)?,
+            ban_reason: row.get(7)?,
+            totp: row.get(8)?,
+        })
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let text = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".into());
+        vec![
+            ("uuid", self.uuid.to_string()),
+            ("username", self.username.clone()),
+            ("ethaddr", text(&self.ethaddr)),
+            ("actived", self.actived.to_string()),
+            ("email", text(&self.email)),
+            ("searchable", self.searchable.to_string()),
+            ("banned", self.banned.to_string()),
+            ("ban_reason", text(&self.ban_reason)),
+            ("totp", self.totp.to_string()),
+        ]
+    }
+}
+
+/// Accounts ordered by username, after `after` if given.
+pub fn list_accounts(after: Option<&str>, limit: usize) -> Result<Vec<AccountDetails>, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare(&format!(
+        "SELECT {} FROM users WHERE username > ?1 ORDER BY username LIMIT ?2",
+        ACCOUNT_COLUMNS
+    ))?;
+    let accounts = stmt
+        .query_map(params![after.map(decapitalize).unwrap_or_default(), limit as i64], AccountDetails::from_row)?
+        .collect::<Result<_, _>>()?;
+    Ok(accounts)
+}
+
+/// Accounts whose username or email contains `text`, or whose ethaddr is
+/// `text`. Unlike `GET /v1/users/search` this includes unsearchable accounts.
+pub fn search_accounts(text: &str) -> Result<Vec<AccountDetails>, AuthError> {
+    let text = decapitalize(text);
+    let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+    let db = auth::db()?;
+    let mut stmt = db.prepare(&format!(
+        "SELECT {} FROM users
+         WHERE username LIKE ?1 ESCAPE '\\' OR lower(email) LIKE ?1 ESCAPE '\\' OR ethaddr == ?2
+         ORDER BY username",
+        ACCOUNT_COLUMNS
+    ))?;
+    let accounts = stmt
+        .query_map(params![pattern, text], AccountDetails::from_row)?
+        .collect::<Result<_, _>>()?;
+    Ok(accounts)
+}
+
+/// The account `key` names: a uuid, an ethaddr starting with `0x` or a
+/// username.
+pub fn find_account(key: &str) -> Result<AccountDetails, AuthError> {
+    let (column, value) = match Uuid::parse_str(key) {
+        Ok(uuid) => ("uuid", uuid.to_simple().to_string()),
+        Err(_) if key.starts_with("0x") => ("ethaddr", decapitalize(key)),
+        Err(_) => ("username", decapitalize(key)),
+    };
+    let db = auth::db()?;
+    let mut stmt = db.prepare(&format!("SELECT {} FROM users WHERE {} == ?1", ACCOUNT_COLUMNS, column))?;
+    let mut accounts = stmt.query_map(params![value], AccountDetails::from_row)?;
+    match accounts.next() {
+        Some(account) => Ok(account?),
+        None => Err(AuthError::UserDoesNotExist),
+    }
+}
+
+/// Checks the database file, its schema version, and the accounts in it.
+pub fn check_integrity() -> HealthResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert("integrity".to_owned(), check("integrity", sqlite_integrity));
+    checks.insert("migrations".to_owned(), health::migrations());
+    checks.insert("usernames".to_owned(), check("usernames", usernames));
+    checks.insert("password_hashes".to_owned(), check("password_hashes", password_hashes));
+    checks.insert("orphans".to_owned(), check("orphans", orphans));
+    health::summarize(checks)
+}
+
+fn check(name: &str, run: fn() -> Result<HealthCheck, AuthError>) -> HealthCheck {
+    run().unwrap_or_else(|err| health::fail(format!("{} could not be checked: {}", name, err)))
+}
+
+fn sqlite_integrity() -> Result<HealthCheck, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare("PRAGMA integrity_check")?;
+    let problems: Vec<String> = stmt
+        .query_map(params![], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(match problems.as_slice() {
+        [ok] if ok == "ok" => health::ok(None),
+        _ => health::fail(problems.join("; ")),
+    })
+}
+
+/// Lookups rely on `username` being the lowercase `display_username`.
+fn usernames() -> Result<HealthCheck, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare("SELECT username, display_username FROM users")?;
+    let mismatched: Vec<String> = stmt
+        .query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .filter_map(|s| s.ok())
+        .filter(|(username, display)| *username != decapitalize(display))
+        .map(|(_, display)| display)
+        .collect();
+    Ok(if mismatched.is_empty() {
+        health::ok(None)
+    } else {
+        health::fail(format!("username doesn't match display_username for {}", mismatched.join(", ")))
+    })
+}
+
+fn password_hashes() -> Result<HealthCheck, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare("SELECT display_username, pwhash FROM users")?;
+    let (mut empty, mut malformed) = (0, Vec::new());
+    let rows = stmt.query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    for (username, pwhash) in rows.filter_map(|s| s.ok()) {
+        if pwhash.is_empty() {
+            empty += 1;
+        } else if !pwhash.starts_with("$argon2") {
+            malformed.push(username);
+        }
+    }
+    Ok(if !malformed.is_empty() {
+        health::fail(format!("not an argon2 hash for {}", malformed.join(", ")))
+    } else if empty > 0 {
+        health::ok(format!("{} accounts have no password and can't sign in", empty))
+    } else {
+        health::ok(None)
+    })
+}
+
+/// Rows left behind for accounts that no longer exist.
+fn orphans() -> Result<HealthCheck, AuthError> {
+    let db = auth::db()?;
+    let mut found = Vec::new();
+    for table in auth::ACCOUNT_TABLES {
+        let count: i64 = db.query_row(
+            &format!(
+                "SELECT count(*) FROM {} WHERE uuid IS NOT NULL AND uuid NOT IN (SELECT uuid FROM users)",
+                table
+            ),
+            params![],
+            |row| row.get(0),
+        )?;
+        if count > 0 {
+            found.push(format!("{} in {}", count, table));
+        }
+    }
+    Ok(if found.is_empty() {
+        health::ok(None)
+    } else {
+        health::fail(format!("rows of deleted accounts: {}", found.join(", ")))
+    })
+}
+
+/// Runs `auth-admin` with the arguments after the program name.
+pub fn run(args: &[String]) -> Result<bool, AuthError> {
+    let mut json = false;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--db" => {
+                let path = value(&mut args, "--db")?;
+                env::set_var("AUTH_DB_DIR", path);
+                // The server falls back to its default path, this shouldn't.
+                if auth::db_path() != path {
+                    return Err(invalid(&format!("{} is not a usable database path.", path)));
+                }
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+    let (command, args) = match rest.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => return Err(invalid("Name a command: list, search, show, passwd, set-actived, rename, delete, ban, unban or check.")),
+    };
+    // Reading is safe next to a running server, changing accounts under it is not.
+    let changes = ["passwd", "set-actived", "rename", "delete", "ban", "unban"].contains(&command);
+    let _lock = if changes { Some(backup::lock_offline("changing accounts")?) } else { None };
+    auth::init_db()?;
+
+    match command {
+        "list" => {
+            let (mut after, mut limit) = (None, DEFAULT_LIMIT);
+            let mut args = args.iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--after" => after = Some(value(&mut args, "--after")?.to_owned()),
+                    "--limit" => {
+                        limit = value(&mut args, "--limit")?
+                            .parse()
+                            .map_err(|_| invalid("--limit takes a number."))?
+                    }
+                    other => return Err(unexpected(other)),
+                }
+            }
+            print_accounts(&list_accounts(after.as_deref(), limit)?, json)?;
+        }
+        "search" => match args {
+            [text] => print_accounts(&search_accounts(text)?, json)?,
+            _ => return Err(invalid("search takes the text to look for.")),
+        },
+        "show" => match args {
+            [key] => print_account(&find_account(key)?, json)?,
+            _ => return Err(invalid("show takes the account to show.")),
+        },
+        "passwd" => {
+            let (key, options) = account_arg(args, "passwd")?;
+            let password = match options {
+                [] => prompt("New password: ")?,
+                [flag, password] if flag == "--password" => password.clone(),
+                _ => return Err(invalid("passwd takes the account and optionally --password.")),
+            };
+            if password.is_empty() {
+                return Err(invalid("The password can't be empty."));
+            }
+            let account = find_account(key)?;
+            // Clients prehash before sending, so store what they will send.
+            auth::change_passwd_by_uuid(&account.uuid, &auth::prehash_password(&password)?)?;
+            print_account(&account, json)?;
+        }
+        "set-actived" => match args {
+            [key, actived] => {
+                let actived = actived.parse().map_err(|_| invalid("actived is a number, such as 1 or -1."))?;
+                let account = find_account(key)?;
+                auth::set_actived(&account.uuid, actived)?;
+                print_account(&find_account(key)?, json)?;
+            }
+            _ => return Err(invalid("set-actived takes the account and the new value.")),
+        },
+        "rename" => match args {
+            [key, username] => {
+                verify_username(username)?;
+                let account = find_account(key)?;
+                auth::rename_user(&account.uuid, username)?;
+                print_account(&find_account(&account.uuid.to_string())?, json)?;
+            }
+            _ => return Err(invalid("rename takes the account and its new username.")),
+        },
+        "delete" => {
+            let (key, options) = account_arg(args, "delete")?;
+            if options != ["--yes"] {
+                return Err(invalid("Deleting can't be undone, confirm it with --yes."));
+            }
+            let account = find_account(key)?;
+            auth::delete_user(&account.uuid)?;
+            print_account(&account, json)?;
+        }
+        "ban" => {
+            let (key, options) = account_arg(args, "ban")?;
+            let reason = match options {
+                [] => None,
+                [flag, reason] if flag == "--reason" => Some(reason.as_str()),
+                _ => return Err(invalid("ban takes the account and optionally --reason.")),
+            };
+            let account = find_account(key)?;
+            auth::set_banned(&account.uuid, true, reason)?;
+            print_account(&find_account(key)?, json)?;
+        }
+        "unban" => match args {
+            [key] => {
+                let account = find_account(key)?;
+                auth::set_banned(&account.uuid, false, None)?;
+                print_account(&find_account(key)?, json)?;
+            }
+            _ => return Err(invalid("unban takes the account to unban.")),
+        },
+        "check" => {
+            let report = check_integrity();
+            if json {
+                println!("{:#}", serde_json::to_value(&report)?);
+            } else {
+                for (name, check) in &report.checks {
+                    let status = if check.status == HealthStatus::Ok { "ok" } else { "FAIL" };
+                    println!("{:<16} {:<4} {}", name, status, check.detail.as_deref().unwrap_or(""));
+                }
+            }
+            return Ok(report.status == HealthStatus::Ok);
+        }
+        other => return Err(invalid(&format!("Unknown command {}.", other))),
+    }
+    Ok(true)
+}
+
+fn invalid(reason: &str) -> AuthError {
+    AuthError::InvalidRequest(reason.into())
+}
+
+fn unexpected(arg: &str) -> AuthError {
+    invalid(&format!("Unexpected argument {}.", arg))
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<&'a str, AuthError> {
+    args.next()
+        .map(String::as_str)
+        .ok_or_else(|| invalid(&format!("{} needs a value.", flag)))
+}
+
+fn account_arg<'a>(args: &'a [String], command: &str) -> Result<(&'a str, &'a [String]), AuthError> {
+    match args.split_first() {
+        Some((key, options)) => Ok((key, options)),
+        None => Err(invalid(&format!("{} takes the account to change.", command))),
+    }
+}
+
+fn prompt(question: &str) -> Result<String, AuthError> {
+    eprint!("{}", question);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn print_account(account: &AccountDetails, json: bool) -> Result<(), AuthError> {
+    if json {
+        println!("{:#}", serde_json::to_value(account)?);
+    } else {
+        for (name, value) in account.fields() {
+            println!("{:<11} {}", name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Prints accounts as a table, or as a JSON array.
+fn print_accounts(accounts: &[AccountDetails], json: bool) -> Result<(), AuthError> {
+    if json {
+        println!("{:#}", serde_json::to_value(accounts)?);
+        return Ok(());
+    }
+    let header = ["uuid", "username", "ethaddr", "actived", "banned", "email"];
+    let rows: Vec<Vec<String>> = accounts
+        .iter()
+        .map(|account| {
+            let fields: BTreeMap<_, _> = account.fields().into_iter().collect();
+            header.iter().map(|column| fields[column].clone()).collect()
+        })
+        .collect();
+    let widths: Vec<usize> = header
+        .iter()
+        .enumerate()
+        .map(|(i, column)| rows.iter().map(|row| row[i].len()).chain(Some(column.len())).max().unwrap_or(0))
+        .collect();
+    let line = |cells: Vec<String>| {
+        let padded: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:<1$}", cell, width)).collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(header.iter().map(|column| column.to_uppercase()).collect());
+    for row in rows {
+        line(row);
+    }
+    Ok(())
+}
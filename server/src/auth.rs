@@ -1,14 +1,30 @@
-use crate::cache::TimedCache;
+use crate::cache::{TimedCache, TOKEN_TTL};
+use crate::encoding::hex;
+use crate::events;
+use crate::hashpool;
+use crate::mail::{self, Mail, MailError};
+use crate::metrics;
+use crate::totp;
+use crate::webhook;
 use argon2::Error as HashError;
-use auth_common::AuthToken;
+use auth_common::{AccountEvent, AuthToken, UserInfoResponse, UserSearchHit, UserSearchResponse, WebhookEvent};
 use lazy_static::lazy_static;
-use rusqlite::{params, Connection, Error as DbError, NO_PARAMS};
+use rusqlite::{params, Connection, Error as DbError, ErrorCode, OptionalExtension};
 use serde_json::Error as JsonError;
 use std::error::Error;
 use std::fmt;
 use uuid::Uuid;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, path::PathBuf};
 
+/// How often unverified email addresses are looked for.
+const EMAIL_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 lazy_static! {
     static ref TOKENS: TimedCache = TimedCache::new();
 }
@@ -18,9 +34,8 @@ fn apply_db_dir_override(db_dir: &str) -> String {
         let path = PathBuf::from(val);
         if path.exists() || path.parent().map(|x| x.exists()).unwrap_or(false) {
             // Only allow paths with valid unicode characters
-            match path.to_str() {
-                Some(path) => return path.to_owned(),
-                None => {},
+            if let Some(path) = path.to_str() {
+                return path.to_owned();
             }
         }
         log::warn!("AUTH_DB_DIR is an invalid path.");
@@ -28,48 +43,161 @@ fn apply_db_dir_override(db_dir: &str) -> String {
     db_dir.to_string()
 }
 
-fn db() -> Result<Connection, AuthError> {
-    let db_dir = &apply_db_dir_override("/opt/veloren-auth/data/auth.db");
-    Ok(Connection::open(db_dir)?)
+pub(crate) fn db() -> Result<Connection, AuthError> {
+    let mut conn = Connection::open(db_path())?;
+    conn.profile(Some(metrics::observe_query));
+    Ok(conn)
 }
 
 fn salt() -> [u8; 16] {
     rand::random::<u128>().to_le_bytes()
 }
 
-fn decapitalize(string: &str) -> String {
+fn hash_password(password: &str) -> Result<String, AuthError> {
+    let password = password.to_owned();
+    let pwhash = hashpool::run(move || {
+        let hconfig = argon2::Config::default();
+        metrics::PASSWORD_HASH_DURATION.time(&["hash"], || {
+            argon2::hash_encoded(password.as_bytes(), &salt(), &hconfig)
+        })
+    })??;
+    Ok(pwhash)
+}
+
+/// The prehash `authc` applies before a password leaves the client, for
+/// passwords typed into the server's own web pages. Must stay in sync with
+/// `net_prehash` in `authc`.
+pub fn prehash_password(password: &str) -> Result<String, AuthError> {
+    let password = password.to_owned();
+    hashpool::run(move || {
+        let salt = fxhash::hash64(password.as_str());
+        argon2::hash_raw(password.as_bytes(), &salt.to_le_bytes(), &argon2::Config::default())
+            .map(|bytes| hex(&bytes))
+            .map_err(AuthError::from)
+    })?
+}
+
+pub(crate) fn decapitalize(string: &str) -> String {
     string.chars().flat_map(char::to_lowercase).collect()
 }
 
 #[derive(Debug)]
 pub enum AuthError {
     UserExists,
+    /// Another account is already registered with that ethereum address.
+    EthExists,
     UserDoesNotExist,
     EthDoesNotExist,
     InvalidLogin,
+    /// The password confirming a change to an account was wrong or missing.
+    WrongPassword,
     InvalidToken,
     Db(DbError),
     Hash(HashError),
     Json(JsonError),
+    Io(std::io::Error),
     InvalidRequest(String),
     InvalidEthAddr(String),
     RateLimit,
+    /// The server is too busy hashing passwords to take the request.
+    Overloaded,
+    EmailTaken,
+    Mail(MailError),
+    /// The account has two-factor authentication enabled and no code was given.
+    OtpRequired,
+    InvalidOtp,
+    /// Missing or wrong admin token.
+    Unauthorized,
+    ClientDoesNotExist,
+    WebhookDoesNotExist,
+    Banned,
+    /// Key generation, signing or the system random source failed.
+    Crypto,
+    /// A snapshot could not be taken, failed verification or can't be restored.
+    Backup(String),
 }
 
 impl AuthError {
     pub fn status_code(&self) -> u16 {
         match self {
             Self::UserExists => 400,
+            Self::EthExists => 400,
             Self::UserDoesNotExist => 400,
             Self::EthDoesNotExist => 400,
             Self::InvalidLogin => 400,
+            Self::WrongPassword => 401,
             Self::InvalidToken => 400,
             Self::Db(_) => 500,
             Self::Hash(_) => 500,
             Self::Json(_) => 400,
+            Self::Io(_) => 500,
             Self::InvalidRequest(_) => 400,
             Self::InvalidEthAddr(_) => 400,
             Self::RateLimit => 429,
+            Self::Overloaded => 503,
+            Self::EmailTaken => 400,
+            Self::Mail(_) => 500,
+            Self::OtpRequired => 401,
+            Self::InvalidOtp => 400,
+            Self::Unauthorized => 401,
+            Self::ClientDoesNotExist => 400,
+            Self::WebhookDoesNotExist => 400,
+            Self::Banned => 403,
+            Self::Crypto => 500,
+            Self::Backup(_) => 500,
+        }
+    }
+
+    /// Machine-readable name of the error, sent as `X-Error-Code` so clients
+    /// don't depend on the wording of the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UserExists => "username_taken",
+            Self::EthExists => "ethaddr_taken",
+            Self::UserDoesNotExist => "user_does_not_exist",
+            Self::EthDoesNotExist => "ethaddr_does_not_exist",
+            Self::InvalidLogin => "invalid_login",
+            Self::WrongPassword => "wrong_password",
+            Self::InvalidToken => "invalid_token",
+            Self::Db(_) => "database_error",
+            Self::Hash(_) => "hash_error",
+            Self::Json(_) => "invalid_json",
+            Self::Io(_) => "io_error",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::InvalidEthAddr(_) => "invalid_ethaddr",
+            Self::RateLimit => "rate_limited",
+            Self::Overloaded => "overloaded",
+            Self::EmailTaken => "email_taken",
+            Self::Mail(_) => "mail_error",
+            Self::OtpRequired => "otp_required",
+            Self::InvalidOtp => "invalid_otp",
+            Self::Unauthorized => "unauthorized",
+            Self::ClientDoesNotExist => "client_does_not_exist",
+            Self::WebhookDoesNotExist => "webhook_does_not_exist",
+            Self::Banned => "banned",
+            Self::Crypto => "crypto_error",
+            Self::Backup(_) => "backup_error",
+        }
+    }
+
+    /// Seconds a client should wait before retrying, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<u32> {
+        match self {
+            Self::Overloaded => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Status code for the resource-style `/v1` routes, where a missing
+    /// account is a 404 rather than a bad request.
+    pub fn resource_status_code(&self) -> u16 {
+        match self {
+            Self::UserDoesNotExist
+            | Self::EthDoesNotExist
+            | Self::ClientDoesNotExist
+            | Self::WebhookDoesNotExist => 404,
+            Self::EmailTaken => 409,
+            _ => self.status_code(),
         }
     }
 }
@@ -81,20 +209,36 @@ impl fmt::Display for AuthError {
             "{}",
             match self {
                 Self::UserExists => "That username is already taken.".into(),
+                Self::EthExists => "That ethereum address is already registered.".into(),
                 Self::UserDoesNotExist => "That user does not exist.".into(),
                 Self::EthDoesNotExist => "That ethereum address does not exist.".into(),
                 Self::InvalidLogin =>
                     "The username + password or ethaddr combination was incorrect or the user does not exist."
                         .into(),
+                Self::WrongPassword => "The password of the account is incorrect.".into(),
                 Self::InvalidToken => "The given token is invalid.".into(),
                 Self::Db(err) => format!("Database error: {}", err),
                 Self::Hash(err) => format!("Error securely storing password: {}", err),
                 Self::Json(err) => format!("Error decoding JSON: {}", err),
+                Self::Io(err) => format!("I/O error: {}", err),
                 Self::InvalidRequest(s) =>
                     format!("The request was invalid in some form. Reason: {}", s),
                 Self::InvalidEthAddr(s) =>
                     format!("The given eth addr is invalid: {}", s),
                 Self::RateLimit => "You are sending too many requests. Please slow down.".into(),
+                Self::Overloaded => "The server is busy. Please try again shortly.".into(),
+                Self::EmailTaken => "That email address is already in use.".into(),
+                Self::Mail(err) => format!("Error sending mail: {}", err),
+                Self::OtpRequired =>
+                    "This account requires a one-time password from an authenticator app or a recovery code."
+                        .into(),
+                Self::InvalidOtp => "The one-time password or recovery code was incorrect.".into(),
+                Self::Unauthorized => "A valid admin token is required.".into(),
+                Self::ClientDoesNotExist => "That client application does not exist.".into(),
+                Self::WebhookDoesNotExist => "That webhook does not exist.".into(),
+                Self::Banned => "This account is banned.".into(),
+                Self::Crypto => "A cryptographic operation failed.".into(),
+                Self::Backup(s) => format!("Backup error: {}", s),
             }
         )
     }
@@ -114,26 +258,194 @@ impl From<HashError> for AuthError {
     }
 }
 
+impl From<std::io::Error> for AuthError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<MailError> for AuthError {
+    fn from(err: MailError) -> Self {
+        Self::Mail(err)
+    }
+}
+
 impl From<JsonError> for AuthError {
     fn from(err: JsonError) -> Self {
         Self::Json(err)
     }
 }
 
-pub fn init_db() -> Result<(), AuthError> {    //add ethaddr filed in users tables  -max
-    db()?.execute(
-        "
-        CREATE TABLE IF NOT EXISTS users (
-            uuid TEXT NOT NULL PRIMARY KEY,
-            username TEXT NOT NULL UNIQUE,
-            display_username TEXT NOT NULL UNIQUE,
-            ethaddr TEXT NOT NULL UNIQUE,
-            actived INTEGER DEFAULT -1 NOT NULL,
-            pwhash TEXT NOT NULL
-        )
+/// Schema migrations, applied in order. `PRAGMA user_version` records how
+/// many of them have run, so only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    //add ethaddr filed in users tables  -max
+    "
+    CREATE TABLE IF NOT EXISTS users (
+        uuid TEXT NOT NULL PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        display_username TEXT NOT NULL UNIQUE,
+        ethaddr TEXT NOT NULL UNIQUE,
+        actived INTEGER DEFAULT -1 NOT NULL,
+        pwhash TEXT NOT NULL
+    );
     ",
-        NO_PARAMS,
-    )?;
+    // Accounts can opt out of username search. The partial index serves the
+    // prefix range scans of searchable accounts only.
+    "
+    ALTER TABLE users ADD COLUMN searchable INTEGER NOT NULL DEFAULT 1;
+    CREATE INDEX users_searchable_username ON users (username) WHERE searchable = 1;
+    ",
+    // Optional verified email. A new address waits in `pending_email` until
+    // the link mailed to it is followed.
+    "
+    ALTER TABLE users ADD COLUMN email TEXT;
+    ALTER TABLE users ADD COLUMN pending_email TEXT;
+    ALTER TABLE users ADD COLUMN pending_email_since INTEGER;
+    CREATE UNIQUE INDEX users_email ON users (email);
+    CREATE TABLE email_tokens (
+        token TEXT NOT NULL PRIMARY KEY,
+        purpose TEXT NOT NULL,
+        uuid TEXT NOT NULL,
+        email TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX email_tokens_uuid ON email_tokens (uuid);
+    ",
+    // TOTP two-factor authentication. A secret waits in `totp_pending` until
+    // a code generated from it confirms the enrollment. `totp_last_step` is
+    // the time step of the last accepted code, which can't be used again.
+    "
+    ALTER TABLE users ADD COLUMN totp_secret TEXT;
+    ALTER TABLE users ADD COLUMN totp_pending TEXT;
+    ALTER TABLE users ADD COLUMN totp_last_step INTEGER;
+    CREATE TABLE recovery_codes (
+        uuid TEXT NOT NULL,
+        code_hash TEXT NOT NULL,
+        PRIMARY KEY (uuid, code_hash)
+    );
+    ",
+    // OpenID Connect provider. Client secrets and access tokens are random,
+    // so only their SHA-256 digests are kept.
+    "
+    CREATE TABLE oauth_clients (
+        client_id TEXT NOT NULL PRIMARY KEY,
+        name TEXT NOT NULL,
+        secret_hash TEXT,
+        redirect_uris TEXT NOT NULL,
+        created INTEGER NOT NULL
+    );
+    CREATE TABLE oauth_codes (
+        code TEXT NOT NULL PRIMARY KEY,
+        client_id TEXT NOT NULL,
+        uuid TEXT NOT NULL,
+        redirect_uri TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        nonce TEXT,
+        code_challenge TEXT NOT NULL,
+        auth_time INTEGER NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE TABLE oauth_tokens (
+        token_hash TEXT NOT NULL PRIMARY KEY,
+        client_id TEXT NOT NULL,
+        uuid TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX oauth_tokens_uuid ON oauth_tokens (uuid);
+    CREATE TABLE signing_keys (
+        kid TEXT NOT NULL PRIMARY KEY,
+        pkcs8 BLOB NOT NULL,
+        created INTEGER NOT NULL
+    );
+    ",
+    // device authorization grant, RFC 8628. client_id is NULL for game clients
+    "
+    CREATE TABLE device_codes (
+        code_hash TEXT NOT NULL PRIMARY KEY,
+        user_code TEXT NOT NULL UNIQUE,
+        client_id TEXT,
+        scope TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        uuid TEXT,
+        auth_time INTEGER,
+        interval INTEGER NOT NULL,
+        last_poll INTEGER,
+        expires INTEGER NOT NULL
+    );
+    ",
+    // bans, and webhook subscriptions with their persistent delivery queue
+    "
+    ALTER TABLE users ADD COLUMN banned INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN ban_reason TEXT;
+    CREATE TABLE webhooks (
+        id TEXT NOT NULL PRIMARY KEY,
+        url TEXT NOT NULL,
+        events TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        created INTEGER NOT NULL
+    );
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        webhook_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        event TEXT NOT NULL,
+        body TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt INTEGER NOT NULL,
+        last_status_code INTEGER,
+        last_error TEXT,
+        created INTEGER NOT NULL,
+        delivered INTEGER
+    );
+    CREATE INDEX webhook_deliveries_due ON webhook_deliveries (status, next_attempt);
+    CREATE INDEX webhook_deliveries_webhook ON webhook_deliveries (webhook_id, id);
+    ",
+    // account event stream for game servers, the id is the resume cursor
+    "
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        type TEXT NOT NULL,
+        uuid TEXT NOT NULL,
+        data TEXT NOT NULL,
+        created INTEGER NOT NULL
+    );
+    CREATE INDEX events_created ON events (created);
+    ",
+];
+
+fn schema_version(db: &Connection) -> Result<usize, AuthError> {
+    let version: i64 = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+pub fn init_db() -> Result<(), AuthError> {
+    let mut db = db()?;
+    let version = schema_version(&db)?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", &(i as i64 + 1))?;
+        tx.commit()?;
+        log::info!("Applied database migration {}", i + 1);
+    }
+    Ok(())
+}
+
+pub fn db_path() -> String {
+    apply_db_dir_override("/opt/veloren-auth/data/auth.db")
+}
+
+/// Returns the applied and the expected schema version.
+pub fn migration_state() -> Result<(usize, usize), AuthError> {
+    Ok((schema_version(&db()?)?, MIGRATIONS.len()))
+}
+
+/// Checks that the database accepts writes by taking and releasing the write lock.
+pub fn check_writable() -> Result<(), AuthError> {
+    db()?.execute_batch("BEGIN IMMEDIATE; ROLLBACK;")?;
     Ok(())
 }
 
@@ -150,6 +462,164 @@ fn eth_exists(ethaddr: &str) -> Result<bool, AuthError> {
     Ok(stmt.exists(params![ethaddr])?)
 }
 
+fn uuid_exists(uuid: &str) -> Result<bool, AuthError> {
+    let db = db()?;
+    let mut stmt = db.prepare("SELECT uuid FROM users WHERE uuid == ?1")?;
+    Ok(stmt.exists(params![uuid])?)
+}
+
+/// Reads `uuid, display_username, ethaddr, actived` starting at column `first`.
+fn row_to_info(row: &rusqlite::Row, first: usize) -> rusqlite::Result<Option<UserInfoResponse>> {
+    let uuid: String = row.get(first)?;
+    let username = row.get(first + 1)?;
+    let ethaddr = row.get(first + 2)?;
+    let actived = row.get(first + 3)?;
+    Ok(Uuid::parse_str(&uuid).ok().map(|uuid| UserInfoResponse {
+        uuid,
+        username,
+        ethaddr,
+        actived,
+    }))
+}
+
+/// Fetches the whole account record where `column` equals `value`.
+fn user_info(column: &str, value: &str, missing: AuthError) -> Result<UserInfoResponse, AuthError> {
+    let db = db()?;
+    let mut stmt = db.prepare_cached(&format!(
+        "SELECT uuid, display_username, ethaddr, actived FROM users WHERE {} == ?1",
+        column
+    ))?;
+    let result = stmt
+        .query_map(params![value], |row| row_to_info(row, 0))?
+        .filter_map(|s| s.ok())
+        .flatten()
+        .next()
+        .ok_or(missing);
+    result
+}
+
+/// Fetches the account records whose `column` is one of `values` with a
+/// single `IN (...)` query, keyed by the matching column value.
+fn users_info(column: &str, values: &[String]) -> Result<HashMap<String, UserInfoResponse>, AuthError> {
+    if values.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let placeholders = vec!["?"; values.len()].join(", ");
+    let db = db()?;
+    let mut stmt = db.prepare(&format!(
+        "SELECT {0}, uuid, display_username, ethaddr, actived FROM users WHERE {0} IN ({1})",
+        column, placeholders
+    ))?;
+    let found = stmt
+        .query_map(values, |row| Ok((row.get::<_, String>(0)?, row_to_info(row, 1)?)))?
+        .filter_map(|s| s.ok())
+        .filter_map(|(key, info)| Some((key, info?)))
+        .collect();
+    Ok(found)
+}
+
+/// Looks up every value of `requested` in `column`, normalising each one
+/// with `key` first. The result is keyed by the values as requested.
+fn batch_info(
+    column: &str,
+    requested: &[String],
+    key: impl Fn(&str) -> String,
+) -> Result<BTreeMap<String, Option<UserInfoResponse>>, AuthError> {
+    let mut keys: Vec<String> = requested.iter().map(|v| key(v)).collect();
+    keys.sort();
+    keys.dedup();
+    let found = users_info(column, &keys)?;
+    Ok(requested
+        .iter()
+        .map(|value| (value.clone(), found.get(&key(value)).cloned()))
+        .collect())
+}
+
+pub fn uuids_to_info(uuids: &[Uuid]) -> Result<BTreeMap<String, Option<UserInfoResponse>>, AuthError> {
+    let requested: Vec<String> = uuids.iter().map(|uuid| uuid.to_string()).collect();
+    batch_info("uuid", &requested, |uuid| {
+        Uuid::parse_str(uuid)
+            .map(|uuid| uuid.to_simple().to_string())
+            .unwrap_or_default()
+    })
+}
+
+pub fn usernames_to_info(usernames: &[String]) -> Result<BTreeMap<String, Option<UserInfoResponse>>, AuthError> {
+    batch_info("username", usernames, decapitalize)
+}
+
+pub fn eths_to_info(ethaddrs: &[String]) -> Result<BTreeMap<String, Option<UserInfoResponse>>, AuthError> {
+    batch_info("ethaddr", ethaddrs, decapitalize)
+}
+
+pub fn uuid_to_info(uuid: &Uuid) -> Result<UserInfoResponse, AuthError> {
+    let uuid = uuid.to_simple().to_string();
+    user_info("uuid", &uuid, AuthError::UserDoesNotExist)
+}
+
+pub fn username_to_info(username_unfiltered: &str) -> Result<UserInfoResponse, AuthError> {
+    let username = decapitalize(username_unfiltered);
+    user_info("username", &username, AuthError::UserDoesNotExist)
+}
+
+pub fn eth_to_info(ethaddr_unfiltered: &str) -> Result<UserInfoResponse, AuthError> {
+    let ethaddr = decapitalize(ethaddr_unfiltered);
+    user_info("ethaddr", &ethaddr, AuthError::EthDoesNotExist)
+}
+
+/// Lists up to `limit` searchable accounts whose username starts with
+/// `prefix`, in username order and starting after the `after` cursor.
+pub fn search_users(prefix: &str, after: Option<&str>, limit: usize) -> Result<UserSearchResponse, AuthError> {
+    let prefix = decapitalize(prefix);
+    // Usernames are ASCII, so every one starting with the prefix sorts
+    // below the prefix followed by the largest code point.
+    let upper = format!("{}\u{10FFFF}", prefix);
+    let after = after.map(decapitalize).unwrap_or_default();
+    let db = db()?;
+    let mut stmt = db.prepare_cached(
+        "SELECT username, uuid, display_username FROM users
+         WHERE searchable = 1 AND username >= ?1 AND username < ?2 AND username > ?3
+         ORDER BY username LIMIT ?4",
+    )?;
+    let mut rows: Vec<(String, UserSearchHit)> = stmt
+        .query_map(params![prefix, upper, after, limit as i64 + 1], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .filter_map(|s| s.ok())
+        .filter_map(|(key, uuid, username)| {
+            Some((key, UserSearchHit { uuid: Uuid::parse_str(&uuid).ok()?, username }))
+        })
+        .collect();
+    let next = if rows.len() > limit {
+        rows.truncate(limit);
+        rows.last().map(|(key, _)| key.clone())
+    } else {
+        None
+    };
+    Ok(UserSearchResponse {
+        users: rows.into_iter().map(|(_, hit)| hit).collect(),
+        next,
+    })
+}
+
+/// Sets whether the account shows up in username search.
+pub fn set_searchable(uuid: &Uuid, password: &str, searchable: bool) -> Result<(), AuthError> {
+    let uuid = uuid.to_simple().to_string();
+    confirm_password(&uuid, password)?;
+    let changed = db()?.execute(
+        "UPDATE users SET searchable = ?1 WHERE uuid == ?2",
+        params![searchable, uuid],
+    )?;
+    if changed == 0 {
+        return Err(AuthError::UserDoesNotExist);
+    }
+    Ok(())
+}
+
 pub fn username_to_uuid(username_unfiltered: &str) -> Result<Uuid, AuthError> {
     let username = decapitalize(username_unfiltered);
     let db = db()?;
@@ -217,21 +687,37 @@ pub fn eth_to_actived(ethaddr_unfiltered: &str) -> Result<i32, AuthError> {
 
 
 
-// change password by ethaddr
+// change password by ethaddr. Goes through the uuid path so OAuth access
+// tokens of the account stop working as well.
 pub fn change_passwd(ethaddr_unfiltered: &str, password: &str ) -> Result<(), AuthError> {
-    let ethaddr = decapitalize(ethaddr_unfiltered);
-    if !eth_exists(&ethaddr)? {
-        println!("ethaddr not exists");
-        return Err(AuthError::EthDoesNotExist);
+    change_passwd_by_uuid(&eth_to_uuid(ethaddr_unfiltered)?, password)?;
+    println!("Change password ok");
+    Ok(())
+}
+
+/// Changes the password of an account, confirmed with its current one.
+pub fn change_own_passwd(uuid: &Uuid, current: &str, password: &str) -> Result<(), AuthError> {
+    confirm_password(&uuid.to_simple().to_string(), current)?;
+    change_passwd_by_uuid(uuid, password)
+}
+
+// change password by uuid, without confirmation. OAuth access tokens of the
+// account stop working.
+pub fn change_passwd_by_uuid(uuid: &Uuid, password: &str) -> Result<(), AuthError> {
+    let simple = uuid.to_simple().to_string();
+    if !uuid_exists(&simple)? {
+        return Err(AuthError::UserDoesNotExist);
     }
-    let db = db()?;
-    let hconfig = argon2::Config::default();
-    let pwhash = argon2::hash_encoded(password.as_bytes(), &salt(), &hconfig)?;
-    db.execute(
-        "UPDATE users SET pwhash = ?1 WHERE ethaddr == ?2",
-        params![pwhash, ethaddr],
+    let pwhash = hash_password(password)?;
+    let mut db = db()?;
+    let tx = db.transaction()?;
+    tx.execute(
+        "UPDATE users SET pwhash = ?1 WHERE uuid == ?2",
+        params![pwhash, simple],
     )?;
-    println!("Change password ok");
+    tx.execute("DELETE FROM oauth_tokens WHERE uuid == ?1", params![simple])?;
+    tx.commit()?;
+    webhook::emit(WebhookEvent::PasswordChanged, uuid);
     Ok(())
 }
 
@@ -262,24 +748,40 @@ pub fn uuid_to_eth(uuid: &Uuid) -> Result<String, AuthError> {
 
 
 // add new parameter ethaddr -max
-pub fn register(username_unfiltered: &str, password: &str, ethaddr_unfiltered: &str) -> Result<(), AuthError> {
+pub fn register(username_unfiltered: &str, password: &str, ethaddr_unfiltered: &str) -> Result<Uuid, AuthError> {
     let username = decapitalize(username_unfiltered);
     let ethaddr = decapitalize(ethaddr_unfiltered);
     if user_exists(&username)? {
         println!("user exists");
         return Err(AuthError::UserExists);
     }
-    
-    let uuid = Uuid::new_v4().to_simple().to_string();
-    let hconfig = argon2::Config::default();
-    let pwhash = argon2::hash_encoded(password.as_bytes(), &salt(), &hconfig)?;
+    if eth_exists(&ethaddr)? {
+        return Err(AuthError::EthExists);
+    }
+
+    let id = Uuid::new_v4();
+    let uuid = id.to_simple().to_string();
+    let pwhash = hash_password(password)?;
     println!("user go");
-    db()?.execute(
-        "INSERT INTO users (uuid, username, display_username, ethaddr, pwhash) VALUES(?1, ?2, ?3, ?4, ?5)",
-        params![uuid, &username, username_unfiltered, ethaddr, pwhash],
-    )?;
+    // Both checks above can race with a concurrent registration, the
+    // unique indexes decide; the message names the column that clashed.
+    db()?
+        .execute(
+            "INSERT INTO users (uuid, username, display_username, ethaddr, pwhash) VALUES(?1, ?2, ?3, ?4, ?5)",
+            params![uuid, &username, username_unfiltered, ethaddr, pwhash],
+        )
+        .map_err(|err| {
+            if !is_unique_violation(&err) {
+                AuthError::Db(err)
+            } else if err.to_string().contains("ethaddr") {
+                AuthError::EthExists
+            } else {
+                AuthError::UserExists
+            }
+        })?;
     println!("user go2");
-    Ok(())
+    webhook::emit(WebhookEvent::Registered, &id);
+    Ok(id)
 }
 
 
@@ -297,39 +799,639 @@ pub fn eth_active(ethaddr_unfiltered: &str) -> Result<(), AuthError> {
         params![ethaddr],
     )?;
     println!("eth_active go2");
+    webhook::emit(WebhookEvent::Activated, &eth_to_uuid(&ethaddr)?);
+    Ok(())
+}
+
+/// Sets the activation state of an account by uuid, for admins. Setting it
+/// to 1 counts as an activation.
+pub fn set_actived(uuid: &Uuid, actived: i32) -> Result<(), AuthError> {
+    let updated = db()?.execute(
+        "UPDATE users SET actived = ?2 WHERE uuid == ?1",
+        params![uuid.to_simple().to_string(), actived],
+    )?;
+    if updated == 0 {
+        return Err(AuthError::UserDoesNotExist);
+    }
+    if actived == 1 {
+        webhook::emit(WebhookEvent::Activated, uuid);
+    }
     Ok(())
 }
 
 /// Checks if the password is correct and that the user exists.
 fn is_valid(username: &str, password: &str) -> Result<bool, AuthError> {
+    check_password("username", username, password)
+}
+
+/// Checks the password of the account where `column` equals `value`.
+fn check_password(column: &str, value: &str, password: &str) -> Result<bool, AuthError> {
     let db = db()?;
-    let mut stmt = db.prepare_cached("SELECT pwhash FROM users WHERE username == ?1")?;
-    let result = stmt
-        .query_map(params![&username], |row| row.get::<_, String>(0))?
+    let mut stmt = db.prepare_cached(&format!("SELECT pwhash FROM users WHERE {} == ?1", column))?;
+    let correct = stmt
+        .query_map(params![value], |row| row.get::<_, String>(0))?
         .filter_map(|s| s.ok())
-        .filter_map(|correct| argon2::verify_encoded(&correct, password.as_bytes()).ok())
         .next()
-        .ok_or(AuthError::InvalidLogin);
-    result
+        .ok_or(AuthError::InvalidLogin)?;
+    let password = password.to_owned();
+    hashpool::run(move || {
+        metrics::PASSWORD_HASH_DURATION.time(&["verify"], || {
+            argon2::verify_encoded(&correct, password.as_bytes())
+                .map_err(|_| AuthError::InvalidLogin)
+        })
+    })?
+}
+
+/// Checks the password an account holder gave to confirm a change.
+fn confirm_password(uuid: &str, password: &str) -> Result<(), AuthError> {
+    if !uuid_exists(uuid)? {
+        return Err(AuthError::UserDoesNotExist);
+    }
+    match check_password("uuid", uuid, password) {
+        Ok(true) => Ok(()),
+        Ok(false) | Err(AuthError::InvalidLogin) => Err(AuthError::WrongPassword),
+        Err(err) => Err(err),
+    }
 }
 
-pub fn generate_token(username_unfiltered: &str, password: &str) -> Result<AuthToken, AuthError> {
+/// Checks the password and, where enabled, the second factor of an account,
+/// returning its uuid.
+pub fn sign_in(username_unfiltered: &str, password: &str, otp: Option<&str>) -> Result<Uuid, AuthError> {
     let username = decapitalize(username_unfiltered);
     if !is_valid(&username, password)? {
         return Err(AuthError::InvalidLogin);
     }
 
     let uuid = username_to_uuid(&username)?;
+    // Checked first, so a banned player can't burn their recovery codes.
+    if is_banned(&uuid)? {
+        return Err(AuthError::Banned);
+    }
+    check_second_factor(&uuid.to_simple().to_string(), otp)?;
+    Ok(uuid)
+}
+
+pub(crate) fn is_banned(uuid: &Uuid) -> Result<bool, AuthError> {
+    let banned: bool = db()?.query_row(
+        "SELECT banned FROM users WHERE uuid == ?1",
+        params![uuid.to_simple().to_string()],
+        |row| row.get(0),
+    )?;
+    Ok(banned)
+}
+
+/// Revokes the outstanding tokens of an account, returning how many there were.
+pub fn revoke_tokens(uuid: &Uuid) -> Result<usize, AuthError> {
+    if !uuid_exists(&uuid.to_simple().to_string())? {
+        return Err(AuthError::UserDoesNotExist);
+    }
+    let revoked = TOKENS.remove_data(uuid);
+    log::info!("Revoked {} tokens of {}", revoked, uuid);
+    events::publish(AccountEvent::SessionRevoked { uuid: *uuid });
+    Ok(revoked)
+}
+
+/// Gives an account a new username. Only the case may differ from a name
+/// that is taken, and only if it is the account's own.
+pub fn rename_user(uuid: &Uuid, username_unfiltered: &str) -> Result<(), AuthError> {
+    let simple = uuid.to_simple().to_string();
+    let old_username = uuid_to_username(uuid)?;
+    let username = decapitalize(username_unfiltered);
+    if username != decapitalize(&old_username) && user_exists(&username)? {
+        return Err(AuthError::UserExists);
+    }
+    let updated = db()?.execute(
+        "UPDATE users SET username = ?2, display_username = ?3 WHERE uuid == ?1",
+        params![simple, username, username_unfiltered],
+    );
+    match updated {
+        Err(err) if is_unique_violation(&err) => return Err(AuthError::UserExists),
+        other => other?,
+    };
+    events::publish(AccountEvent::UsernameChanged {
+        uuid: *uuid,
+        old_username,
+        username: username_unfiltered.to_owned(),
+    });
+    Ok(())
+}
+
+/// Deletes an account and everything that refers to it, and revokes its
+/// tokens.
+pub fn delete_user(uuid: &Uuid) -> Result<(), AuthError> {
+    let simple = uuid.to_simple().to_string();
+    let mut db = db()?;
+    let tx = db.transaction()?;
+    let deleted = delete_user_rows(&tx, &simple)?;
+    tx.commit()?;
+    if !deleted {
+        return Err(AuthError::UserDoesNotExist);
+    }
+    let revoked = TOKENS.remove_data(uuid);
+    log::info!("Deleted {}, revoked {} tokens", uuid, revoked);
+    events::publish(AccountEvent::Deleted { uuid: *uuid });
+    Ok(())
+}
+
+/// Tables whose rows belong to an account.
+pub(crate) const ACCOUNT_TABLES: &[&str] = &["email_tokens", "recovery_codes", "oauth_codes", "oauth_tokens", "device_codes"];
+
+/// Deletes the account with the simple `uuid` and the rows that refer to it,
+/// returning whether it existed.
+pub(crate) fn delete_user_rows(db: &Connection, uuid: &str) -> Result<bool, AuthError> {
+    let deleted = db.execute("DELETE FROM users WHERE uuid == ?1", params![uuid])?;
+    for table in ACCOUNT_TABLES {
+        db.execute(&format!("DELETE FROM {} WHERE uuid == ?1", table), params![uuid])?;
+    }
+    Ok(deleted > 0)
+}
+
+/// Bans or unbans an account. A ban revokes its outstanding tokens, OAuth
+/// grants included, and keeps it from signing in until lifted.
+pub fn set_banned(uuid: &Uuid, banned: bool, reason: Option<&str>) -> Result<(), AuthError> {
+    let simple = uuid.to_simple().to_string();
+    let mut db = db()?;
+    let tx = db.transaction()?;
+    let updated = tx.execute(
+        "UPDATE users SET banned = ?2, ban_reason = ?3 WHERE uuid == ?1",
+        params![simple, banned, if banned { reason } else { None }],
+    )?;
+    if updated == 0 {
+        return Err(AuthError::UserDoesNotExist);
+    }
+    if banned {
+        // Only approved device codes carry the account.
+        for table in &["oauth_codes", "oauth_tokens", "device_codes"] {
+            tx.execute(&format!("DELETE FROM {} WHERE uuid == ?1", table), params![simple])?;
+        }
+    }
+    tx.commit()?;
+    if banned {
+        let revoked = TOKENS.remove_data(uuid);
+        log::info!("Banned {}, revoked {} tokens", uuid, revoked);
+        events::publish(AccountEvent::Banned {
+            uuid: *uuid,
+            reason: reason.map(str::to_owned),
+        });
+        webhook::emit(WebhookEvent::Banned, uuid);
+    } else {
+        log::info!("Unbanned {}", uuid);
+        webhook::emit(WebhookEvent::Unbanned, uuid);
+    }
+    Ok(())
+}
+
+pub fn generate_token(
+    username_unfiltered: &str,
+    password: &str,
+    otp: Option<&str>,
+) -> Result<AuthToken, AuthError> {
+    let uuid = sign_in(username_unfiltered, password, otp)?;
+    Ok(issue_token(uuid))
+}
+
+/// Hands out a game server token for an account that already signed in.
+pub fn issue_token(uuid: Uuid) -> AuthToken {
     let token = AuthToken::generate();
     TOKENS.insert(token, uuid);
-    Ok(token)
+    metrics::TOKENS_ISSUED.inc(&[]);
+    token
 }
 
 pub fn verify(token: AuthToken) -> Result<Uuid, AuthError> {
     let mut uuid = None;
     TOKENS.run(&token, |entry| {
-        uuid = entry.map(|e| e.data.clone());
+        uuid = entry.map(|e| e.data);
         false
     });
+    metrics::TOKENS_VERIFIED.inc(&[if uuid.is_some() { "valid" } else { "invalid" }]);
     uuid.ok_or(AuthError::InvalidToken)
 }
+
+pub fn token_cache_size() -> usize {
+    TOKENS.len()
+}
+
+pub fn token_cleaner_alive() -> bool {
+    TOKENS.cleaner_alive()
+}
+
+pub(crate) fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn env_secs(name: &str, default: u64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default) as i64
+}
+
+/// How long emailed links stay valid, overridable with `AUTH_EMAIL_TOKEN_TTL_SECS`.
+fn email_token_ttl() -> i64 {
+    env_secs("AUTH_EMAIL_TOKEN_TTL_SECS", 60 * 60 * 24)
+}
+
+/// How long password reset codes stay valid, overridable with `AUTH_PASSWORD_RESET_TTL_SECS`.
+fn password_reset_ttl() -> i64 {
+    env_secs("AUTH_PASSWORD_RESET_TTL_SECS", 60 * 60)
+}
+
+/// How long an address may stay unverified before it is dropped,
+/// overridable with `AUTH_EMAIL_VERIFY_DEADLINE_SECS`.
+fn email_verify_deadline() -> i64 {
+    env_secs("AUTH_EMAIL_VERIFY_DEADLINE_SECS", 60 * 60 * 24 * 7)
+}
+
+fn is_unique_violation(err: &DbError) -> bool {
+    matches!(err, DbError::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation)
+}
+
+/// Stores a fresh single-use token for `uuid` valid for `ttl` seconds,
+/// replacing older ones with the same purpose.
+fn new_email_token(db: &Connection, purpose: &str, uuid: &str, email: &str, ttl: i64) -> Result<String, AuthError> {
+    let token = format!("{:032x}", rand::random::<u128>());
+    db.execute(
+        "DELETE FROM email_tokens WHERE uuid == ?1 AND purpose == ?2",
+        params![uuid, purpose],
+    )?;
+    db.execute(
+        "INSERT INTO email_tokens (token, purpose, uuid, email, expires) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![token, purpose, uuid, email, now_secs() + ttl],
+    )?;
+    Ok(token)
+}
+
+/// Removes a token and returns its account and address, if it was still valid.
+fn take_email_token(db: &Connection, purpose: &str, token: &str) -> Result<Option<(String, String)>, AuthError> {
+    let row = db
+        .query_row(
+            "SELECT uuid, email, expires FROM email_tokens WHERE token == ?1 AND purpose == ?2",
+            params![token, purpose],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)),
+        )
+        .optional()?;
+    db.execute("DELETE FROM email_tokens WHERE token == ?1", params![token])?;
+    Ok(row
+        .filter(|(_, _, expires)| *expires >= now_secs())
+        .map(|(uuid, email, _)| (uuid, email)))
+}
+
+/// Makes `email` the pending address of the account after checking its
+/// password, and mails a verification link to it. The verified address, if
+/// any, stays in place until the link is followed.
+pub fn set_email(uuid: &Uuid, password: &str, email_unfiltered: &str) -> Result<(), AuthError> {
+    let uuid = uuid.to_simple().to_string();
+    let email = decapitalize(email_unfiltered);
+    confirm_password(&uuid, password)?;
+
+    let mut db = db()?;
+    let tx = db.transaction()?;
+    let taken = tx
+        .prepare_cached("SELECT uuid FROM users WHERE email == ?1 AND uuid != ?2")?
+        .exists(params![email, uuid])?;
+    if taken {
+        return Err(AuthError::EmailTaken);
+    }
+    tx.execute(
+        "UPDATE users SET pending_email = ?1, pending_email_since = ?2 WHERE uuid == ?3",
+        params![email, now_secs(), uuid],
+    )?;
+    let token = new_email_token(&tx, "verify", &uuid, &email, email_token_ttl())?;
+    tx.commit()?;
+
+    mail::send(&Mail {
+        to: email,
+        subject: "Verify your email address".into(),
+        body: format!(
+            "Follow this link to confirm your email address:\n\n{}/v1/email/verify?token={}\n\nThe link can be used once and expires in {} hours. If you did not ask for this, ignore this mail.",
+            mail::public_url(),
+            token,
+            email_token_ttl() / 3600
+        ),
+    })?;
+    Ok(())
+}
+
+/// Consumes a verification token, making the pending address the verified one.
+pub fn verify_email(token: &str) -> Result<(), AuthError> {
+    let mut db = db()?;
+    let tx = db.transaction()?;
+    let (uuid, email) = take_email_token(&tx, "verify", token)?.ok_or(AuthError::InvalidToken)?;
+    // The address must still be pending, a newer request supersedes the link.
+    let updated = tx
+        .execute(
+            "UPDATE users SET email = ?1, pending_email = NULL, pending_email_since = NULL
+             WHERE uuid == ?2 AND pending_email == ?1",
+            params![email, uuid],
+        )
+        .map_err(|err| {
+            if is_unique_violation(&err) {
+                AuthError::EmailTaken
+            } else {
+                AuthError::Db(err)
+            }
+        })?;
+    tx.commit()?;
+    if updated == 0 {
+        return Err(AuthError::InvalidToken);
+    }
+    Ok(())
+}
+
+/// Mails a reset code to the verified address of the account named by
+/// `login`, a username or an email address. Unknown accounts and accounts
+/// without a verified address are silently skipped, and the mail goes out in
+/// the background, so the answer is the same either way.
+pub fn request_password_reset(login: &str) -> Result<(), AuthError> {
+    let login = decapitalize(login.trim());
+    let column = if login.contains('@') { "email" } else { "username" };
+    let db = db()?;
+    let account = db
+        .query_row(
+            &format!("SELECT uuid, email FROM users WHERE {} == ?1 AND email IS NOT NULL", column),
+            params![login],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+    let (uuid, email) = match account {
+        Some(account) => account,
+        None => return Ok(()),
+    };
+    let token = new_email_token(&db, "reset", &uuid, &email, password_reset_ttl())?;
+
+    let link = match env::var("AUTH_PASSWORD_RESET_URL") {
+        Ok(url) => format!("\n\nOr follow this link to choose a new password:\n\n{}?token={}", url, token),
+        Err(_) => String::new(),
+    };
+    let reset = Mail {
+        to: email,
+        subject: "Reset your password".into(),
+        body: format!(
+            "Someone asked to reset the password of your account. Enter this code to choose a new password:\n\n{}{}\n\nThe code can be used once and expires in {} minutes. If you did not ask for this, ignore this mail; your password has not changed.",
+            token,
+            link,
+            password_reset_ttl() / 60
+        ),
+    };
+    mail::queue(reset);
+    Ok(())
+}
+
+/// Consumes a reset code and sets the new password. Tokens issued before the
+/// reset stop working, OAuth access tokens included.
+pub fn reset_password(token: &str, password: &str) -> Result<(), AuthError> {
+    let mut db = db()?;
+    // The code is spent before hashing, so guessing codes costs no argon2 work.
+    let tx = db.transaction()?;
+    let taken = take_email_token(&tx, "reset", token)?;
+    tx.commit()?;
+    let (uuid, email) = taken.ok_or(AuthError::InvalidToken)?;
+    let pwhash = hash_password(password)?;
+
+    let tx = db.transaction()?;
+    // The code was mailed to the verified address, which must not have changed since.
+    let updated = tx.execute(
+        "UPDATE users SET pwhash = ?1 WHERE uuid == ?2 AND email == ?3",
+        params![pwhash, uuid, email],
+    )?;
+    tx.execute(
+        "DELETE FROM email_tokens WHERE uuid == ?1 AND purpose == 'reset'",
+        params![uuid],
+    )?;
+    if updated > 0 {
+        tx.execute("DELETE FROM oauth_tokens WHERE uuid == ?1", params![uuid])?;
+    }
+    tx.commit()?;
+    if updated == 0 {
+        return Err(AuthError::InvalidToken);
+    }
+
+    if let Ok(uuid) = Uuid::parse_str(&uuid) {
+        let revoked = TOKENS.remove_data(&uuid);
+        log::info!("Password of {} reset, revoked {} tokens", uuid, revoked);
+        events::publish(AccountEvent::SessionRevoked { uuid });
+        webhook::emit(WebhookEvent::PasswordChanged, &uuid);
+    }
+    Ok(())
+}
+
+/// Drops expired tokens and addresses left unverified past the deadline.
+pub fn purge_unverified_emails() -> Result<usize, AuthError> {
+    let db = db()?;
+    let now = now_secs();
+    db.execute("DELETE FROM email_tokens WHERE expires < ?1", params![now])?;
+    let purged = db.execute(
+        "UPDATE users SET pending_email = NULL, pending_email_since = NULL
+         WHERE pending_email_since < ?1",
+        params![now - email_verify_deadline()],
+    )?;
+    Ok(purged)
+}
+
+/// Runs `purge_unverified_emails` in the background, at least once per deadline.
+pub fn start_email_purger() {
+    let interval = Duration::from_secs(email_verify_deadline().max(1) as u64).min(EMAIL_PURGE_INTERVAL);
+    thread::spawn(move || loop {
+        match purge_unverified_emails() {
+            Ok(0) => {}
+            Ok(n) => log::info!("Dropped {} unverified email addresses", n),
+            Err(err) => log::warn!("Could not purge unverified email addresses: {}", err),
+        }
+        thread::sleep(interval);
+    });
+}
+
+/// Enforces two-factor authentication at sign-in. Accounts without it pass,
+/// otherwise `otp` must be a fresh code from the authenticator or an unused
+/// recovery code, which is then spent.
+fn check_second_factor(uuid: &str, otp: Option<&str>) -> Result<(), AuthError> {
+    let db = db()?;
+    let (secret, last_step) = db.query_row(
+        "SELECT totp_secret, totp_last_step FROM users WHERE uuid == ?1",
+        params![uuid],
+        |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, Option<i64>>(1)?)),
+    )?;
+    let secret = match secret {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+    let otp = otp.filter(|otp| !otp.trim().is_empty()).ok_or(AuthError::OtpRequired)?;
+
+    if totp::is_code(otp) {
+        let step = totp::verify(&secret, otp, now_secs(), last_step).ok_or(AuthError::InvalidOtp)?;
+        // Guards against the same code being raced through twice.
+        let accepted = db.execute(
+            "UPDATE users SET totp_last_step = ?1
+             WHERE uuid == ?2 AND (totp_last_step IS NULL OR totp_last_step < ?1)",
+            params![step, uuid],
+        )?;
+        if accepted == 0 {
+            return Err(AuthError::InvalidOtp);
+        }
+    } else {
+        let spent = db.execute(
+            "DELETE FROM recovery_codes WHERE uuid == ?1 AND code_hash == ?2",
+            params![uuid, totp::hash_recovery_code(otp)],
+        )?;
+        if spent == 0 {
+            return Err(AuthError::InvalidOtp);
+        }
+        log::info!("Recovery code of {} used", uuid);
+    }
+    Ok(())
+}
+
+/// Starts TOTP enrollment after checking the password, returning the new
+/// secret and its `otpauth://` URI. Two-factor authentication stays off
+/// until `confirm_totp` sees a code generated from the secret.
+pub fn begin_totp(uuid: &Uuid, password: &str) -> Result<(String, String), AuthError> {
+    let uuid = uuid.to_simple().to_string();
+    confirm_password(&uuid, password)?;
+    let secret = totp::generate_secret();
+    let db = db()?;
+    let username: String = db
+        .query_row(
+            "SELECT display_username FROM users WHERE uuid == ?1 AND totp_secret IS NULL",
+            params![uuid],
+            |row| row.get(0),
+        )
+        .optional()?
+        .ok_or_else(|| AuthError::InvalidRequest("Two-factor authentication is already enabled.".into()))?;
+    db.execute(
+        "UPDATE users SET totp_pending = ?1 WHERE uuid == ?2",
+        params![secret, uuid],
+    )?;
+    let uri = totp::uri(&secret, &username);
+    Ok((secret, uri))
+}
+
+/// Enables two-factor authentication once `code` shows the authenticator
+/// holds the pending secret, returning a fresh set of recovery codes. Only
+/// their digests are kept, so they can't be shown again.
+pub fn confirm_totp(uuid: &Uuid, password: &str, code: &str) -> Result<Vec<String>, AuthError> {
+    let uuid = uuid.to_simple().to_string();
+    confirm_password(&uuid, password)?;
+    let mut db = db()?;
+    let tx = db.transaction()?;
+    let pending: Option<String> = tx.query_row(
+        "SELECT totp_pending FROM users WHERE uuid == ?1",
+        params![uuid],
+        |row| row.get(0),
+    )?;
+    let pending = pending.ok_or_else(|| {
+        AuthError::InvalidRequest("No two-factor authentication enrollment is pending.".into())
+    })?;
+    let step = totp::verify(&pending, code, now_secs(), None).ok_or(AuthError::InvalidOtp)?;
+
+    tx.execute(
+        "UPDATE users SET totp_secret = totp_pending, totp_pending = NULL, totp_last_step = ?1
+         WHERE uuid == ?2",
+        params![step, uuid],
+    )?;
+    tx.execute("DELETE FROM recovery_codes WHERE uuid == ?1", params![uuid])?;
+    let codes: Vec<String> = (0..totp::RECOVERY_CODES).map(|_| totp::generate_recovery_code()).collect();
+    for code in &codes {
+        tx.execute(
+            "INSERT INTO recovery_codes (uuid, code_hash) VALUES (?1, ?2)",
+            params![uuid, totp::hash_recovery_code(code)],
+        )?;
+    }
+    tx.commit()?;
+    Ok(codes)
+}
+
+/// Turns two-factor authentication off and drops the recovery codes, for
+/// admins helping users who lost both their authenticator and their codes.
+pub fn reset_totp(uuid: &Uuid) -> Result<(), AuthError> {
+    let uuid = uuid.to_simple().to_string();
+    let mut db = db()?;
+    let tx = db.transaction()?;
+    let changed = tx.execute(
+        "UPDATE users SET totp_secret = NULL, totp_pending = NULL, totp_last_step = NULL
+         WHERE uuid == ?1",
+        params![uuid],
+    )?;
+    tx.execute("DELETE FROM recovery_codes WHERE uuid == ?1", params![uuid])?;
+    tx.commit()?;
+    if changed == 0 {
+        return Err(AuthError::UserDoesNotExist);
+    }
+    log::info!("Two-factor authentication of {} reset by an admin", uuid);
+    Ok(())
+}
+
+/// File outstanding tokens are saved to across restarts, if `AUTH_TOKEN_CACHE_FILE` is set.
+fn token_cache_file() -> Option<PathBuf> {
+    env::var_os("AUTH_TOKEN_CACHE_FILE").map(PathBuf::from)
+}
+
+/// Writes the unexpired tokens to the token cache file, one JSON
+/// `[token, uuid, issued]` array per line. The file holds live tokens, so
+/// only the server's user can read it.
+pub fn save_tokens() -> Result<usize, AuthError> {
+    let path = match token_cache_file() {
+        Some(path) => path,
+        None => return Ok(0),
+    };
+    let tokens = TOKENS.snapshot();
+    let now = SystemTime::now();
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    let mut out = BufWriter::new(file);
+    for (token, uuid, age) in &tokens {
+        let issued = now.checked_sub(*age).unwrap_or(now);
+        serde_json::to_writer(&mut out, &(token, uuid, issued))?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(tokens.len())
+}
+
+/// Restores tokens saved by `save_tokens` and removes the file, so a token
+/// can never be brought back twice. Their age counts the time the server
+/// was down.
+pub fn load_tokens() -> Result<usize, AuthError> {
+    let path = match token_cache_file() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(0),
+    };
+    let file = File::open(&path)?;
+    let now = SystemTime::now();
+    let mut restored = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let (token, uuid, issued): (AuthToken, Uuid, SystemTime) = serde_json::from_str(&line)?;
+        // If the clock was set back meanwhile, the token counts as new.
+        let age = now.duration_since(issued).unwrap_or_default();
+        if age < TOKEN_TTL {
+            TOKENS.insert_aged(token, uuid, age);
+            restored += 1;
+        }
+    }
+    fs::remove_file(&path)?;
+    Ok(restored)
+}
+
+/// Drops the tokens saved by `save_tokens`, which don't belong to a restored
+/// database.
+pub fn discard_saved_tokens() -> Result<(), AuthError> {
+    match token_cache_file() {
+        Some(path) if path.exists() => Ok(fs::remove_file(path)?),
+        _ => Ok(()),
+    }
+}
+
+/// Closes the database cleanly once no request is using it anymore.
+pub fn close_db() -> Result<(), AuthError> {
+    let db = db()?;
+    db.execute_batch("PRAGMA optimize;")?;
+    db.close().map_err(|(_, err)| AuthError::Db(err))
+}
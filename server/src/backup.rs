@@ -0,0 +1,270 @@
+//! Snapshots of the database taken with SQLite's online backup API, so they
+//! are consistent even while the server writes, and restoring them offline.
+//!
+//! Snapshots are taken by `POST /admin/backups`, by `auth-server --backup`
+//! and every `AUTH_BACKUP_INTERVAL_SECS` if set. They land in
+//! `AUTH_BACKUP_DIR` as `auth-<UTC time>.db`, pass `PRAGMA integrity_check`
+//! before they count, and only the newest `AUTH_BACKUP_KEEP` are kept.
+
+use crate::auth::{self, AuthError};
+use crate::encoding::civil_date;
+use auth_common::{BackupInfo, BackupList};
+use fs2::FileExt;
+use lazy_static::lazy_static;
+use rusqlite::backup::{Backup, Progress, StepResult};
+use rusqlite::{params, Connection, DatabaseName, OpenFlags};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Snapshots kept, overridable with `AUTH_BACKUP_KEEP`.
+const DEFAULT_KEEP: usize = 7;
+/// How often a snapshot waits for a writer to finish before giving up.
+const BUSY_RETRIES: u32 = 50;
+const BUSY_PAUSE: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    /// Held while a snapshot is taken, so they don't run concurrently.
+    static ref TAKING: Mutex<()> = Mutex::new(());
+}
+
+/// Where snapshots go, `AUTH_BACKUP_DIR` or `backups` next to the database.
+pub fn dir() -> PathBuf {
+    match env::var_os("AUTH_BACKUP_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&auth::db_path())
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join("backups"),
+    }
+}
+
+fn keep() -> usize {
+    env::var("AUTH_BACKUP_KEEP")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_KEEP)
+}
+
+/// Names snapshots like `auth-20240131T235959.123Z.db`, which sort by time.
+fn snapshot_name(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (year, month, day) = civil_date(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    format!(
+        "auth-{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z.db",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+fn is_snapshot(name: &str) -> bool {
+    name.starts_with("auth-") && name.ends_with(".db")
+}
+
+/// Takes a verified snapshot, then drops the oldest beyond `AUTH_BACKUP_KEEP`.
+pub fn create() -> Result<BackupInfo, AuthError> {
+    let _taking = TAKING.lock().unwrap();
+    let path = snapshot()?;
+    rotate(&dir())?;
+    let info = info(&path)?;
+    log::info!("Took snapshot {} ({} bytes)", info.name, info.size);
+    Ok(info)
+}
+
+/// Copies the database into a new snapshot file and verifies it. The copy is
+/// only given its final name once it passed.
+fn snapshot() -> Result<PathBuf, AuthError> {
+    let dir = dir();
+    fs::create_dir_all(&dir)?;
+    let name = snapshot_name(SystemTime::now());
+    let partial = dir.join(format!("{}.partial", name));
+    if let Err(err) = copy(&partial).and_then(|()| verify(&partial)) {
+        let _ = fs::remove_file(&partial);
+        return Err(err);
+    }
+    let path = dir.join(name);
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+fn copy(to: &Path) -> Result<(), AuthError> {
+    let src = auth::db()?;
+    let mut dst = Connection::open(to)?;
+    let backup = Backup::new(&src, &mut dst)?;
+    // All pages in one step, within one read transaction: the snapshot is of
+    // a single point in time, and writes can't make it start over.
+    for _ in 0..BUSY_RETRIES {
+        match backup.step(-1)? {
+            StepResult::Done => return Ok(()),
+            StepResult::More | StepResult::Busy | StepResult::Locked => thread::sleep(BUSY_PAUSE),
+        }
+    }
+    Err(AuthError::Overloaded)
+}
+
+/// Checks that a snapshot is an intact database with accounts in it.
+pub fn verify(path: &Path) -> Result<(), AuthError> {
+    let unreadable = |err: rusqlite::Error| AuthError::Backup(format!("{} can't be read: {}", path.display(), err));
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(unreadable)?;
+    let result: String = db
+        .query_row("PRAGMA integrity_check", params![], |row| row.get(0))
+        .map_err(unreadable)?;
+    if result != "ok" {
+        return Err(AuthError::Backup(format!(
+            "{} failed the integrity check: {}",
+            path.display(),
+            result
+        )));
+    }
+    let has_users: bool = db.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type == 'table' AND name == 'users'",
+        params![],
+        |row| row.get(0),
+    )?;
+    if !has_users {
+        return Err(AuthError::Backup(format!("{} has no users table", path.display())));
+    }
+    Ok(())
+}
+
+/// Snapshot file names in `dir`, newest first.
+fn snapshot_names(dir: &Path) -> Result<Vec<String>, AuthError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_snapshot(name))
+        .collect();
+    names.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(names)
+}
+
+fn rotate(dir: &Path) -> Result<(), AuthError> {
+    for name in snapshot_names(dir)?.iter().skip(keep()) {
+        fs::remove_file(dir.join(name))?;
+        log::info!("Removed old snapshot {}", name);
+    }
+    Ok(())
+}
+
+fn info(path: &Path) -> Result<BackupInfo, AuthError> {
+    let meta = fs::metadata(path)?;
+    let created = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Ok(BackupInfo {
+        name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        size: meta.len(),
+        created,
+    })
+}
+
+pub fn list() -> Result<BackupList, AuthError> {
+    let dir = dir();
+    let backups = snapshot_names(&dir)?
+        .iter()
+        .map(|name| info(&dir.join(name)))
+        .collect::<Result<_, _>>()?;
+    Ok(BackupList { backups })
+}
+
+/// Takes a snapshot every `AUTH_BACKUP_INTERVAL_SECS`, if set.
+pub fn start_scheduler() {
+    let interval = match env::var("AUTH_BACKUP_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()) {
+        Some(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => return,
+    };
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(err) = create() {
+            log::warn!("Scheduled backup failed: {}", err);
+        }
+    });
+}
+
+fn lock_path() -> PathBuf {
+    PathBuf::from(format!("{}.lock", auth::db_path()))
+}
+
+/// Opens the lock file next to the database and locks it, failing with
+/// `WouldBlock` while a running server holds it.
+fn try_lock() -> io::Result<File> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(lock_path())?;
+    file.try_lock_exclusive()?;
+    Ok(file)
+}
+
+/// Marks the database as in use for as long as the returned file is open,
+/// which keeps `--restore` from replacing it under a running server.
+pub fn lock_database() -> Result<File, AuthError> {
+    Ok(try_lock()?)
+}
+
+/// Locks the database for an offline change, failing while a running server
+/// holds it. `doing` completes "Stop it before ...".
+pub fn lock_offline(doing: &str) -> Result<File, AuthError> {
+    try_lock().map_err(|err| {
+        if err.kind() == fs2::lock_contended_error().kind() {
+            AuthError::Backup(format!(
+                "{} is in use by a running server. Stop it before {}.",
+                auth::db_path(),
+                doing
+            ))
+        } else {
+            AuthError::Io(err)
+        }
+    })
+}
+
+/// Runs `--restore` with the arguments after it: replaces the database with
+/// a snapshot, given by name in the backup directory or by path. The current
+/// database is saved as a snapshot first.
+pub fn restore(args: &[String]) -> Result<(), AuthError> {
+    let name = match args {
+        [name] => name,
+        _ => return Err(AuthError::Backup("Name the one snapshot to restore.".into())),
+    };
+    let in_dir = dir().join(name);
+    let path = if !name.contains(std::path::MAIN_SEPARATOR) && in_dir.exists() {
+        in_dir
+    } else {
+        PathBuf::from(name)
+    };
+    if !path.is_file() {
+        return Err(AuthError::Backup(format!("There is no snapshot {}.", path.display())));
+    }
+
+    let _lock = lock_offline("restoring")?;
+    verify(&path)?;
+
+    if Path::new(&auth::db_path()).exists() {
+        let saved = snapshot()?;
+        println!("Saved the current database as {}", saved.display());
+    }
+    let mut db = auth::db()?;
+    db.restore(DatabaseName::Main, &path, None::<fn(Progress)>)?;
+    drop(db);
+    // The snapshot may predate migrations.
+    auth::init_db()?;
+    auth::discard_saved_tokens()?;
+    println!("Restored {} into {}", path.display(), auth::db_path());
+    Ok(())
+}
@@ -0,0 +1,16 @@
+//! Offline account management, see `auth_server::admin` and the README.
+
+use auth_server::admin;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match admin::run(&args) {
+        Ok(true) => {}
+        // `check` found problems and printed them.
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::metrics;
 use auth_common::AuthToken;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -6,6 +7,9 @@ use std::thread;
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+/// How long a token stays valid once issued.
+pub const TOKEN_TTL: Duration = Duration::from_secs(15);
+
 pub struct TimedCacheEntry {
     pub timestamp: Instant,
     pub data: Uuid,
@@ -15,26 +19,34 @@ fn work_clean(map: Arc<Mutex<HashMap<AuthToken, TimedCacheEntry>>>) {
     loop {
         thread::sleep(Duration::from_secs(60));
 
-        map
+        let mut map = map
             .lock()
             // Panic and restart if the authtoken cache is poisoned which should never happen.
-            .expect("AuthToken cache has been poisoned. Panicking to restart.")
-            .retain(|_, v| v.timestamp.elapsed() < Duration::from_secs(15));
+            .expect("AuthToken cache has been poisoned. Panicking to restart.");
+        let before = map.len();
+        map.retain(|_, v| v.timestamp.elapsed() < TOKEN_TTL);
+        metrics::TOKENS_EXPIRED.inc_by(&[], (before - map.len()) as u64);
     }
 }
 
 pub struct TimedCache {
     inner: Arc<Mutex<HashMap<AuthToken, TimedCacheEntry>>>,
+    cleaner: thread::JoinHandle<()>,
 }
 
 impl TimedCache {
     pub fn new() -> Self {
         let inner = Arc::new(Mutex::new(HashMap::new()));
-        {
+        let cleaner = {
             let inner = inner.clone();
-            thread::spawn(|| work_clean(inner));
-        }
-        Self { inner }
+            thread::spawn(|| work_clean(inner))
+        };
+        Self { inner, cleaner }
+    }
+
+    /// Whether the thread expiring old tokens is still running.
+    pub fn cleaner_alive(&self) -> bool {
+        !self.cleaner.is_finished()
     }
 
     pub fn insert(&self, k: AuthToken, v: Uuid) {
@@ -52,6 +64,54 @@ impl TimedCache {
         );
     }
 
+    pub fn len(&self) -> usize {
+        self.inner
+            .lock()
+            // Panic and restart if the authtoken cache is poisoned which should never happen.
+            .expect("AuthToken cache has been poisoned. Panicking to restart.")
+            .len()
+    }
+
+    /// Copies out every live entry along with its age.
+    pub fn snapshot(&self) -> Vec<(AuthToken, Uuid, Duration)> {
+        self.inner
+            .lock()
+            // Panic and restart if the authtoken cache is poisoned which should never happen.
+            .expect("AuthToken cache has been poisoned. Panicking to restart.")
+            .iter()
+            .map(|(k, v)| (*k, v.data, v.timestamp.elapsed()))
+            .filter(|(_, _, age)| *age < TOKEN_TTL)
+            .collect()
+    }
+
+    /// Inserts an entry that was created `age` ago.
+    pub fn insert_aged(&self, k: AuthToken, v: Uuid, age: Duration) {
+        let now = Instant::now();
+        self.inner
+            .lock()
+            // Panic and restart if the authtoken cache is poisoned which should never happen.
+            .expect("AuthToken cache has been poisoned. Panicking to restart.")
+            .insert(
+                k,
+                TimedCacheEntry {
+                    timestamp: now.checked_sub(age).unwrap_or(now),
+                    data: v,
+                },
+            );
+    }
+
+    /// Drops every entry for `v`, returning how many there were.
+    pub fn remove_data(&self, v: &Uuid) -> usize {
+        let mut inner = self
+            .inner
+            .lock()
+            // Panic and restart if the authtoken cache is poisoned which should never happen.
+            .expect("AuthToken cache has been poisoned. Panicking to restart.");
+        let before = inner.len();
+        inner.retain(|_, entry| entry.data != *v);
+        before - inner.len()
+    }
+
     pub fn run(&self, k: &AuthToken, f: impl FnOnce(Option<&mut TimedCacheEntry>) -> bool) {
         let mut inner = self
             .inner
@@ -61,7 +121,7 @@ impl TimedCache {
 
         let v = inner.get_mut(k);
         if !f(v) {
-            inner.remove(&k);
+            inner.remove(k);
         }
     }
 }
@@ -0,0 +1,202 @@
+//! Cross-origin resource sharing, configured through `AUTH_CORS_*` variables.
+//!
+//! Routes under `/admin/` use their own policy (`AUTH_CORS_ADMIN_*`) which
+//! allows no cross-origin access unless configured.
+
+use lazy_static::lazy_static;
+use log::*;
+use rouille::{Request, Response};
+use std::env;
+
+const DEFAULT_METHODS: &str = "GET, POST, PUT, DELETE";
+const DEFAULT_HEADERS: &str = "Content-Type, Authorization";
+const DEFAULT_MAX_AGE: u32 = 600;
+
+lazy_static! {
+    static ref CORS: Cors = Cors::from_env();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Origins {
+    Any,
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    origins: Origins,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    credentials: bool,
+    max_age: u32,
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn flag(value: &str) -> bool {
+    matches!(value.trim(), "1" | "true" | "yes")
+}
+
+impl Policy {
+    /// Reads `{prefix}_ORIGINS`, `_METHODS`, `_HEADERS`, `_CREDENTIALS` and
+    /// `_MAX_AGE`, using `fallback` for whatever is unset.
+    fn from_env(prefix: &str, fallback: Policy) -> Self {
+        let var = |name: &str| env::var(format!("{}_{}", prefix, name)).ok();
+        let origins = match var("ORIGINS") {
+            Some(v) if v.trim() == "*" => Origins::Any,
+            Some(v) => Origins::List(list(&v)),
+            None => fallback.origins,
+        };
+        let mut policy = Policy {
+            origins,
+            methods: var("METHODS").map(|v| list(&v)).unwrap_or(fallback.methods),
+            headers: var("HEADERS").map(|v| list(&v)).unwrap_or(fallback.headers),
+            credentials: var("CREDENTIALS").map(|v| flag(&v)).unwrap_or(fallback.credentials),
+            max_age: var("MAX_AGE")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(fallback.max_age),
+        };
+        if policy.credentials && policy.origins == Origins::Any {
+            warn!(
+                "{}_CREDENTIALS requires an explicit origin list, ignoring it for '*'",
+                prefix
+            );
+            policy.credentials = false;
+        }
+        policy
+    }
+
+    /// The value of `Access-Control-Allow-Origin` for `origin`, if it is allowed.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        match &self.origins {
+            Origins::Any => Some("*".into()),
+            Origins::List(list) if list.iter().any(|o| o.eq_ignore_ascii_case(origin)) => {
+                Some(origin.to_owned())
+            }
+            Origins::List(_) => None,
+        }
+    }
+
+    /// With an origin list the answer depends on `Origin`, whether or not
+    /// it was sent and allowed, so caches must keep them apart.
+    fn vary(&self, response: Response) -> Response {
+        match self.origins {
+            Origins::Any => response,
+            Origins::List(_) => response.with_additional_header("Vary", "Origin"),
+        }
+    }
+
+    fn apply(&self, allowed: String, response: Response) -> Response {
+        let mut response = response
+            .with_unique_header("Access-Control-Allow-Origin", allowed)
+            .with_unique_header("Access-Control-Expose-Headers", "X-Error-Code, Retry-After");
+        if self.credentials {
+            response = response.with_unique_header("Access-Control-Allow-Credentials", "true");
+        }
+        response
+    }
+}
+
+pub struct Cors {
+    default: Policy,
+    /// Policies for paths starting with the given prefix, checked in order.
+    overrides: Vec<(&'static str, Policy)>,
+}
+
+impl Cors {
+    fn from_env() -> Self {
+        let default = Policy::from_env(
+            "AUTH_CORS",
+            Policy {
+                origins: Origins::Any,
+                methods: list(DEFAULT_METHODS),
+                headers: list(DEFAULT_HEADERS),
+                credentials: false,
+                max_age: DEFAULT_MAX_AGE,
+            },
+        );
+        let admin = Policy::from_env(
+            "AUTH_CORS_ADMIN",
+            Policy {
+                origins: Origins::List(Vec::new()),
+                ..default.clone()
+            },
+        );
+        Self {
+            default,
+            overrides: vec![("/admin/", admin)],
+        }
+    }
+
+    fn policy(&self, path: &str) -> &Policy {
+        self.overrides
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix))
+            .map(|(_, policy)| policy)
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Adds the CORS headers to a regular response.
+pub fn apply(request: &Request, path: &str, response: Response) -> Response {
+    let policy = CORS.policy(path);
+    let response = policy.vary(response);
+    let origin = match request.header("Origin") {
+        Some(origin) => origin,
+        None => return response,
+    };
+    match policy.allow_origin(origin) {
+        Some(allowed) => policy.apply(allowed, response),
+        None => response,
+    }
+}
+
+/// Answers an `OPTIONS` preflight for a path served with `route_methods`.
+pub fn preflight(request: &Request, path: &str, route_methods: &[&str]) -> Response {
+    let mut allow = route_methods.to_vec();
+    allow.push("OPTIONS");
+    let policy = CORS.policy(path);
+    let response = policy.vary(Response::empty_204().with_unique_header("Allow", allow.join(", ")));
+
+    let (origin, method) = match (
+        request.header("Origin"),
+        request.header("Access-Control-Request-Method"),
+    ) {
+        (Some(origin), Some(method)) => (origin, method),
+        // A plain OPTIONS request rather than a CORS preflight.
+        _ => return response,
+    };
+    let allowed = match policy.allow_origin(origin) {
+        Some(allowed) => allowed,
+        None => {
+            debug!("CORS preflight from {} to {} rejected: origin", origin, path);
+            return response;
+        }
+    };
+    let method_ok = route_methods.contains(&method)
+        && policy.methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+    let headers_ok = request
+        .header("Access-Control-Request-Headers")
+        .map(|requested| {
+            list(requested)
+                .iter()
+                .all(|h| policy.headers.iter().any(|a| a.eq_ignore_ascii_case(h)))
+        })
+        .unwrap_or(true);
+    if !method_ok || !headers_ok {
+        debug!("CORS preflight from {} to {} rejected: {}", origin, path, method);
+        return response;
+    }
+
+    policy
+        .apply(allowed, response)
+        .with_unique_header("Access-Control-Allow-Methods", policy.methods.join(", "))
+        .with_unique_header("Access-Control-Allow-Headers", policy.headers.join(", "))
+        .with_unique_header("Access-Control-Max-Age", policy.max_age.to_string())
+}
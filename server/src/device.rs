@@ -0,0 +1,301 @@
+//! Device authorization grant (RFC 8628) for clients that can't show a login
+//! form, such as dedicated servers and console-style game clients.
+//!
+//! The device asks for a device code and a short user code, shows the user
+//! code and polls. The user opens `/device` on another machine, signs in and
+//! approves the code. Registered OpenID Connect clients poll the token
+//! endpoint and get OAuth tokens, game clients poll `/v1/device/token` and get
+//! an `AuthToken`.
+
+use crate::auth::{self, AuthError};
+use crate::encoding::{escape_html, percent_encode};
+use crate::oidc::{self, OAuthError, SCOPES};
+use auth_common::DeviceAuthorizationResponse;
+use rusqlite::{params, OptionalExtension};
+use uuid::Uuid;
+
+/// The `grant_type` devices poll the token endpoint with.
+pub const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// How long the user has to approve a device.
+const CODE_TTL_SECS: i64 = 10 * 60;
+/// Seconds a device must wait between polls, raised by `SLOW_DOWN_SECS` each
+/// time it polls too fast.
+const INTERVAL_SECS: i64 = 5;
+const SLOW_DOWN_SECS: i64 = 5;
+/// User codes are typed by hand, so they use consonants only: no lookalike
+/// digits and no accidental words.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LEN: usize = 8;
+
+fn generate_user_code() -> String {
+    (0..USER_CODE_LEN)
+        .map(|_| USER_CODE_ALPHABET[rand::random::<usize>() % USER_CODE_ALPHABET.len()] as char)
+        .collect()
+}
+
+/// Reads a user code the way people type it: any case, with or without the
+/// dash or spaces.
+fn normalize_user_code(input: &str) -> Option<String> {
+    let code: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let valid = code.len() == USER_CODE_LEN && code.bytes().all(|b| USER_CODE_ALPHABET.contains(&b));
+    if valid {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+/// Shows a user code as `XXXX-XXXX`.
+fn display_user_code(code: &str) -> String {
+    let (first, second) = code.split_at(USER_CODE_LEN / 2);
+    format!("{}-{}", first, second)
+}
+
+/// Starts a device authorization for `client_id`, or for a game client if
+/// it is `None`.
+pub fn start(client_id: Option<&str>, scope: &[&str]) -> Result<DeviceAuthorizationResponse, AuthError> {
+    let device_code = oidc::random_token(32)?;
+    let now = auth::now_secs();
+    let db = auth::db()?;
+    db.execute("DELETE FROM device_codes WHERE expires < ?1", params![now])?;
+
+    // User codes are short enough to collide now and then.
+    let mut user_code = None;
+    for _ in 0..5 {
+        let code = generate_user_code();
+        let inserted = db.execute(
+            "INSERT OR IGNORE INTO device_codes (code_hash, user_code, client_id, scope, interval, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                oidc::sha256_hex(&device_code),
+                code,
+                client_id,
+                scope.join(" "),
+                INTERVAL_SECS,
+                now + CODE_TTL_SECS
+            ],
+        )?;
+        if inserted == 1 {
+            user_code = Some(code);
+            break;
+        }
+    }
+    let user_code = display_user_code(&user_code.ok_or(AuthError::Crypto)?);
+
+    let verification_uri = format!("{}/device", oidc::issuer());
+    Ok(DeviceAuthorizationResponse {
+        verification_uri_complete: format!("{}?user_code={}", verification_uri, percent_encode(&user_code)),
+        verification_uri,
+        device_code,
+        user_code,
+        expires_in: CODE_TTL_SECS as u64,
+        interval: INTERVAL_SECS as u64,
+    })
+}
+
+/// An approved device authorization.
+pub struct DeviceGrant {
+    pub uuid: Uuid,
+    pub scope: String,
+    pub auth_time: i64,
+}
+
+/// Answers a poll from the device. `client_id` must match the one the
+/// authorization was started for. Approved and denied codes are removed, so
+/// each code yields at most one grant.
+pub fn poll(device_code: &str, client_id: Option<&str>) -> Result<DeviceGrant, OAuthError> {
+    let code_hash = oidc::sha256_hex(device_code);
+    let now = auth::now_secs();
+    let mut db = auth::db()?;
+    let tx = db.transaction()?;
+    let row = tx
+        .query_row(
+            "SELECT client_id, scope, status, uuid, auth_time, interval, last_poll, expires
+             FROM device_codes WHERE code_hash == ?1",
+            params![code_hash],
+            |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<i64>>(4)?,
+                    row.get::<_, i64>(5)?,
+                    row.get::<_, Option<i64>>(6)?,
+                    row.get::<_, i64>(7)?,
+                ))
+            },
+        )
+        .optional()?;
+    let (owner, scope, status, uuid, auth_time, interval, last_poll, expires) = row.ok_or(OAuthError::InvalidGrant)?;
+    if owner.as_deref() != client_id {
+        return Err(OAuthError::InvalidGrant);
+    }
+
+    let delete = |tx: &rusqlite::Transaction| tx.execute("DELETE FROM device_codes WHERE code_hash == ?1", params![code_hash]);
+    let result = if expires < now {
+        delete(&tx)?;
+        Err(OAuthError::ExpiredToken)
+    } else if last_poll.is_some_and(|last| now - last < interval) {
+        tx.execute(
+            "UPDATE device_codes SET interval = ?2, last_poll = ?3 WHERE code_hash == ?1",
+            params![code_hash, interval + SLOW_DOWN_SECS, now],
+        )?;
+        Err(OAuthError::SlowDown)
+    } else {
+        match (status.as_str(), uuid, auth_time) {
+            ("approved", Some(uuid), Some(auth_time)) => {
+                delete(&tx)?;
+                let uuid = Uuid::parse_str(&uuid).map_err(|_| OAuthError::InvalidGrant)?;
+                // The account may have been banned since it approved.
+                if auth::is_banned(&uuid)? {
+                    Err(OAuthError::AccessDenied)
+                } else {
+                    Ok(DeviceGrant { uuid, scope, auth_time })
+                }
+            }
+            ("denied", _, _) => {
+                delete(&tx)?;
+                Err(OAuthError::AccessDenied)
+            }
+            _ => {
+                tx.execute(
+                    "UPDATE device_codes SET last_poll = ?2 WHERE code_hash == ?1",
+                    params![code_hash, now],
+                )?;
+                Err(OAuthError::AuthorizationPending)
+            }
+        }
+    };
+    tx.commit()?;
+    result
+}
+
+/// A device authorization waiting for the user, looked up by user code.
+pub struct PendingDevice {
+    user_code: String,
+    client_name: Option<String>,
+    scope: Vec<&'static str>,
+}
+
+/// Finds the pending authorization a user code belongs to.
+pub fn pending(user_code: &str) -> Result<Option<PendingDevice>, AuthError> {
+    let user_code = match normalize_user_code(user_code) {
+        Some(code) => code,
+        None => return Ok(None),
+    };
+    let device = auth::db()?
+        .query_row(
+            "SELECT device_codes.scope, oauth_clients.name FROM device_codes
+             LEFT JOIN oauth_clients ON oauth_clients.client_id == device_codes.client_id
+             WHERE user_code == ?1 AND status == 'pending' AND expires >= ?2",
+            params![user_code, auth::now_secs()],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+        )
+        .optional()?;
+    Ok(device.map(|(scope, client_name)| PendingDevice {
+        user_code,
+        client_name,
+        scope: oidc::parse_scope(&scope),
+    }))
+}
+
+impl PendingDevice {
+    /// Lets the device sign in as `uuid` on its next poll.
+    pub fn approve(&self, uuid: &Uuid) -> Result<(), AuthError> {
+        auth::db()?.execute(
+            "UPDATE device_codes SET status = 'approved', uuid = ?2, auth_time = ?3
+             WHERE user_code == ?1 AND status == 'pending'",
+            params![self.user_code, uuid.to_simple().to_string(), auth::now_secs()],
+        )?;
+        Ok(())
+    }
+
+    pub fn deny(&self) -> Result<(), AuthError> {
+        auth::db()?.execute(
+            "UPDATE device_codes SET status = 'denied' WHERE user_code == ?1 AND status == 'pending'",
+            params![self.user_code],
+        )?;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        self.client_name.as_deref().unwrap_or("A game client")
+    }
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width"><title>{title}</title></head>
+<body>
+<h1>{title}</h1>
+{body}
+</body>
+</html>
+"#,
+        title = escape_html(title),
+        body = body,
+    )
+}
+
+/// The page where the user enters the code shown by the device, signs in and
+/// approves it. `device` is the authorization the code belongs to, if known.
+pub fn page(device: Option<&PendingDevice>, user_code: &str, error: Option<&str>) -> String {
+    let request = match device {
+        Some(device) => {
+            let scopes: String = SCOPES
+                .iter()
+                .filter(|(name, _)| device.scope.contains(name))
+                .map(|(_, description)| format!("<li>{}</li>", description))
+                .collect();
+            let scopes = if scopes.is_empty() {
+                String::new()
+            } else {
+                format!("<p>It would like to know:</p>\n<ul>{}</ul>", scopes)
+            };
+            format!(
+                "<p><strong>{}</strong> would like to sign in with your account. Only continue if the code below is the one your device shows.</p>\n{}",
+                escape_html(device.name()),
+                scopes
+            )
+        }
+        None => "<p>Enter the code shown on your device.</p>".to_owned(),
+    };
+    let user_code = device.map(|device| display_user_code(&device.user_code)).unwrap_or_else(|| user_code.to_owned());
+    let error = error
+        .map(|error| format!(r#"<p class="error">{}</p>"#, escape_html(error)))
+        .unwrap_or_default();
+
+    html_page(
+        "Sign in on a device",
+        &format!(
+            r#"{request}
+{error}
+<form method="post" action="/device">
+<p><label>Code <input name="user_code" value="{user_code}" autocomplete="off" autocapitalize="characters" required></label></p>
+<p><label>Username <input name="username" autocomplete="username" required></label></p>
+<p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+<p><label>Authenticator code <input name="otp" autocomplete="one-time-code"></label> (if enabled)</p>
+<p><button name="decision" value="allow">Allow</button> <button name="decision" value="deny" formnovalidate>Deny</button></p>
+</form>"#,
+            request = request,
+            error = error,
+            user_code = escape_html(&user_code),
+        ),
+    )
+}
+
+/// Shown once the user approved or denied a device.
+pub fn done_page(approved: bool) -> String {
+    if approved {
+        html_page("Device approved", "<p>You are signed in. You can close this page and return to your device.</p>")
+    } else {
+        html_page("Device denied", "<p>The device was not signed in. You can close this page.</p>")
+    }
+}
@@ -0,0 +1,218 @@
+//! The account event stream game servers subscribe to, so they can kick
+//! players who were banned, revoked or deleted while online.
+//!
+//! Events are kept in the database for `AUTH_EVENT_RETENTION_SECS` and
+//! numbered by an ever-increasing cursor. `/v1/events` sends them as
+//! server-sent events whose `id` is the cursor, so a subscriber that
+//! reconnects with `Last-Event-ID` (or `?after=`) picks up where it left off.
+//! If the events it missed were already dropped, it gets a `lost` event first
+//! and should resynchronize its players.
+
+use crate::auth::{self, AuthError};
+use crate::shutdown;
+use auth_common::{AccountEvent, AccountEventRecord};
+use lazy_static::lazy_static;
+use rusqlite::{params, OptionalExtension};
+use std::env;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long events are kept, overridable with `AUTH_EVENT_RETENTION_SECS`.
+const DEFAULT_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+/// Streams open at once, overridable with `AUTH_EVENT_STREAMS`. Each one
+/// holds a thread of the blocking pool, which has room for them on top of
+/// the request handlers.
+const DEFAULT_MAX_STREAMS: usize = 16;
+/// Quiet streams get a comment this often, so dead connections are noticed.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// How often an idle stream checks whether the server is shutting down.
+const WAKE_INTERVAL: Duration = Duration::from_secs(1);
+/// Events read from the database per round.
+const BATCH_SIZE: i64 = 100;
+
+lazy_static! {
+    /// Cursor of the newest event, for waking up streams.
+    static ref LATEST: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
+}
+
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Records `event`. Failures are logged rather than returned, since the
+/// change the event reports has already happened.
+pub fn publish(event: AccountEvent) {
+    if let Err(err) = append(&event) {
+        log::warn!("Could not record {} event for {}: {}", event.name(), event.uuid(), err);
+    }
+}
+
+fn append(event: &AccountEvent) -> Result<(), AuthError> {
+    let now = auth::now_secs();
+    let db = auth::db()?;
+    db.execute(
+        "INSERT INTO events (type, uuid, data, created) VALUES (?1, ?2, ?3, ?4)",
+        params![event.name(), event.uuid().to_simple().to_string(), serde_json::to_string(event)?, now],
+    )?;
+    let cursor = db.last_insert_rowid() as u64;
+    db.execute(
+        "DELETE FROM events WHERE created < ?1",
+        params![now - env_number("AUTH_EVENT_RETENTION_SECS", DEFAULT_RETENTION_SECS)],
+    )?;
+
+    let (latest, wake) = &*LATEST;
+    let mut latest = latest.lock().unwrap();
+    *latest = (*latest).max(cursor);
+    wake.notify_all();
+    Ok(())
+}
+
+/// Cursor of the newest event ever recorded, even if it was dropped since.
+fn latest_cursor() -> Result<u64, AuthError> {
+    let seq: Option<i64> = auth::db()?
+        .query_row("SELECT seq FROM sqlite_sequence WHERE name == 'events'", params![], |row| row.get(0))
+        .optional()?;
+    Ok(seq.unwrap_or(0) as u64)
+}
+
+fn oldest_cursor() -> Result<Option<u64>, AuthError> {
+    let oldest: Option<i64> = auth::db()?.query_row("SELECT min(id) FROM events", params![], |row| row.get(0))?;
+    Ok(oldest.map(|id| id as u64))
+}
+
+fn since(cursor: u64) -> Result<Vec<AccountEventRecord>, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare("SELECT id, data, created FROM events WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+    let events = stmt
+        .query_map(params![cursor as i64, BATCH_SIZE], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?))
+        })?
+        .filter_map(|s| s.ok())
+        .filter_map(|(id, data, created)| {
+            serde_json::from_str(&data).ok().map(|event| AccountEventRecord {
+                cursor: id as u64,
+                created,
+                event,
+            })
+        })
+        .collect();
+    Ok(events)
+}
+
+/// How many streams may be open at once.
+pub fn max_streams() -> usize {
+    env_number("AUTH_EVENT_STREAMS", DEFAULT_MAX_STREAMS)
+}
+
+/// A slot among the `AUTH_EVENT_STREAMS` allowed streams, freed on drop.
+struct StreamSlot(());
+
+impl StreamSlot {
+    fn take() -> Option<Self> {
+        let max = max_streams();
+        let taken = OPEN_STREAMS.fetch_add(1, Ordering::SeqCst);
+        if taken >= max {
+            OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(StreamSlot(()))
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The body of an event stream. Reading blocks until there is something to
+/// send and ends once the server shuts down.
+pub struct EventStream {
+    cursor: u64,
+    pending: Vec<u8>,
+    sent: usize,
+    last_write: Instant,
+    _slot: StreamSlot,
+}
+
+/// Opens a stream of the events after `after`, or of new events only if it
+/// is `None`. Fails with `Overloaded` when too many streams are open.
+pub fn open(after: Option<u64>) -> Result<EventStream, AuthError> {
+    let slot = StreamSlot::take().ok_or(AuthError::Overloaded)?;
+    let latest = latest_cursor()?;
+    // Asks the client to wait a few seconds before reconnecting.
+    let mut pending = "retry: 3000\n\n".to_owned();
+    let cursor = match after {
+        Some(after) => {
+            let first_kept = oldest_cursor()?.unwrap_or(latest + 1);
+            if after + 1 < first_kept && after < latest {
+                let resumed = first_kept - 1;
+                pending.push_str(&format!("id: {}\nevent: lost\ndata: {{\"cursor\":{}}}\n\n", resumed, resumed));
+                resumed
+            } else {
+                after.min(latest)
+            }
+        }
+        None => latest,
+    };
+    Ok(EventStream {
+        cursor,
+        pending: pending.into_bytes(),
+        sent: 0,
+        last_write: Instant::now(),
+        _slot: slot,
+    })
+}
+
+impl EventStream {
+    /// Fills `pending` with the next events, a keepalive, or nothing if it
+    /// is time to end the stream.
+    fn refill(&mut self) -> io::Result<()> {
+        self.pending.clear();
+        self.sent = 0;
+        loop {
+            if shutdown::requested() {
+                return Ok(());
+            }
+            let events = since(self.cursor).map_err(|err| io::Error::other(err.to_string()))?;
+            if let Some(last) = events.last() {
+                self.cursor = last.cursor;
+                for record in &events {
+                    let data = serde_json::to_string(record)?;
+                    self.pending.extend_from_slice(
+                        format!("id: {}\nevent: {}\ndata: {}\n\n", record.cursor, record.event.name(), data).as_bytes(),
+                    );
+                }
+                break;
+            }
+            if self.last_write.elapsed() >= KEEPALIVE {
+                self.pending.extend_from_slice(b": keepalive\n\n");
+                break;
+            }
+
+            let (latest, wake) = &*LATEST;
+            let latest = latest.lock().unwrap();
+            if *latest <= self.cursor {
+                let _ = wake.wait_timeout(latest, WAKE_INTERVAL).unwrap();
+            }
+        }
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.sent == self.pending.len() {
+            self.refill()?;
+        }
+        let n = buf.len().min(self.pending.len() - self.sent);
+        buf[..n].copy_from_slice(&self.pending[self.sent..self.sent + n]);
+        self.sent += n;
+        Ok(n)
+    }
+}
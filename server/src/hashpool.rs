@@ -0,0 +1,101 @@
+//! Bounded pool of threads running argon2.
+//!
+//! Password hashing is deliberately expensive, so it gets a fixed number of
+//! worker threads and a bounded queue in front of them. When the queue is
+//! full, callers are turned away with `AuthError::Overloaded` instead of
+//! piling up behind each other.
+
+use crate::auth::AuthError;
+use crate::metrics;
+use lazy_static::lazy_static;
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    static ref POOL: HashPool = HashPool::new(workers(), queue());
+}
+
+fn available_cpus() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Worker count, overridable with `AUTH_HASH_WORKERS`.
+fn workers() -> usize {
+    env::var("AUTH_HASH_WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(available_cpus)
+}
+
+/// Jobs allowed to wait for a worker, overridable with `AUTH_HASH_QUEUE`.
+fn queue() -> usize {
+    env::var("AUTH_HASH_QUEUE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| workers() * 16)
+}
+
+struct HashPool {
+    sender: SyncSender<Job>,
+}
+
+fn work(jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = jobs
+            .lock()
+            // Panic and restart if the job queue is poisoned which should never happen.
+            .expect("Hash job queue has been poisoned. Panicking to restart.")
+            .recv();
+        match job {
+            // A panicking job drops its result sender, which the caller sees.
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+impl HashPool {
+    fn new(workers: usize, queue: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("argon2-{}", i))
+                .spawn(move || work(receiver))
+                .expect("Failed to spawn hashing thread");
+        }
+        Self { sender }
+    }
+}
+
+/// Runs `f` on the pool and waits for its result.
+pub fn run<T, F>(f: F) -> Result<T, AuthError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let job: Job = Box::new(move || {
+        let _ = tx.send(f());
+    });
+    match POOL.sender.try_send(job) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+            metrics::HASH_POOL_REJECTIONS.inc(&[]);
+            return Err(AuthError::Overloaded);
+        }
+    }
+    // The sender is only dropped without sending if the job panicked.
+    rx.recv().map_err(|_| AuthError::Overloaded)
+}
@@ -0,0 +1,134 @@
+use crate::auth;
+use auth_common::{HealthCheck, HealthResponse, HealthStatus};
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+/// Free space below which `/readyz` fails, overridable with `AUTH_MIN_FREE_DISK_MB`.
+const DEFAULT_MIN_FREE_DISK_MB: u64 = 64;
+
+pub(crate) fn ok(detail: impl Into<Option<String>>) -> HealthCheck {
+    HealthCheck {
+        status: HealthStatus::Ok,
+        detail: detail.into(),
+    }
+}
+
+pub(crate) fn fail(detail: String) -> HealthCheck {
+    HealthCheck {
+        status: HealthStatus::Fail,
+        detail: Some(detail),
+    }
+}
+
+fn database() -> HealthCheck {
+    match auth::check_writable() {
+        Ok(()) => ok(None),
+        Err(err) => fail(err.to_string()),
+    }
+}
+
+pub(crate) fn migrations() -> HealthCheck {
+    match auth::migration_state() {
+        Ok((applied, expected)) if applied == expected => {
+            ok(format!("schema version {}", applied))
+        }
+        Ok((applied, expected)) => fail(format!(
+            "schema version {}, expected {}",
+            applied, expected
+        )),
+        Err(err) => fail(err.to_string()),
+    }
+}
+
+fn disk() -> HealthCheck {
+    let min_mb = env::var("AUTH_MIN_FREE_DISK_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MIN_FREE_DISK_MB);
+    let db_path = auth::db_path();
+    let dir = match Path::new(&db_path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => Path::new(".").to_owned(),
+    };
+    match fs2::available_space(&dir) {
+        Ok(bytes) if bytes / (1024 * 1024) >= min_mb => {
+            ok(format!("{} MiB free", bytes / (1024 * 1024)))
+        }
+        Ok(bytes) => fail(format!(
+            "{} MiB free, need {} MiB",
+            bytes / (1024 * 1024),
+            min_mb
+        )),
+        Err(err) => fail(format!("{}: {}", dir.display(), err)),
+    }
+}
+
+fn token_cleaner() -> HealthCheck {
+    if auth::token_cleaner_alive() {
+        ok(None)
+    } else {
+        fail("token cleaner thread has exited".into())
+    }
+}
+
+pub(crate) fn summarize(checks: BTreeMap<String, HealthCheck>) -> HealthResponse {
+    let status = if checks.values().all(|c| c.status == HealthStatus::Ok) {
+        HealthStatus::Ok
+    } else {
+        HealthStatus::Fail
+    };
+    HealthResponse { status, checks }
+}
+
+/// Liveness: the process is up and serving requests.
+pub fn liveness() -> HealthResponse {
+    summarize(BTreeMap::new())
+}
+
+/// Readiness: every dependency needed to serve traffic is usable.
+pub fn readiness() -> HealthResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert("database".to_owned(), database());
+    checks.insert("migrations".to_owned(), migrations());
+    checks.insert("disk".to_owned(), disk());
+    checks.insert("token_cleaner".to_owned(), token_cleaner());
+    summarize(checks)
+}
+
+/// Queries `/readyz` of the server listening on `addr` and reports whether it
+/// is ready. Backs `auth-server --healthcheck`, since the docker image has no curl.
+pub fn probe(addr: &str) -> Result<(), String> {
+    // A wildcard listen address is reachable through loopback.
+    let addr = addr.replace("0.0.0.0", "127.0.0.1");
+    let addr = addr
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", addr))?;
+    let mut stream =
+        TcpStream::connect_timeout(&addr, Duration::from_secs(5)).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .map_err(|e| e.to_string())?;
+    write!(
+        stream,
+        "GET /readyz HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| e.to_string())?;
+    let status_line = response.lines().next().unwrap_or_default();
+    if status_line.split(' ').nth(1) == Some("200") {
+        Ok(())
+    } else {
+        Err(response)
+    }
+}
@@ -0,0 +1,23 @@
+//! The auth server as a library, shared by its binaries: `auth-server`
+//! itself and `auth-admin`, which manages accounts in the same database.
+
+pub mod admin;
+pub mod auth;
+pub mod backup;
+mod cache;
+mod cors;
+mod device;
+mod encoding;
+mod events;
+mod hashpool;
+pub mod health;
+mod mail;
+mod metrics;
+mod oidc;
+pub mod openapi;
+mod ratelimit;
+pub mod shutdown;
+mod totp;
+pub mod transfer;
+pub mod web;
+pub mod webhook;
@@ -0,0 +1,229 @@
+//! Outgoing email, configured through `AUTH_MAIL_*` and `AUTH_SMTP_*` variables.
+//!
+//! `AUTH_MAIL_TRANSPORT` picks the `Mailer`: `smtp` delivers through a relay,
+//! `file` appends every message to `AUTH_MAIL_FILE` and `log` (the default)
+//! only logs them, which is enough for local testing.
+
+use crate::encoding::civil_date;
+use lazy_static::lazy_static;
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, EmailAddress, Envelope, SendableEmail, SmtpClient, Transport};
+use log::*;
+use native_tls::TlsConnector;
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Mails waiting for the background sender, overridable with `AUTH_MAIL_QUEUE`.
+const DEFAULT_QUEUE: usize = 100;
+
+lazy_static! {
+    static ref MAILER: Box<dyn Mailer> = from_env();
+    static ref OUTBOX: SyncSender<Mail> = start_sender();
+}
+
+/// A plain text message to a single recipient.
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Delivers mail. Implementations must be usable from many threads at once.
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+/// Sends through an SMTP relay.
+pub struct SmtpMailer {
+    client: SmtpClient,
+    from: String,
+}
+
+impl SmtpMailer {
+    /// Reads `AUTH_SMTP_HOST`, `_PORT`, `_TLS` (`starttls`, `tls` or `none`),
+    /// `_USER` and `_PASSWORD`.
+    fn from_env(from: String) -> Result<Self, MailError> {
+        let host = env::var("AUTH_SMTP_HOST").map_err(|_| MailError("AUTH_SMTP_HOST is not set".into()))?;
+        let tls = env::var("AUTH_SMTP_TLS").unwrap_or_else(|_| "starttls".into());
+        let default_port = if tls == "tls" { 465 } else { 587 };
+        let port = env::var("AUTH_SMTP_PORT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default_port);
+
+        let tls_parameters = || -> Result<ClientTlsParameters, MailError> {
+            let connector = TlsConnector::new().map_err(|err| MailError(err.to_string()))?;
+            Ok(ClientTlsParameters::new(host.clone(), connector))
+        };
+        let security = match tls.as_str() {
+            "none" => ClientSecurity::None,
+            "tls" => ClientSecurity::Wrapper(tls_parameters()?),
+            _ => ClientSecurity::Required(tls_parameters()?),
+        };
+        let mut client = SmtpClient::new((host.as_str(), port), security)
+            .map_err(|err| MailError(err.to_string()))?;
+        if let (Ok(user), Ok(password)) = (env::var("AUTH_SMTP_USER"), env::var("AUTH_SMTP_PASSWORD")) {
+            client = client.credentials(Credentials::new(user, password));
+        }
+        Ok(Self { client, from })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let from = EmailAddress::new(self.from.clone()).map_err(|err| MailError(err.to_string()))?;
+        let to = EmailAddress::new(mail.to.clone()).map_err(|err| MailError(err.to_string()))?;
+        let envelope = Envelope::new(Some(from), vec![to]).map_err(|err| MailError(err.to_string()))?;
+        let id = message_id();
+        let message = format_message(&self.from, mail, &id);
+        let email = SendableEmail::new(envelope, id, message.into_bytes());
+        self.client
+            .clone()
+            .transport()
+            .send(email)
+            .map(|_| ())
+            .map_err(|err| MailError(err.to_string()))
+    }
+}
+
+/// Appends every message to a file, or only logs it if there is none.
+pub struct FileMailer {
+    path: Option<PathBuf>,
+    from: String,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let message = format_message(&self.from, mail, &message_id());
+        match &self.path {
+            Some(path) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| MailError(err.to_string()))?;
+                writeln!(file, "{}", message).map_err(|err| MailError(err.to_string()))
+            }
+            None => {
+                info!("Not sending mail to {}:\n{}", mail.to, message);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn from_env() -> Box<dyn Mailer> {
+    let from = env::var("AUTH_MAIL_FROM").unwrap_or_else(|_| "noreply@localhost".into());
+    match env::var("AUTH_MAIL_TRANSPORT").as_deref() {
+        Ok("smtp") => match SmtpMailer::from_env(from.clone()) {
+            Ok(mailer) => return Box::new(mailer),
+            Err(err) => error!("Cannot send mail over SMTP, only logging it: {}", err),
+        },
+        Ok("file") => {
+            return Box::new(FileMailer {
+                path: env::var_os("AUTH_MAIL_FILE").map(PathBuf::from),
+                from,
+            })
+        }
+        Ok("log") | Err(_) => {}
+        Ok(other) => warn!("Unknown AUTH_MAIL_TRANSPORT '{}', only logging mail", other),
+    }
+    Box::new(FileMailer { path: None, from })
+}
+
+/// Whether `address` is a syntactically valid email address.
+pub fn valid_address(address: &str) -> bool {
+    address.len() <= 254 && EmailAddress::new(address.to_owned()).is_ok()
+}
+
+pub fn send(mail: &Mail) -> Result<(), MailError> {
+    MAILER.send(mail)
+}
+
+/// Starts the one thread that sends queued mail.
+fn start_sender() -> SyncSender<Mail> {
+    let size = env::var("AUTH_MAIL_QUEUE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_QUEUE);
+    let (sender, outbox) = mpsc::sync_channel::<Mail>(size);
+    thread::spawn(move || {
+        for mail in outbox {
+            if let Err(err) = send(&mail) {
+                warn!("Could not send mail to {}: {}", mail.to, err);
+            }
+        }
+    });
+    sender
+}
+
+/// Sends `mail` in the background. When the queue is full the mail is
+/// dropped, so a flood of requests can't pile up work.
+pub fn queue(mail: Mail) {
+    match OUTBOX.try_send(mail) {
+        Ok(()) => {}
+        Err(TrySendError::Full(mail)) => warn!("Mail queue is full, dropping mail to {}", mail.to),
+        Err(TrySendError::Disconnected(mail)) => error!("Mail sender is gone, dropping mail to {}", mail.to),
+    }
+}
+
+/// Base of the links put into mails, overridable with `AUTH_PUBLIC_URL`.
+pub fn public_url() -> String {
+    let url = env::var("AUTH_PUBLIC_URL").unwrap_or_else(|_| "http://localhost:8081".into());
+    url.trim_end_matches('/').to_owned()
+}
+
+fn message_id() -> String {
+    format!("{:032x}@auth-server", rand::random::<u128>())
+}
+
+fn format_message(from: &str, mail: &Mail, id: &str) -> String {
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+        from,
+        mail.to,
+        mail.subject,
+        date(SystemTime::now()),
+        id,
+        mail.body.replace('\n', "\r\n"),
+    )
+}
+
+/// Formats `time` as an RFC 5322 date in UTC.
+fn date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    let (year, month, day) = civil_date(days);
+
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
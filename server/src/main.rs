@@ -1,10 +1,87 @@
-mod auth;
-mod cache;
-mod ratelimit;
-mod web;
+use auth_server::{auth, backup, health, openapi, shutdown, transfer, web, webhook};
+use std::io::Write;
 
 fn main() {
+    match std::env::args().nth(1).as_deref() {
+        // Used to regenerate `openapi.json`, see the README.
+        Some("--print-openapi") => {
+            println!("{:#}", openapi::document());
+            return;
+        }
+        // Used by container healthchecks.
+        Some("--healthcheck") => match health::probe(&web::listen_addr()) {
+            Ok(()) => return,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        // Offline account export and import, see the README.
+        Some(command @ "--export") | Some(command @ "--import") => {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            let result = if command == "--export" {
+                transfer::export(&args)
+            } else {
+                transfer::import(&args)
+            };
+            if let Err(err) = result {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        // Snapshots can be taken while the server runs, restores only while
+        // it is stopped.
+        Some("--backup") => match backup::create() {
+            Ok(info) => {
+                println!("{}", backup::dir().join(info.name).display());
+                return;
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        Some("--restore") => {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            if let Err(err) = backup::restore(&args) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
     env_logger::init();
     auth::init_db().expect("Failed to initialize database");
-    web::start();
+    // Released when the process exits.
+    let _lock = backup::lock_database()
+        .map_err(|err| log::warn!("Could not lock the database, --restore won't notice this server: {}", err))
+        .ok();
+    shutdown::install().expect("Failed to install signal handlers");
+    auth::start_email_purger();
+    webhook::start_worker();
+    backup::start_scheduler();
+    match auth::load_tokens() {
+        Ok(0) => {}
+        Ok(n) => log::info!("Restored {} tokens from the previous run", n),
+        Err(err) => log::warn!("Could not restore saved tokens: {}", err),
+    }
+
+    let drained = web::start();
+
+    match auth::save_tokens() {
+        Ok(0) => {}
+        Ok(n) => log::info!("Saved {} outstanding tokens", n),
+        Err(err) => log::warn!("Could not save outstanding tokens: {}", err),
+    }
+    if let Err(err) = auth::close_db() {
+        log::warn!("Could not close the database cleanly: {}", err);
+    }
+    log::info!("Shutdown complete");
+    log::logger().flush();
+    let _ = std::io::stdout().flush();
+
+    std::process::exit(if drained { 0 } else { shutdown::EXIT_DRAIN_TIMEOUT });
 }
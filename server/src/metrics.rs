@@ -0,0 +1,286 @@
+//! Prometheus metrics in the text exposition format, served at `/metrics`.
+//!
+//! Metric names are part of the public interface of the server; dashboards and
+//! alerts depend on them, so rename nothing here without a good reason.
+
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets for request and query latency, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets for argon2 hashing, which is deliberately slow.
+const HASH_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: CounterVec = CounterVec::new(
+        "auth_http_requests_total",
+        "HTTP requests handled, by route pattern, method and status code.",
+        &["route", "method", "status"],
+    );
+    pub static ref HTTP_DURATION: HistogramVec = HistogramVec::new(
+        "auth_http_request_duration_seconds",
+        "Time spent handling HTTP requests, by route pattern, method and status code.",
+        &["route", "method", "status"],
+        LATENCY_BUCKETS,
+    );
+    pub static ref TOKENS_ISSUED: CounterVec = CounterVec::new(
+        "auth_tokens_issued_total",
+        "One-time game server tokens handed out.",
+        &[],
+    );
+    pub static ref TOKENS_VERIFIED: CounterVec = CounterVec::new(
+        "auth_tokens_verified_total",
+        "Token verifications, by result.",
+        &["result"],
+    );
+    pub static ref TOKENS_EXPIRED: CounterVec = CounterVec::new(
+        "auth_tokens_expired_total",
+        "Tokens dropped by the cache cleaner without being verified.",
+        &[],
+    );
+    pub static ref RATELIMIT_REJECTIONS: CounterVec = CounterVec::new(
+        "auth_ratelimit_rejections_total",
+        "Requests rejected by the rate limiter, by route pattern.",
+        &["route"],
+    );
+    pub static ref HASH_POOL_REJECTIONS: CounterVec = CounterVec::new(
+        "auth_hash_pool_rejections_total",
+        "Requests turned away with 503 because the argon2 queue was full.",
+        &[],
+    );
+    pub static ref OVERLOAD_REJECTIONS: CounterVec = CounterVec::new(
+        "auth_overload_rejections_total",
+        "Requests turned away with 503 because every handler thread was busy.",
+        &[],
+    );
+    pub static ref PASSWORD_HASH_DURATION: HistogramVec = HistogramVec::new(
+        "auth_password_hash_duration_seconds",
+        "Time spent in argon2, by operation.",
+        &["op"],
+        HASH_BUCKETS,
+    );
+    pub static ref DB_QUERY_DURATION: HistogramVec = HistogramVec::new(
+        "auth_db_query_duration_seconds",
+        "Time spent executing SQL statements, by statement kind.",
+        &["statement"],
+        LATENCY_BUCKETS,
+    );
+}
+
+/// A counter family keyed by label values.
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    pub fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        self.inc_by(labels, 1);
+    }
+
+    pub fn inc_by(&self, labels: &[&str], n: u64) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        *self
+            .values
+            .lock()
+            .expect("Metrics have been poisoned. Panicking to restart.")
+            .entry(key)
+            .or_default() += n;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        let values = self
+            .values
+            .lock()
+            .expect("Metrics have been poisoned. Panicking to restart.");
+        if self.labels.is_empty() && values.is_empty() {
+            let _ = writeln!(out, "{} 0", self.name);
+        }
+        for (key, value) in values.iter() {
+            let _ = writeln!(out, "{}{} {}", self.name, label_set(self.labels, key, None), value);
+        }
+    }
+}
+
+#[derive(Default)]
+struct HistogramData {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A histogram family keyed by label values.
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, HistogramData>>,
+}
+
+impl HistogramVec {
+    pub fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        buckets: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, labels: &[&str], duration: Duration) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        let secs = duration.as_secs_f64();
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        let mut values = self
+            .values
+            .lock()
+            .expect("Metrics have been poisoned. Panicking to restart.");
+        let data = values.entry(key).or_default();
+        data.buckets.resize(self.buckets.len(), 0);
+        for (bound, count) in self.buckets.iter().zip(data.buckets.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        data.sum += secs;
+        data.count += 1;
+    }
+
+    /// Runs `f` and records how long it took.
+    pub fn time<T>(&self, labels: &[&str], f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.observe(labels, start.elapsed());
+        result
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        let values = self
+            .values
+            .lock()
+            .expect("Metrics have been poisoned. Panicking to restart.");
+        for (key, data) in values.iter() {
+            for (bound, count) in self.buckets.iter().zip(data.buckets.iter()) {
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    self.name,
+                    label_set(self.labels, key, Some(&le)),
+                    count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                label_set(self.labels, key, Some("+Inf")),
+                data.count
+            );
+            let labels = label_set(self.labels, key, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, labels, data.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, labels, data.count);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn label_set(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Records the latency of a statement, installed as the profiler of every connection.
+pub fn observe_query(sql: &str, duration: Duration) {
+    let statement = match sql.split_whitespace().next() {
+        Some(word) => word.to_ascii_uppercase(),
+        None => return,
+    };
+    let statement = match statement.as_str() {
+        "SELECT" | "INSERT" | "UPDATE" | "DELETE" | "CREATE" | "PRAGMA" => statement,
+        _ => "OTHER".into(),
+    };
+    DB_QUERY_DURATION.observe(&[&statement], duration);
+}
+
+/// Renders every metric. Gauges are sampled by the caller at scrape time.
+pub fn render(token_cache_size: usize, tracked_ips: usize) -> String {
+    let mut out = String::new();
+    HTTP_REQUESTS.render(&mut out);
+    HTTP_DURATION.render(&mut out);
+    TOKENS_ISSUED.render(&mut out);
+    TOKENS_VERIFIED.render(&mut out);
+    TOKENS_EXPIRED.render(&mut out);
+    RATELIMIT_REJECTIONS.render(&mut out);
+    HASH_POOL_REJECTIONS.render(&mut out);
+    OVERLOAD_REJECTIONS.render(&mut out);
+    PASSWORD_HASH_DURATION.render(&mut out);
+    DB_QUERY_DURATION.render(&mut out);
+    gauge(
+        &mut out,
+        "auth_token_cache_size",
+        "Tokens currently waiting to be verified.",
+        token_cache_size,
+    );
+    gauge(
+        &mut out,
+        "auth_ratelimit_tracked_ips",
+        "Addresses currently tracked by the rate limiter.",
+        tracked_ips,
+    );
+    out
+}
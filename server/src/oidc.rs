@@ -0,0 +1,715 @@
+//! OpenID Connect provider mode, so websites can offer "log in with Domeland
+//! account". The authorization code flow with PKCE (S256) and the device
+//! authorization grant (see `device`) are supported.
+//!
+//! ID tokens are ES256 JWTs signed with a key that is created on first use,
+//! kept in the database and published at `/oauth/jwks`. Access tokens are
+//! opaque and only good for the userinfo endpoint.
+
+use crate::auth::{self, AuthError};
+use crate::device;
+use crate::encoding::{base64url, escape_html, hex, percent_encode};
+use crate::mail;
+use auth_common::{DeviceAuthorizationResponse, OAuthClientResponse, OAuthTokenResponse, UserInfoClaims};
+use lazy_static::lazy_static;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rusqlite::{params, OptionalExtension};
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// How long an authorization code may wait to be exchanged.
+const CODE_TTL_SECS: i64 = 5 * 60;
+/// How long access and ID tokens are valid.
+const TOKEN_TTL_SECS: i64 = 60 * 60;
+
+/// Scopes clients may ask for, with how the consent pages describe them.
+/// `openid` is required, unknown scopes are ignored.
+pub const SCOPES: &[(&str, &str)] = &[
+    ("openid", "Your account id"),
+    ("profile", "Your username"),
+    ("ethaddr", "Your ethereum address"),
+];
+
+lazy_static! {
+    static ref RNG: SystemRandom = SystemRandom::new();
+    static ref SIGNING_KEY: Mutex<Option<Arc<SigningKey>>> = Mutex::new(None);
+}
+
+/// Errors of the token and userinfo endpoints, reported as RFC 6749 error codes.
+#[derive(Debug)]
+pub enum OAuthError {
+    InvalidRequest(String),
+    InvalidClient,
+    InvalidGrant,
+    UnsupportedGrantType,
+    InvalidScope,
+    InvalidToken,
+    /// The device flow is still waiting for the user.
+    AuthorizationPending,
+    /// The device flow is polled faster than its interval.
+    SlowDown,
+    AccessDenied,
+    ExpiredToken,
+    Server(AuthError),
+}
+
+impl OAuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::InvalidClient => "invalid_client",
+            Self::InvalidGrant => "invalid_grant",
+            Self::UnsupportedGrantType => "unsupported_grant_type",
+            Self::InvalidScope => "invalid_scope",
+            Self::InvalidToken => "invalid_token",
+            Self::AuthorizationPending => "authorization_pending",
+            Self::SlowDown => "slow_down",
+            Self::AccessDenied => "access_denied",
+            Self::ExpiredToken => "expired_token",
+            Self::Server(_) => "server_error",
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::InvalidClient | Self::InvalidToken => 401,
+            Self::Server(err) => err.status_code(),
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRequest(reason) => write!(f, "{}", reason),
+            Self::InvalidClient => write!(f, "Client authentication failed."),
+            Self::InvalidGrant => write!(f, "The authorization code is invalid, expired or was issued to another client."),
+            Self::UnsupportedGrantType => write!(f, "Only the authorization_code and device_code grants are supported."),
+            Self::InvalidScope => write!(f, "The openid scope is required."),
+            Self::InvalidToken => write!(f, "The access token is invalid or expired."),
+            Self::AuthorizationPending => write!(f, "The user has not approved the device yet."),
+            Self::SlowDown => write!(f, "Polling too fast, wait longer between requests."),
+            Self::AccessDenied => write!(f, "The user denied access."),
+            Self::ExpiredToken => write!(f, "The device code has expired."),
+            Self::Server(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<AuthError> for OAuthError {
+    fn from(err: AuthError) -> Self {
+        Self::Server(err)
+    }
+}
+
+impl From<rusqlite::Error> for OAuthError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Server(err.into())
+    }
+}
+
+/// The issuer identifier, which is the public address of the server.
+pub(crate) fn issuer() -> String {
+    mail::public_url()
+}
+
+pub(crate) fn random_token(bytes: usize) -> Result<String, AuthError> {
+    let mut buf = vec![0; bytes];
+    RNG.fill(&mut buf).map_err(|_| AuthError::Crypto)?;
+    Ok(base64url(&buf))
+}
+
+pub(crate) fn sha256_hex(data: &str) -> String {
+    hex(digest(&SHA256, data.as_bytes()).as_ref())
+}
+
+/// Redirect URIs must be absolute, without a fragment, and use https unless
+/// they point at the local machine.
+fn valid_redirect_uri(uri: &str) -> bool {
+    let loopback = ["http://localhost", "http://127.0.0.1", "http://[::1]"]
+        .iter()
+        .any(|prefix| {
+            uri.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(':') || rest.starts_with('/'))
+        });
+    (uri.starts_with("https://") && uri.len() > "https://".len() || loopback)
+        && !uri.contains('#')
+        && !uri.chars().any(char::is_whitespace)
+}
+
+/// A registered client application.
+pub struct Client {
+    pub id: String,
+    pub name: String,
+    secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+}
+
+impl Client {
+    fn response(&self, secret: Option<String>) -> OAuthClientResponse {
+        OAuthClientResponse {
+            client_id: self.id.clone(),
+            client_secret: secret,
+            name: self.name.clone(),
+            redirect_uris: self.redirect_uris.clone(),
+            confidential: self.secret_hash.is_some(),
+        }
+    }
+}
+
+fn row_to_client(row: &rusqlite::Row) -> rusqlite::Result<Client> {
+    let redirect_uris: String = row.get(3)?;
+    Ok(Client {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        secret_hash: row.get(2)?,
+        redirect_uris: redirect_uris.lines().map(str::to_owned).collect(),
+    })
+}
+
+pub fn client(id: &str) -> Result<Option<Client>, AuthError> {
+    let client = auth::db()?
+        .query_row(
+            "SELECT client_id, name, secret_hash, redirect_uris FROM oauth_clients WHERE client_id == ?1",
+            params![id],
+            row_to_client,
+        )
+        .optional()?;
+    Ok(client)
+}
+
+/// Registers a client application. The secret of a confidential client is
+/// returned here once and only its digest is kept.
+pub fn register_client(name: &str, redirect_uris: &[String], confidential: bool) -> Result<OAuthClientResponse, AuthError> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 64 {
+        return Err(AuthError::InvalidRequest("The client name must be between 1 and 64 characters.".into()));
+    }
+    if redirect_uris.is_empty() {
+        return Err(AuthError::InvalidRequest("At least one redirect URI is required.".into()));
+    }
+    if let Some(uri) = redirect_uris.iter().find(|uri| !valid_redirect_uri(uri)) {
+        return Err(AuthError::InvalidRequest(format!(
+            "Invalid redirect URI '{}'. Use https, or http on localhost, and no fragment.",
+            uri
+        )));
+    }
+
+    let client = Client {
+        id: hex(&rand::random::<u128>().to_le_bytes()),
+        name: name.to_owned(),
+        secret_hash: None,
+        redirect_uris: redirect_uris.to_vec(),
+    };
+    let secret = if confidential { Some(random_token(32)?) } else { None };
+    auth::db()?.execute(
+        "INSERT INTO oauth_clients (client_id, name, secret_hash, redirect_uris, created) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            client.id,
+            client.name,
+            secret.as_deref().map(sha256_hex),
+            client.redirect_uris.join("\n"),
+            auth::now_secs()
+        ],
+    )?;
+    Ok(client.response(secret))
+}
+
+pub fn list_clients() -> Result<Vec<OAuthClientResponse>, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare("SELECT client_id, name, secret_hash, redirect_uris FROM oauth_clients ORDER BY created")?;
+    let clients = stmt
+        .query_map(params![], row_to_client)?
+        .filter_map(|s| s.ok())
+        .map(|client| client.response(None))
+        .collect();
+    Ok(clients)
+}
+
+/// Removes a client along with its outstanding codes and access tokens.
+pub fn delete_client(id: &str) -> Result<(), AuthError> {
+    let mut db = auth::db()?;
+    let tx = db.transaction()?;
+    let deleted = tx.execute("DELETE FROM oauth_clients WHERE client_id == ?1", params![id])?;
+    tx.execute("DELETE FROM oauth_codes WHERE client_id == ?1", params![id])?;
+    tx.execute("DELETE FROM oauth_tokens WHERE client_id == ?1", params![id])?;
+    tx.execute("DELETE FROM device_codes WHERE client_id == ?1", params![id])?;
+    tx.commit()?;
+    if deleted == 0 {
+        return Err(AuthError::ClientDoesNotExist);
+    }
+    Ok(())
+}
+
+/// A validated authorization request, waiting for the user's consent.
+pub struct AuthorizationRequest {
+    pub client: Client,
+    pub redirect_uri: String,
+    pub scope: Vec<&'static str>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+}
+
+/// Why an authorization request can't go ahead. Until the client and its
+/// redirect URI check out, the error is shown to the user, afterwards it is
+/// sent back to the client.
+pub enum AuthorizeError {
+    Show(AuthError),
+    Redirect(String),
+}
+
+impl From<AuthError> for AuthorizeError {
+    fn from(err: AuthError) -> Self {
+        Self::Show(err)
+    }
+}
+
+/// Appends `pairs` to the query of `uri`.
+fn with_query(uri: &str, pairs: &[(&str, &str)]) -> String {
+    let query: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, percent_encode(value)))
+        .collect();
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", uri, separator, query.join("&"))
+}
+
+/// Validates the parameters of an authorization request, read through `param`
+/// from either the query string or the submitted consent form.
+pub fn authorization_request(param: impl Fn(&str) -> Option<String>) -> Result<AuthorizationRequest, AuthorizeError> {
+    let client_id = param("client_id").unwrap_or_default();
+    let client = client(&client_id)?.ok_or(AuthError::ClientDoesNotExist)?;
+    let redirect_uri = match param("redirect_uri") {
+        Some(uri) if client.redirect_uris.contains(&uri) => uri,
+        None if client.redirect_uris.len() == 1 => client.redirect_uris[0].clone(),
+        _ => {
+            return Err(AuthorizeError::Show(AuthError::InvalidRequest(
+                "The redirect URI is not registered for this client.".into(),
+            )))
+        }
+    };
+
+    let state = param("state");
+    let fail = |error: &str, description: &str| {
+        let mut pairs = vec![("error", error), ("error_description", description)];
+        if let Some(state) = &state {
+            pairs.push(("state", state));
+        }
+        Err(AuthorizeError::Redirect(with_query(&redirect_uri, &pairs)))
+    };
+    if param("response_type").as_deref() != Some("code") {
+        return fail("unsupported_response_type", "Only the code response type is supported.");
+    }
+    let requested = param("scope").unwrap_or_default();
+    let scope = parse_scope(&requested);
+    if !scope.contains(&"openid") {
+        return fail("invalid_scope", "The openid scope is required.");
+    }
+    let code_challenge = param("code_challenge").unwrap_or_default();
+    let valid_challenge = code_challenge.len() == 43
+        && code_challenge
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_challenge || param("code_challenge_method").as_deref() != Some("S256") {
+        return fail("invalid_request", "PKCE with the S256 method is required.");
+    }
+
+    Ok(AuthorizationRequest {
+        client,
+        redirect_uri,
+        scope,
+        nonce: param("nonce").filter(|nonce| !nonce.is_empty()),
+        state,
+        code_challenge,
+    })
+}
+
+impl AuthorizationRequest {
+    /// Where to send the user if they decline.
+    pub fn denied(&self) -> String {
+        let mut pairs = vec![("error", "access_denied")];
+        if let Some(state) = &self.state {
+            pairs.push(("state", state));
+        }
+        with_query(&self.redirect_uri, &pairs)
+    }
+
+    /// Stores a single-use code for `uuid` and returns where to send the user with it.
+    pub fn approve(&self, uuid: &Uuid) -> Result<String, AuthError> {
+        let code = random_token(32)?;
+        let now = auth::now_secs();
+        let db = auth::db()?;
+        db.execute("DELETE FROM oauth_codes WHERE expires < ?1", params![now])?;
+        db.execute(
+            "INSERT INTO oauth_codes (code, client_id, uuid, redirect_uri, scope, nonce, code_challenge, auth_time, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                code,
+                self.client.id,
+                uuid.to_simple().to_string(),
+                self.redirect_uri,
+                self.scope.join(" "),
+                self.nonce,
+                self.code_challenge,
+                now,
+                now + CODE_TTL_SECS
+            ],
+        )?;
+        let mut pairs = vec![("code", code.as_str())];
+        if let Some(state) = &self.state {
+            pairs.push(("state", state));
+        }
+        Ok(with_query(&self.redirect_uri, &pairs))
+    }
+
+    /// The login and consent form. It posts every parameter of the request
+    /// back, so nothing has to be kept on the server in between.
+    pub fn consent_page(&self, error: Option<&str>) -> String {
+        let scopes: String = SCOPES
+            .iter()
+            .filter(|(name, _)| self.scope.contains(name))
+            .map(|(_, description)| format!("<li>{}</li>", description))
+            .collect();
+        let mut hidden = vec![
+            ("client_id", self.client.id.as_str()),
+            ("redirect_uri", &self.redirect_uri),
+            ("response_type", "code"),
+            ("code_challenge", &self.code_challenge),
+            ("code_challenge_method", "S256"),
+        ];
+        let scope = self.scope.join(" ");
+        hidden.push(("scope", &scope));
+        if let Some(state) = &self.state {
+            hidden.push(("state", state));
+        }
+        if let Some(nonce) = &self.nonce {
+            hidden.push(("nonce", nonce));
+        }
+        let hidden: String = hidden
+            .iter()
+            .map(|(name, value)| format!(r#"<input type="hidden" name="{}" value="{}">"#, name, escape_html(value)))
+            .collect();
+        let error = error
+            .map(|error| format!(r#"<p class="error">{}</p>"#, escape_html(error)))
+            .unwrap_or_default();
+
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width"><title>Sign in to {name}</title></head>
+<body>
+<h1>Sign in to {name}</h1>
+<p><strong>{name}</strong> would like to know:</p>
+<ul>{scopes}</ul>
+{error}
+<form method="post" action="/oauth/authorize">
+{hidden}
+<p><label>Username <input name="username" autocomplete="username" required></label></p>
+<p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+<p><label>Authenticator code <input name="otp" autocomplete="one-time-code"></label> (if enabled)</p>
+<p><button name="decision" value="allow">Allow</button> <button name="decision" value="deny" formnovalidate>Deny</button></p>
+</form>
+</body>
+</html>
+"#,
+            name = escape_html(&self.client.name),
+            scopes = scopes,
+            error = error,
+            hidden = hidden,
+        )
+    }
+}
+
+/// Checks how the client authenticated at the token endpoint. Public
+/// clients send only their id, confidential ones must add their secret.
+fn authenticate_client(client_id: Option<&str>, secret: Option<&str>) -> Result<Client, OAuthError> {
+    let client = client(client_id.unwrap_or_default())?.ok_or(OAuthError::InvalidClient)?;
+    match (&client.secret_hash, secret) {
+        (None, _) => Ok(client),
+        (Some(expected), Some(secret)) if verify_slices_are_equal(expected.as_bytes(), sha256_hex(secret).as_bytes()).is_ok() => {
+            Ok(client)
+        }
+        _ => Err(OAuthError::InvalidClient),
+    }
+}
+
+/// Picks the requested scopes this server knows, in a stable order.
+pub fn parse_scope(requested: &str) -> Vec<&'static str> {
+    SCOPES
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| requested.split_whitespace().any(|s| s == *name))
+        .collect()
+}
+
+/// Answers the token endpoint. `form` holds the request parameters and
+/// `basic` the HTTP Basic credentials, if any.
+pub fn token(form: &[(String, String)], basic: Option<(String, String)>) -> Result<OAuthTokenResponse, OAuthError> {
+    let field = |name: &str| form.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+    let client = match &basic {
+        Some((id, secret)) => authenticate_client(Some(id), Some(secret))?,
+        None => authenticate_client(field("client_id"), field("client_secret"))?,
+    };
+    match field("grant_type") {
+        Some("authorization_code") => exchange_code(&client, field),
+        Some(device::GRANT_TYPE) => {
+            let device_code = field("device_code")
+                .ok_or_else(|| OAuthError::InvalidRequest("The device_code is missing.".into()))?;
+            let grant = device::poll(device_code, Some(&client.id))?;
+            let scope: Vec<&str> = grant.scope.split_whitespace().collect();
+            issue_tokens(&client, &grant.uuid, &scope, None, grant.auth_time)
+        }
+        _ => Err(OAuthError::UnsupportedGrantType),
+    }
+}
+
+/// Starts a device authorization for the client at the device authorization
+/// endpoint. The client authenticates the same way as at the token endpoint.
+pub fn device_authorization(
+    form: &[(String, String)],
+    basic: Option<(String, String)>,
+) -> Result<DeviceAuthorizationResponse, OAuthError> {
+    let field = |name: &str| form.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+    let client = match &basic {
+        Some((id, secret)) => authenticate_client(Some(id), Some(secret))?,
+        None => authenticate_client(field("client_id"), field("client_secret"))?,
+    };
+    let scope = parse_scope(field("scope").unwrap_or_default());
+    if !scope.contains(&"openid") {
+        return Err(OAuthError::InvalidScope);
+    }
+    Ok(device::start(Some(&client.id), &scope)?)
+}
+
+/// Redeems an authorization code.
+fn exchange_code<'a>(client: &Client, field: impl Fn(&str) -> Option<&'a str>) -> Result<OAuthTokenResponse, OAuthError> {
+    let code = field("code").ok_or_else(|| OAuthError::InvalidRequest("The code is missing.".into()))?;
+    let verifier = field("code_verifier").unwrap_or_default();
+    if !(43..=128).contains(&verifier.len()) {
+        return Err(OAuthError::InvalidRequest("A code_verifier of 43 to 128 characters is required.".into()));
+    }
+
+    let mut db = auth::db()?;
+    let tx = db.transaction()?;
+    let grant = tx
+        .query_row(
+            "SELECT client_id, uuid, redirect_uri, scope, nonce, code_challenge, auth_time, expires
+             FROM oauth_codes WHERE code == ?1",
+            params![code],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, String>(5)?,
+                    row.get::<_, i64>(6)?,
+                    row.get::<_, i64>(7)?,
+                ))
+            },
+        )
+        .optional()?;
+    // Codes are single use, whatever happens next.
+    tx.execute("DELETE FROM oauth_codes WHERE code == ?1", params![code])?;
+    tx.commit()?;
+
+    let (client_id, uuid, redirect_uri, scope, nonce, challenge, auth_time, expires) =
+        grant.ok_or(OAuthError::InvalidGrant)?;
+    let challenge_matches = base64url(digest(&SHA256, verifier.as_bytes()).as_ref()) == challenge;
+    if client_id != client.id
+        || field("redirect_uri").is_some_and(|uri| uri != redirect_uri)
+        || expires < auth::now_secs()
+        || !challenge_matches
+    {
+        return Err(OAuthError::InvalidGrant);
+    }
+    let uuid = Uuid::parse_str(&uuid).map_err(|_| OAuthError::InvalidGrant)?;
+    let scope: Vec<&str> = scope.split_whitespace().collect();
+    issue_tokens(client, &uuid, &scope, nonce.as_deref(), auth_time)
+}
+
+/// Issues an access token and an ID token for a completed grant.
+fn issue_tokens(
+    client: &Client,
+    uuid: &Uuid,
+    scope: &[&str],
+    nonce: Option<&str>,
+    auth_time: i64,
+) -> Result<OAuthTokenResponse, OAuthError> {
+    let access_token = random_token(32)?;
+    let db = auth::db()?;
+    db.execute("DELETE FROM oauth_tokens WHERE expires < ?1", params![auth::now_secs()])?;
+    db.execute(
+        "INSERT INTO oauth_tokens (token_hash, client_id, uuid, scope, expires) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            sha256_hex(&access_token),
+            client.id,
+            uuid.to_simple().to_string(),
+            scope.join(" "),
+            auth::now_secs() + TOKEN_TTL_SECS
+        ],
+    )?;
+    let id_token = id_token(&client.id, uuid, scope, nonce, auth_time)?;
+
+    Ok(OAuthTokenResponse {
+        access_token,
+        token_type: "Bearer".into(),
+        expires_in: TOKEN_TTL_SECS as u64,
+        id_token,
+        scope: scope.join(" "),
+    })
+}
+
+/// The claims `scope` allows about the account.
+fn claims(uuid: &Uuid, scope: &[&str]) -> Result<UserInfoClaims, AuthError> {
+    let info = auth::uuid_to_info(uuid)?;
+    let profile = scope.contains(&"profile");
+    Ok(UserInfoClaims {
+        sub: info.uuid.to_string(),
+        uuid: Some(info.uuid),
+        username: Some(info.username.clone()).filter(|_| profile),
+        preferred_username: Some(info.username).filter(|_| profile),
+        ethaddr: Some(info.ethaddr).filter(|_| scope.contains(&"ethaddr")),
+    })
+}
+
+/// Answers the userinfo endpoint for a bearer access token.
+pub fn userinfo(access_token: &str) -> Result<UserInfoClaims, OAuthError> {
+    let grant = auth::db()?
+        .query_row(
+            "SELECT uuid, scope FROM oauth_tokens WHERE token_hash == ?1 AND expires >= ?2",
+            params![sha256_hex(access_token), auth::now_secs()],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+    let (uuid, scope) = grant.ok_or(OAuthError::InvalidToken)?;
+    let uuid = Uuid::parse_str(&uuid).map_err(|_| OAuthError::InvalidToken)?;
+    let scope: Vec<&str> = scope.split_whitespace().collect();
+    match claims(&uuid, &scope) {
+        Ok(_) if auth::is_banned(&uuid)? => Err(OAuthError::InvalidToken),
+        Ok(claims) => Ok(claims),
+        // The account is gone.
+        Err(AuthError::UserDoesNotExist) => Err(OAuthError::InvalidToken),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn id_token(client_id: &str, uuid: &Uuid, scope: &[&str], nonce: Option<&str>, auth_time: i64) -> Result<String, AuthError> {
+    let now = auth::now_secs();
+    let mut token = serde_json::to_value(claims(uuid, scope)?)?;
+    let fields = token.as_object_mut().expect("claims are an object");
+    fields.insert("iss".into(), issuer().into());
+    fields.insert("aud".into(), client_id.into());
+    fields.insert("iat".into(), now.into());
+    fields.insert("exp".into(), (now + TOKEN_TTL_SECS).into());
+    fields.insert("auth_time".into(), auth_time.into());
+    if let Some(nonce) = nonce {
+        fields.insert("nonce".into(), nonce.into());
+    }
+    sign_jwt(&token)
+}
+
+struct SigningKey {
+    kid: String,
+    pair: EcdsaKeyPair,
+}
+
+impl SigningKey {
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, AuthError> {
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8).map_err(|_| AuthError::Crypto)?;
+        let kid = hex(&digest(&SHA256, pair.public_key().as_ref()).as_ref()[..8]);
+        Ok(Self { kid, pair })
+    }
+}
+
+/// Loads the newest signing key, creating one on first use.
+fn signing_key() -> Result<Arc<SigningKey>, AuthError> {
+    let mut cached = SIGNING_KEY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(key) = &*cached {
+        return Ok(key.clone());
+    }
+    let db = auth::db()?;
+    let stored: Option<Vec<u8>> = db
+        .query_row(
+            "SELECT pkcs8 FROM signing_keys ORDER BY created DESC LIMIT 1",
+            params![],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let key = match stored {
+        Some(pkcs8) => SigningKey::from_pkcs8(&pkcs8)?,
+        None => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &*RNG)
+                .map_err(|_| AuthError::Crypto)?;
+            let key = SigningKey::from_pkcs8(pkcs8.as_ref())?;
+            db.execute(
+                "INSERT INTO signing_keys (kid, pkcs8, created) VALUES (?1, ?2, ?3)",
+                params![key.kid, pkcs8.as_ref(), auth::now_secs()],
+            )?;
+            log::info!("Created OpenID Connect signing key {}", key.kid);
+            key
+        }
+    };
+    let key = Arc::new(key);
+    *cached = Some(key.clone());
+    Ok(key)
+}
+
+fn sign_jwt(claims: &Value) -> Result<String, AuthError> {
+    let key = signing_key()?;
+    let header = json!({ "alg": "ES256", "typ": "JWT", "kid": key.kid });
+    let input = format!(
+        "{}.{}",
+        base64url(header.to_string().as_bytes()),
+        base64url(claims.to_string().as_bytes())
+    );
+    let signature = key.pair.sign(&*RNG, input.as_bytes()).map_err(|_| AuthError::Crypto)?;
+    Ok(format!("{}.{}", input, base64url(signature.as_ref())))
+}
+
+/// The JSON Web Key Set relying parties verify ID tokens with.
+pub fn jwks() -> Result<Value, AuthError> {
+    let key = signing_key()?;
+    // An uncompressed P-256 point: 0x04, then 32 bytes each of x and y.
+    let point = key.pair.public_key().as_ref();
+    Ok(json!({
+        "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "x": base64url(&point[1..33]),
+            "y": base64url(&point[33..65]),
+            "kid": key.kid,
+            "use": "sig",
+            "alg": "ES256",
+        }]
+    }))
+}
+
+/// The OpenID Connect discovery document.
+pub fn discovery() -> Value {
+    let issuer = issuer();
+    json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+        "jwks_uri": format!("{}/oauth/jwks", issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", device::GRANT_TYPE],
+        "device_authorization_endpoint": format!("{}/oauth/device_authorization", issuer),
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["ES256"],
+        "scopes_supported": SCOPES.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported": ["sub", "uuid", "username", "preferred_username", "ethaddr", "iss", "aud", "exp", "iat", "auth_time", "nonce"],
+    })
+}
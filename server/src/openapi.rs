@@ -0,0 +1,219 @@
+use crate::web::{Api, Reply, Route, ROUTES};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use serde_json::{json, Map, Value};
+
+fn text(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" } } },
+    })
+}
+
+fn path_parameters(route: &Route) -> Vec<Value> {
+    route
+        .path
+        .split('/')
+        .filter(|s| s.starts_with('{') && s.ends_with('}'))
+        .map(|s| {
+            let name = &s[1..s.len() - 1];
+            let schema = match name {
+                "uuid" => json!({ "type": "string", "format": "uuid" }),
+                _ => json!({ "type": "string" }),
+            };
+            json!({ "name": name, "in": "path", "required": true, "schema": schema })
+        })
+        .collect()
+}
+
+fn operation(route: &Route, gen: &mut SchemaGenerator) -> Value {
+    let mut op = Map::new();
+    op.insert("summary".into(), route.summary.into());
+    op.insert(
+        "tags".into(),
+        json!([match route.api {
+            Api::Legacy => "legacy",
+            Api::V1 => "v1",
+            Api::Ops => "ops",
+            Api::Admin => "admin",
+            Api::OAuth => "oauth",
+            Api::Events => "events",
+        }]),
+    );
+    match route.api {
+        Api::Admin => {
+            op.insert("security".into(), json!([{ "adminToken": [] }]));
+        }
+        Api::Events => {
+            op.insert("security".into(), json!([{ "eventToken": [] }, { "adminToken": [] }]));
+        }
+        _ => {}
+    }
+
+    let mut parameters = path_parameters(route);
+    parameters.extend(route.query.iter().map(|q| {
+        json!({
+            "name": q.name,
+            "in": "query",
+            "required": q.required,
+            "description": q.description,
+            "schema": { "type": "string" },
+        })
+    }));
+    if !parameters.is_empty() {
+        op.insert("parameters".into(), parameters.into());
+    }
+
+    if let Some(body) = route.body {
+        op.insert(
+            "requestBody".into(),
+            json!({
+                "required": true,
+                "content": { "application/json": { "schema": body(gen) } },
+            }),
+        );
+    }
+
+    if !route.form.is_empty() {
+        let properties: Map<String, Value> = route
+            .form
+            .iter()
+            .map(|f| {
+                let mut property = json!({ "type": "string" });
+                if !f.description.is_empty() {
+                    property["description"] = f.description.into();
+                }
+                (f.name.to_owned(), property)
+            })
+            .collect();
+        let required: Vec<&str> = route.form.iter().filter(|f| f.required).map(|f| f.name).collect();
+        op.insert(
+            "requestBody".into(),
+            json!({
+                "required": true,
+                "content": {
+                    "application/x-www-form-urlencoded": {
+                        "schema": { "type": "object", "properties": properties, "required": required },
+                    },
+                },
+            }),
+        );
+    }
+
+    let mut responses = Map::new();
+    match route.reply {
+        Reply::Json(schema) => {
+            responses.insert(
+                "200".into(),
+                json!({
+                    "description": "Success.",
+                    "content": { "application/json": { "schema": schema(gen) } },
+                }),
+            );
+        }
+        Reply::Created(schema) => {
+            responses.insert(
+                "201".into(),
+                json!({
+                    "description": "Created.",
+                    "content": { "application/json": { "schema": schema(gen) } },
+                }),
+            );
+        }
+        Reply::Text => {
+            responses.insert("200".into(), text("Success."));
+        }
+        Reply::Html => {
+            responses.insert(
+                "200".into(),
+                json!({
+                    "description": "Success.",
+                    "content": { "text/html": { "schema": { "type": "string" } } },
+                }),
+            );
+        }
+        Reply::EventStream(schema) => {
+            responses.insert(
+                "200".into(),
+                json!({
+                    "description": "Server-sent events. The `data` of each event is JSON of this schema.",
+                    "content": { "text/event-stream": { "schema": schema(gen) } },
+                }),
+            );
+        }
+        Reply::Empty(status) => {
+            responses.insert(status.to_string(), json!({ "description": "Success." }));
+        }
+    }
+    if let Some((status, description)) = route.also {
+        let mut response = json!({ "description": description });
+        if let Reply::Json(schema) = route.reply {
+            response["content"] = json!({ "application/json": { "schema": schema(gen) } });
+        }
+        responses.insert(status.to_string(), response);
+    }
+    if route.body.is_some() || !route.form.is_empty() || route.path.contains('{') || !route.query.is_empty() {
+        responses.insert("400".into(), text("The request was rejected."));
+    }
+    if route.confirmed {
+        responses.insert("401".into(), text("The password of the account is incorrect."));
+    }
+    match route.api {
+        Api::Admin => {
+            responses.insert("401".into(), text("The admin token is missing or wrong."));
+        }
+        Api::Events => {
+            responses.insert("401".into(), text("The event or admin token is missing or wrong."));
+            responses.insert("503".into(), text("Too many streams are open."));
+        }
+        _ => {}
+    }
+    if matches!(route.api, Api::V1 | Api::Admin) && route.path.contains('{') {
+        responses.insert("404".into(), text("The account does not exist."));
+    }
+    if route.limit.is_some() {
+        responses.insert("429".into(), text("Too many requests from this address."));
+    }
+    responses.insert("500".into(), text("Internal server error."));
+    op.insert("responses".into(), responses.into());
+
+    op.into()
+}
+
+/// Builds the OpenAPI 3 description of every route in `ROUTES` along with
+/// the `auth-common` types they exchange.
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+
+    for route in ROUTES {
+        let item = paths
+            .entry(route.path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        let op = operation(route, &mut gen);
+        item.as_object_mut()
+            .expect("path items are always objects")
+            .insert(route.method.to_lowercase(), op);
+    }
+
+    let schemas: Map<String, Value> = gen
+        .take_definitions()
+        .into_iter()
+        .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap_or_default()))
+        .collect();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Domeland auth server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "adminToken": { "type": "http", "scheme": "bearer" },
+                "eventToken": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
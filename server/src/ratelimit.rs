@@ -3,16 +3,18 @@ use std::net::IpAddr;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
-const MAX: usize = 60;
-const TIMEOUT: Duration = Duration::from_secs(60 * 10);
-
 pub struct RateLimiter {
+    /// Requests allowed per address within `window`.
+    max: usize,
+    window: Duration,
     limits: Mutex<HashMap<IpAddr, Vec<Instant>>>,
 }
 
 impl RateLimiter {
-    pub fn new() -> Self {
+    pub fn new(max: usize, window: Duration) -> Self {
         Self {
+            max,
+            window,
             limits: Mutex::new(HashMap::new()),
         }
     }
@@ -30,7 +32,16 @@ impl RateLimiter {
 
         let v = limits.entry(addr).or_default();
         v.push(Instant::now());
-        v.retain(|t| t.elapsed() < TIMEOUT);
-        v.len() <= MAX
+        v.retain(|t| t.elapsed() < self.window);
+        v.len() <= self.max
+    }
+
+    /// Number of addresses with a request history.
+    pub fn tracked_ips(&self) -> usize {
+        self.limits
+            .lock()
+            // Panic and restart if the rate limit map is poisoned which should never happen.
+            .expect("Rate limit map has been poisoned. Panicking to restart.")
+            .len()
     }
 }
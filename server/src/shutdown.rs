@@ -0,0 +1,65 @@
+//! SIGTERM/SIGINT handling and in-flight request tracking.
+//!
+//! The first signal asks the web server to stop accepting connections and
+//! drain; a second one exits immediately.
+
+use lazy_static::lazy_static;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::env;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Drain deadline, overridable with `AUTH_SHUTDOWN_TIMEOUT_SECS`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit status when in-flight requests were still running at the deadline.
+pub const EXIT_DRAIN_TIMEOUT: i32 = 3;
+
+lazy_static! {
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn install() -> io::Result<()> {
+    for &signal in &[SIGTERM, SIGINT] {
+        // Registered first so that it only fires once the flag is already set.
+        signal_hook::flag::register_conditional_shutdown(signal, 1, REQUESTED.clone())?;
+        signal_hook::flag::register(signal, REQUESTED.clone())?;
+    }
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn timeout() -> Duration {
+    env::var("AUTH_SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+/// Marks a request as in flight for as long as it is alive.
+pub struct InFlight(());
+
+impl InFlight {
+    pub fn begin() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlight(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
@@ -0,0 +1,129 @@
+//! RFC 6238 time-based one-time passwords (HMAC-SHA1, 6 digits, 30 second
+//! steps), which is what authenticator apps expect by default.
+
+use crate::encoding::{hex, percent_encode};
+use ring::{digest, hmac};
+use std::env;
+
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many steps before or after the current one are accepted,
+/// to allow for clock drift and slow typing.
+const SKEW_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Number of recovery codes handed out when two-factor authentication is enabled.
+pub const RECOVERY_CODES: usize = 10;
+
+/// A fresh random secret, base32 encoded as authenticator apps expect it.
+pub fn generate_secret() -> String {
+    let secret: Vec<u8> = (0..SECRET_BYTES).map(|_| rand::random()).collect();
+    base32_encode(&secret)
+}
+
+/// The `otpauth://` URI authenticator apps enroll from, usually shown as a QR code.
+pub fn uri(secret: &str, account: &str) -> String {
+    let issuer = issuer();
+    format!(
+        "otpauth://totp/{0}:{1}?secret={2}&issuer={0}&algorithm=SHA1&digits={3}&period={4}",
+        percent_encode(&issuer),
+        percent_encode(account),
+        secret,
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+/// Name shown next to the account in authenticator apps, overridable with `AUTH_TOTP_ISSUER`.
+fn issuer() -> String {
+    env::var("AUTH_TOTP_ISSUER").unwrap_or_else(|_| "Domeland".into())
+}
+
+/// Checks `code` against the steps around `now` that come after `last_step`,
+/// returning the step it matched so it can't be used again.
+pub fn verify(secret: &str, code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    if !is_code(code) {
+        return None;
+    }
+    let code: u32 = code.trim().parse().ok()?;
+    let key = base32_decode(secret)?;
+    let current = now.div_euclid(STEP_SECS);
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|&step| step >= 0 && last_step.is_none_or(|last| step > last))
+        .find(|&step| hotp(&key, step as u64) == code)
+}
+
+/// Whether `code` looks like a one-time password rather than a recovery code.
+pub fn is_code(code: &str) -> bool {
+    let code = code.trim();
+    code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit())
+}
+
+/// RFC 4226 HOTP value for `counter`.
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key), &counter.to_be_bytes());
+    let mac = tag.as_ref();
+    let offset = (mac[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    (value & 0x7fff_ffff) % 10u32.pow(DIGITS)
+}
+
+/// A random recovery code like `k7rq-2m4x-pd9a`, about 60 bits strong.
+pub fn generate_recovery_code() -> String {
+    let chars: Vec<u8> = (0..12)
+        .map(|_| BASE32[rand::random::<usize>() % BASE32.len()].to_ascii_lowercase())
+        .collect();
+    chars
+        .chunks(4)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// What is stored of a recovery code. The codes are random enough that a
+/// plain digest is safe, and it allows looking them up directly.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect();
+    hex(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, normalized.as_bytes()).as_ref())
+}
+
+/// RFC 4648 base32 without padding.
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// Decodes base32, ignoring case, spaces and padding.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32.iter().position(|&b| b as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,606 @@
+//! Offline export and import of accounts, for moving players between
+//! environments and for taking them over from an upstream Veloren auth
+//! database, which has no ethaddr.
+//!
+//! ```text
+//! auth-server --export [--format jsonl|csv] [--no-hashes] [--output FILE]
+//! auth-server --import FILE [--format jsonl|csv|sqlite] [--on-conflict fail|skip|overwrite] [--dry-run]
+//! ```
+//!
+//! Both work on the database at `AUTH_DB_DIR` directly, so run them while
+//! the server is stopped. Imports run in one transaction: they either apply
+//! completely or not at all.
+
+use crate::auth::{self, decapitalize, AuthError};
+use crate::backup;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use uuid::Uuid;
+
+/// Accounts imported without an ethaddr get this prefix and their uuid
+/// instead, since the column is required and unique. It never passes
+/// address validation, and exports turn it back into no address.
+pub(crate) const NO_ETHADDR_PREFIX: &str = "none:";
+
+/// CSV columns, in the order they are exported.
+const COLUMNS: &[&str] = &[
+    "uuid",
+    "username",
+    "ethaddr",
+    "actived",
+    "pwhash",
+    "email",
+    "searchable",
+    "banned",
+    "ban_reason",
+    "totp_secret",
+];
+
+/// One account as exported and imported. Only `uuid` and `username` are
+/// required on import.
+#[derive(Debug, Serialize, Deserialize)]
+struct UserRecord {
+    uuid: Uuid,
+    /// As displayed. Lookups ignore case.
+    username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ethaddr: Option<String>,
+    #[serde(default = "inactive")]
+    actived: i32,
+    /// The argon2 hash. Accounts without one can't sign in until their
+    /// password is reset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pwhash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(default = "searchable")]
+    searchable: bool,
+    #[serde(default)]
+    banned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ban_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp_secret: Option<String>,
+}
+
+fn inactive() -> i32 {
+    -1
+}
+
+fn searchable() -> bool {
+    true
+}
+
+impl UserRecord {
+    fn csv_fields(&self) -> Vec<String> {
+        let text = |value: &Option<String>| value.clone().unwrap_or_default();
+        vec![
+            self.uuid.to_string(),
+            self.username.clone(),
+            text(&self.ethaddr),
+            self.actived.to_string(),
+            text(&self.pwhash),
+            text(&self.email),
+            self.searchable.to_string(),
+            self.banned.to_string(),
+            text(&self.ban_reason),
+            text(&self.totp_secret),
+        ]
+    }
+
+    /// Checks the record and brings addresses into the stored form.
+    fn validate(mut self) -> Result<Self, String> {
+        if self.username.trim().is_empty() {
+            return Err("the username is empty".into());
+        }
+        if let Some(ethaddr) = &self.ethaddr {
+            let ethaddr = decapitalize(ethaddr);
+            let valid = ethaddr.len() == 42
+                && ethaddr.starts_with("0x")
+                && ethaddr[2..].chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                return Err(format!("`{}` is not an ethereum address", ethaddr));
+            }
+            self.ethaddr = Some(ethaddr);
+        }
+        if let Some(pwhash) = &self.pwhash {
+            if !pwhash.starts_with("$argon2") {
+                return Err("the password hash is not an argon2 hash".into());
+            }
+        }
+        self.email = self.email.as_deref().map(decapitalize);
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    JsonLines,
+    Csv,
+    /// A database of this server or of upstream Veloren auth. Import only.
+    Sqlite,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Self, AuthError> {
+        match name {
+            "jsonl" | "json" => Ok(Self::JsonLines),
+            "csv" => Ok(Self::Csv),
+            "sqlite" | "db" => Ok(Self::Sqlite),
+            _ => Err(usage(&format!("Unknown format `{}`, expected jsonl, csv or sqlite.", name))),
+        }
+    }
+
+    /// Guesses the format from the file extension, defaulting to JSON lines.
+    fn guess(path: &str) -> Self {
+        let extension = path.rsplit('.').next().unwrap_or_default().to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => Self::Csv,
+            "db" | "sqlite" | "sqlite3" => Self::Sqlite,
+            _ => Self::JsonLines,
+        }
+    }
+}
+
+/// What to do with a record whose uuid, username, ethaddr or email is
+/// already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictPolicy {
+    /// Import nothing if any record clashes.
+    Fail,
+    /// Keep the existing account and leave the record out.
+    Skip,
+    /// Replace the clashing accounts with the record. An account with the
+    /// same uuid is updated, keeping its password and second factor if the
+    /// record has none; any other clashing account is deleted.
+    Overwrite,
+}
+
+impl ConflictPolicy {
+    fn parse(name: &str) -> Result<Self, AuthError> {
+        match name {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => Err(usage(&format!("Unknown conflict policy `{}`, expected fail, skip or overwrite.", name))),
+        }
+    }
+}
+
+fn usage(message: &str) -> AuthError {
+    AuthError::InvalidRequest(message.to_owned())
+}
+
+/// The value following the flag `flag`.
+fn flag_value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<&'a str, AuthError> {
+    args.next()
+        .map(String::as_str)
+        .ok_or_else(|| usage(&format!("{} needs a value.", flag)))
+}
+
+/// Runs `--export` with the arguments after it.
+pub fn export(args: &[String]) -> Result<(), AuthError> {
+    let mut format = None;
+    let mut output = None;
+    let mut hashes = true;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(Format::parse(flag_value(&mut args, arg)?)?),
+            "--output" | "-o" => output = Some(flag_value(&mut args, arg)?),
+            "--no-hashes" => hashes = false,
+            other => return Err(usage(&format!("Unexpected argument `{}`.", other))),
+        }
+    }
+    let format = format.unwrap_or_else(|| output.map(Format::guess).unwrap_or(Format::JsonLines));
+    if format == Format::Sqlite {
+        return Err(usage("Exports are written as jsonl or csv."));
+    }
+
+    auth::init_db()?;
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+    if format == Format::Csv {
+        write_csv_row(&mut out, COLUMNS)?;
+    }
+
+    let db = auth::db()?;
+    let mut stmt = db.prepare(
+        "SELECT uuid, display_username, ethaddr, actived, pwhash, email, searchable, banned, ban_reason, totp_secret
+         FROM users ORDER BY username",
+    )?;
+    let mut rows = stmt.query(params![])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let uuid: String = row.get(0)?;
+        let ethaddr: String = row.get(2)?;
+        let pwhash: String = row.get(4)?;
+        let mut record = UserRecord {
+            uuid: Uuid::parse_str(&uuid).map_err(|_| usage(&format!("Stored uuid `{}` is invalid.", uuid)))?,
+            username: row.get(1)?,
+            ethaddr: Some(ethaddr).filter(|ethaddr| !ethaddr.starts_with(NO_ETHADDR_PREFIX)),
+            actived: row.get(3)?,
+            pwhash: Some(pwhash).filter(|pwhash| !pwhash.is_empty()),
+            email: row.get(5)?,
+            searchable: row.get(6)?,
+            banned: row.get(7)?,
+            ban_reason: row.get(8)?,
+            totp_secret: row.get(9)?,
+        };
+        if !hashes {
+            record.pwhash = None;
+            record.totp_secret = None;
+        }
+        match format {
+            Format::Csv => write_csv_row(&mut out, &record.csv_fields())?,
+            _ => {
+                serde_json::to_writer(&mut out, &record)?;
+                writeln!(out)?;
+            }
+        }
+        count += 1;
+    }
+    out.flush()?;
+    eprintln!("Exported {} accounts.", count);
+    Ok(())
+}
+
+fn write_csv_row<S: AsRef<str>>(out: &mut impl Write, fields: &[S]) -> io::Result<()> {
+    let line: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            if field.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_owned()
+            }
+        })
+        .collect();
+    writeln!(out, "{}", line.join(","))
+}
+
+/// Splits CSV text into rows, returning each with the line it starts on.
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut row_line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line += 1;
+        }
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                row.push(std::mem::take(&mut field));
+                // Blank lines separate nothing.
+                if row.len() > 1 || !row[0].is_empty() {
+                    rows.push((row_line, std::mem::take(&mut row)));
+                }
+                row_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("line {}: unterminated quoted field", row_line));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push((row_line, row));
+    }
+    Ok(rows)
+}
+
+/// Deserializes a record from named fields, leaving out empty ones.
+fn record_from_fields(fields: Map<String, Value>) -> Result<UserRecord, String> {
+    serde_json::from_value(Value::Object(fields)).map_err(|err| err.to_string())
+}
+
+/// Records read from a file, each with its line or row number, or why it
+/// could not be read.
+type Records = Vec<(usize, Result<UserRecord, String>)>;
+
+fn read_json_lines(text: &str) -> Records {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| (i + 1, serde_json::from_str(line).map_err(|err| err.to_string())))
+        .collect()
+}
+
+fn read_csv(text: &str) -> Result<Records, AuthError> {
+    let mut rows = parse_csv(text).map_err(|err| usage(&err))?.into_iter();
+    let header = match rows.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    let records = rows
+        .map(|(line, row)| {
+            let mut fields = Map::new();
+            for (name, value) in header.iter().zip(row) {
+                if value.is_empty() {
+                    continue;
+                }
+                let value = match name.as_str() {
+                    "actived" => match value.parse::<i32>() {
+                        Ok(actived) => Value::from(actived),
+                        Err(_) => return (line, Err(format!("actived `{}` is not a number", value))),
+                    },
+                    "searchable" | "banned" => match value.as_str() {
+                        "true" | "1" => Value::Bool(true),
+                        "false" | "0" => Value::Bool(false),
+                        _ => return (line, Err(format!("{} `{}` is not true or false", name, value))),
+                    },
+                    _ => Value::String(value),
+                };
+                fields.insert(name.clone(), value);
+            }
+            (line, record_from_fields(fields))
+        })
+        .collect();
+    Ok(records)
+}
+
+/// Reads the accounts of a database of this server, or of upstream Veloren
+/// auth, whose `users` table has only `uuid`, `username`,
+/// `display_username` and `pwhash`.
+fn read_sqlite(path: &str) -> Result<Records, AuthError> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let columns: Vec<String> = db
+        .prepare("PRAGMA table_info(users)")?
+        .query_map(params![], |row| row.get(1))?
+        .collect::<Result<_, _>>()?;
+    if columns.is_empty() {
+        return Err(usage(&format!("{} has no users table.", path)));
+    }
+    let selected: Vec<(&str, &str)> = COLUMNS
+        .iter()
+        .map(|&name| match name {
+            "username" if columns.iter().any(|c| c == "display_username") => (name, "display_username"),
+            _ => (name, name),
+        })
+        .filter(|(_, column)| columns.iter().any(|c| c == column))
+        .collect();
+    let list: Vec<&str> = selected.iter().map(|(_, column)| *column).collect();
+
+    let mut stmt = db.prepare(&format!("SELECT {} FROM users ORDER BY rowid", list.join(", ")))?;
+    let mut rows = stmt.query(params![])?;
+    let mut records = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut fields = Map::new();
+        for (i, (name, _)) in selected.iter().enumerate() {
+            let value = match (row.get::<_, SqlValue>(i)?, *name) {
+                (SqlValue::Null, _) => continue,
+                (SqlValue::Integer(value), "searchable") | (SqlValue::Integer(value), "banned") => Value::Bool(value != 0),
+                (SqlValue::Integer(value), _) => Value::from(value),
+                (SqlValue::Text(value), _) => Value::String(value),
+                (SqlValue::Real(value), _) => Value::from(value),
+                (SqlValue::Blob(_), _) => continue,
+            };
+            fields.insert((*name).to_owned(), value);
+        }
+        records.push((records.len() + 1, record_from_fields(fields)));
+    }
+    Ok(records)
+}
+
+#[derive(Default)]
+struct Report {
+    read: usize,
+    inserted: usize,
+    updated: usize,
+    skipped: usize,
+    /// Existing accounts deleted to make room for imported ones.
+    replaced: usize,
+    conflicts: Vec<String>,
+    invalid: Vec<String>,
+}
+
+/// Runs `--import` with the arguments after it.
+pub fn import(args: &[String]) -> Result<(), AuthError> {
+    let mut path = None;
+    let mut format = None;
+    let mut policy = ConflictPolicy::Fail;
+    let mut dry_run = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(Format::parse(flag_value(&mut args, arg)?)?),
+            "--on-conflict" => policy = ConflictPolicy::parse(flag_value(&mut args, arg)?)?,
+            "--dry-run" => dry_run = true,
+            other if path.is_none() && !other.starts_with("--") => path = Some(other),
+            other => return Err(usage(&format!("Unexpected argument `{}`.", other))),
+        }
+    }
+    let path = path.ok_or_else(|| usage("Name the file to import, or - for standard input."))?;
+    let format = format.unwrap_or_else(|| Format::guess(path));
+
+    let records = match format {
+        Format::Sqlite => read_sqlite(path)?,
+        _ => {
+            let text = if path == "-" {
+                let mut text = String::new();
+                io::stdin().read_to_string(&mut text)?;
+                text
+            } else {
+                fs::read_to_string(path)?
+            };
+            if format == Format::Csv {
+                read_csv(&text)?
+            } else {
+                read_json_lines(&text)
+            }
+        }
+    };
+
+    // A dry run only reads, so it may look at the database of a running server.
+    let _lock = if dry_run { None } else { Some(backup::lock_offline("importing")?) };
+    auth::init_db()?;
+    let mut db = auth::db()?;
+    let tx = db.transaction()?;
+    let mut report = Report {
+        read: records.len(),
+        ..Report::default()
+    };
+    for (line, record) in records {
+        match record.and_then(UserRecord::validate) {
+            Ok(record) => import_record(&tx, line, record, policy, &mut report)?,
+            Err(err) => report.invalid.push(format!("line {}: {}", line, err)),
+        }
+    }
+
+    let failed = !report.invalid.is_empty() || (policy == ConflictPolicy::Fail && !report.conflicts.is_empty());
+    println!("Read {} records from {}.", report.read, path);
+    println!("  inserted:    {}", report.inserted);
+    println!("  updated:     {}", report.updated);
+    println!("  replaced:    {}", report.replaced);
+    println!("  skipped:     {}", report.skipped);
+    println!("  conflicts:   {}", report.conflicts.len());
+    println!("  invalid:     {}", report.invalid.len());
+    for line in report.invalid.iter().chain(&report.conflicts) {
+        println!("{}", line);
+    }
+
+    if failed {
+        tx.rollback()?;
+        Err(usage("Nothing was imported. Fix the invalid records, or choose --on-conflict skip or overwrite."))
+    } else if dry_run {
+        tx.rollback()?;
+        println!("Dry run, nothing was written.");
+        Ok(())
+    } else {
+        tx.commit()?;
+        println!("Import complete.");
+        Ok(())
+    }
+}
+
+fn import_record(
+    db: &Connection,
+    line: usize,
+    record: UserRecord,
+    policy: ConflictPolicy,
+    report: &mut Report,
+) -> Result<(), AuthError> {
+    let uuid = record.uuid.to_simple().to_string();
+    let username = decapitalize(&record.username);
+    let ethaddr = record
+        .ethaddr
+        .clone()
+        .unwrap_or_else(|| format!("{}{}", NO_ETHADDR_PREFIX, uuid));
+
+    let mut stmt = db.prepare_cached(
+        "SELECT uuid, username == ?2, ethaddr == ?3, email IS NOT NULL AND email == ?4 FROM users
+         WHERE uuid == ?1 OR username == ?2 OR ethaddr == ?3 OR (email IS NOT NULL AND email == ?4)",
+    )?;
+    let clashes: Vec<(String, bool, bool, bool)> = stmt
+        .query_map(params![uuid, username, ethaddr, record.email], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let mut same_uuid = false;
+    if !clashes.is_empty() {
+        let reasons: Vec<String> = clashes
+            .iter()
+            .map(|(other, same_name, same_eth, same_email)| {
+                if *other == uuid {
+                    same_uuid = true;
+                    return "the uuid exists".to_owned();
+                }
+                let taken: Vec<&str> = [(*same_name, "username"), (*same_eth, "ethaddr"), (*same_email, "email")]
+                    .iter()
+                    .filter(|(clash, _)| *clash)
+                    .map(|(_, what)| *what)
+                    .collect();
+                format!("{} taken by {}", taken.join(" and "), other)
+            })
+            .collect();
+        report
+            .conflicts
+            .push(format!("line {} ({}): {}", line, record.username, reasons.join(", ")));
+
+        match policy {
+            ConflictPolicy::Fail => return Ok(()),
+            ConflictPolicy::Skip => {
+                report.skipped += 1;
+                return Ok(());
+            }
+            ConflictPolicy::Overwrite => {
+                for (other, ..) in clashes.iter().filter(|(other, ..)| *other != uuid) {
+                    if auth::delete_user_rows(db, other)? {
+                        report.replaced += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    if same_uuid {
+        db.execute(
+            "UPDATE users SET username = ?2, display_username = ?3, ethaddr = ?4, actived = ?5,
+                 pwhash = coalesce(?6, pwhash), email = ?7, searchable = ?8, banned = ?9, ban_reason = ?10,
+                 totp_secret = coalesce(?11, totp_secret)
+             WHERE uuid == ?1",
+            params![
+                uuid,
+                username,
+                record.username,
+                ethaddr,
+                record.actived,
+                record.pwhash,
+                record.email,
+                record.searchable,
+                record.banned,
+                record.ban_reason,
+                record.totp_secret
+            ],
+        )?;
+        report.updated += 1;
+    } else {
+        db.execute(
+            "INSERT INTO users (uuid, username, display_username, ethaddr, actived, pwhash, email, searchable, banned, ban_reason, totp_secret)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                uuid,
+                username,
+                record.username,
+                ethaddr,
+                record.actived,
+                record.pwhash.unwrap_or_default(),
+                record.email,
+                record.searchable,
+                record.banned,
+                record.ban_reason,
+                record.totp_secret
+            ],
+        )?;
+        report.inserted += 1;
+    }
+    Ok(())
+}
@@ -1,19 +1,69 @@
 use crate::auth::{self, AuthError};
+use crate::backup;
+use crate::cors;
+use crate::health;
+use crate::mail;
+use crate::metrics;
+use crate::device;
+use crate::events;
+use crate::oidc::{self, AuthorizeError, OAuthError};
+use crate::webhook;
+use crate::openapi;
+use crate::shutdown;
 use crate::ratelimit::RateLimiter;
 use auth_common::{
-    RegisterPayload, SignInPayload, SignInResponse, UsernameLookupPayload, UsernameLookupResponse,
+    RegisterPayload, RegisterResponse, SignInPayload, SignInResponse, UsernameLookupPayload, UsernameLookupResponse,
     UuidLookupPayload, UuidLookupResponse, ValidityCheckPayload, ValidityCheckResponse, 
     EthLookupResponse, EthLookupPayload,EthActivePayload, 
-    UserinfoLookupResponse,Userinfo2LookupResponse,
-    ChangePassPayload
+    UserinfoLookupPayload, UserinfoLookupResponse, Userinfo2LookupPayload, Userinfo2LookupResponse,
+    ChangePassPayload, PasswordChangePayload, UserInfoResponse, HealthResponse, HealthStatus,
+    UuidBatchPayload, UsernameBatchPayload, EthBatchPayload, UserBatchResponse, MAX_BATCH_LOOKUP,
+    UserSearchResponse, SearchVisibilityPayload, MAX_SEARCH_RESULTS, EmailChangePayload,
+    PasswordResetRequestPayload, PasswordResetPayload, TotpEnrollPayload, TotpEnrollResponse,
+    TotpConfirmPayload, TotpConfirmResponse, OAuthClientPayload, OAuthClientResponse, OAuthClientList,
+    OAuthTokenResponse, UserInfoClaims, DeviceAuthorizationResponse, DeviceTokenPayload, OAuthErrorResponse,
+    BanPayload, UsernameChangePayload, AccountEventRecord, WebhookSubscriptionPayload, WebhookSubscriptionResponse, WebhookSubscriptionList, WebhookDeliveryList,
+    BackupInfo, BackupList,
 };
 use lazy_static::lazy_static;
 use log::*;
-use rouille::{start_server, Request, Response};
-use std::net::IpAddr;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, StatusCode};
+use ring::constant_time::verify_slices_are_equal;
+use rouille::{Request, Response};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use std::convert::Infallible;
+use std::env;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::{self, Handle};
+use tokio::sync::Semaphore;
+use tokio::task;
+use uuid::Uuid;
+
+/// How often the shutdown flag is checked.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Largest request body accepted, all payloads are small JSON documents.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Default cap on threads running the (blocking) request handlers,
+/// overridable with `AUTH_BLOCKING_THREADS`. Requests beyond it are turned
+/// away rather than queued.
+const DEFAULT_BLOCKING_THREADS: usize = 64;
+
+/// Results returned by a search that does not ask for a number.
+const DEFAULT_SEARCH_RESULTS: usize = 10;
 
 lazy_static! {
-    static ref RATELIMITER: RateLimiter = RateLimiter::new();
+    /// Limits account creation and sign-in.
+    static ref RATELIMITER: RateLimiter = RateLimiter::new(60, Duration::from_secs(60 * 10));
+    /// Search backs autocomplete, which sends a request per keystroke.
+    static ref SEARCH_RATELIMITER: RateLimiter = RateLimiter::new(120, Duration::from_secs(60));
 }
 
 fn legal_char(c: char) -> bool {
@@ -30,7 +80,7 @@ fn legal_pwchar(c: char) -> bool {
 }
 
 
-fn verify_username(username: &str) -> Result<(), AuthError> {
+pub fn verify_username(username: &str) -> Result<(), AuthError> {
     if !(3..=32).contains(&username.len()) {
         Err(AuthError::InvalidRequest(
             "Username must be between 3 and 32 characters inclusive.".into(),
@@ -44,10 +94,11 @@ fn verify_username(username: &str) -> Result<(), AuthError> {
     }
 }
 
+/// `authc` sends a prehash of the password, 64 hex digits.
 fn verify_password(password: &str) -> Result<(), AuthError> {
-    if !(6..=32).contains(&password.len()) {
+    if !(6..=64).contains(&password.len()) {
         Err(AuthError::InvalidRequest(
-            "Password must be between 6 and 32 characters and digits inclusive.".into(),
+            "Password must be between 6 and 64 characters and digits inclusive.".into(),
         ))
     } else if !password.chars().all(legal_pwchar) {
         Err(AuthError::InvalidRequest(
@@ -62,7 +113,7 @@ fn verify_password(password: &str) -> Result<(), AuthError> {
 // add new verify fn -max
 fn verify_ethaddr(ethaddr: &str) -> Result<(), AuthError> {
     //Eth address save with the hex prefix ("0x"), so it's 42 characters length.
-    if !(42 == ethaddr.len()) {   
+    if ethaddr.len() != 42 {
         println!("eth addr verify error 1");
         Err(AuthError::InvalidEthAddr(
             "Eth address must be between 42 characters with the hex prefix '0x'.".into(),
@@ -79,15 +130,522 @@ fn verify_ethaddr(ethaddr: &str) -> Result<(), AuthError> {
 }
 
 
+pub type Handler = fn(&Request, &Params) -> Result<Response, AuthError>;
+pub type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
 
-fn ratelimit(
-    req: &Request,
-    f: fn(&Request) -> Result<Response, AuthError>,
-) -> Result<Response, AuthError> {
-    if RATELIMITER.check(remote(req)) {
-        f(req)
-    } else {
-        Err(AuthError::RateLimit)
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+/// Which API a route belongs to. The legacy routes keep their original
+/// error status codes so existing clients see no change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Api {
+    Legacy,
+    V1,
+    /// Operational endpoints such as metrics, not meant for game clients.
+    Ops,
+    /// Routes under `/admin/`, which need the `AUTH_ADMIN_TOKEN` bearer token.
+    Admin,
+    /// The OpenID Connect provider, which reports errors the OAuth way.
+    OAuth,
+    /// The account event stream, which needs one of the `AUTH_EVENT_TOKENS`
+    /// or the admin token.
+    Events,
+}
+
+/// Which rate limiter a route counts against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Account,
+    Search,
+}
+
+impl Limit {
+    fn limiter(self) -> &'static RateLimiter {
+        match self {
+            Limit::Account => &RATELIMITER,
+            Limit::Search => &SEARCH_RATELIMITER,
+        }
+    }
+}
+
+/// A query string or form parameter, listed in the OpenAPI document.
+pub struct Query {
+    pub name: &'static str,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+/// What a route answers with on success.
+#[derive(Clone, Copy)]
+pub enum Reply {
+    Json(SchemaFn),
+    /// JSON of the given schema describing the resource just created, with 201.
+    Created(SchemaFn),
+    Text,
+    Html,
+    /// Server-sent events whose data has the given schema.
+    EventStream(SchemaFn),
+    Empty(u16),
+}
+
+pub struct Route {
+    pub method: &'static str,
+    /// Path pattern, `{name}` segments are captured into `Params`.
+    pub path: &'static str,
+    pub handler: Handler,
+    pub api: Api,
+    pub limit: Option<Limit>,
+    pub summary: &'static str,
+    pub query: &'static [Query],
+    pub body: Option<SchemaFn>,
+    /// Fields of an `application/x-www-form-urlencoded` body.
+    pub form: &'static [Query],
+    pub reply: Reply,
+    /// Another answer besides success and the usual errors: its status and
+    /// description. A JSON reply has the same schema as on success.
+    pub also: Option<(u16, &'static str)>,
+    /// Whether the change is confirmed with the account's password, which
+    /// is answered with 401 when wrong.
+    pub confirmed: bool,
+}
+
+const fn route(method: &'static str, path: &'static str, handler: Handler, api: Api) -> Route {
+    Route {
+        method,
+        path,
+        handler,
+        api,
+        limit: None,
+        summary: "",
+        query: &[],
+        body: None,
+        form: &[],
+        reply: Reply::Text,
+        also: None,
+        confirmed: false,
+    }
+}
+
+impl Route {
+    const fn limited(self) -> Self {
+        self.limited_by(Limit::Account)
+    }
+
+    const fn limited_by(mut self, limit: Limit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    const fn query(mut self, query: &'static [Query]) -> Self {
+        self.query = query;
+        self
+    }
+
+    const fn summary(mut self, summary: &'static str) -> Self {
+        self.summary = summary;
+        self
+    }
+
+    const fn body<T: JsonSchema>(mut self) -> Self {
+        self.body = Some(schema::<T>);
+        self
+    }
+
+    const fn form(mut self, form: &'static [Query]) -> Self {
+        self.form = form;
+        self
+    }
+
+    const fn html(mut self) -> Self {
+        self.reply = Reply::Html;
+        self
+    }
+
+    const fn event_stream<T: JsonSchema>(mut self) -> Self {
+        self.reply = Reply::EventStream(schema::<T>);
+        self
+    }
+
+    const fn json<T: JsonSchema>(mut self) -> Self {
+        self.reply = Reply::Json(schema::<T>);
+        self
+    }
+
+    const fn created<T: JsonSchema>(mut self) -> Self {
+        self.reply = Reply::Created(schema::<T>);
+        self
+    }
+
+    const fn empty(mut self, status: u16) -> Self {
+        self.reply = Reply::Empty(status);
+        self
+    }
+
+    const fn also(mut self, status: u16, description: &'static str) -> Self {
+        self.also = Some((status, description));
+        self
+    }
+
+    const fn confirmed(mut self) -> Self {
+        self.confirmed = true;
+        self
+    }
+}
+
+/// Every route the server answers. Dispatch and the OpenAPI document are both
+/// driven by this table, so a route added here is documented automatically.
+pub const ROUTES: &[Route] = &[
+    route("GET", "/ping", ping, Api::Legacy)
+        .summary("Echo the caller's address."),
+    route("POST", "/username_to_uuid", username_to_uuid, Api::Legacy)
+        .summary("Look up the uuid of a username.")
+        .body::<UuidLookupPayload>()
+        .json::<UuidLookupResponse>(),
+    route("POST", "/uuid_to_username", uuid_to_username, Api::Legacy)
+        .summary("Look up the display username of a uuid.")
+        .body::<UsernameLookupPayload>()
+        .json::<UsernameLookupResponse>(),
+    route("POST", "/eth_to_info", eth_to_user, Api::Legacy)
+        .summary("Look up an account by ethereum address.")
+        .body::<EthLookupPayload>()
+        .json::<EthLookupResponse>(),
+    route("POST", "/username_to_info", username_to_info, Api::Legacy)
+        .summary("Look up the uuid and ethereum address of a username.")
+        .body::<UserinfoLookupPayload>()
+        .json::<UserinfoLookupResponse>(),
+    route("POST", "/uuid_to_info", uuid_to_info, Api::Legacy)
+        .summary("Look up the username and ethereum address of a uuid.")
+        .body::<Userinfo2LookupPayload>()
+        .json::<Userinfo2LookupResponse>(),
+    route("POST", "/eth_active", eth_active, Api::Legacy)
+        .summary("Mark the account of an ethereum address as activated.")
+        .body::<EthActivePayload>(),
+    route("POST", "/change_pass", change_pass, Api::Legacy)
+        .summary("Change the password of the account of an ethereum address.")
+        .body::<ChangePassPayload>(),
+    route("POST", "/register", register, Api::Legacy)
+        .limited()
+        .summary("Register a new account.")
+        .body::<RegisterPayload>()
+        .json::<RegisterResponse>(),
+    route("POST", "/generate_token", generate_token, Api::Legacy)
+        .limited()
+        .summary("Exchange credentials for a one-time game server token.")
+        .body::<SignInPayload>()
+        .json::<SignInResponse>(),
+    route("POST", "/verify", verify, Api::Legacy)
+        .summary("Consume a one-time token and return its owner.")
+        .body::<ValidityCheckPayload>()
+        .json::<ValidityCheckResponse>(),
+    route("GET", "/openapi.json", openapi, Api::Ops)
+        .summary("This OpenAPI document."),
+    route("GET", "/metrics", metrics, Api::Ops)
+        .summary("Prometheus metrics in the text exposition format."),
+    route("GET", "/healthz", healthz, Api::Ops)
+        .summary("Liveness probe.")
+        .json::<HealthResponse>(),
+    route("GET", "/readyz", readyz, Api::Ops)
+        .summary("Readiness probe checking the database, migrations, disk space and token cleaner.")
+        .json::<HealthResponse>()
+        .also(503, "At least one check failed."),
+    route("GET", "/v1/ping", ping, Api::V1)
+        .summary("Echo the caller's address."),
+    route("POST", "/v1/users", v1_register, Api::V1)
+        .limited()
+        .summary("Register a new account.")
+        .body::<RegisterPayload>()
+        .created::<RegisterResponse>(),
+    // Ahead of `/v1/users/{uuid}`, which would otherwise capture it.
+    route("GET", "/v1/users/search", v1_search, Api::V1)
+        .limited_by(Limit::Search)
+        .summary("Find searchable accounts by username prefix, for autocomplete.")
+        .query(&[
+            Query {
+                name: "prefix",
+                required: true,
+                description: "At least 2 characters the username starts with, case insensitive.",
+            },
+            Query {
+                name: "limit",
+                required: false,
+                description: "Results per page, 10 by default and at most 50.",
+            },
+            Query {
+                name: "after",
+                required: false,
+                description: "The `next` cursor of the previous page.",
+            },
+        ])
+        .json::<UserSearchResponse>(),
+    route("GET", "/v1/users/{uuid}", v1_user_by_uuid, Api::V1)
+        .summary("Fetch an account by uuid.")
+        .json::<UserInfoResponse>(),
+    route("GET", "/v1/users/by-name/{name}", v1_user_by_name, Api::V1)
+        .summary("Fetch an account by username.")
+        .json::<UserInfoResponse>(),
+    route("GET", "/v1/users/by-eth/{addr}", v1_user_by_eth, Api::V1)
+        .summary("Fetch an account by ethereum address.")
+        .json::<UserInfoResponse>(),
+    route("POST", "/v1/users/batch/by-uuid", v1_users_by_uuid, Api::V1)
+        .summary("Fetch up to 100 accounts by uuid. Unknown uuids map to null.")
+        .body::<UuidBatchPayload>()
+        .json::<UserBatchResponse>(),
+    route("POST", "/v1/users/batch/by-name", v1_users_by_name, Api::V1)
+        .summary("Fetch up to 100 accounts by username. Unknown usernames map to null.")
+        .body::<UsernameBatchPayload>()
+        .json::<UserBatchResponse>(),
+    route("POST", "/v1/users/batch/by-eth", v1_users_by_eth, Api::V1)
+        .summary("Fetch up to 100 accounts by ethereum address. Unknown addresses map to null.")
+        .body::<EthBatchPayload>()
+        .json::<UserBatchResponse>(),
+    route("PUT", "/v1/users/by-eth/{addr}/active", v1_eth_active, Api::V1)
+        .summary("Mark the account of an ethereum address as activated.")
+        .empty(204),
+    route("PUT", "/v1/users/{uuid}/password", v1_change_pass, Api::V1)
+        .confirmed()
+        .summary("Change the password of an account, confirmed with its current password.")
+        .body::<PasswordChangePayload>()
+        .empty(204),
+    route("PUT", "/v1/users/{uuid}/searchable", v1_set_searchable, Api::V1)
+        .confirmed()
+        .summary("Choose whether an account shows up in username search, confirmed with its password.")
+        .body::<SearchVisibilityPayload>()
+        .empty(204),
+    route("PUT", "/v1/users/{uuid}/email", v1_set_email, Api::V1)
+        .limited()
+        .confirmed()
+        .summary("Set or change the email address of an account. A verification link is mailed to the new address.")
+        .body::<EmailChangePayload>()
+        .empty(202),
+    route("GET", "/v1/email/verify", v1_verify_email, Api::V1)
+        .summary("Confirm an email address with the single-use token from the verification mail.")
+        .query(&[Query {
+            name: "token",
+            required: true,
+            description: "The token from the verification link.",
+        }]),
+    route("POST", "/v1/password-reset", v1_request_password_reset, Api::V1)
+        .limited()
+        .summary("Mail a password reset code to the verified address of an account. Always answers 202, whether or not the account exists.")
+        .body::<PasswordResetRequestPayload>()
+        .empty(202),
+    route("POST", "/v1/password-reset/complete", v1_reset_password, Api::V1)
+        .limited()
+        .summary("Set a new password with a reset code. Outstanding tokens of the account are revoked.")
+        .body::<PasswordResetPayload>()
+        .empty(204),
+    route("POST", "/v1/users/{uuid}/2fa", v1_begin_totp, Api::V1)
+        .limited()
+        .confirmed()
+        .summary("Start enrolling an authenticator app for two-factor authentication. Returns the TOTP secret and its otpauth URI.")
+        .body::<TotpEnrollPayload>()
+        .json::<TotpEnrollResponse>(),
+    route("POST", "/v1/users/{uuid}/2fa/confirm", v1_confirm_totp, Api::V1)
+        .limited()
+        .confirmed()
+        .summary("Enable two-factor authentication with a code from the enrolled app. Returns single-use recovery codes, shown only this once.")
+        .body::<TotpConfirmPayload>()
+        .json::<TotpConfirmResponse>(),
+    route("DELETE", "/admin/users/{uuid}/2fa", admin_reset_totp, Api::Admin)
+        .summary("Turn off two-factor authentication of an account and drop its recovery codes.")
+        .empty(204),
+    route("PUT", "/admin/users/{uuid}/ban", admin_ban, Api::Admin)
+        .summary("Ban an account. Its outstanding tokens are revoked and it can't sign in until unbanned.")
+        .body::<BanPayload>()
+        .empty(204),
+    route("DELETE", "/admin/users/{uuid}/ban", admin_unban, Api::Admin)
+        .summary("Lift the ban of an account.")
+        .empty(204),
+    route("PUT", "/admin/users/{uuid}/username", admin_rename_user, Api::Admin)
+        .summary("Rename an account.")
+        .body::<UsernameChangePayload>()
+        .empty(204),
+    route("DELETE", "/admin/users/{uuid}/tokens", admin_revoke_tokens, Api::Admin)
+        .summary("Revoke the outstanding tokens of an account.")
+        .empty(204),
+    route("DELETE", "/admin/users/{uuid}", admin_delete_user, Api::Admin)
+        .summary("Delete an account for good and revoke its tokens.")
+        .empty(204),
+    route("GET", "/v1/events", v1_events, Api::Events)
+        .summary("Stream of bans, revoked sessions, renames and deletions as server-sent events, for game servers to act on. Each event's id is a cursor to resume from.")
+        .query(&[Query {
+            name: "after",
+            required: false,
+            description: "Cursor of the last event seen, like the `Last-Event-ID` header. Without either, only new events are sent.",
+        }])
+        .event_stream::<AccountEventRecord>(),
+    route("POST", "/admin/webhooks", admin_subscribe_webhook, Api::Admin)
+        .summary("Subscribe a URL to account events. The signing secret is only shown in this answer.")
+        .body::<WebhookSubscriptionPayload>()
+        .json::<WebhookSubscriptionResponse>(),
+    route("GET", "/admin/webhooks", admin_list_webhooks, Api::Admin)
+        .summary("List the webhook subscriptions.")
+        .json::<WebhookSubscriptionList>(),
+    route("DELETE", "/admin/webhooks/{id}", admin_unsubscribe_webhook, Api::Admin)
+        .summary("Remove a webhook subscription and drop its queued deliveries.")
+        .empty(204),
+    route("GET", "/admin/webhooks/{id}/deliveries", admin_webhook_deliveries, Api::Admin)
+        .summary("The latest 100 deliveries of a webhook, newest first, with their status and last error.")
+        .json::<WebhookDeliveryList>(),
+    route("POST", "/admin/backups", admin_create_backup, Api::Admin)
+        .summary("Take a snapshot of the database with SQLite's online backup API. It is verified before it is kept, and the oldest snapshots beyond `AUTH_BACKUP_KEEP` are removed.")
+        .json::<BackupInfo>(),
+    route("GET", "/admin/backups", admin_list_backups, Api::Admin)
+        .summary("The snapshots in the backup directory, newest first.")
+        .json::<BackupList>(),
+    route("POST", "/admin/oauth/clients", admin_register_client, Api::Admin)
+        .summary("Register an OpenID Connect client application. The secret of a confidential client is only shown in this answer.")
+        .body::<OAuthClientPayload>()
+        .json::<OAuthClientResponse>(),
+    route("GET", "/admin/oauth/clients", admin_list_clients, Api::Admin)
+        .summary("List the registered OpenID Connect client applications.")
+        .json::<OAuthClientList>(),
+    route("DELETE", "/admin/oauth/clients/{client_id}", admin_delete_client, Api::Admin)
+        .summary("Remove a client application and revoke its access tokens.")
+        .empty(204),
+    route("GET", "/.well-known/openid-configuration", oidc_discovery, Api::OAuth)
+        .summary("OpenID Connect discovery document."),
+    route("GET", "/oauth/jwks", oidc_jwks, Api::OAuth)
+        .summary("Public keys ID tokens are signed with, as a JSON Web Key Set."),
+    route("GET", "/oauth/authorize", oauth_authorize, Api::OAuth)
+        .summary("Start the authorization code flow. Shows the login and consent page.")
+        .query(AUTHORIZE_PARAMS)
+        .html()
+        .also(303, "Back to the client's redirect URI, with a code or an error."),
+    route("POST", "/oauth/authorize", oauth_authorize_submit, Api::OAuth)
+        .limited()
+        .summary("Submit the login and consent page. Redirects back to the client with a code or an error.")
+        .form(AUTHORIZE_FORM)
+        .html()
+        .also(303, "Back to the client's redirect URI, with a code or an error."),
+    route("POST", "/oauth/device_authorization", oauth_device_authorization, Api::OAuth)
+        .limited()
+        .summary("Start the device authorization grant for a registered client.")
+        .form(&[
+            Query { name: "client_id", required: false, description: "Client id, unless sent with HTTP Basic authentication." },
+            Query { name: "client_secret", required: false, description: "Secret of a confidential client, unless sent with HTTP Basic authentication." },
+            Query { name: "scope", required: true, description: "Space separated scopes, must include `openid`." },
+        ])
+        .json::<DeviceAuthorizationResponse>(),
+    route("POST", "/oauth/token", oauth_token, Api::OAuth)
+        .summary("Exchange an authorization code or an approved device code for an access token and an ID token.")
+        .form(&[
+            Query {
+                name: "grant_type",
+                required: true,
+                description: "`authorization_code`, or `urn:ietf:params:oauth:grant-type:device_code` to poll a device authorization.",
+            },
+            Query { name: "code", required: false, description: "The code from the redirect." },
+            Query { name: "redirect_uri", required: false, description: "The redirect URI of the authorization request." },
+            Query { name: "code_verifier", required: false, description: "The PKCE verifier." },
+            Query { name: "device_code", required: false, description: "The device code, when polling a device authorization." },
+            Query { name: "client_id", required: false, description: "Client id, unless sent with HTTP Basic authentication." },
+            Query { name: "client_secret", required: false, description: "Secret of a confidential client, unless sent with HTTP Basic authentication." },
+        ])
+        .json::<OAuthTokenResponse>(),
+    route("GET", "/oauth/userinfo", oauth_userinfo, Api::OAuth)
+        .summary("Claims about the account an access token was issued for.")
+        .json::<UserInfoClaims>(),
+    route("POST", "/oauth/userinfo", oauth_userinfo, Api::OAuth)
+        .summary("Claims about the account an access token was issued for.")
+        .json::<UserInfoClaims>(),
+    route("POST", "/v1/device", v1_device_authorization, Api::V1)
+        .limited()
+        .summary("Start a device login for a game client that can't ask for a password. Show the user code and poll `/v1/device/token`.")
+        .json::<DeviceAuthorizationResponse>(),
+    route("POST", "/v1/device/token", v1_device_token, Api::V1)
+        .summary("Poll a device login. Answers a game server token once the user approved it, otherwise a 400 with an OAuth error such as `authorization_pending` or `slow_down`.")
+        .body::<DeviceTokenPayload>()
+        .json::<SignInResponse>(),
+    route("GET", "/device", device_page, Api::OAuth)
+        .summary("The page where users sign in and approve a device.")
+        .query(&[Query {
+            name: "user_code",
+            required: false,
+            description: "The code shown by the device.",
+        }])
+        .html(),
+    route("POST", "/device", device_submit, Api::OAuth)
+        .limited()
+        .summary("Submit the device approval page.")
+        .form(&[
+            Query { name: "user_code", required: true, description: "The code shown by the device." },
+            Query { name: "username", required: true, description: "Account name." },
+            Query { name: "password", required: true, description: "Account password." },
+            Query { name: "otp", required: false, description: "Authenticator or recovery code, if two-factor authentication is enabled." },
+            Query { name: "decision", required: true, description: "`allow` or `deny`." },
+        ])
+        .html(),
+    route("POST", "/v1/tokens", generate_token, Api::V1)
+        .limited()
+        .summary("Exchange credentials for a one-time game server token.")
+        .body::<SignInPayload>()
+        .json::<SignInResponse>(),
+    route("POST", "/v1/tokens/verify", verify, Api::V1)
+        .summary("Consume a one-time token and return its owner.")
+        .body::<ValidityCheckPayload>()
+        .json::<ValidityCheckResponse>(),
+];
+
+const AUTHORIZE_PARAMS: &[Query] = &[
+    Query { name: "client_id", required: true, description: "Id of the registered client." },
+    Query { name: "redirect_uri", required: false, description: "One of the client's registered redirect URIs. May be left out if it has only one." },
+    Query { name: "response_type", required: true, description: "Always `code`." },
+    Query { name: "scope", required: true, description: "Space separated, must include `openid`. `profile` adds the username, `ethaddr` the ethereum address." },
+    Query { name: "state", required: false, description: "Passed back to the client unchanged." },
+    Query { name: "nonce", required: false, description: "Copied into the ID token." },
+    Query { name: "code_challenge", required: true, description: "The PKCE challenge, base64url of the SHA-256 of the verifier." },
+    Query { name: "code_challenge_method", required: true, description: "Always `S256`." },
+];
+
+const AUTHORIZE_FORM: &[Query] = &[
+    Query { name: "client_id", required: true, description: "As in the authorization request, and so are the fields up to `code_challenge_method`." },
+    Query { name: "redirect_uri", required: false, description: "" },
+    Query { name: "response_type", required: true, description: "" },
+    Query { name: "scope", required: true, description: "" },
+    Query { name: "state", required: false, description: "" },
+    Query { name: "nonce", required: false, description: "" },
+    Query { name: "code_challenge", required: true, description: "" },
+    Query { name: "code_challenge_method", required: true, description: "" },
+    Query { name: "username", required: true, description: "" },
+    Query { name: "password", required: true, description: "The plain password, it is prehashed by the server." },
+    Query { name: "otp", required: false, description: "Authenticator or recovery code, for accounts with two-factor authentication." },
+    Query { name: "decision", required: true, description: "`allow` or `deny`." },
+];
+
+/// Path parameters captured while matching a route.
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    fn get(&self, name: &str) -> &str {
+        self.0
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_default()
+    }
+
+    fn uuid(&self, name: &str) -> Result<Uuid, AuthError> {
+        Uuid::parse_str(self.get(name))
+            .map_err(|_| AuthError::InvalidRequest("Malformed uuid in path.".into()))
+    }
+}
+
+fn match_path(pattern: &'static str, path: &str) -> Option<Params> {
+    let mut params = Vec::new();
+    let mut pattern_segments = pattern.split('/');
+    let mut path_segments = path.split('/');
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (None, None) => return Some(Params(params)),
+            (Some(p), Some(s)) if p.starts_with('{') && p.ends_with('}') && !s.is_empty() => {
+                params.push((&p[1..p.len() - 1], s.to_owned()))
+            }
+            (Some(p), Some(s)) if p == s => {}
+            _ => return None,
+        }
     }
 }
 
@@ -97,11 +655,11 @@ fn remote(req: &Request) -> IpAddr {
         .unwrap_or(req.remote_addr().ip())
 }
 
-fn ping(req: &Request) -> Response {
-    Response::text(format!("Pong! {}", remote(req).to_string()))
+fn ping(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(Response::text(format!("Pong! {}", remote(req))))
 }
 
-fn username_to_uuid(req: &Request) -> Result<Response, AuthError> {
+fn username_to_uuid(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
     let payload: UuidLookupPayload = serde_json::from_reader(body)?;
     let uuid = auth::username_to_uuid(&payload.username)?;
@@ -109,7 +667,7 @@ fn username_to_uuid(req: &Request) -> Result<Response, AuthError> {
     Ok(Response::json(&response))
 }
 
-fn uuid_to_username(req: &Request) -> Result<Response, AuthError> {
+fn uuid_to_username(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
     let payload: UsernameLookupPayload = serde_json::from_reader(body)?;
     let username = auth::uuid_to_username(&payload.uuid)?;
@@ -118,7 +676,7 @@ fn uuid_to_username(req: &Request) -> Result<Response, AuthError> {
 }
 
 
-fn eth_to_user(req: &Request) -> Result<Response, AuthError> {
+fn eth_to_user(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
     let payload: EthLookupPayload = serde_json::from_reader(body)?;
     let uuid = auth::eth_to_uuid(&payload.ethaddr)?;
@@ -128,7 +686,7 @@ fn eth_to_user(req: &Request) -> Result<Response, AuthError> {
     Ok(Response::json(&response))
 }
 
-fn eth_active(req: &Request) -> Result<Response, AuthError> {
+fn eth_active(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
     let payload: EthActivePayload = serde_json::from_reader(body)?;
     auth::eth_active(&payload.ethaddr )?;
@@ -136,7 +694,7 @@ fn eth_active(req: &Request) -> Result<Response, AuthError> {
 }
 
 
-fn change_pass(req: &Request) -> Result<Response, AuthError> {
+fn change_pass(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
     let payload: ChangePassPayload = serde_json::from_reader(body)?;
     verify_password(&payload.password)?;
@@ -146,9 +704,9 @@ fn change_pass(req: &Request) -> Result<Response, AuthError> {
 }
 
 
-fn username_to_info(req: &Request) -> Result<Response, AuthError> {
+fn username_to_info(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
-    let payload: UuidLookupPayload = serde_json::from_reader(body)?;
+    let payload: UserinfoLookupPayload = serde_json::from_reader(body)?;
     let uuid = auth::username_to_uuid(&payload.username)?;
     let ethaddr = auth::username_to_eth(&payload.username)?;
     let response = UserinfoLookupResponse { uuid, ethaddr };
@@ -156,36 +714,36 @@ fn username_to_info(req: &Request) -> Result<Response, AuthError> {
 }
 
 
-fn uuid_to_info(req: &Request) -> Result<Response, AuthError> {
+fn uuid_to_info(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
-    let payload: UsernameLookupPayload = serde_json::from_reader(body)?;
+    let payload: Userinfo2LookupPayload = serde_json::from_reader(body)?;
     let username = auth::uuid_to_username(&payload.uuid)?;
     let ethaddr = auth::uuid_to_eth(&payload.uuid)?;
     let response = Userinfo2LookupResponse { username, ethaddr };
     Ok(Response::json(&response))
 }
 
-fn register(req: &Request) -> Result<Response, AuthError> {
+fn register(req: &Request, _: &Params) -> Result<Response, AuthError> {
     println!("Server register process....");
     let body = req.data().unwrap();
     let payload: RegisterPayload = serde_json::from_reader(body)?;
     verify_username(&payload.username)?;
     verify_ethaddr(&payload.ethaddr)?;   // new verify  -max
-    auth::register(&payload.username, &payload.password, &payload.ethaddr)?;
+    let uuid = auth::register(&payload.username, &payload.password, &payload.ethaddr)?;
     println!("register ok");
-    Ok(Response::text("Ok"))
+    Ok(Response::json(&RegisterResponse { uuid }))
 }
 
-fn generate_token(req: &Request) -> Result<Response, AuthError> {
+fn generate_token(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
     let payload: SignInPayload = serde_json::from_reader(body)?;
     verify_username(&payload.username)?;
-    let token = auth::generate_token(&payload.username, &payload.password)?;
+    let token = auth::generate_token(&payload.username, &payload.password, payload.otp.as_deref())?;
     let response = SignInResponse { token };
     Ok(Response::json(&response))
 }
 
-fn verify(req: &Request) -> Result<Response, AuthError> {
+fn verify(req: &Request, _: &Params) -> Result<Response, AuthError> {
     let body = req.data().unwrap();
     let payload: ValidityCheckPayload = serde_json::from_reader(body)?;
     let uuid = auth::verify(payload.token)?;
@@ -193,45 +751,805 @@ fn verify(req: &Request) -> Result<Response, AuthError> {
     Ok(Response::json(&response))
 }
 
-pub fn start() {
-    let addr = "0.0.0.0:8081";
-    debug!("Starting webserver on {}", addr);
-    println!("ok Starting webserver on {}", addr);
+fn openapi(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(Response::json(&openapi::document()))
+}
+
+fn metrics(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = metrics::render(
+        auth::token_cache_size(),
+        RATELIMITER.tracked_ips() + SEARCH_RATELIMITER.tracked_ips(),
+    );
+    Ok(Response::from_data("text/plain; version=0.0.4", body))
+}
+
+fn health_response(health: HealthResponse) -> Response {
+    let status = match health.status {
+        HealthStatus::Ok => 200,
+        HealthStatus::Fail => 503,
+    };
+    Response::json(&health).with_status_code(status).with_no_cache()
+}
+
+fn healthz(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(health_response(health::liveness()))
+}
+
+fn readyz(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(health_response(health::readiness()))
+}
+
+fn v1_register(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    Ok(register(req, params)?.with_status_code(201))
+}
+
+fn v1_user_by_uuid(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    let info = auth::uuid_to_info(&params.uuid("uuid")?)?;
+    Ok(Response::json(&info))
+}
+
+fn v1_user_by_name(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    let info = auth::username_to_info(params.get("name"))?;
+    Ok(Response::json(&info))
+}
+
+fn v1_user_by_eth(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    let info = auth::eth_to_info(params.get("addr"))?;
+    Ok(Response::json(&info))
+}
+
+fn v1_search(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let prefix = req.get_param("prefix").unwrap_or_default();
+    if prefix.len() < 2 || prefix.len() > 32 || !prefix.chars().all(legal_char) {
+        return Err(AuthError::InvalidRequest(
+            "The search prefix must be between 2 and 32 legal username characters.".into(),
+        ));
+    }
+    let limit = match req.get_param("limit") {
+        Some(limit) => limit
+            .parse()
+            .ok()
+            .filter(|n| (1..=MAX_SEARCH_RESULTS).contains(n))
+            .ok_or_else(|| {
+                AuthError::InvalidRequest(format!(
+                    "The limit must be between 1 and {}.",
+                    MAX_SEARCH_RESULTS
+                ))
+            })?,
+        None => DEFAULT_SEARCH_RESULTS,
+    };
+    let after = req.get_param("after");
+    let results = auth::search_users(&prefix, after.as_deref(), limit)?;
+    Ok(Response::json(&results))
+}
+
+fn v1_set_searchable(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    let uuid = params.uuid("uuid")?;
+    let body = req.data().unwrap();
+    let payload: SearchVisibilityPayload = serde_json::from_reader(body)?;
+    auth::set_searchable(&uuid, &payload.password, payload.searchable)?;
+    Ok(Response::empty_204())
+}
+
+fn verify_email(email: &str) -> Result<(), AuthError> {
+    if mail::valid_address(email) {
+        Ok(())
+    } else {
+        Err(AuthError::InvalidRequest("Invalid email address.".into()))
+    }
+}
+
+fn v1_set_email(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    let uuid = params.uuid("uuid")?;
+    let body = req.data().unwrap();
+    let payload: EmailChangePayload = serde_json::from_reader(body)?;
+    verify_email(&payload.email)?;
+    auth::set_email(&uuid, &payload.password, &payload.email)?;
+    Ok(Response::empty_204().with_status_code(202))
+}
+
+fn v1_verify_email(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let token = req.get_param("token").unwrap_or_default();
+    auth::verify_email(&token)?;
+    Ok(Response::text("Your email address has been verified."))
+}
+
+fn v1_request_password_reset(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: PasswordResetRequestPayload = serde_json::from_reader(body)?;
+    auth::request_password_reset(&payload.login)?;
+    Ok(Response::empty_204().with_status_code(202))
+}
+
+fn v1_reset_password(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: PasswordResetPayload = serde_json::from_reader(body)?;
+    // Clients send a prehash of the password, like on registration.
+    verify_password(&payload.password)?;
+    auth::reset_password(&payload.token, &payload.password)?;
+    Ok(Response::empty_204())
+}
+
+fn verify_batch<T>(values: &[T]) -> Result<(), AuthError> {
+    if values.len() > MAX_BATCH_LOOKUP {
+        Err(AuthError::InvalidRequest(format!(
+            "At most {} values can be looked up at once.",
+            MAX_BATCH_LOOKUP
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn v1_users_by_uuid(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: UuidBatchPayload = serde_json::from_reader(body)?;
+    verify_batch(&payload.uuids)?;
+    let users = auth::uuids_to_info(&payload.uuids)?;
+    Ok(Response::json(&UserBatchResponse { users }))
+}
+
+fn v1_users_by_name(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: UsernameBatchPayload = serde_json::from_reader(body)?;
+    verify_batch(&payload.usernames)?;
+    let users = auth::usernames_to_info(&payload.usernames)?;
+    Ok(Response::json(&UserBatchResponse { users }))
+}
+
+fn v1_users_by_eth(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: EthBatchPayload = serde_json::from_reader(body)?;
+    verify_batch(&payload.ethaddrs)?;
+    let users = auth::eths_to_info(&payload.ethaddrs)?;
+    Ok(Response::json(&UserBatchResponse { users }))
+}
+
+fn v1_eth_active(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    auth::eth_active(params.get("addr"))?;
+    Ok(Response::empty_204())
+}
+
+fn v1_change_pass(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    let uuid = params.uuid("uuid")?;
+    let body = req.data().unwrap();
+    let payload: PasswordChangePayload = serde_json::from_reader(body)?;
+    verify_password(&payload.password)?;
+    auth::change_own_passwd(&uuid, &payload.current_password, &payload.password)?;
+    Ok(Response::empty_204())
+}
+
+fn v1_begin_totp(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    let uuid = params.uuid("uuid")?;
+    let body = req.data().unwrap();
+    let payload: TotpEnrollPayload = serde_json::from_reader(body)?;
+    let (secret, uri) = auth::begin_totp(&uuid, &payload.password)?;
+    Ok(Response::json(&TotpEnrollResponse { secret, uri }))
+}
+
+fn v1_confirm_totp(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    let uuid = params.uuid("uuid")?;
+    let body = req.data().unwrap();
+    let payload: TotpConfirmPayload = serde_json::from_reader(body)?;
+    let recovery_codes = auth::confirm_totp(&uuid, &payload.password, &payload.otp)?;
+    Ok(Response::json(&TotpConfirmResponse { recovery_codes }))
+}
+
+fn admin_reset_totp(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    auth::reset_totp(&params.uuid("uuid")?)?;
+    Ok(Response::empty_204())
+}
+
+fn admin_ban(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: BanPayload = serde_json::from_reader(body)?;
+    auth::set_banned(&params.uuid("uuid")?, true, payload.reason.as_deref())?;
+    Ok(Response::empty_204())
+}
+
+fn admin_unban(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    auth::set_banned(&params.uuid("uuid")?, false, None)?;
+    Ok(Response::empty_204())
+}
+
+fn admin_rename_user(req: &Request, params: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: UsernameChangePayload = serde_json::from_reader(body)?;
+    verify_username(&payload.username)?;
+    auth::rename_user(&params.uuid("uuid")?, &payload.username)?;
+    Ok(Response::empty_204())
+}
+
+fn admin_revoke_tokens(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    auth::revoke_tokens(&params.uuid("uuid")?)?;
+    Ok(Response::empty_204())
+}
+
+fn admin_delete_user(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    auth::delete_user(&params.uuid("uuid")?)?;
+    Ok(Response::empty_204())
+}
+
+fn v1_events(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let after = match req.header("Last-Event-ID").map(str::to_owned).or_else(|| req.get_param("after")) {
+        Some(cursor) => Some(
+            cursor
+                .trim()
+                .parse()
+                .map_err(|_| AuthError::InvalidRequest("The cursor must be a number.".into()))?,
+        ),
+        None => None,
+    };
+    let stream = events::open(after)?;
+    Ok(Response {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".into(), "text/event-stream".into()),
+            ("Cache-Control".into(), "no-cache".into()),
+            // Keeps nginx from buffering the stream.
+            ("X-Accel-Buffering".into(), "no".into()),
+        ],
+        data: rouille::ResponseBody::from_reader(stream),
+        upgrade: None,
+    })
+}
+
+fn admin_create_backup(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(Response::json(&backup::create()?))
+}
+
+fn admin_list_backups(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(Response::json(&backup::list()?))
+}
+
+fn admin_subscribe_webhook(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: WebhookSubscriptionPayload = serde_json::from_reader(body)?;
+    let webhook = webhook::subscribe(&payload.url, &payload.events)?;
+    Ok(Response::json(&webhook))
+}
+
+fn admin_list_webhooks(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    let webhooks = webhook::list()?;
+    Ok(Response::json(&WebhookSubscriptionList { webhooks }))
+}
+
+fn admin_unsubscribe_webhook(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    webhook::unsubscribe(params.get("id"))?;
+    Ok(Response::empty_204())
+}
+
+fn admin_webhook_deliveries(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    let deliveries = webhook::deliveries(params.get("id"))?;
+    Ok(Response::json(&WebhookDeliveryList { deliveries }))
+}
+
+fn admin_register_client(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: OAuthClientPayload = serde_json::from_reader(body)?;
+    let client = oidc::register_client(&payload.name, &payload.redirect_uris, payload.confidential)?;
+    Ok(Response::json(&client))
+}
+
+fn admin_list_clients(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    let clients = oidc::list_clients()?;
+    Ok(Response::json(&OAuthClientList { clients }))
+}
+
+fn admin_delete_client(_: &Request, params: &Params) -> Result<Response, AuthError> {
+    oidc::delete_client(params.get("client_id"))?;
+    Ok(Response::empty_204())
+}
+
+fn oidc_discovery(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(Response::json(&oidc::discovery()))
+}
+
+fn oidc_jwks(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    Ok(Response::json(&oidc::jwks()?))
+}
+
+/// Sends a login page, which must not be framed by other sites.
+fn consent_response(page: String) -> Response {
+    Response::html(page)
+        .with_unique_header("X-Frame-Options", "DENY")
+        .with_unique_header("Content-Security-Policy", "frame-ancestors 'none'")
+        .with_no_cache()
+}
+
+fn oauth_authorize(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    match oidc::authorization_request(|name| req.get_param(name)) {
+        Ok(request) => Ok(consent_response(request.consent_page(None))),
+        Err(AuthorizeError::Redirect(url)) => Ok(Response::redirect_303(url)),
+        Err(AuthorizeError::Show(err)) => Err(err),
+    }
+}
+
+fn form_input(req: &Request) -> Result<Vec<(String, String)>, AuthError> {
+    rouille::input::post::raw_urlencoded_post_input(req)
+        .map_err(|err| AuthError::InvalidRequest(format!("Could not read the form: {}", err)))
+}
+
+fn oauth_authorize_submit(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let form = form_input(req)?;
+    let field = |name: &str| form.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+    let request = match oidc::authorization_request(field) {
+        Ok(request) => request,
+        Err(AuthorizeError::Redirect(url)) => return Ok(Response::redirect_303(url)),
+        Err(AuthorizeError::Show(err)) => return Err(err),
+    };
+    if field("decision").as_deref() != Some("allow") {
+        return Ok(Response::redirect_303(request.denied()));
+    }
+
+    let username = field("username").unwrap_or_default();
+    let password = auth::prehash_password(&field("password").unwrap_or_default())?;
+    let otp = field("otp").filter(|otp| !otp.trim().is_empty());
+    match auth::sign_in(&username, &password, otp.as_deref()) {
+        Ok(uuid) => Ok(Response::redirect_303(request.approve(&uuid)?)),
+        Err(err @ AuthError::InvalidLogin) | Err(err @ AuthError::OtpRequired) | Err(err @ AuthError::InvalidOtp) => {
+            info!("[{}] OAuth sign-in for {} rejected: {}", remote(req), request.client.id, err);
+            Ok(consent_response(request.consent_page(Some(&err.to_string()))).with_status_code(err.status_code()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The RFC 6749 error answer of the token and userinfo endpoints.
+fn oauth_error(err: &OAuthError) -> Response {
+    let response = Response::json(&OAuthErrorResponse {
+        error: err.code().to_owned(),
+        error_description: Some(err.to_string()),
+    })
+    .with_status_code(err.status_code());
+    match err {
+        OAuthError::InvalidToken => response.with_unique_header("WWW-Authenticate", "Bearer error=\"invalid_token\""),
+        OAuthError::InvalidClient => response.with_unique_header("WWW-Authenticate", "Basic"),
+        _ => response,
+    }
+}
+
+/// Client id and secret from an `Authorization: Basic` header.
+fn basic_auth(req: &Request) -> Option<(String, String)> {
+    let encoded = req.header("Authorization")?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    Some((id.to_owned(), secret.to_owned()))
+}
+
+fn bearer(req: &Request) -> Option<&str> {
+    req.header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+fn oauth_token(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let result = match form_input(req) {
+        Ok(form) => oidc::token(&form, basic_auth(req)),
+        Err(err) => Err(OAuthError::InvalidRequest(err.to_string())),
+    };
+    let response = match result {
+        Ok(tokens) => Response::json(&tokens),
+        Err(err) => {
+            info!("[{}] OAuth token request rejected: {}", remote(req), err);
+            oauth_error(&err)
+        }
+    };
+    Ok(response
+        .with_unique_header("Cache-Control", "no-store")
+        .with_unique_header("Pragma", "no-cache"))
+}
+
+fn oauth_device_authorization(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let result = match form_input(req) {
+        Ok(form) => oidc::device_authorization(&form, basic_auth(req)),
+        Err(err) => Err(OAuthError::InvalidRequest(err.to_string())),
+    };
+    Ok(match result {
+        Ok(device) => Response::json(&device).with_unique_header("Cache-Control", "no-store"),
+        Err(err) => oauth_error(&err),
+    })
+}
+
+fn v1_device_authorization(_: &Request, _: &Params) -> Result<Response, AuthError> {
+    let device = device::start(None, &[])?;
+    Ok(Response::json(&device).with_unique_header("Cache-Control", "no-store"))
+}
+
+fn v1_device_token(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let body = req.data().unwrap();
+    let payload: DeviceTokenPayload = serde_json::from_reader(body)?;
+    let response = match device::poll(&payload.device_code, None) {
+        Ok(grant) => Response::json(&SignInResponse {
+            token: auth::issue_token(grant.uuid),
+        }),
+        Err(err) => oauth_error(&err),
+    };
+    Ok(response.with_unique_header("Cache-Control", "no-store"))
+}
+
+fn device_page(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let user_code = req.get_param("user_code").unwrap_or_default();
+    let device = device::pending(&user_code)?;
+    Ok(consent_response(device::page(device.as_ref(), &user_code, None)))
+}
 
-    start_server(addr, move |request| {
-        debug!("[{}] -> {}", remote(request), request.url());
-
-        let path = request.raw_url().split('?').next().unwrap();
-
-        let response = match (request.method(), path) {
-            ("GET", "/ping") => ping(request),
-            ("POST", path) => {
-                let result = match path {
-                    "/username_to_uuid" => username_to_uuid(request),
-                    "/uuid_to_username" => uuid_to_username(request),
-                    "/eth_to_info" => eth_to_user(request),
-                    "/username_to_info" => username_to_info(request),
-                    "/uuid_to_info" => uuid_to_info(request),
-                    "/eth_active" => eth_active(request),
-                    "/change_pass" => change_pass(request),
-                    "/register" => ratelimit(request, register),
-                    "/generate_token" => ratelimit(request, generate_token),
-                    "/verify" => verify(request),
-                    _ => Ok(Response::empty_404()),
+fn device_submit(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let form = form_input(req)?;
+    let field = |name: &str| form.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+    let user_code = field("user_code").unwrap_or_default();
+    let device = match device::pending(&user_code)? {
+        Some(device) => device,
+        None => {
+            let page = device::page(None, &user_code, Some("That code is unknown or has expired."));
+            return Ok(consent_response(page).with_status_code(400));
+        }
+    };
+    if field("decision").as_deref() != Some("allow") {
+        device.deny()?;
+        return Ok(consent_response(device::done_page(false)));
+    }
+
+    let username = field("username").unwrap_or_default();
+    let password = auth::prehash_password(&field("password").unwrap_or_default())?;
+    let otp = field("otp").filter(|otp| !otp.trim().is_empty());
+    match auth::sign_in(&username, &password, otp.as_deref()) {
+        Ok(uuid) => {
+            device.approve(&uuid)?;
+            Ok(consent_response(device::done_page(true)))
+        }
+        Err(err @ AuthError::InvalidLogin) | Err(err @ AuthError::OtpRequired) | Err(err @ AuthError::InvalidOtp) => {
+            info!("[{}] Device sign-in rejected: {}", remote(req), err);
+            let page = device::page(Some(&device), &user_code, Some(&err.to_string()));
+            Ok(consent_response(page).with_status_code(err.status_code()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn oauth_userinfo(req: &Request, _: &Params) -> Result<Response, AuthError> {
+    let result = bearer(req)
+        .ok_or(OAuthError::InvalidToken)
+        .and_then(oidc::userinfo);
+    Ok(match result {
+        Ok(claims) => Response::json(&claims).with_no_cache(),
+        Err(err) => oauth_error(&err),
+    })
+}
+
+/// Checks the `Authorization: Bearer` header against `AUTH_ADMIN_TOKEN`.
+/// Admin routes are refused outright while the variable is unset.
+fn check_admin(req: &Request) -> Result<(), AuthError> {
+    let expected = env::var("AUTH_ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    match (expected, bearer(req)) {
+        (Some(expected), Some(given)) if verify_slices_are_equal(expected.as_bytes(), given.as_bytes()).is_ok() => Ok(()),
+        _ => Err(AuthError::Unauthorized),
+    }
+}
+
+/// Checks the `Authorization: Bearer` header against the comma separated
+/// `AUTH_EVENT_TOKENS`, falling back to the admin token.
+fn check_event_token(req: &Request) -> Result<(), AuthError> {
+    let tokens = env::var("AUTH_EVENT_TOKENS").unwrap_or_default();
+    let given = bearer(req).unwrap_or_default();
+    let known = tokens
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .fold(false, |found, token| verify_slices_are_equal(token.as_bytes(), given.as_bytes()).is_ok() | found);
+    if known {
+        Ok(())
+    } else {
+        check_admin(req)
+    }
+}
+
+/// Finds and runs the route for `path`, returning the matched route pattern
+/// (used as the metrics label) alongside the response.
+fn dispatch(request: &Request, path: &str) -> (&'static str, Response) {
+    if request.method() == "OPTIONS" {
+        let matching: Vec<&Route> = ROUTES
+            .iter()
+            .filter(|route| match_path(route.path, path).is_some())
+            .collect();
+        return match matching.first() {
+            Some(route) => {
+                let methods: Vec<&str> = matching.iter().map(|route| route.method).collect();
+                (route.path, cors::preflight(request, path, &methods))
+            }
+            None => ("unmatched", Response::empty_404()),
+        };
+    }
+
+    let mut allowed = Vec::new();
+    for route in ROUTES {
+        let params = match match_path(route.path, path) {
+            Some(params) => params,
+            None => continue,
+        };
+        if route.method != request.method() {
+            allowed.push(route.method);
+            continue;
+        }
+
+        let limited = route
+            .limit
+            .is_some_and(|limit| !limit.limiter().check(remote(request)));
+        let result = if limited {
+            metrics::RATELIMIT_REJECTIONS.inc(&[route.path]);
+            Err(AuthError::RateLimit)
+        } else if route.api == Api::Admin {
+            check_admin(request).and_then(|()| (route.handler)(request, &params))
+        } else if route.api == Api::Events {
+            check_event_token(request).and_then(|()| (route.handler)(request, &params))
+        } else {
+            (route.handler)(request, &params)
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                info!("[{}:{}] rejected: {}", remote(request), path, err);
+
+                let status = match route.api {
+                    Api::Legacy | Api::Ops | Api::OAuth => err.status_code(),
+                    Api::V1 | Api::Admin | Api::Events => err.resource_status_code(),
                 };
+                let response = Response::text(format!("{}", err))
+                    .with_status_code(status)
+                    .with_unique_header("X-Error-Code", err.code());
+                match err.retry_after() {
+                    Some(secs) => response.with_unique_header("Retry-After", secs.to_string()),
+                    None => response,
+                }
+            }
+        };
+        return (route.path, response);
+    }
+
+    let response = if allowed.is_empty() {
+        Response::empty_404()
+    } else {
+        Response::text("Method not allowed.")
+            .with_status_code(405)
+            .with_unique_header("Allow", allowed.join(", "))
+    };
+    ("unmatched", response)
+}
+
+/// Address to listen on, overridable with `AUTH_ADDR`.
+pub fn listen_addr() -> String {
+    env::var("AUTH_ADDR").unwrap_or_else(|_| "0.0.0.0:8081".into())
+}
+
+/// Runs one request through routing, metrics and CORS.
+fn handle(request: &Request) -> Response {
+    let _in_flight = shutdown::InFlight::begin();
+    debug!("[{}] -> {}", remote(request), request.url());
 
-                match result {
-                    Ok(response) => response,
-                    Err(err) => {
-                        info!("[{}:{}] rejected: {}", remote(request), path, err);
+    let start = Instant::now();
+    let path = request.raw_url().split('?').next().unwrap();
+    let (route, response) = dispatch(request, path);
 
-                        Response::text(format!("{}", err)).with_status_code(err.status_code())
+    let status = response.status_code.to_string();
+    let labels = [route, request.method(), status.as_str()];
+    metrics::HTTP_REQUESTS.inc(&labels);
+    metrics::HTTP_DURATION.observe(&labels, start.elapsed());
+
+    if request.method() == "OPTIONS" {
+        // Preflight responses carry their CORS headers already.
+        response
+    } else {
+        cors::apply(request, path, response)
+    }
+}
+
+fn plain(status: u16, text: &'static str) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::from(text));
+    *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    response
+}
+
+/// Converts a handler response, streaming bodies of unknown length chunk by
+/// chunk. Runs on the blocking pool since handler bodies are plain readers.
+fn into_hyper(response: Response, runtime: &Handle) -> hyper::Response<Body> {
+    let (mut reader, size) = response.data.into_reader_and_size();
+    let body = match size {
+        Some(size) => {
+            let mut data = Vec::with_capacity(size);
+            match reader.read_to_end(&mut data) {
+                Ok(_) => Body::from(data),
+                Err(err) => {
+                    error!("Failed to read response body: {}", err);
+                    return plain(500, "Internal server error.");
+                }
+            }
+        }
+        None => {
+            let (mut sender, body) = Body::channel();
+            let handle = runtime.clone();
+            runtime.spawn_blocking(move || {
+                let mut chunk = [0; 8192];
+                loop {
+                    match reader.read(&mut chunk) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => {
+                            let data = hyper::body::Bytes::copy_from_slice(&chunk[..n]);
+                            // Stops once the client has gone away.
+                            if handle.block_on(sender.send_data(data)).is_err() {
+                                return;
+                            }
+                        }
                     }
                 }
+            });
+            body
+        }
+    };
+
+    let mut converted = hyper::Response::new(body);
+    *converted.status_mut() =
+        StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    for (name, value) in response.headers {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                converted.headers_mut().append(name, value);
             }
-            _ => Response::empty_404(),
-        };
+            _ => warn!("Dropping malformed response header {}", name),
+        }
+    }
+    converted
+}
 
-        response.with_unique_header("Access-Control-Allow-Origin", "*")
+/// The answer when every handler thread is busy, like a full hash queue.
+fn overloaded() -> hyper::Response<Body> {
+    let err = AuthError::Overloaded;
+    let mut response = hyper::Response::new(Body::from(err.to_string()));
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    response.headers_mut().insert("x-error-code", HeaderValue::from_static(err.code()));
+    if let Some(secs) = err.retry_after() {
+        response.headers_mut().insert(hyper::header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+    response
+}
+
+/// Reads the request, then runs the (blocking) handlers on the blocking pool
+/// if one of its `handlers` permits is free.
+async fn serve(
+    remote: SocketAddr,
+    req: hyper::Request<Body>,
+    handlers: Arc<Semaphore>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let too_large = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .is_some_and(|len| len > MAX_BODY);
+    if too_large {
+        return Ok(plain(413, "Request body too large."));
+    }
+
+    let method = req.method().as_str().to_owned();
+    let url = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| "/".into());
+    let headers = req
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_owned(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() <= MAX_BODY => body.to_vec(),
+        Ok(_) => return Ok(plain(413, "Request body too large.")),
+        Err(err) => {
+            debug!("[{}] failed to read request body: {}", remote, err);
+            return Ok(plain(400, "Could not read the request body."));
+        }
+    };
+
+    // Tokio queues blocking tasks without bound once its threads are busy.
+    let permit = match handlers.try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            metrics::OVERLOAD_REJECTIONS.inc(&[]);
+            return Ok(overloaded());
+        }
+    };
+    let runtime = Handle::current();
+    let response = task::spawn_blocking(move || {
+        let request = Request::fake_http_from(remote, method, url, headers, body);
+        let response = into_hyper(handle(&request), &runtime);
+        drop(permit);
+        response
+    })
+    .await;
+    Ok(response.unwrap_or_else(|err| {
+        error!("Request handler failed: {}", err);
+        plain(500, "Internal server error.")
+    }))
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    env::var(name).ok().and_then(|v| v.parse().ok()).filter(|&n| n > 0)
+}
+
+/// Serves requests until a shutdown is requested, then stops accepting
+/// connections and waits for the requests in flight. Returns `false` if they
+/// did not finish within the shutdown deadline.
+pub fn start() -> bool {
+    let addr = listen_addr();
+    debug!("Starting webserver on {}", addr);
+    println!("ok Starting webserver on {}", addr);
+
+    let blocking_threads = env_usize("AUTH_BLOCKING_THREADS").unwrap_or(DEFAULT_BLOCKING_THREADS);
+    let handlers = Arc::new(Semaphore::new(blocking_threads));
+    // Open event streams keep their thread after giving back the permit, so
+    // they get threads of their own.
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder
+        .enable_all()
+        .max_blocking_threads(blocking_threads + events::max_streams());
+    if let Some(workers) = env_usize("AUTH_WORKER_THREADS") {
+        builder.worker_threads(workers);
+    }
+    let runtime = builder.build().expect("Failed to start async runtime");
+
+    let drained = runtime.block_on(async {
+        let addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .expect("Invalid listen address");
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let remote = conn.remote_addr();
+            let handlers = handlers.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| serve(remote, req, handlers.clone()))) }
+        });
+        let server = hyper::Server::try_bind(&addr)
+            .expect("Failed to start webserver")
+            .serve(make_service)
+            .with_graceful_shutdown(async {
+                while !shutdown::requested() {
+                    tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+                }
+                info!("Shutting down, draining in-flight requests");
+            });
+
+        let server = tokio::spawn(server);
+        while !shutdown::requested() {
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+        match tokio::time::timeout(shutdown::timeout(), server).await {
+            Ok(Ok(Ok(()))) => true,
+            Ok(Ok(Err(err))) => {
+                error!("Webserver failed: {}", err);
+                true
+            }
+            Ok(Err(err)) => {
+                error!("Webserver task failed: {}", err);
+                true
+            }
+            Err(_) => {
+                warn!(
+                    "Shutdown deadline passed with {} requests still in flight",
+                    shutdown::in_flight()
+                );
+                false
+            }
+        }
     });
+    // Handlers still running past the deadline are abandoned.
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    drained
 }
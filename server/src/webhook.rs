@@ -0,0 +1,373 @@
+//! Webhooks for account lifecycle events, so game servers and bots don't have
+//! to poll for changes.
+//!
+//! Events are written to a delivery queue in the database, one row per
+//! subscribed webhook, right after the change that caused them. A worker
+//! thread posts due deliveries and retries failures with exponential
+//! backoff, so queued events survive restarts. Delivery is at least once:
+//! receivers should drop repeated event ids.
+//!
+//! Every request carries `X-Webhook-Signature: t=<unix time>,v1=<hex>`, an
+//! HMAC-SHA256 of `<unix time>.<body>` keyed with the webhook's secret.
+
+use crate::auth::{self, AuthError};
+use crate::encoding::hex;
+use auth_common::{
+    WebhookDeliveryInfo, WebhookDeliveryStatus, WebhookEvent, WebhookNotification, WebhookSubscriptionResponse,
+};
+use lazy_static::lazy_static;
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+use ring::hmac;
+use rusqlite::params;
+use std::env;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+use uuid::Uuid;
+
+/// Delay before the first retry, doubled for each further one. Overridable
+/// with `AUTH_WEBHOOK_RETRY_SECS`.
+const DEFAULT_RETRY_SECS: i64 = 30;
+/// Longest wait between two attempts.
+const MAX_RETRY_DELAY_SECS: i64 = 60 * 60;
+/// Attempts before a delivery is given up, overridable with `AUTH_WEBHOOK_MAX_ATTEMPTS`.
+const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+/// How long finished deliveries stay in the log.
+const LOG_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+/// Deliveries the worker picks up per round.
+const BATCH_SIZE: i64 = 20;
+/// How often the worker looks for due retries when nothing new was queued.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How many log entries `deliveries` returns.
+const LOG_PAGE: i64 = 100;
+
+lazy_static! {
+    /// Set when an event was queued, to wake the worker early.
+    static ref QUEUED: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn max_attempts() -> u32 {
+    env_number("AUTH_WEBHOOK_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS).max(1)
+}
+
+/// Seconds to wait after the `attempts`th failed attempt.
+fn retry_delay(attempts: u32) -> i64 {
+    let base = env_number("AUTH_WEBHOOK_RETRY_SECS", DEFAULT_RETRY_SECS).max(1);
+    base.saturating_mul(1 << attempts.saturating_sub(1).min(20)).min(MAX_RETRY_DELAY_SECS)
+}
+
+/// An absolute http or https URL with a host and without credentials.
+fn valid_url(url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(parsed) => {
+            matches!(parsed.scheme(), "http" | "https")
+                && parsed.host().is_some()
+                && parsed.username().is_empty()
+                && parsed.password().is_none()
+                && !url.chars().any(char::is_whitespace)
+        }
+        Err(_) => false,
+    }
+}
+
+fn row_to_subscription(row: &rusqlite::Row) -> rusqlite::Result<WebhookSubscriptionResponse> {
+    let events: String = row.get(2)?;
+    Ok(WebhookSubscriptionResponse {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        events: events.split(' ').filter_map(WebhookEvent::parse).collect(),
+        secret: None,
+        created: row.get(3)?,
+    })
+}
+
+/// Adds a subscription. Its signing secret is only returned here.
+pub fn subscribe(url: &str, events: &[WebhookEvent]) -> Result<WebhookSubscriptionResponse, AuthError> {
+    if !valid_url(url) {
+        return Err(AuthError::InvalidRequest(format!("Invalid webhook URL '{}'. Use http or https.", url)));
+    }
+    if events.is_empty() {
+        return Err(AuthError::InvalidRequest("Subscribe to at least one event.".into()));
+    }
+    let mut names: Vec<&str> = events.iter().map(|event| event.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let id = hex(&rand::random::<u128>().to_le_bytes());
+    let secret = hex(&rand::random::<[u8; 32]>());
+    let created = auth::now_secs();
+    auth::db()?.execute(
+        "INSERT INTO webhooks (id, url, events, secret, created) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![id, url, names.join(" "), secret, created],
+    )?;
+    Ok(WebhookSubscriptionResponse {
+        id,
+        url: url.to_owned(),
+        events: names.into_iter().filter_map(WebhookEvent::parse).collect(),
+        secret: Some(secret),
+        created,
+    })
+}
+
+pub fn list() -> Result<Vec<WebhookSubscriptionResponse>, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare("SELECT id, url, events, created FROM webhooks ORDER BY created")?;
+    let webhooks = stmt
+        .query_map(params![], row_to_subscription)?
+        .filter_map(|s| s.ok())
+        .collect();
+    Ok(webhooks)
+}
+
+/// Removes a subscription along with its queue and delivery log.
+pub fn unsubscribe(id: &str) -> Result<(), AuthError> {
+    let mut db = auth::db()?;
+    let tx = db.transaction()?;
+    let deleted = tx.execute("DELETE FROM webhooks WHERE id == ?1", params![id])?;
+    tx.execute("DELETE FROM webhook_deliveries WHERE webhook_id == ?1", params![id])?;
+    tx.commit()?;
+    if deleted == 0 {
+        return Err(AuthError::WebhookDoesNotExist);
+    }
+    Ok(())
+}
+
+fn row_to_delivery(row: &rusqlite::Row) -> rusqlite::Result<Option<WebhookDeliveryInfo>> {
+    let event_id: String = row.get(1)?;
+    let event: String = row.get(2)?;
+    let status = match row.get::<_, String>(3)?.as_str() {
+        "delivered" => WebhookDeliveryStatus::Delivered,
+        "failed" => WebhookDeliveryStatus::Failed,
+        _ => WebhookDeliveryStatus::Pending,
+    };
+    let (event_id, event) = match (Uuid::parse_str(&event_id), WebhookEvent::parse(&event)) {
+        (Ok(event_id), Some(event)) => (event_id, event),
+        _ => return Ok(None),
+    };
+    Ok(Some(WebhookDeliveryInfo {
+        id: row.get(0)?,
+        event_id,
+        event,
+        status,
+        attempts: row.get(4)?,
+        last_status_code: row.get(5)?,
+        last_error: row.get(6)?,
+        created: row.get(7)?,
+        next_attempt: if status == WebhookDeliveryStatus::Pending { Some(row.get(8)?) } else { None },
+        delivered: row.get(9)?,
+    }))
+}
+
+/// The latest deliveries of a webhook, newest first.
+pub fn deliveries(id: &str) -> Result<Vec<WebhookDeliveryInfo>, AuthError> {
+    let db = auth::db()?;
+    let exists = db.prepare("SELECT id FROM webhooks WHERE id == ?1")?.exists(params![id])?;
+    if !exists {
+        return Err(AuthError::WebhookDoesNotExist);
+    }
+    let mut stmt = db.prepare(
+        "SELECT id, event_id, event, status, attempts, last_status_code, last_error, created, next_attempt, delivered
+         FROM webhook_deliveries WHERE webhook_id == ?1 ORDER BY id DESC LIMIT ?2",
+    )?;
+    let deliveries = stmt
+        .query_map(params![id, LOG_PAGE], row_to_delivery)?
+        .filter_map(|s| s.ok())
+        .flatten()
+        .collect();
+    Ok(deliveries)
+}
+
+/// Queues `event` about the account `uuid` for every webhook subscribed to
+/// it. Failures are logged rather than returned, since the change the event
+/// reports has already happened.
+pub fn emit(event: WebhookEvent, uuid: &Uuid) {
+    if let Err(err) = enqueue(event, uuid) {
+        log::warn!("Could not queue {} webhooks for {}: {}", event.as_str(), uuid, err);
+    }
+}
+
+fn enqueue(event: WebhookEvent, uuid: &Uuid) -> Result<(), AuthError> {
+    let db = auth::db()?;
+    let subscribers: Vec<String> = db
+        .prepare("SELECT id, events FROM webhooks")?
+        .query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .filter_map(|s| s.ok())
+        .filter(|(_, events)| events.split(' ').any(|name| name == event.as_str()))
+        .map(|(id, _)| id)
+        .collect();
+    if subscribers.is_empty() {
+        return Ok(());
+    }
+
+    let now = auth::now_secs();
+    let notification = WebhookNotification {
+        id: Uuid::new_v4(),
+        event,
+        created: now,
+        user: auth::uuid_to_info(uuid)?,
+    };
+    let body = serde_json::to_string(&notification)?;
+    for webhook_id in subscribers {
+        db.execute(
+            "INSERT INTO webhook_deliveries (webhook_id, event_id, event, body, next_attempt, created)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            params![webhook_id, notification.id.to_string(), event.as_str(), body, now],
+        )?;
+    }
+
+    let (queued, wake) = &*QUEUED;
+    *queued.lock().unwrap() = true;
+    wake.notify_one();
+    Ok(())
+}
+
+/// The `X-Webhook-Signature` value for `body` sent at `timestamp`.
+fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes());
+    format!("t={},v1={}", timestamp, hex(tag.as_ref()))
+}
+
+struct Delivery {
+    id: i64,
+    event_id: String,
+    event: String,
+    body: String,
+    attempts: u32,
+    url: String,
+    secret: String,
+}
+
+fn due_deliveries() -> Result<Vec<Delivery>, AuthError> {
+    let db = auth::db()?;
+    let mut stmt = db.prepare(
+        "SELECT d.id, d.event_id, d.event, d.body, d.attempts, w.url, w.secret
+         FROM webhook_deliveries d JOIN webhooks w ON w.id == d.webhook_id
+         WHERE d.status == 'pending' AND d.next_attempt <= ?1
+         ORDER BY d.next_attempt, d.id LIMIT ?2",
+    )?;
+    let deliveries = stmt
+        .query_map(params![auth::now_secs(), BATCH_SIZE], |row| {
+            Ok(Delivery {
+                id: row.get(0)?,
+                event_id: row.get(1)?,
+                event: row.get(2)?,
+                body: row.get(3)?,
+                attempts: row.get(4)?,
+                url: row.get(5)?,
+                secret: row.get(6)?,
+            })
+        })?
+        .filter_map(|s| s.ok())
+        .collect();
+    Ok(deliveries)
+}
+
+/// Makes one attempt at `delivery` and records the outcome.
+fn attempt(client: &Client, delivery: &Delivery) -> Result<(), AuthError> {
+    let now = auth::now_secs();
+    let headers = [
+        ("Content-Type", "application/json".to_owned()),
+        ("User-Agent", format!("auth-server/{}", env!("CARGO_PKG_VERSION"))),
+        ("X-Webhook-Event", delivery.event.clone()),
+        ("X-Webhook-Id", delivery.event_id.clone()),
+        ("X-Webhook-Signature", signature(&delivery.secret, now, &delivery.body)),
+    ];
+    let result = post(client, &delivery.url, &headers, &delivery.body);
+    let attempts = delivery.attempts + 1;
+    let (code, error) = match &result {
+        Ok(code) if (200..300).contains(code) => (Some(*code), None),
+        Ok(code) => (Some(*code), Some(format!("answered {}", code))),
+        Err(err) => (None, Some(err.clone())),
+    };
+
+    let db = auth::db()?;
+    match error {
+        None => {
+            db.execute(
+                "UPDATE webhook_deliveries SET status = 'delivered', attempts = ?2, last_status_code = ?3,
+                 last_error = NULL, delivered = ?4 WHERE id == ?1",
+                params![delivery.id, attempts, code, now],
+            )?;
+        }
+        Some(error) if attempts >= max_attempts() => {
+            log::warn!("Giving up on webhook delivery {} to {}: {}", delivery.id, delivery.url, error);
+            db.execute(
+                "UPDATE webhook_deliveries SET status = 'failed', attempts = ?2, last_status_code = ?3,
+                 last_error = ?4 WHERE id == ?1",
+                params![delivery.id, attempts, code, error],
+            )?;
+        }
+        Some(error) => {
+            log::info!("Webhook delivery {} to {} failed, will retry: {}", delivery.id, delivery.url, error);
+            db.execute(
+                "UPDATE webhook_deliveries SET attempts = ?2, last_status_code = ?3, last_error = ?4,
+                 next_attempt = ?5 WHERE id == ?1",
+                params![delivery.id, attempts, code, error, now + retry_delay(attempts)],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn purge_log() -> Result<usize, AuthError> {
+    Ok(auth::db()?.execute(
+        "DELETE FROM webhook_deliveries WHERE status != 'pending' AND created < ?1",
+        params![auth::now_secs() - LOG_RETENTION_SECS],
+    )?)
+}
+
+/// Starts the thread that works through the delivery queue, including what
+/// was left over from the previous run.
+pub fn start_worker() {
+    // Redirects are not followed, the answer to the delivery itself counts.
+    let client = Client::builder().timeout(TIMEOUT).redirect(Policy::none()).build();
+    let client = match client {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("Could not start the webhook worker: {}", err);
+            return;
+        }
+    };
+    thread::spawn(move || loop {
+        match due_deliveries() {
+            Ok(deliveries) if !deliveries.is_empty() => {
+                for delivery in &deliveries {
+                    if let Err(err) = attempt(&client, delivery) {
+                        log::warn!("Could not record webhook delivery {}: {}", delivery.id, err);
+                    }
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(err) => log::warn!("Could not read the webhook queue: {}", err),
+        }
+        if let Err(err) = purge_log() {
+            log::warn!("Could not purge the webhook delivery log: {}", err);
+        }
+
+        let (queued, wake) = &*QUEUED;
+        let mut queued = queued.lock().unwrap();
+        if !*queued {
+            queued = wake.wait_timeout(queued, POLL_INTERVAL).unwrap().0;
+        }
+        *queued = false;
+    });
+}
+
+/// Posts `body` to `url` and returns the status code of the answer.
+fn post(client: &Client, url: &str, headers: &[(&str, String)], body: &str) -> Result<u16, String> {
+    let mut request = client.post(url).body(body.to_owned());
+    for (name, value) in headers {
+        request = request.header(*name, value.as_str());
+    }
+    let response = request.send().map_err(|e| e.to_string())?;
+    Ok(response.status().as_u16())
+}
@@ -0,0 +1,169 @@
+//! `auth-admin`, managing accounts in the database directly.
+
+mod common;
+
+use common::{prehash, temp_file, TestServer};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn admin(db: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_auth-admin"))
+        .arg("--db")
+        .arg(db)
+        .args(args)
+        .output()
+        .expect("failed to run auth-admin")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn admin_json(db: &Path, args: &[&str]) -> Value {
+    let mut with_json = vec!["--json"];
+    with_json.extend_from_slice(args);
+    let output = admin(db, &with_json);
+    assert!(output.status.success(), "{}", stderr(&output));
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn sign_in(server: &TestServer, username: &str, password: &str) -> u16 {
+    Client::new()
+        .post(&server.endpoint("v1/tokens"))
+        .json(&json!({ "username": username, "password": password }))
+        .send()
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+#[test]
+fn accounts_are_listed_found_and_shown() {
+    let mut server = TestServer::start();
+    let alice = server.register("Alice", 1);
+    server.register("bob", 2);
+    server.register("alina", 3);
+    server.terminate();
+
+    let output = admin(&server.db, &["list"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let table = stdout(&output);
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("UUID"));
+    assert!(lines[1].contains("Alice") && lines[2].contains("alina") && lines[3].contains("bob"));
+
+    let page = admin_json(&server.db, &["list", "--after", "alice", "--limit", "1"]);
+    assert_eq!(page.as_array().unwrap().len(), 1);
+    assert_eq!(page[0]["username"], "alina");
+
+    let found = admin_json(&server.db, &["search", "AL"]);
+    assert_eq!(found.as_array().unwrap().len(), 2);
+    let found = admin_json(&server.db, &["search", "0x0000000000000000000000000000000000000002"]);
+    assert_eq!(found[0]["username"], "bob");
+
+    for key in &[alice.as_str(), "ALICE", "0x0000000000000000000000000000000000000001"] {
+        let account = admin_json(&server.db, &["show", key]);
+        assert_eq!(account["uuid"], alice);
+        assert_eq!(account["username"], "Alice");
+        assert!(account.get("pwhash").is_none());
+    }
+    let output = admin(&server.db, &["show", "nobody"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("does not exist"), "{}", stderr(&output));
+}
+
+#[test]
+fn accounts_are_changed_offline() {
+    let mut server = TestServer::start();
+    let uuid = server.register("player", 1);
+    server.register("other", 2);
+    server.terminate();
+    let db = server.db.clone();
+
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    conn.execute(
+        "INSERT INTO webhooks (id, url, events, secret, created) VALUES ('hook', 'http://127.0.0.1:9/', 'user.activated', 'x', 0)",
+        rusqlite::params![],
+    )
+    .unwrap();
+
+    let output = admin(&db, &["passwd", "player", "--password", "hunter22"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(admin_json(&db, &["set-actived", "player", "1"])["actived"], 1);
+    // Activating offline queues the webhook like activating online does.
+    let queued: i64 = conn
+        .query_row("SELECT count(*) FROM webhook_deliveries WHERE event == 'user.activated'", rusqlite::params![], |row| row.get(0))
+        .unwrap();
+    assert_eq!(queued, 1);
+    drop(conn);
+
+    let output = admin(&db, &["rename", "player", "OTHER"]);
+    assert!(!output.status.success());
+    let output = admin(&db, &["rename", "player", "no spaces"]);
+    assert!(stderr(&output).contains("Illegal character"), "{}", stderr(&output));
+    assert_eq!(admin_json(&db, &["rename", &uuid, "Champion"])["username"], "Champion");
+
+    let banned = admin_json(&db, &["ban", "champion", "--reason", "aimbot"]);
+    assert_eq!((banned["banned"].clone(), banned["ban_reason"].clone()), (json!(true), json!("aimbot")));
+    assert_eq!(admin_json(&db, &["unban", "champion"])["banned"], false);
+
+    assert!(!admin(&db, &["delete", "other"]).status.success());
+    assert!(admin(&db, &["delete", "other", "--yes"]).status.success());
+    assert!(!admin(&db, &["show", "other"]).status.success());
+
+    server.start_again();
+    assert_eq!(sign_in(&server, "champion", "123456"), 400);
+    assert_eq!(sign_in(&server, "champion", &prehash("hunter22")), 200);
+    assert_eq!(sign_in(&server, "other", "123456"), 400);
+}
+
+#[test]
+fn changes_wait_for_the_server_to_stop() {
+    let mut server = TestServer::start();
+    server.register("player", 1);
+
+    assert_eq!(admin_json(&server.db, &["show", "player"])["username"], "player");
+    let output = admin(&server.db, &["ban", "player"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("in use by a running server"), "{}", stderr(&output));
+
+    server.terminate();
+    assert_eq!(admin_json(&server.db, &["ban", "player"])["banned"], true);
+}
+
+#[test]
+fn checks_find_damaged_accounts() {
+    let mut server = TestServer::start();
+    server.register("player", 1);
+    server.terminate();
+
+    let output = admin(&server.db, &["check"]);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).lines().all(|line| line.contains(" ok ") || line.ends_with(" ok")));
+
+    let conn = rusqlite::Connection::open(&server.db).unwrap();
+    conn.execute_batch(
+        "UPDATE users SET username = 'someone' WHERE username = 'player';
+         INSERT INTO recovery_codes VALUES ('0123456789abcdef0123456789abcdef', 'x');",
+    )
+    .unwrap();
+    drop(conn);
+
+    let output = admin(&server.db, &["--json", "check"]);
+    assert!(!output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["status"], "fail");
+    assert_eq!(report["checks"]["integrity"]["status"], "ok");
+    assert_eq!(report["checks"]["usernames"]["status"], "fail");
+    assert_eq!(report["checks"]["orphans"]["detail"], "rows of deleted accounts: 1 in recovery_codes");
+
+    let output = admin(&temp_file("missing/dir/auth.db"), &["check"]);
+    assert!(stderr(&output).contains("not a usable database path"), "{}", stderr(&output));
+}
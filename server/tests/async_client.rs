@@ -0,0 +1,76 @@
+//! `authc::AsyncAuthClient`, which shares its requests with the blocking
+//! client.
+
+mod common;
+
+use authc::{AccountEvent, ApiVersion, AsyncAuthClient, AuthClientError, StreamEvent};
+use common::TestServer;
+use std::future::Future;
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio02::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[test]
+fn accounts_are_registered_and_looked_up() {
+    let server = TestServer::start();
+    for api in [ApiVersion::Legacy, ApiVersion::V1] {
+        let client = AsyncAuthClient::new(&server.endpoint("")).unwrap().with_api_version(api);
+        let username = format!("player{:?}", api);
+        let ethaddr = format!("0x{:040x}", api as u8 + 1);
+        block_on(async {
+            client.register(&username, "hunter22", &ethaddr).await.unwrap();
+            let uuid = client.username_to_uuid(&username).await.unwrap();
+            assert_eq!(client.uuid_to_username(uuid).await.unwrap(), username);
+
+            let token = client.sign_in(&username, "hunter22").await.unwrap();
+            assert_eq!(client.validate(token).await.unwrap(), uuid);
+            assert!(matches!(
+                client.sign_in(&username, "wrong").await,
+                Err(AuthClientError::ServerError(400, _))
+            ));
+
+            let found = client.usernames_to_uuids(&[username.as_str(), "nobody"]).await.unwrap();
+            assert_eq!(found[&username], Some(uuid));
+            assert_eq!(found["nobody"], None);
+            let names = client.uuids_to_usernames(&[uuid]).await.unwrap();
+            assert_eq!(names[&uuid].as_deref(), Some(username.as_str()));
+        });
+    }
+}
+
+#[test]
+fn events_are_streamed() {
+    let server = TestServer::start_with(&[("AUTH_ADMIN_TOKEN", "admin"), ("AUTH_EVENT_TOKENS", "game-server")]);
+    let uuid = server.register("player", 1);
+    let client = AsyncAuthClient::new(&server.endpoint("")).unwrap();
+    assert!(matches!(
+        block_on(client.subscribe_events("nope", None, |_| true)),
+        Err(AuthClientError::ServerError(401, _))
+    ));
+
+    let status = reqwest::blocking::Client::new()
+        .delete(&server.endpoint(&format!("admin/users/{}/tokens", uuid)))
+        .bearer_auth("admin")
+        .send()
+        .unwrap()
+        .status();
+    assert_eq!(status.as_u16(), 204);
+
+    let mut seen = Vec::new();
+    let last = block_on(client.subscribe_events("game-server", Some(0), |event| {
+        seen.push(event);
+        false
+    }))
+    .unwrap();
+    assert_eq!(last, Some(1));
+    match &seen[0] {
+        StreamEvent::Account(record) => assert!(matches!(record.event, AccountEvent::SessionRevoked { .. })),
+        other => panic!("unexpected {:?}", other),
+    }
+}
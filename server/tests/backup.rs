@@ -0,0 +1,119 @@
+//! Online snapshots, their rotation, and restoring them offline.
+
+mod common;
+
+use common::{run_offline_with, temp_file, TestServer};
+use reqwest::blocking::Client;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+const ADMIN_TOKEN: &str = "backup-admin";
+
+fn backup_dir(name: &str) -> PathBuf {
+    let dir = temp_file(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn admin(server: &TestServer, method: reqwest::Method, path: &str) -> Value {
+    let resp = Client::new()
+        .request(method, &server.endpoint(path))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    resp.json().unwrap()
+}
+
+fn names(list: &Value) -> Vec<String> {
+    list["backups"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|backup| backup["name"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+fn user_status(server: &TestServer, username: &str) -> u16 {
+    Client::new()
+        .get(&server.endpoint(&format!("v1/users/by-name/{}", username)))
+        .send()
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn snapshots_are_rotated_and_restored() {
+    let dir = backup_dir("snapshots");
+    let dir_str = dir.to_str().unwrap().to_owned();
+    let env = [
+        ("AUTH_ADMIN_TOKEN", ADMIN_TOKEN),
+        ("AUTH_BACKUP_DIR", dir_str.as_str()),
+        ("AUTH_BACKUP_KEEP", "2"),
+    ];
+    let mut server = TestServer::start_with(&env);
+
+    server.register("alice", 1);
+    let first = admin(&server, reqwest::Method::POST, "admin/backups");
+    let first = first["name"].as_str().unwrap().to_owned();
+    assert!(first.starts_with("auth-") && first.ends_with("Z.db"));
+    server.register("bob", 2);
+    admin(&server, reqwest::Method::POST, "admin/backups");
+    let latest = admin(&server, reqwest::Method::POST, "admin/backups");
+    assert!(latest["size"].as_u64().unwrap() > 0);
+    let latest = latest["name"].as_str().unwrap().to_owned();
+
+    let listed = names(&admin(&server, reqwest::Method::GET, "admin/backups"));
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0], latest);
+    assert!(!listed.contains(&first));
+    assert!(!dir.join(&first).exists());
+
+    server.register("carol", 3);
+    let output = run_offline_with(&server.db, &env, &["--restore", &latest]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("in use by a running server"), "{}", stderr(&output));
+
+    server.terminate();
+    let output = run_offline_with(&server.db, &env, &["--restore", &latest]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The replaced database was saved, without rotating anything out.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+    server.start_again();
+    assert_eq!(user_status(&server, "bob"), 200);
+    assert_eq!(user_status(&server, "carol"), 404);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn damaged_snapshots_are_not_restored() {
+    let dir = backup_dir("damaged");
+    let dir_str = dir.to_str().unwrap().to_owned();
+    let env = [("AUTH_BACKUP_DIR", dir_str.as_str())];
+    let mut server = TestServer::start_with(&env);
+    server.register("alice", 1);
+
+    // Snapshots can be taken from the command line while the server runs.
+    let output = run_offline_with(&server.db, &env, &["--backup"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let snapshot = String::from_utf8(output.stdout).unwrap();
+    assert!(Path::new(snapshot.trim()).is_file());
+
+    server.terminate();
+    let bogus = dir.join("auth-bogus.db");
+    std::fs::write(&bogus, vec![7u8; 4096]).unwrap();
+    let output = run_offline_with(&server.db, &env, &["--restore", "auth-bogus.db"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Backup error"), "{}", stderr(&output));
+
+    let output = run_offline_with(&server.db, &env, &["--restore", "auth-missing.db"]);
+    assert!(stderr(&output).contains("There is no snapshot"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
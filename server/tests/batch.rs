@@ -0,0 +1,70 @@
+mod common;
+
+use common::TestServer;
+use serde_json::{json, Value};
+
+fn register(client: &reqwest::blocking::Client, server: &TestServer, username: &str, ethaddr: &str) {
+    let resp = client
+        .post(&server.endpoint("v1/users"))
+        .json(&json!({ "username": username, "password": "123456", "ethaddr": ethaddr }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 201);
+}
+
+fn lookup(client: &reqwest::blocking::Client, server: &TestServer, path: &str, body: Value) -> (u16, Value) {
+    let resp = client.post(&server.endpoint(path)).json(&body).send().unwrap();
+    let status = resp.status().as_u16();
+    (status, resp.json().unwrap_or(Value::Null))
+}
+
+#[test]
+fn batch_lookups_mark_missing_accounts() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+    register(&client, &server, "Alice", "0x1111111111111111111111111111111111111111");
+    register(&client, &server, "bob", "0x2222222222222222222222222222222222222222");
+
+    let (status, body) = lookup(
+        &client,
+        &server,
+        "v1/users/batch/by-name",
+        json!({ "usernames": ["alice", "BOB", "carol"] }),
+    );
+    assert_eq!(status, 200);
+    let users = &body["users"];
+    assert_eq!(users["alice"]["username"], "Alice");
+    assert_eq!(users["BOB"]["username"], "bob");
+    assert!(users["carol"].is_null());
+    assert_eq!(users.as_object().unwrap().len(), 3);
+
+    let alice = users["alice"]["uuid"].as_str().unwrap().to_owned();
+    let unknown = "00000000-0000-0000-0000-000000000000";
+    let (status, body) = lookup(
+        &client,
+        &server,
+        "v1/users/batch/by-uuid",
+        json!({ "uuids": [alice, unknown] }),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(body["users"][alice.as_str()]["username"], "Alice");
+    assert!(body["users"][unknown].is_null());
+
+    let (status, body) = lookup(
+        &client,
+        &server,
+        "v1/users/batch/by-eth",
+        json!({ "ethaddrs": ["0x2222222222222222222222222222222222222222"] }),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(body["users"]["0x2222222222222222222222222222222222222222"]["username"], "bob");
+}
+
+#[test]
+fn batch_lookups_are_bounded() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+    let usernames: Vec<String> = (0..=auth_common::MAX_BATCH_LOOKUP).map(|i| format!("user{}", i)).collect();
+    let (status, _) = lookup(&client, &server, "v1/users/batch/by-name", json!({ "usernames": usernames }));
+    assert_eq!(status, 400);
+}
@@ -0,0 +1,120 @@
+//! The lookup cache of `authc`: answers are reused until they expire, are
+//! evicted, invalidated or overtaken by an account event.
+
+mod common;
+
+use authc::{AccountEvent, AuthClient, ClientBuilder, StreamEvent};
+use common::TestServer;
+use reqwest::blocking::Client;
+use serde_json::json;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+const ADMIN_TOKEN: &str = "cache-admin";
+const EVENT_TOKEN: &str = "game-server-1";
+const ALICE_ETH: &str = "0x00000000000000000000000000000000000000a1";
+const BOB_ETH: &str = "0x00000000000000000000000000000000000000b0";
+
+fn start() -> TestServer {
+    TestServer::start_with(&[("AUTH_ADMIN_TOKEN", ADMIN_TOKEN), ("AUTH_EVENT_TOKENS", EVENT_TOKEN)])
+}
+
+fn cached(server: &TestServer, capacity: usize) -> AuthClient {
+    ClientBuilder::new(&server.endpoint(""))
+        .cache_capacity(capacity)
+        .build()
+        .unwrap()
+}
+
+fn rename(server: &TestServer, uuid: authc::Uuid, username: &str) {
+    let status = Client::new()
+        .put(&server.endpoint(&format!("admin/users/{}/username", uuid)))
+        .bearer_auth(ADMIN_TOKEN)
+        .json(&json!({ "username": username }))
+        .send()
+        .unwrap()
+        .status();
+    assert_eq!(status.as_u16(), 204);
+}
+
+#[test]
+fn answers_are_reused_until_invalidated_or_evicted() {
+    let server = start();
+    let uncached = AuthClient::new(&server.endpoint("")).unwrap();
+    let client = cached(&server, 1);
+    let alice = client.register("alice", "hunter22", ALICE_ETH).unwrap();
+
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "alice");
+    rename(&server, alice, "Alicia");
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "alice");
+    client.invalidate_uuid(alice);
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "Alicia");
+
+    // "Does not exist" is remembered too.
+    assert!(client.username_to_uuid("bob").is_err());
+    let bob = uncached.register("bob", "hunter22", BOB_ETH).unwrap();
+    assert!(client.username_to_uuid("BOB").is_err());
+    client.invalidate_username("bob");
+    assert_eq!(client.username_to_uuid("bob").unwrap(), bob);
+
+    // With room for one answer, looking up bob evicts alice.
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "Alicia");
+    assert_eq!(client.uuid_to_username(bob).unwrap(), "bob");
+    rename(&server, alice, "alice");
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "alice");
+}
+
+#[test]
+fn answers_expire() {
+    let server = start();
+    let client = ClientBuilder::new(&server.endpoint(""))
+        .cache_capacity(10)
+        .negative_cache_ttl(Duration::from_secs(2))
+        .build()
+        .unwrap();
+    client.register("alice", "hunter22", ALICE_ETH).unwrap();
+    assert_eq!(client.eth_to_info(ALICE_ETH).unwrap().actived, -1);
+    assert!(client.eth_to_info(BOB_ETH).is_err());
+
+    // Activating through the client forgets the cached address.
+    client.eth_active(ALICE_ETH).unwrap();
+    assert_eq!(client.eth_to_info(ALICE_ETH).unwrap().actived, 1);
+
+    AuthClient::new(&server.endpoint("")).unwrap().register("bob", "hunter22", BOB_ETH).unwrap();
+    assert!(client.eth_to_info(BOB_ETH).is_err());
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(client.eth_to_info(BOB_ETH).unwrap().username, "bob");
+}
+
+#[test]
+fn renames_from_the_event_stream_are_applied() {
+    let server = start();
+    let client = Arc::new(cached(&server, 10));
+    let alice = client.register("alice", "hunter22", ALICE_ETH).unwrap();
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "alice");
+    assert_eq!(client.username_to_uuid("alice").unwrap(), alice);
+
+    let (events_tx, events_rx) = mpsc::channel();
+    let subscriber = client.clone();
+    thread::spawn(move || {
+        subscriber.subscribe_events(EVENT_TOKEN, Some(0), |event| {
+            events_tx.send(event).unwrap();
+            false
+        })
+    });
+    rename(&server, alice, "Champion");
+    match events_rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+        StreamEvent::Account(record) => assert!(matches!(record.event, AccountEvent::UsernameChanged { .. })),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "Champion");
+    assert!(client.username_to_uuid("alice").is_err());
+    assert_eq!(client.username_to_uuid("champion").unwrap(), alice);
+
+    // A gap in the stream empties the cache.
+    rename(&server, alice, "Legend");
+    client.apply_event(&StreamEvent::Lost { cursor: 9 });
+    assert_eq!(client.uuid_to_username(alice).unwrap(), "Legend");
+}
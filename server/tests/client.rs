@@ -0,0 +1,164 @@
+//! Every `authc::AuthClient` method against a real server, on both API
+//! versions where the method has a route on both.
+
+mod common;
+
+use authc::{ApiVersion, AsyncAuthClient, AuthClient, AuthClientError, RegisterError, Uuid};
+use common::{mail_file, TestServer};
+
+const ALICE_ETH: &str = "0x00000000000000000000000000000000000000a1";
+const BOB_ETH: &str = "0x00000000000000000000000000000000000000b0";
+
+fn client(server: &TestServer, api: ApiVersion) -> AuthClient {
+    AuthClient::new(&server.endpoint("")).unwrap().with_api_version(api)
+}
+
+fn status<T: std::fmt::Debug>(result: Result<T, AuthClientError>) -> u16 {
+    match result {
+        Err(AuthClientError::ServerError(status, _)) => status,
+        other => panic!("expected a server error, got {:?}", other),
+    }
+}
+
+#[test]
+fn lookups_on_both_api_versions() {
+    let server = TestServer::start();
+    let setup = client(&server, ApiVersion::V1);
+    setup.register("Alice", "hunter22", ALICE_ETH).unwrap();
+    setup.register("bob", "hunter22", BOB_ETH).unwrap();
+    let alice = setup.username_to_uuid("alice").unwrap();
+
+    for api in [ApiVersion::Legacy, ApiVersion::V1] {
+        let client = client(&server, api);
+        assert!(client.ping().unwrap().starts_with("Pong!"));
+        assert_eq!(client.username_to_uuid("ALICE").unwrap(), alice);
+        assert_eq!(client.uuid_to_username(alice).unwrap(), "Alice");
+
+        let info = client.eth_to_info(ALICE_ETH).unwrap();
+        assert_eq!((info.uuid, info.username.as_str(), info.actived), (alice, "Alice", -1));
+        let info = client.username_to_info("alice").unwrap();
+        assert_eq!((info.uuid, info.ethaddr.as_str()), (alice, ALICE_ETH));
+        let info = client.uuid_to_info(alice).unwrap();
+        assert_eq!((info.username.as_str(), info.ethaddr.as_str()), ("Alice", ALICE_ETH));
+        assert!(client.eth_to_info("0x00000000000000000000000000000000000000ff").is_err());
+
+        let uuids = client.usernames_to_uuids(&["alice", "nobody"]).unwrap();
+        assert_eq!((uuids["alice"], uuids["nobody"]), (Some(alice), None));
+        let names = client.uuids_to_usernames(&[alice, Uuid::nil()]).unwrap();
+        assert_eq!((names[&alice].as_deref(), names[&Uuid::nil()].as_deref()), (Some("Alice"), None));
+
+        let token = client.sign_in("alice", "hunter22").unwrap();
+        assert_eq!(client.validate(token).unwrap(), alice);
+    }
+
+    // Only on `/v1`.
+    let info = setup.user_info(alice).unwrap();
+    assert_eq!((info.username.as_str(), info.ethaddr.as_str()), ("Alice", ALICE_ETH));
+    assert_eq!(setup.user_info_by_name("alice").unwrap().uuid, alice);
+    assert_eq!(setup.user_info_by_eth(ALICE_ETH).unwrap().uuid, alice);
+    assert_eq!(status(setup.user_info(Uuid::nil())), 404);
+    // Names are sent as one path segment, so these can't reach Alice.
+    for name in ["alice?x", "alice#x", "x/../alice", "alice%3F"] {
+        assert_eq!(status(setup.user_info_by_name(name)), 404, "{}", name);
+        assert!(setup.username_to_uuid(name).is_err(), "{}", name);
+    }
+    assert_eq!(status(setup.user_info_by_eth(format!("{}?x", ALICE_ETH))), 404);
+    let found = setup.ethaddrs_to_info(&[ALICE_ETH, "0x00000000000000000000000000000000000000ff"]).unwrap();
+    assert_eq!(found[ALICE_ETH].as_ref().unwrap().uuid, alice);
+    assert!(found["0x00000000000000000000000000000000000000ff"].is_none());
+
+    let page = setup.search_users("al", Some(10), None).unwrap();
+    assert_eq!(page.users.len(), 1);
+    assert_eq!(page.users[0].uuid, alice);
+    assert_eq!(status(setup.set_searchable(alice, "wrong", false)), 401);
+    setup.set_searchable(alice, "hunter22", false).unwrap();
+    assert!(setup.search_users("al", None, None).unwrap().users.is_empty());
+    assert_eq!(status(setup.search_users("a", None, None)), 400);
+}
+
+fn refusal(result: Result<Uuid, AuthClientError>) -> RegisterError {
+    match result {
+        Err(AuthClientError::Register(refused)) => refused,
+        other => panic!("expected a refused registration, got {:?}", other),
+    }
+}
+
+#[test]
+fn register_returns_the_uuid_or_why_it_was_refused() {
+    let server = TestServer::start();
+    for (api, name, eth) in [(ApiVersion::Legacy, "alice", ALICE_ETH), (ApiVersion::V1, "bob", BOB_ETH)] {
+        let client = client(&server, api);
+        let uuid = client.register(name, "hunter22", eth).unwrap();
+        assert_eq!(client.username_to_uuid(name).unwrap(), uuid);
+
+        let other_eth = "0x00000000000000000000000000000000000000cc";
+        assert_eq!(refusal(client.register(name.to_uppercase(), "hunter22", other_eth)), RegisterError::UsernameTaken);
+        assert_eq!(refusal(client.register("carol", "hunter22", eth)), RegisterError::EthaddrTaken);
+        assert!(matches!(refusal(client.register("carol", "hunter22", "0x12")), RegisterError::Invalid(_)));
+        assert!(matches!(refusal(client.register("c", "hunter22", other_eth)), RegisterError::Invalid(_)));
+    }
+
+    // Refusals are told apart by their code, whatever the message says.
+    let resp = reqwest::blocking::Client::new()
+        .post(&server.endpoint("v1/users"))
+        .body(format!(r#"{{"username":"Bob","password":"123456","ethaddr":"{}"}}"#, ALICE_ETH))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+    assert_eq!(resp.headers()["X-Error-Code"], "username_taken");
+}
+
+#[test]
+fn account_changes_on_both_api_versions() {
+    let mails = mail_file("client");
+    let server = TestServer::start_with(&[
+        ("AUTH_MAIL_TRANSPORT", "file"),
+        ("AUTH_MAIL_FILE", mails.to_str().unwrap()),
+    ]);
+    let setup = client(&server, ApiVersion::Legacy);
+    setup.register("alice", "hunter22", ALICE_ETH).unwrap();
+    let alice = setup.username_to_uuid("alice").unwrap();
+
+    for (api, password) in [(ApiVersion::Legacy, "legacy-pw"), (ApiVersion::V1, "v1-password")] {
+        let client = client(&server, api);
+        client.eth_active(ALICE_ETH).unwrap();
+        assert_eq!(client.eth_to_info(ALICE_ETH).unwrap().actived, 1);
+
+        client.change_password(ALICE_ETH, password).unwrap();
+        assert!(client.sign_in("alice", password).is_ok());
+        assert!(client.change_password(BOB_ETH, password).is_err());
+    }
+
+    setup.change_password_by_uuid(alice, "v1-password", "by-uuid").unwrap();
+    assert!(setup.sign_in("alice", "by-uuid").is_ok());
+    assert_eq!(status(setup.change_password_by_uuid(alice, "wrong", "taken-over")), 401);
+    assert_eq!(status(setup.change_password_by_uuid(Uuid::nil(), "by-uuid", "by-uuid")), 404);
+    assert!(setup.sign_in("alice", "by-uuid").is_ok());
+
+    setup.set_email(alice, "by-uuid", "alice@example.com").unwrap();
+    assert!(std::fs::read_to_string(&mails).unwrap().contains("alice@example.com"));
+    assert_eq!(status(setup.set_email(alice, "wrong", "alice@example.com")), 401);
+
+    let _ = std::fs::remove_file(&mails);
+}
+
+#[test]
+fn async_client_has_the_same_methods() {
+    let server = TestServer::start();
+    let client = AsyncAuthClient::new(&server.endpoint("")).unwrap();
+    let mut runtime = tokio02::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        client.register("alice", "hunter22", ALICE_ETH).await.unwrap();
+        let alice = client.username_to_uuid("alice").await.unwrap();
+        assert_eq!(client.uuid_to_info(alice).await.unwrap().ethaddr, ALICE_ETH);
+        client.eth_active(ALICE_ETH).await.unwrap();
+        assert_eq!(client.user_info_by_eth(ALICE_ETH).await.unwrap().actived, 1);
+        client.change_password(ALICE_ETH, "changed").await.unwrap();
+        assert!(client.sign_in("alice", "changed").await.is_ok());
+        assert_eq!(client.search_users("al", None, None).await.unwrap().users.len(), 1);
+    });
+}
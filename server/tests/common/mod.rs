@@ -0,0 +1,174 @@
+//! Helpers shared by the integration tests, which drive a real `auth-server`
+//! process listening on a free local port with a throwaway database.
+
+#![allow(dead_code)]
+
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct TestServer {
+    child: Child,
+    pub url: String,
+    pub db: PathBuf,
+    port: u16,
+    env: Vec<(String, String)>,
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("no free port")
+        .port()
+}
+
+fn spawn(port: u16, db: &Path, env: &[(String, String)]) -> Child {
+    let child = Command::new(env!("CARGO_BIN_EXE_auth-server"))
+        .env("AUTH_ADDR", format!("127.0.0.1:{}", port))
+        .env("AUTH_DB_DIR", db)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start auth-server");
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "auth-server did not start listening");
+        thread::sleep(Duration::from_millis(20));
+    }
+    child
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        Self::start_with(&[])
+    }
+
+    /// Starts the server with extra environment variables set.
+    pub fn start_with(env: &[(&str, &str)]) -> Self {
+        let db = std::env::temp_dir().join(format!(
+            "auth-test-{}-{}.db",
+            std::process::id(),
+            free_port()
+        ));
+        let _ = std::fs::remove_file(&db);
+        Self::start_on(db, env)
+    }
+
+    /// Starts the server on an existing database, which is removed when the
+    /// server is dropped.
+    pub fn start_on(db: PathBuf, env: &[(&str, &str)]) -> Self {
+        let port = free_port();
+        let env: Vec<(String, String)> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+
+        Self {
+            child: spawn(port, &db, &env),
+            url: format!("http://127.0.0.1:{}/", port),
+            db,
+            port,
+            env,
+        }
+    }
+
+    /// Stops the server and starts it again on the same port and database.
+    pub fn restart(&mut self) {
+        self.terminate();
+        self.start_again();
+    }
+
+    /// Starts the server again after `terminate`.
+    pub fn start_again(&mut self) {
+        self.child = spawn(self.port, &self.db, &self.env);
+    }
+
+    /// Sends SIGTERM and waits for the server to exit.
+    pub fn terminate(&mut self) -> ExitStatus {
+        let sent = Command::new("kill")
+            .args(["-TERM", &self.child.id().to_string()])
+            .status()
+            .expect("failed to run kill");
+        assert!(sent.success());
+        self.child.wait().expect("auth-server was not running")
+    }
+
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url, path.trim_start_matches('/'))
+    }
+
+    /// Registers `username` with password `123456` and an eth address derived
+    /// from `n`, returning the new uuid.
+    pub fn register(&self, username: &str, n: u8) -> String {
+        self.register_with_password(username, n, "123456")
+    }
+
+    /// Like `register`, with the password sent as is.
+    pub fn register_with_password(&self, username: &str, n: u8, password: &str) -> String {
+        let client = reqwest::blocking::Client::new();
+        let resp = client
+            .post(&self.endpoint("v1/users"))
+            .json(&serde_json::json!({
+                "username": username,
+                "password": password,
+                "ethaddr": format!("0x{:040x}", n),
+            }))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 201);
+        let info: serde_json::Value = client
+            .get(&self.endpoint(&format!("v1/users/by-name/{}", username)))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        info["uuid"].as_str().unwrap().to_owned()
+    }
+}
+
+/// Runs one of the offline commands of `auth-server` against `db`.
+pub fn run_offline(db: &Path, args: &[&str]) -> Output {
+    run_offline_with(db, &[], args)
+}
+
+/// Like `run_offline`, with extra environment variables set.
+pub fn run_offline_with(db: &Path, env: &[(&str, &str)], args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_auth-server"))
+        .args(args)
+        .env("AUTH_DB_DIR", db)
+        .envs(env.iter().copied())
+        .output()
+        .expect("failed to run auth-server")
+}
+
+/// A fresh path for a database or export file.
+pub fn temp_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("auth-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// The prehash `authc` sends instead of the password the player typed.
+pub fn prehash(password: &str) -> String {
+    let salt = fxhash::hash64(password);
+    let bytes = argon2::hash_raw(password.as_bytes(), &salt.to_le_bytes(), &argon2::Config::default()).unwrap();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A fresh path for the file mail transport.
+pub fn mail_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("auth-test-mail-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.db);
+        let mut lock = self.db.clone().into_os_string();
+        lock.push(".lock");
+        let _ = std::fs::remove_file(lock);
+    }
+}
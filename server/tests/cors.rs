@@ -0,0 +1,123 @@
+//! The CORS policies: allowed and rejected origins, preflights, credentials
+//! and the separate policy of the admin routes.
+
+mod common;
+
+use common::TestServer;
+use reqwest::blocking::{Client, Response};
+use reqwest::Method;
+
+const GAME: &str = "https://game.example";
+const ADMIN: &str = "https://admin.example";
+
+fn get(server: &TestServer, path: &str, origin: Option<&str>) -> Response {
+    let mut request = Client::new().get(&server.endpoint(path));
+    if let Some(origin) = origin {
+        request = request.header("Origin", origin);
+    }
+    request.send().unwrap()
+}
+
+fn preflight(server: &TestServer, path: &str, origin: &str, method: &str, headers: Option<&str>) -> Response {
+    let mut request = Client::new()
+        .request(Method::OPTIONS, &server.endpoint(path))
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", method);
+    if let Some(headers) = headers {
+        request = request.header("Access-Control-Request-Headers", headers);
+    }
+    request.send().unwrap()
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response.headers().get(name).map(|v| v.to_str().unwrap())
+}
+
+#[test]
+fn any_origin_by_default_but_not_on_admin_routes() {
+    let server = TestServer::start_with(&[("AUTH_CORS_CREDENTIALS", "true")]);
+
+    let response = get(&server, "ping", Some(GAME));
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("*"));
+    assert_eq!(header(&response, "Vary"), None);
+    // Credentials are never allowed with a wildcard.
+    assert_eq!(header(&response, "Access-Control-Allow-Credentials"), None);
+
+    let response = preflight(&server, "v1/users", GAME, "POST", Some("content-type"));
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("*"));
+    assert_eq!(header(&response, "Access-Control-Allow-Methods"), Some("GET, POST, PUT, DELETE"));
+    assert_eq!(header(&response, "Access-Control-Max-Age"), Some("600"));
+    assert_eq!(header(&response, "Allow"), Some("POST, OPTIONS"));
+
+    let response = preflight(&server, "admin/webhooks", GAME, "GET", None);
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+    assert_eq!(header(&response, "Vary"), Some("Origin"));
+}
+
+#[test]
+fn origin_lists_methods_headers_and_credentials() {
+    let server = TestServer::start_with(&[
+        ("AUTH_CORS_ORIGINS", GAME),
+        ("AUTH_CORS_METHODS", "GET, POST"),
+        ("AUTH_CORS_HEADERS", "Content-Type"),
+        ("AUTH_CORS_CREDENTIALS", "true"),
+        ("AUTH_CORS_MAX_AGE", "60"),
+    ]);
+
+    let response = get(&server, "v1/ping", Some(GAME));
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(GAME));
+    assert_eq!(header(&response, "Access-Control-Allow-Credentials"), Some("true"));
+    assert_eq!(header(&response, "Vary"), Some("Origin"));
+
+    // Answers without CORS headers vary by origin too, or a cache could
+    // hand them to an allowed origin.
+    for origin in [Some("https://evil.example"), None] {
+        let response = get(&server, "v1/ping", origin);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&response, "Access-Control-Allow-Credentials"), None);
+        assert_eq!(header(&response, "Vary"), Some("Origin"));
+    }
+
+    let response = preflight(&server, "v1/users", GAME, "POST", Some("Content-Type"));
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(GAME));
+    assert_eq!(header(&response, "Access-Control-Allow-Methods"), Some("GET, POST"));
+    assert_eq!(header(&response, "Access-Control-Allow-Headers"), Some("Content-Type"));
+    assert_eq!(header(&response, "Access-Control-Max-Age"), Some("60"));
+
+    // A method the policy or the route doesn't allow, an unlisted header or
+    // origin: a plain answer without CORS headers.
+    for (origin, path, method, headers) in [
+        (GAME, "v1/users/00000000-0000-0000-0000-000000000000/password", "PUT", None),
+        (GAME, "v1/users", "DELETE", None),
+        (GAME, "v1/users", "POST", Some("Content-Type, X-Debug")),
+        ("https://evil.example", "v1/users", "POST", None),
+    ] {
+        let response = preflight(&server, path, origin, method, headers);
+        assert_eq!(response.status().as_u16(), 204);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&response, "Access-Control-Allow-Methods"), None);
+        assert_eq!(header(&response, "Vary"), Some("Origin"));
+    }
+}
+
+#[test]
+fn admin_routes_have_their_own_origins() {
+    let server = TestServer::start_with(&[
+        ("AUTH_CORS_ORIGINS", GAME),
+        ("AUTH_CORS_ADMIN_ORIGINS", ADMIN),
+        ("AUTH_ADMIN_TOKEN", "cors-admin"),
+    ]);
+
+    let response = preflight(&server, "admin/webhooks", ADMIN, "GET", Some("Authorization"));
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(ADMIN));
+    let response = preflight(&server, "admin/webhooks", GAME, "GET", Some("Authorization"));
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+    let response = get(&server, "admin/webhooks", Some(ADMIN));
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(ADMIN));
+
+    let response = get(&server, "v1/ping", Some(ADMIN));
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+}
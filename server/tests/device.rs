@@ -0,0 +1,224 @@
+//! The device authorization grant, for game clients and for registered
+//! OpenID Connect clients.
+
+mod common;
+
+use common::{prehash, TestServer};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+
+const ADMIN_TOKEN: &str = "device-admin";
+/// What the player types into the approval page.
+const PASSWORD: &str = "correct-horse";
+
+fn start() -> TestServer {
+    TestServer::start_with(&[("AUTH_ADMIN_TOKEN", ADMIN_TOKEN), ("AUTH_PUBLIC_URL", "https://auth.example.com")])
+}
+
+fn start_game_login(server: &TestServer) -> Value {
+    let resp = Client::new().post(&server.endpoint("v1/device")).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    resp.json().unwrap()
+}
+
+fn poll_game_login(server: &TestServer, device: &Value) -> (u16, Value) {
+    let resp = Client::new()
+        .post(&server.endpoint("v1/device/token"))
+        .json(&json!({ "device_code": device["device_code"] }))
+        .send()
+        .unwrap();
+    (resp.status().as_u16(), resp.json().unwrap())
+}
+
+/// Submits the approval page, returning the status and the page.
+fn submit(server: &TestServer, user_code: &str, password: &str, decision: &str) -> (u16, String) {
+    let resp = Client::new()
+        .post(&server.endpoint("device"))
+        .form(&[
+            ("user_code", user_code),
+            ("username", "player"),
+            ("password", password),
+            ("decision", decision),
+        ])
+        .send()
+        .unwrap();
+    (resp.status().as_u16(), resp.text().unwrap())
+}
+
+#[test]
+fn game_client_receives_a_token_once_approved() {
+    let server = start();
+    let uuid = server.register_with_password("player", 1, &prehash(PASSWORD));
+
+    let device = start_game_login(&server);
+    let user_code = device["user_code"].as_str().unwrap();
+    assert_eq!(user_code.len(), 9);
+    assert_eq!(device["verification_uri"], "https://auth.example.com/device");
+    assert_eq!(device["interval"], 5);
+    assert_eq!(
+        device["verification_uri_complete"].as_str().unwrap(),
+        format!("https://auth.example.com/device?user_code={}", user_code)
+    );
+
+    let page = Client::new()
+        .get(&server.endpoint(&format!("device?user_code={}", user_code)))
+        .send()
+        .unwrap();
+    assert_eq!(page.headers()["x-frame-options"], "DENY");
+    assert!(page.text().unwrap().contains("A game client"));
+
+    let (status, page) = submit(&server, user_code, "wrong", "allow");
+    assert_eq!(status, 400);
+    assert!(page.contains(user_code), "the code stays filled in");
+
+    // Codes are accepted the way people type them.
+    let typed = user_code.replace('-', " ").to_lowercase();
+    let (status, page) = submit(&server, &typed, PASSWORD, "allow");
+    assert_eq!(status, 200);
+    assert!(page.contains("Device approved"));
+
+    let (status, body) = poll_game_login(&server, &device);
+    assert_eq!(status, 200);
+    let token = body["token"].clone();
+    let verified: Value = Client::new()
+        .post(&server.endpoint("v1/tokens/verify"))
+        .json(&json!({ "token": token }))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(verified["uuid"].as_str().unwrap().replace('-', ""), uuid.replace('-', ""));
+
+    // The device code is spent, and so is the user code.
+    let (status, body) = poll_game_login(&server, &device);
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "invalid_grant");
+    let (status, _) = submit(&server, user_code, PASSWORD, "allow");
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn polling_reports_pending_slow_down_and_denial() {
+    let server = start();
+    server.register_with_password("player", 1, &prehash(PASSWORD));
+
+    let waiting = start_game_login(&server);
+    let (status, body) = poll_game_login(&server, &waiting);
+    assert_eq!((status, body["error"].as_str()), (400, Some("authorization_pending")));
+    let (_, body) = poll_game_login(&server, &waiting);
+    assert_eq!(body["error"], "slow_down");
+
+    let denied = start_game_login(&server);
+    let (status, page) = submit(&server, denied["user_code"].as_str().unwrap(), "", "deny");
+    assert_eq!(status, 200);
+    assert!(page.contains("Device denied"));
+    let (_, body) = poll_game_login(&server, &denied);
+    assert_eq!(body["error"], "access_denied");
+
+    let (status, page) = submit(&server, "BCDF-GHJK", PASSWORD, "allow");
+    assert_eq!(status, 400);
+    assert!(page.contains("unknown or has expired"));
+}
+
+#[test]
+fn a_ban_revokes_approved_devices() {
+    let server = start();
+    let uuid = server.register_with_password("player", 1, &prehash(PASSWORD));
+
+    let device = start_game_login(&server);
+    let (status, _) = submit(&server, device["user_code"].as_str().unwrap(), PASSWORD, "allow");
+    assert_eq!(status, 200);
+    let resp = Client::new()
+        .put(&server.endpoint(&format!("admin/users/{}/ban", uuid)))
+        .bearer_auth(ADMIN_TOKEN)
+        .json(&json!({}))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 204);
+
+    let (status, body) = poll_game_login(&server, &device);
+    assert_eq!((status, body["error"].as_str()), (400, Some("invalid_grant")));
+}
+
+#[test]
+fn oauth_clients_poll_the_token_endpoint() {
+    let server = start();
+    server.register_with_password("player", 1, &prehash(PASSWORD));
+    let http = Client::new();
+
+    let discovery: Value = http
+        .get(&server.endpoint(".well-known/openid-configuration"))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(
+        discovery["device_authorization_endpoint"],
+        "https://auth.example.com/oauth/device_authorization"
+    );
+
+    let client: Value = http
+        .post(&server.endpoint("admin/oauth/clients"))
+        .bearer_auth(ADMIN_TOKEN)
+        .json(&json!({ "name": "Server panel", "redirect_uris": ["https://panel.example.com/cb"], "confidential": false }))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let client_id = client["client_id"].as_str().unwrap();
+
+    let resp = http
+        .post(&server.endpoint("oauth/device_authorization"))
+        .form(&[("client_id", client_id), ("scope", "profile")])
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+    assert_eq!(resp.json::<Value>().unwrap()["error"], "invalid_scope");
+
+    let device: Value = http
+        .post(&server.endpoint("oauth/device_authorization"))
+        .form(&[("client_id", client_id), ("scope", "openid profile")])
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let device_code = device["device_code"].as_str().unwrap();
+    let user_code = device["user_code"].as_str().unwrap();
+
+    let page = http
+        .get(&server.endpoint(&format!("device?user_code={}", user_code)))
+        .send()
+        .unwrap()
+        .text()
+        .unwrap();
+    assert!(page.contains("Server panel"));
+    assert!(page.contains("Your username"));
+    assert_eq!(submit(&server, user_code, PASSWORD, "allow").0, 200);
+
+    // Game clients can't redeem an OAuth client's code.
+    let (_, body) = poll_game_login(&server, &device);
+    assert_eq!(body["error"], "invalid_grant");
+
+    let resp = http
+        .post(&server.endpoint("oauth/token"))
+        .form(&[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", device_code),
+            ("client_id", client_id),
+        ])
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let tokens: Value = resp.json().unwrap();
+    assert_eq!(tokens["scope"], "openid profile");
+    assert!(tokens["id_token"].as_str().unwrap().split('.').count() == 3);
+
+    let claims: Value = http
+        .get(&server.endpoint("oauth/userinfo"))
+        .bearer_auth(tokens["access_token"].as_str().unwrap())
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(claims["preferred_username"], "player");
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use common::TestServer;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+fn mail_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("auth-test-mail-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn register(client: &reqwest::blocking::Client, server: &TestServer, username: &str, n: u8) -> String {
+    let ethaddr = format!("0x{:040x}", n);
+    let resp = client
+        .post(&server.endpoint("v1/users"))
+        .json(&json!({ "username": username, "password": "123456", "ethaddr": ethaddr }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 201);
+    let info: Value = client
+        .get(&server.endpoint(&format!("v1/users/by-name/{}", username)))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    info["uuid"].as_str().unwrap().to_owned()
+}
+
+fn set_email(client: &reqwest::blocking::Client, server: &TestServer, uuid: &str, password: &str, email: &str) -> u16 {
+    client
+        .put(&server.endpoint(&format!("v1/users/{}/email", uuid)))
+        .json(&json!({ "password": password, "email": email }))
+        .send()
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+/// The path and query of the last link mailed out.
+fn last_link(mails: &PathBuf) -> String {
+    let text = std::fs::read_to_string(mails).unwrap();
+    let start = text.rfind("/v1/").expect("no link in mail");
+    text[start..].split_whitespace().next().unwrap().to_owned()
+}
+
+fn get(client: &reqwest::blocking::Client, server: &TestServer, path: &str) -> u16 {
+    client.get(&server.endpoint(path)).send().unwrap().status().as_u16()
+}
+
+#[test]
+fn email_is_verified_by_single_use_link() {
+    let mails = mail_file("verify");
+    let server = TestServer::start_with(&[
+        ("AUTH_MAIL_TRANSPORT", "file"),
+        ("AUTH_MAIL_FILE", mails.to_str().unwrap()),
+    ]);
+    let client = reqwest::blocking::Client::new();
+    let alice = register(&client, &server, "alice", 1);
+    let bob = register(&client, &server, "bob", 2);
+
+    assert_eq!(set_email(&client, &server, &alice, "wrong1", "alice@example.com"), 401);
+    assert_eq!(set_email(&client, &server, "00000000000000000000000000000000", "123456", "alice@example.com"), 404);
+    assert_eq!(set_email(&client, &server, &alice, "123456", "not an address"), 400);
+    assert_eq!(set_email(&client, &server, &alice, "123456", "Alice@Example.com"), 202);
+
+    let mail = std::fs::read_to_string(&mails).unwrap();
+    assert!(mail.contains("To: alice@example.com\r\n"));
+    let link = last_link(&mails);
+    assert_eq!(get(&client, &server, &link), 200);
+    assert_eq!(get(&client, &server, &link), 400);
+    assert_eq!(get(&client, &server, "v1/email/verify?token=0123"), 400);
+
+    assert_eq!(set_email(&client, &server, &bob, "123456", "alice@example.com"), 409);
+
+    let _ = std::fs::remove_file(&mails);
+}
+
+#[test]
+fn newer_request_supersedes_pending_link() {
+    let mails = mail_file("supersede");
+    let server = TestServer::start_with(&[
+        ("AUTH_MAIL_TRANSPORT", "file"),
+        ("AUTH_MAIL_FILE", mails.to_str().unwrap()),
+    ]);
+    let client = reqwest::blocking::Client::new();
+    let carol = register(&client, &server, "carol", 3);
+
+    assert_eq!(set_email(&client, &server, &carol, "123456", "old@example.com"), 202);
+    let old = last_link(&mails);
+    assert_eq!(set_email(&client, &server, &carol, "123456", "new@example.com"), 202);
+    let new = last_link(&mails);
+
+    assert_eq!(get(&client, &server, &old), 400);
+    assert_eq!(get(&client, &server, &new), 200);
+
+    let _ = std::fs::remove_file(&mails);
+}
+
+#[test]
+fn unverified_email_is_purged_after_deadline() {
+    let mails = mail_file("purge");
+    let server = TestServer::start_with(&[
+        ("AUTH_MAIL_TRANSPORT", "file"),
+        ("AUTH_MAIL_FILE", mails.to_str().unwrap()),
+        ("AUTH_EMAIL_VERIFY_DEADLINE_SECS", "1"),
+    ]);
+    let client = reqwest::blocking::Client::new();
+    let dave = register(&client, &server, "dave", 4);
+
+    assert_eq!(set_email(&client, &server, &dave, "123456", "dave@example.com"), 202);
+    let link = last_link(&mails);
+    thread::sleep(Duration::from_millis(3500));
+    assert_eq!(get(&client, &server, &link), 400);
+
+    let _ = std::fs::remove_file(&mails);
+}
@@ -0,0 +1,196 @@
+//! The account event stream game servers follow, read raw and through the
+//! `authc` subscriber.
+
+mod common;
+
+use authc::{AccountEvent, AuthClient, StreamEvent};
+use common::TestServer;
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const ADMIN_TOKEN: &str = "events-admin";
+const EVENT_TOKEN: &str = "game-server-1";
+
+fn start(env: &[(&str, &str)]) -> TestServer {
+    let mut env = env.to_vec();
+    env.push(("AUTH_ADMIN_TOKEN", ADMIN_TOKEN));
+    env.push(("AUTH_EVENT_TOKENS", "other-server, game-server-1"));
+    TestServer::start_with(&env)
+}
+
+fn admin(server: &TestServer, method: reqwest::Method, path: &str, body: Option<Value>) -> u16 {
+    let request = Client::new()
+        .request(method, &server.endpoint(path))
+        .bearer_auth(ADMIN_TOKEN);
+    match body {
+        Some(body) => request.json(&body),
+        None => request,
+    }
+    .send()
+    .unwrap()
+    .status()
+    .as_u16()
+}
+
+/// Reads events off a raw stream until `n` arrived, returning their
+/// `(id, event, data)`.
+fn read_events(server: &TestServer, query: &str, n: usize) -> Vec<(String, String, String)> {
+    let resp = Client::new()
+        .get(&server.endpoint(&format!("v1/events{}", query)))
+        .bearer_auth(EVENT_TOKEN)
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+
+    let mut lines = BufReader::new(resp).lines();
+    let mut events = Vec::new();
+    let (mut id, mut event, mut data) = (String::new(), String::new(), String::new());
+    while events.len() < n {
+        let line = lines.next().expect("the stream ended early").unwrap();
+        if let Some(value) = line.strip_prefix("id: ") {
+            id = value.to_owned();
+        } else if let Some(value) = line.strip_prefix("event: ") {
+            event = value.to_owned();
+        } else if let Some(value) = line.strip_prefix("data: ") {
+            data = value.to_owned();
+        } else if line.is_empty() && !event.is_empty() {
+            events.push((id.clone(), event.clone(), data.clone()));
+            event.clear();
+        }
+    }
+    events
+}
+
+#[test]
+fn streams_need_an_event_or_admin_token() {
+    let server = start(&[]);
+    let http = Client::new();
+    let status = |request: reqwest::blocking::RequestBuilder| request.send().unwrap().status().as_u16();
+
+    assert_eq!(status(http.get(&server.endpoint("v1/events"))), 401);
+    assert_eq!(status(http.get(&server.endpoint("v1/events")).bearer_auth("game-server")), 401);
+    assert_eq!(status(http.get(&server.endpoint("v1/events")).bearer_auth("other-server")), 200);
+    assert_eq!(status(http.get(&server.endpoint("v1/events")).bearer_auth(ADMIN_TOKEN)), 200);
+    assert_eq!(
+        status(http.get(&server.endpoint("v1/events?after=soon")).bearer_auth(EVENT_TOKEN)),
+        400
+    );
+
+    let client = AuthClient::new(&server.endpoint("")).unwrap();
+    let result = client.subscribe_events("game-server", None, |_| true);
+    assert!(matches!(result, Err(authc::AuthClientError::ServerError(401, _))));
+}
+
+#[test]
+fn subscriber_is_told_about_account_changes() {
+    let server = start(&[]);
+    let player = server.register("player", 1);
+    let cheater = server.register("cheater", 2);
+
+    let (events_tx, events_rx) = mpsc::channel();
+    let url = server.endpoint("");
+    let subscriber = thread::spawn(move || {
+        let client = AuthClient::new(&url).unwrap();
+        client.subscribe_events(EVENT_TOKEN, Some(0), |event| {
+            // Stops once the test stopped listening.
+            events_tx.send(event).is_ok()
+        })
+    });
+
+    assert_eq!(
+        admin(&server, reqwest::Method::PUT, &format!("admin/users/{}/ban", cheater), Some(json!({ "reason": "aimbot" }))),
+        204
+    );
+    assert_eq!(admin(&server, reqwest::Method::DELETE, &format!("admin/users/{}/tokens", player), None), 204);
+    assert_eq!(
+        admin(&server, reqwest::Method::PUT, &format!("admin/users/{}/username", player), Some(json!({ "username": "Champion" }))),
+        204
+    );
+    assert_eq!(admin(&server, reqwest::Method::DELETE, &format!("admin/users/{}", cheater), None), 204);
+
+    let mut records = Vec::new();
+    while records.len() < 4 {
+        match events_rx.recv_timeout(Duration::from_secs(10)).expect("missing events") {
+            StreamEvent::Account(record) => records.push(record),
+            lost => panic!("unexpected {:?}", lost),
+        }
+    }
+    assert!(records.windows(2).all(|pair| pair[0].cursor < pair[1].cursor));
+    let simple = |uuid: uuid::Uuid| uuid.to_simple().to_string();
+    match &records[0].event {
+        AccountEvent::Banned { uuid, reason } => {
+            assert_eq!(simple(*uuid), cheater.replace('-', ""));
+            assert_eq!(reason.as_deref(), Some("aimbot"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(records[1].event, AccountEvent::SessionRevoked { .. }));
+    match &records[2].event {
+        AccountEvent::UsernameChanged { old_username, username, .. } => {
+            assert_eq!((old_username.as_str(), username.as_str()), ("player", "Champion"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(records[3].event, AccountEvent::Deleted { .. }));
+    drop(events_rx);
+    drop(subscriber);
+
+    // Deleted accounts are gone, renamed ones answer to the new name.
+    let http = Client::new();
+    let status = |path: &str| http.get(&server.endpoint(path)).send().unwrap().status().as_u16();
+    assert_eq!(status(&format!("v1/users/{}", cheater)), 404);
+    assert_eq!(status("v1/users/by-name/Champion"), 200);
+    assert_eq!(status("v1/users/by-name/player"), 404);
+
+    // Taken names and unknown accounts are refused.
+    server.register("other", 3);
+    let rename = |uuid: &str, name: &str| {
+        admin(&server, reqwest::Method::PUT, &format!("admin/users/{}/username", uuid), Some(json!({ "username": name })))
+    };
+    assert_eq!(rename(&player, "OTHER"), 400);
+    assert_eq!(rename(&player, "champion"), 204);
+    assert_eq!(rename(&cheater, "ghost"), 404);
+    assert_eq!(admin(&server, reqwest::Method::DELETE, &format!("admin/users/{}", cheater), None), 404);
+
+    // A new stream resumes after the cursor it is given.
+    let resumed = read_events(&server, &format!("?after={}", records[1].cursor), 3);
+    assert_eq!(resumed[0].0, records[2].cursor.to_string());
+    assert_eq!(resumed[0].1, "username_changed");
+    assert_eq!(resumed[2].1, "username_changed");
+    let data: Value = serde_json::from_str(&resumed[2].2).unwrap();
+    assert_eq!(data["username"], "champion");
+}
+
+#[test]
+fn resuming_past_dropped_events_reports_them_lost() {
+    let server = start(&[("AUTH_EVENT_RETENTION_SECS", "1")]);
+    let uuid = server.register("player", 1);
+    let revoke = || admin(&server, reqwest::Method::DELETE, &format!("admin/users/{}/tokens", uuid), None);
+
+    assert_eq!(revoke(), 204);
+    assert_eq!(revoke(), 204);
+    thread::sleep(Duration::from_millis(2100));
+    // Recording this one drops the first two.
+    assert_eq!(revoke(), 204);
+
+    let events = read_events(&server, "?after=1", 2);
+    assert_eq!(events[0], ("2".to_owned(), "lost".to_owned(), r#"{"cursor":2}"#.to_owned()));
+    assert_eq!(events[1].0, "3");
+    assert_eq!(events[1].1, "session_revoked");
+
+    let client = AuthClient::new(&server.endpoint("")).unwrap();
+    let mut seen = Vec::new();
+    let last = client
+        .subscribe_events(EVENT_TOKEN, Some(0), |event| {
+            seen.push(event);
+            seen.len() < 2
+        })
+        .unwrap();
+    assert_eq!(last, Some(3));
+    assert_eq!(seen[0], StreamEvent::Lost { cursor: 2 });
+}
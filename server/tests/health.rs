@@ -0,0 +1,43 @@
+mod common;
+
+use common::TestServer;
+use std::process::Command;
+
+#[test]
+fn ready_server_passes_every_check() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+
+    let live = client.get(&server.endpoint("healthz")).send().unwrap();
+    assert_eq!(live.status().as_u16(), 200);
+
+    let ready: serde_json::Value = client
+        .get(&server.endpoint("readyz"))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(ready["status"], "ok", "{}", ready);
+    for check in &["database", "migrations", "disk", "token_cleaner"] {
+        assert_eq!(ready["checks"][check]["status"], "ok", "{}", ready);
+    }
+
+    let addr = server.url.trim_start_matches("http://").trim_end_matches('/');
+    let status = Command::new(env!("CARGO_BIN_EXE_auth-server"))
+        .arg("--healthcheck")
+        .env("AUTH_ADDR", addr)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn readiness_fails_when_disk_is_short() {
+    let server = TestServer::start_with(&[("AUTH_MIN_FREE_DISK_MB", "18446744073709551615")]);
+
+    let resp = reqwest::blocking::get(&server.endpoint("readyz")).unwrap();
+    assert_eq!(resp.status().as_u16(), 503);
+    let ready: serde_json::Value = resp.json().unwrap();
+    assert_eq!(ready["checks"]["disk"]["status"], "fail");
+    assert_eq!(ready["checks"]["database"]["status"], "ok");
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::TestServer;
+use std::collections::HashMap;
+
+/// Parses the text exposition format into `series -> value`, checking that
+/// every sample belongs to a family announced by a `# TYPE` line.
+fn parse(body: &str) -> HashMap<String, f64> {
+    let mut families = Vec::new();
+    let mut samples = HashMap::new();
+    for line in body.lines() {
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let mut parts = rest.split(' ');
+            let name = parts.next().unwrap().to_owned();
+            let kind = parts.next().unwrap();
+            assert!(["counter", "gauge", "histogram"].contains(&kind), "{}", line);
+            families.push(name);
+            continue;
+        }
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let (series, value) = line.rsplit_once(' ').expect("sample without value");
+        let name = series.split('{').next().unwrap();
+        assert!(
+            families.iter().any(|f| name == f
+                || name.strip_prefix(f.as_str()).is_some_and(|s| ["_bucket", "_sum", "_count"].contains(&s))),
+            "sample {} has no TYPE line",
+            name
+        );
+        samples.insert(series.to_owned(), value.parse().expect("value is not a float"));
+    }
+    samples
+}
+
+#[test]
+fn metrics_are_scrapable() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+
+    let resp = client
+        .post(&server.endpoint("v1/tokens"))
+        .body(r#"{"username":"nobody","password":"whatever"}"#)
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+    client.get(&server.endpoint("v1/ping")).send().unwrap();
+
+    let resp = client.get(&server.endpoint("metrics")).send().unwrap();
+    assert!(resp.status().is_success());
+    let samples = parse(&resp.text().unwrap());
+
+    assert_eq!(
+        samples[r#"auth_http_requests_total{route="/v1/tokens",method="POST",status="400"}"#],
+        1.0
+    );
+    assert_eq!(
+        samples[r#"auth_http_requests_total{route="/v1/ping",method="GET",status="200"}"#],
+        1.0
+    );
+    assert_eq!(
+        samples[r#"auth_http_request_duration_seconds_count{route="/v1/ping",method="GET",status="200"}"#],
+        1.0
+    );
+    for name in &[
+        "auth_tokens_issued_total",
+        "auth_tokens_expired_total",
+        "auth_token_cache_size",
+        "auth_ratelimit_tracked_ips",
+    ] {
+        assert!(samples.contains_key(*name), "missing {}", name);
+    }
+    assert!(samples
+        .keys()
+        .any(|k| k.starts_with(r#"auth_db_query_duration_seconds_count{statement="SELECT"}"#)));
+}
@@ -0,0 +1,408 @@
+//! Conformance checks of the OpenID Connect provider, driven by a minimal
+//! relying party that does what a website using it would.
+
+mod common;
+
+use common::{prehash, TestServer};
+use reqwest::blocking::Client;
+use ring::digest::{digest, SHA256};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde_json::{json, Value};
+
+const ADMIN_TOKEN: &str = "oidc-admin";
+/// What the player types into the consent page.
+const PASSWORD: &str = "correct-horse";
+const REDIRECT_URI: &str = "http://127.0.0.1:9/callback";
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(data: &str) -> Vec<u8> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).unwrap()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                out.push(u8::from_str_radix(&text[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            }
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).unwrap()
+}
+
+/// Value of a query parameter of a URL, decoded.
+fn query_param(url: &str, name: &str) -> Option<String> {
+    let query = url.split_once('?')?.1;
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| percent_decode(value))
+    })
+}
+
+/// A stand-in for a website logging its users in through the server.
+struct RelyingParty<'a> {
+    server: &'a TestServer,
+    http: Client,
+    client_id: String,
+    client_secret: Option<String>,
+    verifier: String,
+}
+
+impl<'a> RelyingParty<'a> {
+    fn register(server: &'a TestServer, confidential: bool) -> Self {
+        let http = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let resp = http
+            .post(&server.endpoint("admin/oauth/clients"))
+            .bearer_auth(ADMIN_TOKEN)
+            .json(&json!({ "name": "Forum <beta>", "redirect_uris": [REDIRECT_URI], "confidential": confidential }))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: Value = resp.json().unwrap();
+        Self {
+            server,
+            http,
+            client_id: body["client_id"].as_str().unwrap().to_owned(),
+            client_secret: body["client_secret"].as_str().map(str::to_owned),
+            verifier: b64(&rand::random::<[u8; 32]>()),
+        }
+    }
+
+    fn authorize_params(&self, scope: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("client_id", self.client_id.clone()),
+            ("redirect_uri", REDIRECT_URI.into()),
+            ("response_type", "code".into()),
+            ("scope", scope.into()),
+            ("state", "st&te".into()),
+            ("nonce", "n-0S6_WzA2Mj".into()),
+            ("code_challenge", b64(digest(&SHA256, self.verifier.as_bytes()).as_ref())),
+            ("code_challenge_method", "S256".into()),
+        ]
+    }
+
+    /// Opens the consent page and submits it, returning the redirect target
+    /// or, if there is none, the status and page.
+    fn log_in(&self, scope: &str, form: &[(&str, &str)]) -> Result<String, (u16, String)> {
+        let params = self.authorize_params(scope);
+        let resp = self
+            .http
+            .get(&self.server.endpoint("oauth/authorize"))
+            .query(&params)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.headers()["x-frame-options"], "DENY");
+        assert!(resp.text().unwrap().contains("Forum &lt;beta&gt;"));
+
+        let mut fields: Vec<(&str, String)> = params;
+        fields.extend(form.iter().map(|(k, v)| (*k, v.to_string())));
+        let resp = self
+            .http
+            .post(&self.server.endpoint("oauth/authorize"))
+            .form(&fields)
+            .send()
+            .unwrap();
+        match resp.headers().get("location") {
+            Some(location) => {
+                assert_eq!(resp.status().as_u16(), 303);
+                Ok(location.to_str().unwrap().to_owned())
+            }
+            None => Err((resp.status().as_u16(), resp.text().unwrap())),
+        }
+    }
+
+    fn exchange(&self, code: &str, verifier: &str) -> (u16, Value) {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", REDIRECT_URI),
+            ("code_verifier", verifier),
+        ];
+        let mut req = self.http.post(&self.server.endpoint("oauth/token"));
+        match &self.client_secret {
+            Some(secret) => req = req.basic_auth(&self.client_id, Some(secret)),
+            None => form.push(("client_id", &self.client_id)),
+        }
+        let resp = req.form(&form).send().unwrap();
+        assert_eq!(resp.headers()["cache-control"], "no-store");
+        (resp.status().as_u16(), resp.json().unwrap())
+    }
+
+    /// Checks the signature of an ID token against the published keys and
+    /// returns its claims.
+    fn verify_id_token(&self, token: &str) -> Value {
+        let discovery: Value = self
+            .http
+            .get(&self.server.endpoint(".well-known/openid-configuration"))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        let jwks_uri = discovery["jwks_uri"].as_str().unwrap();
+        let jwks: Value = self
+            .http
+            .get(&self.server.endpoint(&jwks_uri[jwks_uri.find("/oauth/").unwrap()..]))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+
+        let parts: Vec<&str> = token.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let header: Value = serde_json::from_slice(&unb64(parts[0])).unwrap();
+        assert_eq!(header["alg"], "ES256");
+        let key = jwks["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|key| key["kid"] == header["kid"])
+            .expect("signing key is published");
+        let mut point = vec![4];
+        point.extend(unb64(key["x"].as_str().unwrap()));
+        point.extend(unb64(key["y"].as_str().unwrap()));
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &point)
+            .verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &unb64(parts[2]))
+            .expect("ID token signature is valid");
+
+        let claims: Value = serde_json::from_slice(&unb64(parts[1])).unwrap();
+        assert_eq!(claims["iss"], discovery["issuer"]);
+        assert_eq!(claims["aud"], self.client_id.as_str());
+        assert_eq!(claims["nonce"], "n-0S6_WzA2Mj");
+        assert!(claims["exp"].as_i64().unwrap() > claims["iat"].as_i64().unwrap());
+        claims
+    }
+}
+
+fn start() -> TestServer {
+    TestServer::start_with(&[("AUTH_ADMIN_TOKEN", ADMIN_TOKEN), ("AUTH_PUBLIC_URL", "https://auth.example.com")])
+}
+
+#[test]
+fn authorization_code_flow_with_pkce() {
+    let server = start();
+    let uuid = server.register_with_password("judy", 1, &prehash(PASSWORD));
+    let rp = RelyingParty::register(&server, false);
+    assert!(rp.client_secret.is_none());
+
+    let location = rp
+        .log_in("openid profile ethaddr", &[("username", "Judy"), ("password", PASSWORD), ("decision", "allow")])
+        .unwrap_or_else(|(status, _)| panic!("login failed with {}", status));
+    assert!(location.starts_with(REDIRECT_URI));
+    assert_eq!(query_param(&location, "state").as_deref(), Some("st&te"));
+    let code = query_param(&location, "code").unwrap();
+
+    assert_eq!(rp.exchange(&code, &b64(&[7; 32])).0, 400);
+    // A failed exchange spends the code as well.
+    let (status, body) = rp.exchange(&code, &rp.verifier);
+    assert_eq!((status, body["error"].as_str()), (400, Some("invalid_grant")));
+
+    let location = rp
+        .log_in("openid profile ethaddr", &[("username", "judy"), ("password", PASSWORD), ("decision", "allow")])
+        .unwrap();
+    let code = query_param(&location, "code").unwrap();
+    let (status, tokens) = rp.exchange(&code, &rp.verifier);
+    assert_eq!(status, 200);
+    assert_eq!(tokens["token_type"], "Bearer");
+    assert_eq!(rp.exchange(&code, &rp.verifier).0, 400);
+
+    let claims = rp.verify_id_token(tokens["id_token"].as_str().unwrap());
+    assert_eq!(claims["sub"], uuid.as_str());
+    assert_eq!(claims["preferred_username"], "judy");
+    assert_eq!(claims["ethaddr"], format!("0x{:040x}", 1));
+
+    let userinfo: Value = rp
+        .http
+        .get(&server.endpoint("oauth/userinfo"))
+        .bearer_auth(tokens["access_token"].as_str().unwrap())
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(userinfo["sub"], uuid.as_str());
+    assert_eq!(userinfo["uuid"], uuid.as_str());
+    assert_eq!(userinfo["username"], "judy");
+    let resp = rp.http.get(&server.endpoint("oauth/userinfo")).bearer_auth("nope").send().unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[test]
+fn scopes_limit_claims_and_consent_can_be_denied() {
+    let server = start();
+    server.register_with_password("ken", 2, &prehash(PASSWORD));
+    let rp = RelyingParty::register(&server, true);
+
+    let (status, page) = rp
+        .log_in("openid", &[("username", "ken"), ("password", "wrong1"), ("decision", "allow")])
+        .unwrap_err();
+    assert_eq!(status, 400);
+    assert!(page.contains("incorrect"));
+
+    let location = rp.log_in("openid", &[("decision", "deny")]).unwrap();
+    assert_eq!(query_param(&location, "error").as_deref(), Some("access_denied"));
+
+    let location = rp
+        .log_in("openid", &[("username", "ken"), ("password", PASSWORD), ("decision", "allow")])
+        .unwrap();
+    let code = query_param(&location, "code").unwrap();
+    let (status, tokens) = rp.exchange(&code, &rp.verifier);
+    assert_eq!(status, 200);
+    let claims = rp.verify_id_token(tokens["id_token"].as_str().unwrap());
+    assert!(claims.get("preferred_username").is_none());
+    assert!(claims.get("ethaddr").is_none());
+
+    // A confidential client can't redeem codes without its secret.
+    let location = rp
+        .log_in("openid", &[("username", "ken"), ("password", PASSWORD), ("decision", "allow")])
+        .unwrap();
+    let code = query_param(&location, "code").unwrap();
+    let resp = rp
+        .http
+        .post(&server.endpoint("oauth/token"))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("client_id", rp.client_id.as_str()),
+            ("code_verifier", rp.verifier.as_str()),
+        ])
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[test]
+fn authorization_requests_are_validated() {
+    let server = start();
+    let rp = RelyingParty::register(&server, false);
+    let authorize = |params: &[(&str, String)]| {
+        rp.http
+            .get(&server.endpoint("oauth/authorize"))
+            .query(params)
+            .send()
+            .unwrap()
+    };
+
+    // Unregistered redirect URIs are never redirected to.
+    let mut params = rp.authorize_params("openid");
+    params[1].1 = "https://evil.example.com/callback".into();
+    let resp = authorize(&params);
+    assert_eq!(resp.status().as_u16(), 400);
+    assert!(resp.headers().get("location").is_none());
+
+    let mut params = rp.authorize_params("openid");
+    params.retain(|(name, _)| !name.starts_with("code_challenge"));
+    let resp = authorize(&params);
+    assert_eq!(resp.status().as_u16(), 303);
+    let location = resp.headers()["location"].to_str().unwrap();
+    assert_eq!(query_param(location, "error").as_deref(), Some("invalid_request"));
+
+    let resp = authorize(&rp.authorize_params("profile"));
+    let location = resp.headers()["location"].to_str().unwrap();
+    assert_eq!(query_param(location, "error").as_deref(), Some("invalid_scope"));
+
+    let resp = rp
+        .http
+        .post(&server.endpoint("admin/oauth/clients"))
+        .bearer_auth(ADMIN_TOKEN)
+        .json(&json!({ "name": "Bad", "redirect_uris": ["http://example.com/callback"] }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+
+    let discovery: Value = rp
+        .http
+        .get(&server.endpoint(".well-known/openid-configuration"))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(discovery["issuer"], "https://auth.example.com");
+    assert_eq!(discovery["token_endpoint"], "https://auth.example.com/oauth/token");
+    assert_eq!(discovery["code_challenge_methods_supported"], json!(["S256"]));
+}
+
+#[test]
+fn bans_and_password_changes_revoke_access_tokens() {
+    let server = start();
+    let uuid = server.register_with_password("mallory", 3, &prehash(PASSWORD));
+    let rp = RelyingParty::register(&server, false);
+    let access_token = |password: &str| {
+        let location = rp
+            .log_in("openid", &[("username", "mallory"), ("password", password), ("decision", "allow")])
+            .unwrap();
+        let (status, tokens) = rp.exchange(&query_param(&location, "code").unwrap(), &rp.verifier);
+        assert_eq!(status, 200);
+        tokens["access_token"].as_str().unwrap().to_owned()
+    };
+    let userinfo = |token: &str| {
+        rp.http
+            .get(&server.endpoint("oauth/userinfo"))
+            .bearer_auth(token)
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+    };
+    let admin = |method: reqwest::Method| {
+        let resp = rp
+            .http
+            .request(method, &server.endpoint(&format!("admin/users/{}/ban", uuid)))
+            .bearer_auth(ADMIN_TOKEN)
+            .json(&json!({}))
+            .send()
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 204);
+    };
+
+    let token = access_token(PASSWORD);
+    assert_eq!(userinfo(&token), 200);
+    // An unredeemed code goes with the ban too.
+    let location = rp
+        .log_in("openid", &[("username", "mallory"), ("password", PASSWORD), ("decision", "allow")])
+        .unwrap();
+    admin(reqwest::Method::PUT);
+    assert_eq!(userinfo(&token), 401);
+    let (status, body) = rp.exchange(&query_param(&location, "code").unwrap(), &rp.verifier);
+    assert_eq!((status, body["error"].as_str()), (400, Some("invalid_grant")));
+    admin(reqwest::Method::DELETE);
+
+    let token = access_token(PASSWORD);
+    let resp = rp
+        .http
+        .put(&server.endpoint(&format!("v1/users/{}/password", uuid)))
+        .json(&json!({ "current_password": prehash(PASSWORD), "password": prehash("battery-staple") }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 204);
+    assert_eq!(userinfo(&token), 401);
+
+    // The legacy route revokes them the same way.
+    let token = access_token("battery-staple");
+    assert_eq!(userinfo(&token), 200);
+    let resp = rp
+        .http
+        .post(&server.endpoint("change_pass"))
+        .json(&json!({ "ethaddr": format!("0x{:040x}", 3), "password": prehash(PASSWORD) }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(userinfo(&token), 401);
+}
@@ -0,0 +1,26 @@
+//! Guards `openapi.json` against drifting from the routes and payload types.
+//!
+//! When this fails after an intentional API change, regenerate the document with
+//! `cargo run -p auth-server -- --print-openapi > server/openapi.json`.
+
+use std::process::Command;
+
+#[test]
+fn committed_spec_matches_generated_spec() {
+    let output = Command::new(env!("CARGO_BIN_EXE_auth-server"))
+        .arg("--print-openapi")
+        .output()
+        .expect("failed to run auth-server");
+    assert!(output.status.success());
+
+    let generated: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("generated spec is not valid JSON");
+    let committed: serde_json::Value = serde_json::from_str(include_str!("../openapi.json"))
+        .expect("server/openapi.json is not valid JSON");
+
+    assert!(
+        generated == committed,
+        "server/openapi.json is out of date, regenerate it with \
+         `cargo run -p auth-server -- --print-openapi > server/openapi.json`"
+    );
+}
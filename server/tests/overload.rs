@@ -0,0 +1,128 @@
+//! Load beyond what the server can take is turned away with `503` and
+//! `Retry-After` instead of queueing without bound.
+
+mod common;
+
+use common::TestServer;
+use reqwest::blocking::Client;
+use serde_json::json;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+/// Signs in `n` times at once, returning the status and `Retry-After` of each
+/// answer.
+fn sign_in_burst(server: &TestServer, n: usize) -> Vec<(u16, Option<String>)> {
+    let barrier = Arc::new(Barrier::new(n));
+    let threads: Vec<_> = (0..n)
+        .map(|_| {
+            let barrier = barrier.clone();
+            let endpoint = server.endpoint("v1/tokens");
+            thread::spawn(move || {
+                barrier.wait();
+                let response = Client::new()
+                    .post(&endpoint)
+                    .json(&json!({ "username": "crowd", "password": "123456" }))
+                    .send()
+                    .unwrap();
+                let retry_after = response
+                    .headers()
+                    .get("Retry-After")
+                    .map(|v| v.to_str().unwrap().to_owned());
+                (response.status().as_u16(), retry_after)
+            })
+        })
+        .collect();
+    threads.into_iter().map(|t| t.join().unwrap()).collect()
+}
+
+/// Sends bursts until one of them is partly turned away, checking that
+/// the rejected requests carry `Retry-After: 1`.
+fn assert_sheds_load(server: &TestServer) {
+    // Even a lone request can find the only hash worker busy.
+    let registered = (0..50).any(|_| {
+        let status = Client::new()
+            .post(&server.endpoint("v1/users"))
+            .json(&json!({ "username": "crowd", "password": "123456", "ethaddr": format!("0x{:040x}", 1) }))
+            .send()
+            .unwrap()
+            .status()
+            .as_u16();
+        if status == 503 {
+            thread::sleep(Duration::from_millis(20));
+        }
+        status == 201
+    });
+    assert!(registered);
+    for _ in 0..10 {
+        let answers = sign_in_burst(server, 16);
+        for (status, retry_after) in &answers {
+            match status {
+                200 => assert_eq!(retry_after, &None),
+                503 => assert_eq!(retry_after.as_deref(), Some("1")),
+                other => panic!("unexpected status {}", other),
+            }
+        }
+        if answers.iter().any(|(status, _)| *status == 503) {
+            return;
+        }
+    }
+    panic!("no request was turned away");
+}
+
+fn metric(server: &TestServer, name: &str) -> f64 {
+    let body = Client::new().get(&server.endpoint("metrics")).send().unwrap().text().unwrap();
+    body.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+        .unwrap()
+}
+
+#[test]
+fn a_full_hash_queue_answers_503() {
+    let server = TestServer::start_with(&[("AUTH_HASH_WORKERS", "1"), ("AUTH_HASH_QUEUE", "0")]);
+    assert_sheds_load(&server);
+    assert!(metric(&server, "auth_hash_pool_rejections_total") > 0.0);
+}
+
+#[test]
+fn requests_beyond_the_handler_threads_answer_503() {
+    let server = TestServer::start_with(&[("AUTH_BLOCKING_THREADS", "1")]);
+    assert_sheds_load(&server);
+    assert!(metric(&server, "auth_overload_rejections_total") > 0.0);
+    assert_eq!(metric(&server, "auth_hash_pool_rejections_total"), 0.0);
+}
+
+#[test]
+fn open_event_streams_leave_the_handler_threads_free() {
+    let server = TestServer::start_with(&[
+        ("AUTH_BLOCKING_THREADS", "2"),
+        ("AUTH_EVENT_STREAMS", "2"),
+        ("AUTH_EVENT_TOKENS", "game-server"),
+    ]);
+    let http = Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+    let subscribe = || {
+        http.get(&server.endpoint("v1/events"))
+            .bearer_auth("game-server")
+            .send()
+            .unwrap()
+    };
+    let streams: Vec<_> = (0..2).map(|_| subscribe()).collect();
+    assert!(streams.iter().all(|stream| stream.status().as_u16() == 200));
+    assert_eq!(subscribe().status().as_u16(), 503);
+
+    // As many requests at once as there are handler threads all get answered.
+    let barrier = Arc::new(Barrier::new(2));
+    let pings: Vec<_> = (0..2)
+        .map(|_| {
+            let (barrier, http, endpoint) = (barrier.clone(), http.clone(), server.endpoint("ping"));
+            thread::spawn(move || {
+                barrier.wait();
+                http.get(&endpoint).send().map(|resp| resp.status().as_u16())
+            })
+        })
+        .collect();
+    for ping in pings {
+        assert_eq!(ping.join().unwrap().unwrap(), 200);
+    }
+    drop(streams);
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use common::{mail_file, TestServer};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn post(client: &reqwest::blocking::Client, server: &TestServer, path: &str, body: Value) -> (u16, Value) {
+    let resp = client.post(&server.endpoint(path)).json(&body).send().unwrap();
+    let status = resp.status().as_u16();
+    (status, resp.json().unwrap_or(Value::Null))
+}
+
+fn mail_count(mails: &Path) -> usize {
+    std::fs::read_to_string(mails)
+        .map(|text| text.matches("Message-ID:").count())
+        .unwrap_or(0)
+}
+
+/// Waits for the `n`th mail, which is sent in the background, and returns
+/// the code, the second paragraph of its body.
+fn wait_for_code(mails: &Path, n: usize) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while mail_count(mails) < n {
+        assert!(Instant::now() < deadline, "no reset mail arrived");
+        thread::sleep(Duration::from_millis(20));
+    }
+    let text = std::fs::read_to_string(mails).unwrap();
+    let mail = text.split("Message-ID:").nth(n).unwrap();
+    // Headers, the first paragraph, then the code.
+    mail.split("\r\n\r\n").nth(2).unwrap().trim().to_owned()
+}
+
+/// How many passwords the server has hashed so far.
+fn hash_count(client: &reqwest::blocking::Client, server: &TestServer) -> u64 {
+    let metrics = client.get(&server.endpoint("metrics")).send().unwrap().text().unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(r#"auth_password_hash_duration_seconds_count{op="hash"} "#))
+        .map_or(0, |count| count.parse().unwrap())
+}
+
+fn verify_email(client: &reqwest::blocking::Client, server: &TestServer, mails: &Path, uuid: &str, email: &str) {
+    let resp = client
+        .put(&server.endpoint(&format!("v1/users/{}/email", uuid)))
+        .json(&json!({ "password": "123456", "email": email }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 202);
+    let text = std::fs::read_to_string(mails).unwrap();
+    let link = &text[text.rfind("/v1/").unwrap()..];
+    let link = link.split_whitespace().next().unwrap();
+    let resp = client.get(&server.endpoint(link)).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+}
+
+#[test]
+fn reset_by_mailed_code_revokes_tokens() {
+    let mails = mail_file("reset");
+    let server = TestServer::start_with(&[
+        ("AUTH_MAIL_TRANSPORT", "file"),
+        ("AUTH_MAIL_FILE", mails.to_str().unwrap()),
+    ]);
+    let client = reqwest::blocking::Client::new();
+    let erin = server.register("erin", 1);
+    verify_email(&client, &server, &mails, &erin, "erin@example.com");
+
+    let (status, body) = post(&client, &server, "v1/tokens", json!({ "username": "erin", "password": "123456" }));
+    assert_eq!(status, 200);
+    let token = body["token"].clone();
+
+    assert_eq!(post(&client, &server, "v1/password-reset", json!({ "login": "Erin" })).0, 202);
+    let first = wait_for_code(&mails, 2);
+    assert_eq!(post(&client, &server, "v1/password-reset", json!({ "login": "ERIN@example.com" })).0, 202);
+    let code = wait_for_code(&mails, 3);
+    // Asking again replaces the earlier code.
+    let (status, _) = post(
+        &client,
+        &server,
+        "v1/password-reset/complete",
+        json!({ "token": first, "password": "abcdef" }),
+    );
+    assert_eq!(status, 400);
+
+    // Passwords signup would refuse are refused before the code is spent,
+    // and unknown codes are turned away without hashing anything.
+    for password in ["abc", "not allowed!"] {
+        let (status, _) = post(&client, &server, "v1/password-reset/complete", json!({ "token": code, "password": password }));
+        assert_eq!(status, 400);
+    }
+    let hashed = hash_count(&client, &server);
+    assert!(hashed > 0);
+    let (status, _) = post(&client, &server, "v1/password-reset/complete", json!({ "token": "guess", "password": "abcdef" }));
+    assert_eq!(status, 400);
+    assert_eq!(hash_count(&client, &server), hashed);
+
+    let (status, _) = post(
+        &client,
+        &server,
+        "v1/password-reset/complete",
+        json!({ "token": code, "password": "abcdef" }),
+    );
+    assert_eq!(status, 204);
+    let (status, _) = post(
+        &client,
+        &server,
+        "v1/password-reset/complete",
+        json!({ "token": code, "password": "ghijkl" }),
+    );
+    assert_eq!(status, 400);
+
+    assert_eq!(post(&client, &server, "v1/tokens/verify", json!({ "token": token })).0, 400);
+    assert_eq!(post(&client, &server, "v1/tokens", json!({ "username": "erin", "password": "123456" })).0, 400);
+    assert_eq!(post(&client, &server, "v1/tokens", json!({ "username": "erin", "password": "abcdef" })).0, 200);
+
+    let _ = std::fs::remove_file(&mails);
+}
+
+#[test]
+fn reset_request_does_not_reveal_accounts() {
+    let mails = mail_file("reset-unknown");
+    let server = TestServer::start_with(&[
+        ("AUTH_MAIL_TRANSPORT", "file"),
+        ("AUTH_MAIL_FILE", mails.to_str().unwrap()),
+    ]);
+    let client = reqwest::blocking::Client::new();
+    // Registered, but without an email address to send the code to.
+    server.register("frank", 2);
+
+    for login in ["frank", "nobody", "nobody@example.com"] {
+        let (status, body) = post(&client, &server, "v1/password-reset", json!({ "login": login }));
+        assert_eq!(status, 202);
+        assert!(body.is_null());
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(mail_count(&mails), 0);
+
+    let _ = std::fs::remove_file(&mails);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::TestServer;
+use serde_json::{json, Value};
+
+fn register(client: &reqwest::blocking::Client, server: &TestServer, username: &str, n: u8) -> String {
+    let ethaddr = format!("0x{:040x}", n);
+    let resp = client
+        .post(&server.endpoint("v1/users"))
+        .json(&json!({ "username": username, "password": "123456", "ethaddr": ethaddr }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 201);
+    let info: Value = client
+        .get(&server.endpoint(&format!("v1/users/by-name/{}", username)))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    info["uuid"].as_str().unwrap().to_owned()
+}
+
+fn search(client: &reqwest::blocking::Client, server: &TestServer, query: &str) -> (u16, Value) {
+    let resp = client
+        .get(&server.endpoint(&format!("v1/users/search?{}", query)))
+        .send()
+        .unwrap();
+    let status = resp.status().as_u16();
+    (status, resp.json().unwrap_or(Value::Null))
+}
+
+fn names(page: &Value) -> Vec<&str> {
+    page["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["username"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn prefix_search_pages_and_respects_opt_out() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+    register(&client, &server, "Mira", 1);
+    register(&client, &server, "miranda", 2);
+    let hidden = register(&client, &server, "mirabel", 3);
+    register(&client, &server, "Mirko", 4);
+    register(&client, &server, "milo", 5);
+
+    let (status, page) = search(&client, &server, "prefix=MIR&limit=2");
+    assert_eq!(status, 200);
+    assert_eq!(names(&page), ["Mira", "mirabel"]);
+    let next = page["next"].as_str().unwrap().to_owned();
+
+    let (_, page) = search(&client, &server, &format!("prefix=mir&limit=2&after={}", next));
+    assert_eq!(names(&page), ["miranda", "Mirko"]);
+    assert!(page["next"].is_null());
+
+    let hide = |body| {
+        client
+            .put(&server.endpoint(&format!("v1/users/{}/searchable", hidden)))
+            .json(&body)
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+    };
+    assert_eq!(hide(json!({ "searchable": false })), 401);
+    assert_eq!(hide(json!({ "password": "654321", "searchable": false })), 401);
+    let (_, page) = search(&client, &server, "prefix=mir");
+    assert_eq!(names(&page).len(), 4);
+    assert_eq!(hide(json!({ "password": "123456", "searchable": false })), 204);
+
+    let (_, page) = search(&client, &server, "prefix=mir");
+    assert_eq!(names(&page), ["Mira", "miranda", "Mirko"]);
+}
+
+#[test]
+fn prefix_search_rejects_bad_queries() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+    assert_eq!(search(&client, &server, "prefix=m").0, 400);
+    assert_eq!(search(&client, &server, "prefix=m%25").0, 400);
+    assert_eq!(search(&client, &server, "prefix=mi&limit=51").0, 400);
+    assert_eq!(search(&client, &server, "").0, 400);
+}
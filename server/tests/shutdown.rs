@@ -0,0 +1,69 @@
+mod common;
+
+use common::TestServer;
+use serde_json::{json, Value};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn sigterm_finishes_in_flight_requests() {
+    let mut server = TestServer::start();
+
+    // Registration hashes the password, which keeps the request busy long
+    // enough for the signal to arrive while it is in flight.
+    let endpoint = server.endpoint("v1/users");
+    let request = thread::spawn(move || {
+        reqwest::blocking::Client::new()
+            .post(&endpoint)
+            .body(r#"{"username":"drained","password":"123456","ethaddr":"0x9c5eb6ccb92e551ec1671cdaff7b55d44a28615b"}"#)
+            .send()
+            .map(|resp| resp.status().as_u16())
+    });
+    thread::sleep(Duration::from_millis(20));
+
+    let status = server.terminate();
+    assert_eq!(status.code(), Some(0));
+    assert_eq!(request.join().unwrap().unwrap(), 201);
+}
+
+#[test]
+fn saved_tokens_expire_while_the_server_is_down() {
+    let tokens = common::temp_file("tokens.jsonl");
+    let mut server = TestServer::start_with(&[("AUTH_TOKEN_CACHE_FILE", tokens.to_str().unwrap())]);
+    server.register("sleeper", 1);
+    let client = reqwest::blocking::Client::new();
+    let sign_in = || -> Value {
+        client
+            .post(&server.endpoint("v1/tokens"))
+            .json(&json!({ "username": "sleeper", "password": "123456" }))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap()
+    };
+    let (first, second) = (sign_in()["token"].clone(), sign_in()["token"].clone());
+    let issued = Instant::now();
+    let verify = |server: &TestServer, token: &Value| {
+        client
+            .post(&server.endpoint("v1/tokens/verify"))
+            .json(&json!({ "token": token }))
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+    };
+
+    server.terminate();
+    assert_eq!(fs::metadata(&tokens).unwrap().permissions().mode() & 0o777, 0o600);
+    server.start_again();
+    assert_eq!(verify(&server, &first), 200);
+
+    // The second token has less than 15 seconds left; wait them out offline.
+    server.terminate();
+    thread::sleep(Duration::from_secs(15).saturating_sub(issued.elapsed()) + Duration::from_millis(500));
+    server.start_again();
+    assert_eq!(verify(&server, &second), 400);
+    assert!(!tokens.exists());
+}
@@ -0,0 +1,180 @@
+mod common;
+
+use common::TestServer;
+use serde_json::{json, Value};
+use ring::hmac;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn post(client: &reqwest::blocking::Client, server: &TestServer, path: &str, body: Value) -> (u16, Value) {
+    let resp = client.post(&server.endpoint(path)).json(&body).send().unwrap();
+    let status = resp.status().as_u16();
+    (status, resp.json().unwrap_or(Value::Null))
+}
+
+fn sign_in(client: &reqwest::blocking::Client, server: &TestServer, username: &str, otp: Option<&str>) -> u16 {
+    let mut body = json!({ "username": username, "password": "123456" });
+    if let Some(otp) = otp {
+        body["otp"] = otp.into();
+    }
+    post(client, server, "v1/tokens", body).0
+}
+
+fn base32_decode(text: &str) -> Vec<u8> {
+    let alphabet = "ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let (mut out, mut buffer, mut bits) = (Vec::new(), 0u64, 0);
+    for c in text.chars() {
+        buffer = (buffer << 5) | alphabet.find(c).unwrap() as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    out
+}
+
+/// RFC 4226 HOTP value of `key` for `counter`, with `digits` digits.
+fn hotp(key: &[u8], counter: u64, digits: u32) -> String {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key), &counter.to_be_bytes());
+    let mac = tag.as_ref();
+    let offset = (mac[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    format!("{:0width$}", (value & 0x7fff_ffff) % 10u32.pow(digits), width = digits as usize)
+}
+
+/// The code an authenticator app shows for `step`, per RFC 6238.
+fn code(secret: &str, step: u64) -> String {
+    hotp(&base32_decode(secret), step, 6)
+}
+
+fn current_step() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 30
+}
+
+/// Enrolls and confirms an authenticator, returning the secret, the step of
+/// the code used to confirm and the recovery codes.
+fn enable(client: &reqwest::blocking::Client, server: &TestServer, uuid: &str) -> (String, u64, Vec<String>) {
+    let (status, body) = post(client, server, &format!("v1/users/{}/2fa", uuid), json!({ "password": "123456" }));
+    assert_eq!(status, 200);
+    let secret = body["secret"].as_str().unwrap().to_owned();
+    assert!(body["uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+    assert!(body["uri"].as_str().unwrap().contains(&format!("secret={}", secret)));
+
+    let step = current_step();
+    let (status, body) = post(
+        client,
+        server,
+        &format!("v1/users/{}/2fa/confirm", uuid),
+        json!({ "password": "123456", "otp": code(&secret, step) }),
+    );
+    assert_eq!(status, 200);
+    let codes = body["recovery_codes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c.as_str().unwrap().to_owned())
+        .collect();
+    (secret, step, codes)
+}
+
+/// RFC 6238 Appendix B, SHA-1. The server accepting the codes `code`
+/// computes is only meaningful if they match the reference ones.
+#[test]
+fn codes_match_the_rfc_test_vectors() {
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    assert_eq!(base32_decode(secret), b"12345678901234567890");
+    for (time, expected) in [
+        (59u64, "94287082"),
+        (1_111_111_109, "07081804"),
+        (1_111_111_111, "14050471"),
+        (1_234_567_890, "89005924"),
+        (2_000_000_000, "69279037"),
+        (20_000_000_000, "65353130"),
+    ] {
+        assert_eq!(hotp(&base32_decode(secret), time / 30, 8), expected);
+        assert_eq!(code(secret, time / 30), expected[2..]);
+    }
+}
+
+#[test]
+fn sign_in_requires_second_factor_once_enabled() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+    let uuid = server.register("grace", 1);
+
+    let (status, _) = post(&client, &server, &format!("v1/users/{}/2fa", uuid), json!({ "password": "wrong1" }));
+    assert_eq!(status, 401);
+    let nobody = "v1/users/00000000000000000000000000000000/2fa";
+    assert_eq!(post(&client, &server, nobody, json!({ "password": "123456" })).0, 404);
+    let (status, body) = post(&client, &server, &format!("v1/users/{}/2fa", uuid), json!({ "password": "123456" }));
+    assert_eq!(status, 200);
+    let wrong = if code(body["secret"].as_str().unwrap(), current_step()) == "000000" { "111111" } else { "000000" };
+    let (status, _) = post(
+        &client,
+        &server,
+        &format!("v1/users/{}/2fa/confirm", uuid),
+        json!({ "password": "123456", "otp": wrong }),
+    );
+    assert_eq!(status, 400);
+    let (status, _) = post(
+        &client,
+        &server,
+        &format!("v1/users/{}/2fa/confirm", uuid),
+        json!({ "password": "wrong1", "otp": code(body["secret"].as_str().unwrap(), current_step()) }),
+    );
+    assert_eq!(status, 401);
+    // Not enabled until confirmed.
+    assert_eq!(sign_in(&client, &server, "grace", None), 200);
+
+    let (secret, step, recovery) = enable(&client, &server, &uuid);
+    assert_eq!(recovery.len(), 10);
+    assert_eq!(sign_in(&client, &server, "grace", None), 401);
+    assert_eq!(post(&client, &server, "generate_token", json!({ "username": "grace", "password": "123456" })).0, 401);
+    // The code used to confirm can't be replayed, the next one works once.
+    assert_eq!(sign_in(&client, &server, "grace", Some(&code(&secret, step))), 400);
+    assert_eq!(sign_in(&client, &server, "grace", Some(&code(&secret, step + 1))), 200);
+    assert_eq!(sign_in(&client, &server, "grace", Some(&code(&secret, step + 1))), 400);
+
+    let recovery_code = recovery[0].to_uppercase();
+    assert_eq!(sign_in(&client, &server, "grace", Some(&recovery_code)), 200);
+    assert_eq!(sign_in(&client, &server, "grace", Some(&recovery_code)), 400);
+    assert_eq!(sign_in(&client, &server, "grace", Some("not-a-code")), 400);
+
+    let (status, _) = post(&client, &server, &format!("v1/users/{}/2fa", uuid), json!({ "password": "123456" }));
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn admin_resets_second_factor() {
+    let server = TestServer::start_with(&[("AUTH_ADMIN_TOKEN", "s3cret-admin")]);
+    let client = reqwest::blocking::Client::new();
+    let uuid = server.register("heidi", 2);
+    enable(&client, &server, &uuid);
+    assert_eq!(sign_in(&client, &server, "heidi", None), 401);
+
+    let reset = |token: Option<&str>, uuid: &str| {
+        let mut req = client.delete(&server.endpoint(&format!("admin/users/{}/2fa", uuid)));
+        if let Some(token) = token {
+            req = req.bearer_auth(token);
+        }
+        req.send().unwrap().status().as_u16()
+    };
+    assert_eq!(reset(None, &uuid), 401);
+    assert_eq!(reset(Some("wrong"), &uuid), 401);
+    assert_eq!(reset(Some("s3cret-admin"), "00000000-0000-0000-0000-000000000000"), 404);
+    assert_eq!(reset(Some("s3cret-admin"), &uuid), 204);
+    assert_eq!(sign_in(&client, &server, "heidi", None), 200);
+}
+
+#[test]
+fn admin_routes_are_closed_without_a_token() {
+    let server = TestServer::start();
+    let client = reqwest::blocking::Client::new();
+    let uuid = server.register("ivan", 3);
+    let resp = client
+        .delete(&server.endpoint(&format!("admin/users/{}/2fa", uuid)))
+        .bearer_auth("")
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
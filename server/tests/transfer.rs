@@ -0,0 +1,180 @@
+//! Offline export and import of accounts.
+
+mod common;
+
+use common::{run_offline, temp_file, TestServer};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use std::path::Path;
+
+fn stdout(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn sign_in(server: &TestServer, username: &str, password: &str) -> u16 {
+    Client::new()
+        .post(&server.endpoint("v1/tokens"))
+        .json(&json!({ "username": username, "password": password }))
+        .send()
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+/// The accounts in `db`, as exported JSON lines.
+fn exported(db: &Path) -> Vec<Value> {
+    let output = run_offline(db, &["--export"]);
+    assert!(output.status.success());
+    stdout(&output).lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+}
+
+fn write_lines(name: &str, records: &[Value]) -> String {
+    let path = temp_file(name);
+    let lines: Vec<String> = records.iter().map(Value::to_string).collect();
+    std::fs::write(&path, lines.join("\n")).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+#[test]
+fn accounts_move_between_databases_with_their_hashes() {
+    let mut server = TestServer::start();
+    let alice = server.register("Alice", 1);
+    server.register("bob", 2);
+    server.terminate();
+
+    let accounts = exported(&server.db);
+    assert_eq!(accounts.len(), 2);
+    assert_eq!(accounts[0]["username"], "Alice");
+    assert_eq!(accounts[0]["uuid"], alice);
+    assert!(accounts[0]["pwhash"].as_str().unwrap().starts_with("$argon2"));
+
+    let output = run_offline(&server.db, &["--export", "--format", "csv", "--no-hashes"]);
+    let csv = stdout(&output);
+    assert!(csv.starts_with("uuid,username,ethaddr,actived,pwhash,"));
+    assert_eq!(csv.lines().count(), 3);
+    assert!(!csv.contains("$argon2"));
+
+    let csv_path = temp_file("export.csv");
+    let output = run_offline(&server.db, &["--export", "--output", csv_path.to_str().unwrap()]);
+    assert!(output.status.success());
+
+    let target = temp_file("moved.db");
+    let output = run_offline(&target, &["--import", csv_path.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).contains("inserted:    2"));
+
+    let moved = TestServer::start_on(target, &[]);
+    assert_eq!(sign_in(&moved, "alice", "123456"), 200);
+    let info: Value = Client::new()
+        .get(&moved.endpoint("v1/users/by-eth/0x0000000000000000000000000000000000000002"))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(info["username"], "bob");
+}
+
+#[test]
+fn conflicts_fail_skip_or_overwrite() {
+    let db = temp_file("conflicts.db");
+    let existing = "6f1a7b3e-0d5c-4c8e-9b1a-2f3e4d5c6b7a";
+    let newcomer = "0b9c8d7e-6f5a-4b3c-8d2e-1f0a9b8c7d6e";
+    let carol = "5e4d3c2b-1a09-4f8e-a7d6-c5b4a3928170";
+    let base = write_lines(
+        "base.jsonl",
+        &[json!({ "uuid": existing, "username": "Alice", "ethaddr": "0x00000000000000000000000000000000000000AA" })],
+    );
+    assert!(run_offline(&db, &["--import", &base]).status.success());
+
+    let incoming = write_lines(
+        "incoming.jsonl",
+        &[
+            json!({ "uuid": newcomer, "username": "ALICE" }),
+            json!({ "uuid": carol, "username": "carol", "banned": true }),
+        ],
+    );
+
+    let output = run_offline(&db, &["--import", &incoming]);
+    assert!(!output.status.success());
+    let report = stdout(&output);
+    assert!(report.contains("conflicts:   1"), "{}", report);
+    assert!(report.contains(&format!("line 1 (ALICE): username taken by {}", existing.replace('-', ""))));
+    assert_eq!(exported(&db).len(), 1);
+
+    let output = run_offline(&db, &["--import", &incoming, "--on-conflict", "skip", "--dry-run"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("inserted:    1"));
+    assert!(stdout(&output).contains("Dry run"));
+    assert_eq!(exported(&db).len(), 1);
+
+    assert!(run_offline(&db, &["--import", &incoming, "--on-conflict", "skip"]).status.success());
+    let accounts = exported(&db);
+    assert_eq!(accounts.len(), 2);
+    assert_eq!(accounts[1]["username"], "carol");
+    assert_eq!(accounts[1]["banned"], true);
+    assert!(accounts[1].get("ethaddr").is_none());
+
+    // Alice gives way to the newcomer, carol is updated in place.
+    let output = run_offline(&db, &["--import", &incoming, "--on-conflict", "overwrite"]);
+    assert!(output.status.success());
+    let report = stdout(&output);
+    assert!(report.contains("inserted:    1") && report.contains("updated:     1") && report.contains("replaced:    1"));
+    let accounts = exported(&db);
+    assert_eq!(accounts.len(), 2);
+    assert_eq!(accounts[0]["uuid"], newcomer);
+
+    let invalid = write_lines("invalid.jsonl", &[json!({ "uuid": carol, "username": "carol", "pwhash": "hunter2" })]);
+    let output = run_offline(&db, &["--import", &invalid, "--on-conflict", "overwrite"]);
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("line 1: the password hash is not an argon2 hash"));
+    let _ = std::fs::remove_file(&db);
+}
+
+#[test]
+fn upstream_databases_without_ethaddr_are_imported() {
+    let upstream = temp_file("upstream.db");
+    let pwhash = argon2::hash_encoded(b"hunter22", b"somesaltysalt", &argon2::Config::default()).unwrap();
+    let conn = rusqlite::Connection::open(&upstream).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE users (
+            uuid TEXT NOT NULL PRIMARY KEY,
+            username TEXT NOT NULL UNIQUE,
+            display_username TEXT NOT NULL,
+            pwhash TEXT NOT NULL
+        );",
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO users VALUES ('3c2b1a0f9e8d4c7b8a6f5e4d3c2b1a09', 'veteran', 'Veteran', ?1)",
+        rusqlite::params![pwhash],
+    )
+    .unwrap();
+    drop(conn);
+
+    let target = temp_file("taken-over.db");
+    let output = run_offline(&target, &["--import", upstream.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert_eq!(exported(&target)[0]["username"], "Veteran");
+    assert!(exported(&target)[0].get("ethaddr").is_none());
+
+    let server = TestServer::start_on(target, &[]);
+    assert_eq!(sign_in(&server, "VETERAN", "hunter22"), 200);
+    let _ = std::fs::remove_file(&upstream);
+}
+
+#[test]
+fn imports_wait_for_the_server_to_stop() {
+    let mut server = TestServer::start();
+    server.register("alice", 1);
+    let incoming = write_lines("live.jsonl", &[json!({ "uuid": "0123456789abcdef0123456789abcdef", "username": "bob" })]);
+
+    let output = run_offline(&server.db, &["--import", &incoming, "--dry-run"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = run_offline(&server.db, &["--import", &incoming]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("in use by a running server"));
+
+    server.terminate();
+    assert!(run_offline(&server.db, &["--import", &incoming]).status.success());
+    assert_eq!(exported(&server.db).len(), 2);
+}
@@ -0,0 +1,126 @@
+//! Timeouts, retries and failover of `authc`, against stub providers that
+//! are down, unavailable or never answer.
+
+mod common;
+
+use authc::{AuthClientError, AuthToken, ClientBuilder};
+use common::TestServer;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Answers every request with a 503, recording the request lines and
+/// headers it got.
+fn unavailable() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let head = read_request(stream.as_ref().unwrap());
+            log.lock().unwrap().push(head);
+            let _ = stream
+                .unwrap()
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+    });
+    (url, seen)
+}
+
+/// Accepts connections and never answers.
+fn silent() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let mut open = Vec::new();
+        for stream in listener.incoming() {
+            open.push(stream);
+        }
+    });
+    url
+}
+
+/// A port nothing listens on.
+fn down() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}/", listener.local_addr().unwrap())
+}
+
+fn read_request(stream: &TcpStream) -> String {
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+            length = value.trim().parse().unwrap();
+        }
+        if line == "\r\n" {
+            break;
+        }
+        head.push_str(&line);
+    }
+    reader.take(length).read_to_end(&mut Vec::new()).unwrap();
+    head
+}
+
+#[test]
+fn lookups_are_retried_but_tokens_are_sent_once() {
+    let (url, seen) = unavailable();
+    let client = ClientBuilder::new(&url)
+        .retries(2)
+        .retry_backoff(Duration::from_millis(10))
+        .user_agent("game-server/7")
+        .build()
+        .unwrap();
+
+    assert!(matches!(client.username_to_uuid("alice"), Err(AuthClientError::ServerError(503, _))));
+    assert_eq!(seen.lock().unwrap().len(), 3);
+    assert!(seen.lock().unwrap().iter().all(|head| head.contains("user-agent: game-server/7")));
+
+    seen.lock().unwrap().clear();
+    assert!(client.validate(AuthToken { unique: 7 }).is_err());
+    assert!(client.sign_in("alice", "hunter22").is_err());
+    assert_eq!(seen.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn failing_providers_are_passed_over() {
+    let server = TestServer::start();
+    let fallback = |primary: &str| {
+        ClientBuilder::new(primary)
+            .fallback(&server.endpoint(""))
+            .retry_backoff(Duration::from_millis(10))
+            .build()
+            .unwrap()
+    };
+
+    // A lookup fails over within the call, and later calls go to the
+    // fallback while the primary is down.
+    let client = fallback(&down());
+    assert!(client.username_to_uuid("alice").is_err());
+    let alice = client.register("alice", "hunter22", "0x00000000000000000000000000000000000000a1").unwrap();
+    assert_eq!(client.username_to_uuid("alice").unwrap(), alice);
+
+    // A sign in is not sent again, but the next one goes to the fallback.
+    let client = fallback(&down());
+    assert!(matches!(client.sign_in("alice", "hunter22"), Err(AuthClientError::RequestError(_))));
+    let token = client.sign_in("alice", "hunter22").unwrap();
+    assert_eq!(client.validate(token).unwrap(), alice);
+}
+
+#[test]
+fn slow_providers_time_out() {
+    let client = ClientBuilder::new(&silent())
+        .timeout(Duration::from_millis(300))
+        .retries(0)
+        .build()
+        .unwrap();
+    let started = Instant::now();
+    assert!(matches!(client.ping(), Err(AuthClientError::RequestError(_))));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}